@@ -1,20 +1,14 @@
 mod components;
 pub mod keycloak;
+pub mod rpc;
 
 use std::rc::Rc;
 
-use anyhow::Error;
+use common::{jsonrpc::NoParams, openrpc::Discover};
 use components::organisms::NavBar;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::Closure;
-use yew::{
-    format::Nothing,
-    prelude::*,
-    services::{
-        fetch::{FetchTask, Request, Response},
-        FetchService,
-    },
-};
+use yew::{prelude::*, services::fetch::FetchTask};
 use yewtil::future::LinkFuture;
 
 use keycloak::{CallbackHandle, Keycloak, KeycloakConfig};
@@ -25,7 +19,7 @@ pub enum Msg {
     KeycloakInitialized,
     KeycloakStateChanged,
     ClickButton,
-    Response,
+    Response(rpc::Reply<Discover>),
 }
 
 pub struct Model {
@@ -93,25 +87,21 @@ impl Component for Model {
                 true
             }
             Msg::ClickButton => {
-                let get_request = Request::get("http://localhost:8083/")
-                    .header(
-                        "Authorization",
-                        format!("Bearer {}", self.keycloak.token().unwrap()),
-                    )
-                    .body(Nothing)
-                    .unwrap();
-                let task = FetchService::fetch(
-                    get_request,
-                    self._link
-                        .callback(|_r: Response<Result<String, Error>>| Msg::Response),
+                let task = rpc::call::<Discover>(
+                    self.keycloak.token(),
+                    NoParams {},
+                    self._link.callback(Msg::Response),
                 )
                 .unwrap();
                 self._pending_task = Some(task);
                 true
             }
-            Msg::Response => {
+            Msg::Response(reply) => {
                 self._pending_task = None;
-                log::debug!("Response!");
+                match reply {
+                    Ok(document) => log::debug!("{} methods available", document.methods.len()),
+                    Err(err) => log::error!("Request failed: {:?}", err),
+                }
                 true
             }
         }
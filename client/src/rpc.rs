@@ -0,0 +1,81 @@
+/*!
+Calls to the JSON-RPC API of the server
+
+Methods are called through their [`RpcMethod`] definitions, so params and results are the
+types the server reads and returns. Params are checked against their [`Validate`] rules
+before they are sent, forms get the same field errors the server would report without a
+round trip.
+*/
+
+use anyhow::Error;
+use common::errors::AppError;
+use common::jsonrpc::{JSONRPCErrorObject, JSONRPCResponse, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+use yew::{
+    callback::Callback,
+    format::Json,
+    services::{
+        fetch::{FetchTask, Request, Response},
+        FetchService,
+    },
+};
+
+const ENDPOINT: &str = "http://localhost:8083/";
+
+/// Why a call failed
+#[derive(Debug)]
+pub enum CallError {
+    /// The server could not be reached or its reply could not be read
+    Transport(Error),
+    /// The server answered with an error, see [`AppError`] for what went wrong
+    Rpc(JSONRPCErrorObject<AppError>),
+    /// The params break their rules, nothing was sent
+    Invalid(ValidationErrors),
+}
+
+/// Outcome of a call to `M`
+pub type Reply<M> = Result<<M as RpcMethod>::Result, CallError>;
+
+/// Calls `M`, with the access token when signed in. The call is aborted when the task is
+/// dropped before `callback` runs.
+///
+/// Params that break their rules are returned as [`CallError::Invalid`] right away and
+/// `callback` is not run.
+pub fn call<M>(
+    token: Option<String>,
+    params: M::Params,
+    callback: Callback<Reply<M>>,
+) -> Result<FetchTask, CallError>
+where
+    M: RpcMethod + 'static,
+    M::Params: Serialize,
+    M::Result: DeserializeOwned + 'static,
+{
+    params.validate().map_err(CallError::Invalid)?;
+    let body = M::request(params).id(Uuid::new_v4());
+
+    let mut request = Request::post(ENDPOINT).header("Content-Type", "application/json");
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    let request = request
+        .body(Json(&body))
+        .map_err(|err| CallError::Transport(err.into()))?;
+
+    FetchService::fetch(
+        request,
+        Callback::from(
+            move |response: Response<Json<Result<JSONRPCResponse<M::Result>, Error>>>| {
+                let Json(body) = response.into_body();
+                let reply = match body {
+                    Ok(response) => response.into_result().map_err(CallError::Rpc),
+                    Err(err) => Err(CallError::Transport(err)),
+                };
+                callback.emit(reply);
+            },
+        ),
+    )
+    .map_err(CallError::Transport)
+}
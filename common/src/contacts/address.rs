@@ -0,0 +1,462 @@
+/*!
+Structured postal addresses and country specific formatting
+*/
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::phone;
+use crate::validation::{Validate, ValidationErrors};
+
+/// What a postal address is used for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressLabel {
+    Home,
+    Work,
+    #[default]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct PostalAddress {
+    pub label: AddressLabel,
+    /// Street, building, apartment... one entry per line
+    pub street: Vec<String>,
+    /// City or town
+    pub locality: Option<String>,
+    /// State, province or county
+    pub region: Option<String>,
+    pub postal_code: Option<String>,
+    /// ISO 3166-1 alpha-2 code
+    pub country: String,
+}
+
+/// Order in which the parts of an address are written
+#[derive(Clone, Copy, Debug)]
+enum Layout {
+    /// `12 Main St` / `Springfield, IL 62704`
+    LocalityRegionPostalCode,
+    /// `Unter den Linden 1` / `10117 Berlin`
+    PostalCodeLocality,
+    /// `10 Downing St` / `London` / `SW1A 2AA`
+    LocalityThenPostalCode,
+    /// `〒100-0001` / `Tokyo Chiyoda` / `1-1 Chiyoda`, largest to smallest
+    PostalCodeFirst,
+}
+
+struct CountryFormat {
+    name: &'static str,
+    layout: Layout,
+    postal_code: Option<Regex>,
+}
+
+static COUNTRIES: Lazy<HashMap<&'static str, CountryFormat>> = Lazy::new(|| {
+    use Layout::*;
+
+    let formats = vec![
+        (
+            "AR",
+            "Argentina",
+            PostalCodeLocality,
+            Some(r"^[A-Z]?\d{4}([A-Z]{3})?$"),
+        ),
+        ("AT", "Austria", PostalCodeLocality, Some(r"^\d{4}$")),
+        (
+            "AU",
+            "Australia",
+            LocalityRegionPostalCode,
+            Some(r"^\d{4}$"),
+        ),
+        ("BE", "Belgium", PostalCodeLocality, Some(r"^\d{4}$")),
+        (
+            "BR",
+            "Brazil",
+            LocalityRegionPostalCode,
+            Some(r"^\d{5}-?\d{3}$"),
+        ),
+        (
+            "CA",
+            "Canada",
+            LocalityRegionPostalCode,
+            Some(r"^[A-Z]\d[A-Z] ?\d[A-Z]\d$"),
+        ),
+        ("CH", "Switzerland", PostalCodeLocality, Some(r"^\d{4}$")),
+        ("CN", "China", PostalCodeFirst, Some(r"^\d{6}$")),
+        ("CZ", "Czechia", PostalCodeLocality, Some(r"^\d{3} ?\d{2}$")),
+        ("DE", "Germany", PostalCodeLocality, Some(r"^\d{5}$")),
+        ("DK", "Denmark", PostalCodeLocality, Some(r"^\d{4}$")),
+        ("ES", "Spain", PostalCodeLocality, Some(r"^\d{5}$")),
+        ("FI", "Finland", PostalCodeLocality, Some(r"^\d{5}$")),
+        ("FR", "France", PostalCodeLocality, Some(r"^\d{5}$")),
+        (
+            "GB",
+            "United Kingdom",
+            LocalityThenPostalCode,
+            Some(r"^[A-Z]{1,2}\d[A-Z\d]? ?\d[A-Z]{2}$"),
+        ),
+        (
+            "IE",
+            "Ireland",
+            LocalityThenPostalCode,
+            Some(r"^[A-Z]\d[\dW] ?[A-Z\d]{4}$"),
+        ),
+        ("IN", "India", LocalityRegionPostalCode, Some(r"^\d{6}$")),
+        ("IT", "Italy", PostalCodeLocality, Some(r"^\d{5}$")),
+        ("JP", "Japan", PostalCodeFirst, Some(r"^\d{3}-?\d{4}$")),
+        ("MX", "Mexico", PostalCodeLocality, Some(r"^\d{5}$")),
+        (
+            "NL",
+            "Netherlands",
+            PostalCodeLocality,
+            Some(r"^\d{4} ?[A-Z]{2}$"),
+        ),
+        ("NO", "Norway", PostalCodeLocality, Some(r"^\d{4}$")),
+        (
+            "NZ",
+            "New Zealand",
+            LocalityThenPostalCode,
+            Some(r"^\d{4}$"),
+        ),
+        ("PL", "Poland", PostalCodeLocality, Some(r"^\d{2}-\d{3}$")),
+        ("PT", "Portugal", PostalCodeLocality, Some(r"^\d{4}-\d{3}$")),
+        ("RU", "Russia", PostalCodeLocality, Some(r"^\d{6}$")),
+        ("SE", "Sweden", PostalCodeLocality, Some(r"^\d{3} ?\d{2}$")),
+        (
+            "US",
+            "United States",
+            LocalityRegionPostalCode,
+            Some(r"^\d{5}(-\d{4})?$"),
+        ),
+    ];
+
+    formats
+        .into_iter()
+        .map(|(code, name, layout, postal_code)| {
+            (
+                code,
+                CountryFormat {
+                    name,
+                    layout,
+                    postal_code: postal_code.map(|pattern| Regex::new(pattern).unwrap()),
+                },
+            )
+        })
+        .collect()
+});
+
+/// Code of the country with the English `name` given by [`PostalAddress::country_name`], or
+/// `name` itself when it is a code. `None` for other names.
+pub fn country_code(name: &str) -> Option<String> {
+    let name = name.trim();
+    let known = COUNTRIES
+        .iter()
+        .find(|(_, format)| format.name.eq_ignore_ascii_case(name))
+        .map(|(code, _)| String::from(*code));
+
+    known.or_else(|| phone::parse_region(name).map(|_| name.to_uppercase()))
+}
+
+/// Reasons a postal address can be rejected, with the field they apply to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AddressError {
+    InvalidCountry,
+    Empty,
+    InvalidPostalCode,
+}
+
+impl AddressError {
+    /// Name of the invalid field
+    pub fn field(&self) -> &'static str {
+        match self {
+            AddressError::InvalidCountry => "country",
+            AddressError::Empty => "street",
+            AddressError::InvalidPostalCode => "postal_code",
+        }
+    }
+
+    /// Machine readable reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            AddressError::InvalidCountry => "invalid_country",
+            AddressError::Empty => "required",
+            AddressError::InvalidPostalCode => "invalid_postal_code",
+        }
+    }
+
+    /// Human readable reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            AddressError::InvalidCountry => "Unknown country",
+            AddressError::Empty => "The address is empty",
+            AddressError::InvalidPostalCode => "The postal code is not valid for the country",
+        }
+    }
+}
+
+impl Validate for PostalAddress {
+    /// Checks the normalized address, see [`PostalAddress::errors`]
+    fn rules(&self, errors: &mut ValidationErrors) {
+        for err in self.clone().normalize().errors() {
+            errors.add(format!("/{}", err.field()), err.code(), err.message());
+        }
+    }
+}
+
+impl PostalAddress {
+    /// Trims every part, drops blank lines and uppercases the codes
+    pub fn normalize(self) -> Self {
+        fn clean(value: Option<String>) -> Option<String> {
+            value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        }
+
+        PostalAddress {
+            label: self.label,
+            street: self
+                .street
+                .into_iter()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            locality: clean(self.locality),
+            region: clean(self.region),
+            postal_code: clean(self.postal_code).map(|code| code.to_uppercase()),
+            country: self.country.trim().to_uppercase(),
+        }
+    }
+
+    /// Checks the address against the rules of its country
+    pub fn errors(&self) -> Vec<AddressError> {
+        let mut errors = Vec::new();
+
+        if phone::parse_region(&self.country).is_none() {
+            errors.push(AddressError::InvalidCountry);
+        }
+
+        if self.street.is_empty() && self.locality.is_none() && self.postal_code.is_none() {
+            errors.push(AddressError::Empty);
+        }
+
+        let pattern = COUNTRIES
+            .get(self.country.as_str())
+            .and_then(|format| format.postal_code.as_ref());
+        if let (Some(pattern), Some(postal_code)) = (pattern, &self.postal_code) {
+            if !pattern.is_match(postal_code) {
+                errors.push(AddressError::InvalidPostalCode);
+            }
+        }
+
+        errors
+    }
+
+    /// English name of the country, or its code when the country has no known format
+    pub fn country_name(&self) -> &str {
+        COUNTRIES
+            .get(self.country.as_str())
+            .map(|format| format.name)
+            .unwrap_or(&self.country)
+    }
+
+    /// Lines of the address in the conventional order of its country, ready to be written
+    /// on an envelope. The last line is the country name.
+    pub fn format(&self) -> Vec<String> {
+        let layout = COUNTRIES
+            .get(self.country.as_str())
+            .map(|format| format.layout)
+            .unwrap_or(Layout::PostalCodeLocality);
+
+        let join = |parts: &[&Option<String>], separator: &str| {
+            let parts: Vec<&str> = parts.iter().filter_map(|part| part.as_deref()).collect();
+            Some(parts.join(separator)).filter(|line| !line.is_empty())
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+        match layout {
+            Layout::LocalityRegionPostalCode => {
+                lines.extend(self.street.iter().cloned());
+                let region = join(&[&self.region, &self.postal_code], " ");
+                lines.extend(join(&[&self.locality, &region], ", "));
+            }
+            Layout::PostalCodeLocality => {
+                lines.extend(self.street.iter().cloned());
+                lines.extend(join(&[&self.postal_code, &self.locality], " "));
+                lines.extend(self.region.clone());
+            }
+            Layout::LocalityThenPostalCode => {
+                lines.extend(self.street.iter().cloned());
+                lines.extend(self.locality.clone());
+                lines.extend(self.region.clone());
+                lines.extend(self.postal_code.clone());
+            }
+            Layout::PostalCodeFirst => {
+                lines.extend(self.postal_code.clone());
+                lines.extend(join(&[&self.region, &self.locality], " "));
+                lines.extend(self.street.iter().cloned());
+            }
+        }
+        lines.push(self.country_name().to_uppercase());
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(
+        street: &[&str],
+        locality: Option<&str>,
+        region: Option<&str>,
+        postal_code: Option<&str>,
+        country: &str,
+    ) -> PostalAddress {
+        PostalAddress {
+            label: AddressLabel::Home,
+            street: street.iter().map(|line| String::from(*line)).collect(),
+            locality: locality.map(String::from),
+            region: region.map(String::from),
+            postal_code: postal_code.map(String::from),
+            country: String::from(country),
+        }
+    }
+
+    #[test]
+    fn formats_locality_region_and_postal_code_on_one_line() {
+        let address = address(
+            &["12 Main St", "Apt 4"],
+            Some("Springfield"),
+            Some("IL"),
+            Some("62704"),
+            "US",
+        );
+
+        assert_eq!(
+            address.format(),
+            vec![
+                "12 Main St",
+                "Apt 4",
+                "Springfield, IL 62704",
+                "UNITED STATES"
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_the_postal_code_before_the_locality() {
+        let address = address(
+            &["Unter den Linden 1"],
+            Some("Berlin"),
+            None,
+            Some("10117"),
+            "DE",
+        );
+
+        assert_eq!(
+            address.format(),
+            vec!["Unter den Linden 1", "10117 Berlin", "GERMANY"]
+        );
+    }
+
+    #[test]
+    fn formats_the_postal_code_on_its_own_line() {
+        let address = address(
+            &["10 Downing St"],
+            Some("London"),
+            None,
+            Some("SW1A 2AA"),
+            "GB",
+        );
+
+        assert_eq!(
+            address.format(),
+            vec!["10 Downing St", "London", "SW1A 2AA", "UNITED KINGDOM"]
+        );
+    }
+
+    #[test]
+    fn formats_from_largest_to_smallest() {
+        let address = address(
+            &["1-1 Chiyoda"],
+            Some("Chiyoda"),
+            Some("Tokyo"),
+            Some("100-0001"),
+            "JP",
+        );
+
+        assert_eq!(
+            address.format(),
+            vec!["100-0001", "Tokyo Chiyoda", "1-1 Chiyoda", "JAPAN"]
+        );
+    }
+
+    #[test]
+    fn skips_missing_parts() {
+        let address = address(&[], Some("Springfield"), None, None, "US");
+
+        assert_eq!(address.format(), vec!["Springfield", "UNITED STATES"]);
+    }
+
+    #[test]
+    fn formats_unknown_countries_with_their_code() {
+        let address = address(
+            &["Kenyatta Avenue 1"],
+            Some("Nairobi"),
+            None,
+            Some("00100"),
+            "KE",
+        );
+
+        assert_eq!(address.country_name(), "KE");
+        assert_eq!(
+            address.format(),
+            vec!["Kenyatta Avenue 1", "00100 Nairobi", "KE"]
+        );
+    }
+
+    #[test]
+    fn normalizes_before_checking() {
+        let address = address(
+            &["  10 Downing St ", " "],
+            Some(" London "),
+            Some(""),
+            Some("sw1a 2aa"),
+            " gb ",
+        )
+        .normalize();
+
+        assert_eq!(address.street, vec!["10 Downing St"]);
+        assert_eq!(address.locality.as_deref(), Some("London"));
+        assert_eq!(address.region, None);
+        assert_eq!(address.postal_code.as_deref(), Some("SW1A 2AA"));
+        assert_eq!(address.country, "GB");
+        assert!(address.errors().is_empty());
+    }
+
+    #[test]
+    fn checks_postal_codes_against_the_country() {
+        let wrong = address(&["Unter den Linden 1"], None, None, Some("1011"), "DE");
+        assert_eq!(wrong.errors(), vec![AddressError::InvalidPostalCode]);
+
+        let empty = address(&[], None, Some("Bavaria"), None, "ZZ");
+        assert_eq!(
+            empty.errors(),
+            vec![AddressError::InvalidCountry, AddressError::Empty]
+        );
+    }
+
+    #[test]
+    fn finds_country_codes_by_name() {
+        assert_eq!(country_code("United Kingdom").as_deref(), Some("GB"));
+        assert_eq!(country_code("germany").as_deref(), Some("DE"));
+        assert_eq!(country_code("ke").as_deref(), Some("KE"));
+        assert_eq!(country_code("Atlantis"), None);
+    }
+}
@@ -0,0 +1,245 @@
+/*!
+Birthdays, anniversaries and other dates worth remembering
+*/
+
+use chrono::{Datelike, NaiveDate};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DateKind {
+    Birthday,
+    Anniversary,
+    /// Any other date, described by its label
+    Custom,
+}
+
+/// A yearly recurring date. The year is optional because people often know a birthday
+/// without knowing the year of birth.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct SignificantDate {
+    pub kind: DateKind,
+    /// Description of custom dates, e.g. `Name day`
+    #[serde(default)]
+    pub label: Option<String>,
+    pub year: Option<i32>,
+    pub month: u32,
+    pub day: u32,
+}
+
+/// Reasons a date can be rejected, with the field they apply to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DateError {
+    InvalidDate,
+    MissingLabel,
+    DuplicateBirthday,
+}
+
+impl DateError {
+    /// Name of the invalid field
+    pub fn field(&self) -> &'static str {
+        match self {
+            DateError::InvalidDate => "day",
+            DateError::MissingLabel => "label",
+            DateError::DuplicateBirthday => "kind",
+        }
+    }
+
+    /// Machine readable reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            DateError::InvalidDate => "invalid_date",
+            DateError::MissingLabel => "required",
+            DateError::DuplicateBirthday => "duplicate_birthday",
+        }
+    }
+
+    /// Human readable reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            DateError::InvalidDate => "The date does not exist",
+            DateError::MissingLabel => "Custom dates need a label",
+            DateError::DuplicateBirthday => "A contact can only have one birthday",
+        }
+    }
+}
+
+impl Validate for SignificantDate {
+    /// See [`SignificantDate::errors`]
+    fn rules(&self, errors: &mut ValidationErrors) {
+        for err in self.errors() {
+            errors.add(format!("/{}", err.field()), err.code(), err.message());
+        }
+        errors
+            .field("/label", &self.label)
+            .max_length(MAX_NAME_LENGTH);
+    }
+}
+
+impl SignificantDate {
+    /// Checks the date exists. February 29 is only accepted without a year or on leap years.
+    pub fn errors(&self) -> Vec<DateError> {
+        let mut errors = Vec::new();
+
+        // 2000 is a leap year, so it accepts February 29 when the year is unknown
+        if NaiveDate::from_ymd_opt(self.year.unwrap_or(2000), self.month, self.day).is_none() {
+            errors.push(DateError::InvalidDate);
+        }
+
+        let has_label = self
+            .label
+            .as_ref()
+            .is_some_and(|label| !label.trim().is_empty());
+        if self.kind == DateKind::Custom && !has_label {
+            errors.push(DateError::MissingLabel);
+        }
+
+        errors
+    }
+
+    /// Date the event falls on in `year`. Dates on February 29 fall on February 28 on common
+    /// years.
+    pub fn in_year(&self, year: i32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, self.month, self.day).or_else(|| {
+            if (self.month, self.day) == (2, 29) {
+                NaiveDate::from_ymd_opt(year, 2, 28)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// First occurrence of the event on or after `today`
+    pub fn next_occurrence(&self, today: NaiveDate) -> Option<NaiveDate> {
+        self.in_year(today.year())
+            .filter(|date| *date >= today)
+            .or_else(|| self.in_year(today.year() + 1))
+    }
+
+    /// Value of the vCard `BDAY` and `X-ANNIVERSARY` properties. Apple clients write dates
+    /// without a year as year 1604.
+    pub fn to_vcard(&self) -> (String, String) {
+        match self.year {
+            Some(year) => (
+                String::new(),
+                format!("{:04}-{:02}-{:02}", year, self.month, self.day),
+            ),
+            None => (
+                String::from(";X-APPLE-OMIT-YEAR=1604"),
+                format!("1604-{:02}-{:02}", self.month, self.day),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn date(kind: DateKind, year: Option<i32>, month: u32, day: u32) -> SignificantDate {
+        SignificantDate {
+            kind,
+            label: None,
+            year,
+            month,
+            day,
+        }
+    }
+
+    #[test]
+    fn accepts_february_29_only_without_a_year_or_on_leap_years() {
+        assert!(date(DateKind::Birthday, None, 2, 29).errors().is_empty());
+        assert!(date(DateKind::Birthday, Some(2000), 2, 29)
+            .errors()
+            .is_empty());
+        assert_eq!(
+            date(DateKind::Birthday, Some(1900), 2, 29).errors(),
+            vec![DateError::InvalidDate]
+        );
+        assert_eq!(
+            date(DateKind::Birthday, None, 4, 31).errors(),
+            vec![DateError::InvalidDate]
+        );
+    }
+
+    #[test]
+    fn custom_dates_need_a_label() {
+        let mut custom = date(DateKind::Custom, None, 7, 26);
+        assert_eq!(custom.errors(), vec![DateError::MissingLabel]);
+
+        custom.label = Some(String::from("  "));
+        assert_eq!(custom.errors(), vec![DateError::MissingLabel]);
+
+        custom.label = Some(String::from("Name day"));
+        assert!(custom.errors().is_empty());
+    }
+
+    #[test]
+    fn february_29_falls_on_february_28_on_common_years() {
+        let leap_day = date(DateKind::Birthday, Some(2000), 2, 29);
+
+        assert_eq!(leap_day.in_year(2024), Some(day(2024, 2, 29)));
+        assert_eq!(leap_day.in_year(2023), Some(day(2023, 2, 28)));
+        assert_eq!(leap_day.in_year(2100), Some(day(2100, 2, 28)));
+        assert_eq!(date(DateKind::Birthday, None, 4, 31).in_year(2024), None);
+    }
+
+    #[test]
+    fn next_occurrence_is_today_or_later() {
+        let birthday = date(DateKind::Birthday, None, 3, 10);
+
+        assert_eq!(
+            birthday.next_occurrence(day(2023, 3, 10)),
+            Some(day(2023, 3, 10))
+        );
+        assert_eq!(
+            birthday.next_occurrence(day(2023, 3, 11)),
+            Some(day(2024, 3, 10))
+        );
+    }
+
+    #[test]
+    fn next_occurrence_of_february_29() {
+        let leap_day = date(DateKind::Birthday, None, 2, 29);
+
+        assert_eq!(
+            leap_day.next_occurrence(day(2023, 1, 1)),
+            Some(day(2023, 2, 28))
+        );
+        // After February 28 on a common year the next one is on the leap year that follows
+        assert_eq!(
+            leap_day.next_occurrence(day(2023, 3, 1)),
+            Some(day(2024, 2, 29))
+        );
+        assert_eq!(
+            leap_day.next_occurrence(day(2024, 2, 29)),
+            Some(day(2024, 2, 29))
+        );
+        assert_eq!(
+            leap_day.next_occurrence(day(2024, 3, 1)),
+            Some(day(2025, 2, 28))
+        );
+    }
+
+    #[test]
+    fn writes_omitted_years_the_way_apple_clients_do() {
+        assert_eq!(
+            date(DateKind::Birthday, Some(1985), 4, 2).to_vcard(),
+            (String::new(), String::from("1985-04-02"))
+        );
+        assert_eq!(
+            date(DateKind::Birthday, None, 4, 2).to_vcard(),
+            (
+                String::from(";X-APPLE-OMIT-YEAR=1604"),
+                String::from("1604-04-02")
+            )
+        );
+    }
+}
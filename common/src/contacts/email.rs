@@ -0,0 +1,58 @@
+/*!
+Email addresses of a contact
+*/
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::validation::{Validate, ValidationErrors};
+
+/// What an email address is used for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailLabel {
+    Home,
+    Work,
+    #[default]
+    Other,
+}
+
+/// Whether `address` looks like an email address, see [`EmailAddress::is_valid`]
+pub fn is_valid_address(address: &str) -> bool {
+    let mut parts = address.rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !address.chars().any(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct EmailAddress {
+    pub address: String,
+    #[serde(default)]
+    pub label: EmailLabel,
+}
+
+impl Validate for EmailAddress {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/address", &self.address).required().email();
+    }
+}
+
+impl EmailAddress {
+    /// Minimal syntax check, the only real validation of an email address is sending an email
+    pub fn is_valid(&self) -> bool {
+        is_valid_address(&self.address)
+    }
+
+    /// Lowercased address used to compare emails
+    pub fn normalized(&self) -> String {
+        self.address.trim().to_lowercase()
+    }
+}
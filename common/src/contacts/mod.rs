@@ -0,0 +1,311 @@
+/*!
+Fields of a contact and the rules they follow
+
+The client validates its forms with the same rules the server applies, see the [`Validate`]
+implementation of [`ContactInput`]. Rules that depend on the data of the owner, like which custom fields
+and organizations exist, are left to the server.
+*/
+
+pub mod address;
+pub mod dates;
+pub mod email;
+pub mod phone;
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::validation::{Text, Validate, ValidationErrors, MAX_NAME_LENGTH, MAX_TEXT_LENGTH};
+use address::PostalAddress;
+use dates::{DateError, DateKind, SignificantDate};
+use email::EmailAddress;
+use phone::{PhoneNumber, PhoneNumberInput};
+
+/// Link between a contact and an organization, stored in the contact
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Affiliation {
+    pub organization_id: Uuid,
+    pub title: Option<String>,
+    pub department: Option<String>,
+}
+
+/// Fields of a contact the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct ContactData {
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// ISO 3166-1 alpha-2 country the contact lives in
+    pub country: Option<String>,
+    pub emails: Vec<EmailAddress>,
+    pub phones: Vec<PhoneNumber>,
+    pub addresses: Vec<PostalAddress>,
+    pub notes: Option<String>,
+    /// Birthday, anniversary and other yearly dates
+    #[serde(default)]
+    pub dates: Vec<SignificantDate>,
+    /// Organizations the contact belongs to, with their role in each
+    #[serde(default)]
+    pub affiliations: Vec<Affiliation>,
+    /// Values of the custom fields of the owner, by key
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, Value>,
+}
+
+/// Contact fields as sent by the client, before validation
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ContactInput {
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub country: Option<String>,
+    pub emails: Vec<EmailAddress>,
+    pub phones: Vec<PhoneNumberInput>,
+    pub addresses: Vec<PostalAddress>,
+    pub notes: Option<String>,
+    pub dates: Vec<SignificantDate>,
+    pub affiliations: Vec<Affiliation>,
+    pub custom_fields: BTreeMap<String, Value>,
+}
+
+impl From<ContactData> for ContactInput {
+    /// Input that validates back to the same data, phone numbers as the user wrote them
+    fn from(data: ContactData) -> Self {
+        ContactInput {
+            given_name: data.given_name,
+            family_name: data.family_name,
+            country: data.country,
+            emails: data.emails,
+            phones: data
+                .phones
+                .into_iter()
+                .map(|phone| PhoneNumberInput {
+                    number: phone.original,
+                    label: phone.label,
+                })
+                .collect(),
+            addresses: data.addresses,
+            notes: data.notes,
+            dates: data.dates,
+            affiliations: data.affiliations,
+            custom_fields: data.custom_fields,
+        }
+    }
+}
+
+impl Validate for ContactInput {
+    /// Phone numbers are left to [`ContactInput::check`], reading them depends on the
+    /// `default_region` of the owner
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.rule(
+            "/given_name",
+            self.given_name.text().is_some() || self.family_name.text().is_some(),
+            "required",
+            "A contact needs a name",
+        );
+        errors
+            .field("/given_name", &self.given_name)
+            .max_length(MAX_NAME_LENGTH);
+        errors
+            .field("/family_name", &self.family_name)
+            .max_length(MAX_NAME_LENGTH);
+        errors
+            .field("/notes", &self.notes)
+            .max_length(MAX_TEXT_LENGTH);
+
+        errors.field("/country", &self.country).satisfies(
+            |country| phone::parse_region(&country.trim().to_uppercase()).is_some(),
+            "invalid_country",
+            "Unknown country",
+        );
+
+        errors.each("/emails", &self.emails);
+        errors.each("/addresses", &self.addresses);
+        errors.each("/dates", &self.dates);
+
+        let birthdays = self
+            .dates
+            .iter()
+            .enumerate()
+            .filter(|(_, date)| date.kind == DateKind::Birthday);
+        for (index, _) in birthdays.skip(1) {
+            let err = DateError::DuplicateBirthday;
+            errors.add(
+                format!("/dates/{}/{}", index, err.field()),
+                err.code(),
+                err.message(),
+            );
+        }
+
+        for (index, affiliation) in self.affiliations.iter().enumerate() {
+            errors.rule(
+                format!("/affiliations/{}/organization_id", index),
+                !self.affiliations[..index]
+                    .iter()
+                    .any(|other| other.organization_id == affiliation.organization_id),
+                "duplicate_organization",
+                "The contact is already affiliated with this organization",
+            );
+            errors
+                .field(format!("/affiliations/{}/title", index), &affiliation.title)
+                .max_length(MAX_NAME_LENGTH);
+            errors
+                .field(
+                    format!("/affiliations/{}/department", index),
+                    &affiliation.department,
+                )
+                .max_length(MAX_NAME_LENGTH);
+        }
+
+        for (key, value) in &self.custom_fields {
+            if let Some(text) = value.as_str() {
+                errors
+                    .field(format!("/custom_fields/{}", key), text)
+                    .max_length(MAX_TEXT_LENGTH);
+            }
+        }
+    }
+}
+
+impl ContactInput {
+    /// Normalizes input that follows the [`Validate`] rules, recording the phone numbers that
+    /// cannot be read in `errors`.
+    ///
+    /// Phone numbers in national format are interpreted using the country of the contact, or
+    /// the `default_region` of the owner when the contact has no country. Custom field values
+    /// and the organizations of affiliations are only cleaned up, `null` values are dropped:
+    /// whether they exist is for the server to check.
+    pub fn check(self, default_region: Option<&str>, errors: &mut ValidationErrors) -> ContactData {
+        let country = non_empty(self.country).map(|country| country.to_uppercase());
+
+        let region = country.as_deref().or(default_region);
+        let phones = self
+            .phones
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, input)| {
+                PhoneNumber::parse(&input.number, input.label, region)
+                    .map_err(|err| {
+                        errors.add(
+                            format!("/phones/{}/number", index),
+                            err.code(),
+                            err.message(),
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+
+        ContactData {
+            given_name: non_empty(self.given_name),
+            family_name: non_empty(self.family_name),
+            country,
+            emails: self.emails,
+            phones,
+            addresses: self
+                .addresses
+                .into_iter()
+                .map(PostalAddress::normalize)
+                .collect(),
+            notes: non_empty(self.notes),
+            dates: self
+                .dates
+                .into_iter()
+                .map(|date| SignificantDate {
+                    label: non_empty(date.label),
+                    ..date
+                })
+                .collect(),
+            affiliations: self
+                .affiliations
+                .into_iter()
+                .map(|affiliation| Affiliation {
+                    title: non_empty(affiliation.title),
+                    department: non_empty(affiliation.department),
+                    ..affiliation
+                })
+                .collect(),
+            custom_fields: self
+                .custom_fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .collect(),
+        }
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phone::{PhoneLabel, PhoneNumberError};
+
+    fn with_phone(number: &str, country: Option<&str>) -> ContactInput {
+        ContactInput {
+            country: country.map(String::from),
+            phones: vec![PhoneNumberInput {
+                number: String::from(number),
+                label: PhoneLabel::Mobile,
+            }],
+            ..ContactInput::default()
+        }
+    }
+
+    #[test]
+    fn reads_phones_with_the_default_region() {
+        let mut errors = ValidationErrors::new();
+        let data = with_phone("020 7946 0000", None).check(Some("GB"), &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(data.phones[0].e164, "+442079460000");
+    }
+
+    #[test]
+    fn the_country_of_the_contact_takes_precedence() {
+        let mut errors = ValidationErrors::new();
+        let data = with_phone("(415) 555-2671", Some("us")).check(Some("GB"), &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(data.country.as_deref(), Some("US"));
+        assert_eq!(data.phones[0].e164, "+14155552671");
+    }
+
+    #[test]
+    fn reports_phones_that_cannot_be_read_by_index() {
+        let mut errors = ValidationErrors::new();
+        let data = with_phone("020 7946 0000", None).check(None, &mut errors);
+
+        assert!(data.phones.is_empty());
+        assert_eq!(
+            errors.message("/phones/0/number"),
+            Some(PhoneNumberError::MissingRegion.message())
+        );
+    }
+
+    #[test]
+    fn limits_the_length_of_text() {
+        let input = ContactInput {
+            given_name: Some("a".repeat(MAX_NAME_LENGTH + 1)),
+            notes: Some("a".repeat(MAX_TEXT_LENGTH)),
+            custom_fields: vec![(
+                String::from("bio"),
+                Value::from("a".repeat(MAX_TEXT_LENGTH + 1)),
+            )]
+            .into_iter()
+            .collect(),
+            ..ContactInput::default()
+        };
+        let errors = input.validate().unwrap_err();
+
+        let paths: Vec<&str> = errors.errors.iter().map(|err| err.path.as_str()).collect();
+        assert_eq!(paths, vec!["/given_name", "/custom_fields/bio"]);
+        assert!(errors.errors.iter().all(|err| err.code == "too_long"));
+    }
+}
@@ -0,0 +1,182 @@
+/*!
+Phone number parsing and E.164 normalization
+*/
+
+use phonenumber::{country, Mode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// What a phone number is used for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PhoneLabel {
+    Mobile,
+    Home,
+    Work,
+    Fax,
+    Pager,
+    #[default]
+    Other,
+}
+
+/// A phone number as typed by the user
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct PhoneNumberInput {
+    pub number: String,
+    #[serde(default)]
+    pub label: PhoneLabel,
+}
+
+/// A validated phone number
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct PhoneNumber {
+    /// Canonical E.164 form, e.g. `+442079460000`. Used for search and duplicate detection.
+    pub e164: String,
+    /// The number exactly as the user wrote it, kept for display
+    pub original: String,
+    pub label: PhoneLabel,
+}
+
+/// Reasons a phone number can be rejected
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PhoneNumberError {
+    /// The number is written in national format and there is no region to interpret it with
+    MissingRegion,
+    /// The input is not a phone number at all
+    Unparseable,
+    /// The input looks like a phone number but is not valid for its region
+    Invalid,
+}
+
+impl PhoneNumberError {
+    /// Machine readable reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            PhoneNumberError::MissingRegion => "missing_region",
+            PhoneNumberError::Unparseable => "unparseable_phone_number",
+            PhoneNumberError::Invalid => "invalid_phone_number",
+        }
+    }
+
+    /// Human readable reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            PhoneNumberError::MissingRegion => {
+                "Use the international format or set a country for the contact"
+            }
+            PhoneNumberError::Unparseable => "Not a phone number",
+            PhoneNumberError::Invalid => "The phone number is not valid",
+        }
+    }
+}
+
+/// Parses an ISO 3166-1 alpha-2 code, e.g. `GB`, into a numbering region
+pub fn parse_region(region: &str) -> Option<country::Id> {
+    region.to_uppercase().parse().ok()
+}
+
+impl PhoneNumber {
+    /// Parses and validates a phone number.
+    ///
+    /// Numbers in international format (`+44 20 7946 0000`) are parsed as is, numbers in
+    /// national format (`020 7946 0000`) are interpreted with the `default_region`.
+    pub fn parse(
+        input: &str,
+        label: PhoneLabel,
+        default_region: Option<&str>,
+    ) -> Result<Self, PhoneNumberError> {
+        Ok(PhoneNumber {
+            e164: to_e164(input, default_region)?,
+            original: input.trim().to_string(),
+            label,
+        })
+    }
+}
+
+/// Normalizes a phone number into its canonical E.164 form
+pub fn to_e164(input: &str, default_region: Option<&str>) -> Result<String, PhoneNumberError> {
+    let international = input.trim_start().starts_with('+');
+    let region = default_region.and_then(parse_region);
+
+    if !international && region.is_none() {
+        return Err(PhoneNumberError::MissingRegion);
+    }
+
+    let number = phonenumber::parse(region, input).map_err(|err| {
+        log::debug!("Error parsing phone number {:?}: {:?}", input, err);
+        PhoneNumberError::Unparseable
+    })?;
+
+    if !phonenumber::is_valid(&number) {
+        return Err(PhoneNumberError::Invalid);
+    }
+
+    Ok(number.format().mode(Mode::E164).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_international_numbers_without_a_region() {
+        assert_eq!(
+            to_e164("+44 20 7946 0000", None).as_deref(),
+            Ok("+442079460000")
+        );
+        assert_eq!(
+            to_e164(" +1 (415) 555-2671", None).as_deref(),
+            Ok("+14155552671")
+        );
+    }
+
+    #[test]
+    fn reads_national_numbers_with_the_default_region() {
+        assert_eq!(
+            to_e164("020 7946 0000", Some("GB")).as_deref(),
+            Ok("+442079460000")
+        );
+        assert_eq!(
+            to_e164("(415) 555-2671", Some("us")).as_deref(),
+            Ok("+14155552671")
+        );
+    }
+
+    #[test]
+    fn international_numbers_ignore_the_default_region() {
+        assert_eq!(
+            to_e164("+33 1 23 45 67 89", Some("GB")).as_deref(),
+            Ok("+33123456789")
+        );
+    }
+
+    #[test]
+    fn rejects_national_numbers_without_a_region() {
+        assert_eq!(
+            to_e164("020 7946 0000", None),
+            Err(PhoneNumberError::MissingRegion)
+        );
+        assert_eq!(
+            to_e164("020 7946 0000", Some("XX")),
+            Err(PhoneNumberError::MissingRegion)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        assert_eq!(
+            to_e164("not a number", Some("GB")),
+            Err(PhoneNumberError::Unparseable)
+        );
+        assert_eq!(to_e164("+44 12", None), Err(PhoneNumberError::Invalid));
+    }
+
+    #[test]
+    fn keeps_the_number_as_written() {
+        let phone = PhoneNumber::parse(" 020 7946 0000 ", PhoneLabel::Work, Some("GB")).unwrap();
+
+        assert_eq!(phone.e164, "+442079460000");
+        assert_eq!(phone.original, "020 7946 0000");
+        assert_eq!(phone.label, PhoneLabel::Work);
+    }
+}
@@ -0,0 +1,293 @@
+/*!
+Catalog of the errors methods fail with
+
+The `code` of a JSON-RPC error object is the HTTP status it is sent with. Its `data` is an
+[`AppError`] telling what went wrong, tagged with its own `code` and carrying details when
+there are any:
+
+```json
+{ "code": 409, "message": "Conflict", "data": { "code": "already_exists", "details": { "value": "Friends" } } }
+```
+*/
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::jsonrpc::{JSONRPCError, JSONRPCErrorObject};
+use crate::validation::ValidationErrors;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
+pub enum AppError {
+    /// The body is not a JSON-RPC request
+    InvalidRequest,
+    /// Params that cannot be read as the type the method expects
+    InvalidParams { message: String },
+    /// Params that were read but failed validation, with every field that failed
+    ValidationFailed(ValidationErrors),
+    /// No valid access token
+    Unauthorized,
+    /// The caller lacks realm roles the method needs
+    Forbidden { missing_roles: Vec<String> },
+    /// No method by that name
+    MethodNotFound { method: String },
+    /// The entity does not exist or belongs to another user. The two are not told apart, so
+    /// the ids of other users cannot be probed.
+    NotFound,
+    /// A name or key that must be unique is taken
+    AlreadyExists { value: String },
+    /// The entity changed since the caller read it: the caller based its change on the
+    /// `expected` version, the entity is at the `actual` one
+    VersionConflict { expected: String, actual: String },
+    /// The job already ended with `status`
+    JobEnded { status: String },
+    /// A limit of the connection or of the request was reached
+    QuotaExceeded { limit: u64 },
+    /// The method needs a WebSocket connection
+    WebSocketOnly,
+    /// The owner cancelled the job running the request
+    Cancelled,
+    /// Part of an all or nothing operation, rolled back because another part failed
+    RolledBack,
+    /// Unexpected error, details are only logged
+    Internal,
+}
+
+impl AppError {
+    /// One error of each code, for documentation
+    pub fn kinds() -> Vec<AppError> {
+        vec![
+            AppError::InvalidRequest,
+            AppError::InvalidParams {
+                message: String::new(),
+            },
+            AppError::ValidationFailed(ValidationErrors::new()),
+            AppError::Unauthorized,
+            AppError::Forbidden {
+                missing_roles: Vec::new(),
+            },
+            AppError::MethodNotFound {
+                method: String::new(),
+            },
+            AppError::NotFound,
+            AppError::AlreadyExists {
+                value: String::new(),
+            },
+            AppError::VersionConflict {
+                expected: String::new(),
+                actual: String::new(),
+            },
+            AppError::JobEnded {
+                status: String::new(),
+            },
+            AppError::QuotaExceeded { limit: 0 },
+            AppError::WebSocketOnly,
+            AppError::Cancelled,
+            AppError::RolledBack,
+            AppError::Internal,
+        ]
+    }
+
+    /// The `code` the error is tagged with, e.g. `already_exists`
+    pub fn code(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|error| Some(String::from(error.get("code")?.as_str()?)))
+            .unwrap_or_default()
+    }
+
+    /// HTTP status the error is sent with, and its reason phrase
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            AppError::InvalidRequest
+            | AppError::InvalidParams { .. }
+            | AppError::ValidationFailed(_)
+            | AppError::WebSocketOnly => (400, "Bad Request"),
+            AppError::Unauthorized => (401, "Unauthorized"),
+            AppError::Forbidden { .. } => (403, "Forbidden"),
+            AppError::MethodNotFound { .. } | AppError::NotFound => (404, "Not Found"),
+            AppError::AlreadyExists { .. }
+            | AppError::VersionConflict { .. }
+            | AppError::JobEnded { .. }
+            | AppError::QuotaExceeded { .. }
+            | AppError::Cancelled => (409, "Conflict"),
+            AppError::RolledBack => (424, "Failed Dependency"),
+            AppError::Internal => (500, "Internal Server Error"),
+        }
+    }
+}
+
+impl From<AppError> for JSONRPCError<JSONRPCErrorObject<AppError>> {
+    fn from(error: AppError) -> Self {
+        let (code, message) = error.status();
+        JSONRPCError::new(code, message, error)
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::ValidationFailed(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::validation::FieldError;
+
+    fn check(error: AppError, status: u16, reason: &str, serialized: serde_json::Value) {
+        assert_eq!(error.status(), (status, reason), "{:?}", error);
+        assert_eq!(serde_json::to_value(&error).unwrap(), serialized);
+        assert_eq!(
+            serde_json::from_value::<AppError>(serialized).unwrap(),
+            error
+        );
+    }
+
+    #[test]
+    fn bad_requests() {
+        check(
+            AppError::InvalidRequest,
+            400,
+            "Bad Request",
+            json!({"code": "invalid_request"}),
+        );
+        check(
+            AppError::InvalidParams {
+                message: String::from("missing field `id`"),
+            },
+            400,
+            "Bad Request",
+            json!({"code": "invalid_params", "details": {"message": "missing field `id`"}}),
+        );
+        check(
+            AppError::ValidationFailed(ValidationErrors {
+                errors: vec![FieldError {
+                    path: String::from("/name"),
+                    code: String::from("required"),
+                    message: String::from("This field is required"),
+                    column: None,
+                }],
+            }),
+            400,
+            "Bad Request",
+            json!({"code": "validation_failed", "details": {"errors": [
+                {"path": "/name", "code": "required", "message": "This field is required"}
+            ]}}),
+        );
+        check(
+            AppError::WebSocketOnly,
+            400,
+            "Bad Request",
+            json!({"code": "web_socket_only"}),
+        );
+    }
+
+    #[test]
+    fn access() {
+        check(
+            AppError::Unauthorized,
+            401,
+            "Unauthorized",
+            json!({"code": "unauthorized"}),
+        );
+        check(
+            AppError::Forbidden {
+                missing_roles: vec![String::from("bulk")],
+            },
+            403,
+            "Forbidden",
+            json!({"code": "forbidden", "details": {"missing_roles": ["bulk"]}}),
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        check(
+            AppError::MethodNotFound {
+                method: String::from("contacts.nope"),
+            },
+            404,
+            "Not Found",
+            json!({"code": "method_not_found", "details": {"method": "contacts.nope"}}),
+        );
+        check(
+            AppError::NotFound,
+            404,
+            "Not Found",
+            json!({"code": "not_found"}),
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        check(
+            AppError::AlreadyExists {
+                value: String::from("Friends"),
+            },
+            409,
+            "Conflict",
+            json!({"code": "already_exists", "details": {"value": "Friends"}}),
+        );
+        check(
+            AppError::VersionConflict {
+                expected: String::from("3"),
+                actual: String::from("4"),
+            },
+            409,
+            "Conflict",
+            json!({"code": "version_conflict", "details": {"expected": "3", "actual": "4"}}),
+        );
+        check(
+            AppError::JobEnded {
+                status: String::from("succeeded"),
+            },
+            409,
+            "Conflict",
+            json!({"code": "job_ended", "details": {"status": "succeeded"}}),
+        );
+        check(
+            AppError::QuotaExceeded { limit: 16 },
+            409,
+            "Conflict",
+            json!({"code": "quota_exceeded", "details": {"limit": 16}}),
+        );
+        check(
+            AppError::Cancelled,
+            409,
+            "Conflict",
+            json!({"code": "cancelled"}),
+        );
+    }
+
+    #[test]
+    fn failures() {
+        check(
+            AppError::RolledBack,
+            424,
+            "Failed Dependency",
+            json!({"code": "rolled_back"}),
+        );
+        check(
+            AppError::Internal,
+            500,
+            "Internal Server Error",
+            json!({"code": "internal"}),
+        );
+    }
+
+    #[test]
+    fn kinds_cover_every_code() {
+        let codes: Vec<String> = AppError::kinds().iter().map(AppError::code).collect();
+        let schema = serde_json::to_value(schemars::schema_for!(AppError)).unwrap();
+        let variants = schema["oneOf"].as_array().unwrap();
+
+        assert_eq!(codes.len(), variants.len());
+        for variant in variants {
+            let code = &variant["properties"]["code"]["enum"][0];
+            assert!(codes.iter().any(|known| code == known), "{}", code);
+        }
+    }
+}
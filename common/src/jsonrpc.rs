@@ -1,18 +1,84 @@
 /*!
-Helpers that keep the responses standardized
+Helpers that keep the requests and responses standardized
+
+The conversions into tide responses are behind the `server` feature, so clients can share
+these types without pulling an HTTP server in.
 */
 
+use schemars::JsonSchema;
+#[cfg(feature = "server")]
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tide::http::mime;
-use tide::{Body, Response, StatusCode};
+use serde_json::Value;
+#[cfg(feature = "server")]
+use tide::{http::mime, Body, Response};
 use uuid::Uuid;
 
+use crate::errors::AppError;
+use crate::validation::Validate;
+
+/// Representation of a request object
+///
+/// https://www.jsonrpc.org/specification#request_object
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct JSONRPCRequest<P = Value> {
+    /// Id of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// Name of the method to invoke
+    pub method: String,
+    /// Parameters of the method
+    #[serde(default)]
+    pub params: P,
+}
+
+impl<P> JSONRPCRequest<P> {
+    /// Set the request id
+    pub fn id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+}
+
+/// Definition of a method, shared by the server handling it and the clients calling it
+///
+/// `Params` are always passed by name, so they are a struct, [`NoParams`] for methods without
+/// any. They are validated before the method runs.
+pub trait RpcMethod {
+    /// Name the method is called by
+    const NAME: &'static str;
+    type Params: Validate;
+    type Result;
+
+    /// A request calling the method
+    fn request(params: Self::Params) -> JSONRPCRequest<Self::Params> {
+        JSONRPCRequest {
+            id: None,
+            method: String::from(Self::NAME),
+            params,
+        }
+    }
+}
+
+/// Params of a method that takes none, any object is accepted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+pub struct NoParams {}
+
+impl Validate for NoParams {}
+
+/// Params of a method acting on a single entity
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub struct ById {
+    pub id: Uuid,
+}
+
+impl Validate for ById {}
+
 /// Representaiton of a success response
 ///
 /// https://www.jsonrpc.org/specification#response_object
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct JSONRPCSuccess<T: Serialize> {
+pub struct JSONRPCSuccess<T> {
     /// Id of the request
     pub id: Option<Uuid>,
     /// The result
@@ -21,6 +87,7 @@ pub struct JSONRPCSuccess<T: Serialize> {
     http_code: u16,
 }
 
+#[cfg(feature = "server")]
 impl<T: Serialize> From<JSONRPCSuccess<T>> for Response {
     fn from(value: JSONRPCSuccess<T>) -> Self {
         Response::builder(value.http_code)
@@ -54,33 +121,23 @@ impl<T: Serialize> IntoRPCSuccess for T {
     }
 }
 
-// impl<T: Serialize> From<JSONRPCSuccess<T>> for Response {
-//     fn from(success: JSONRPCSuccess<T>) -> Self {
-//         let mut response = Response::new(success.http_code);
-
-//         response.set_body(Body::from_json(&success).unwrap());
-
-//         response
-//     }
-// }
-
 impl<T: Serialize> JSONRPCSuccess<T> {
-    fn new(http_code: StatusCode, result: T) -> Self {
+    fn new(http_code: u16, result: T) -> Self {
         JSONRPCSuccess {
             id: None,
             result,
-            http_code: http_code.into(),
+            http_code,
         }
     }
 
     /// 200 OK
     pub fn ok(result: T) -> Self {
-        Self::new(StatusCode::Ok, result)
+        Self::new(200, result)
     }
 
     /// 201 CREATED
     pub fn created(result: T) -> Self {
-        Self::new(StatusCode::Created, result)
+        Self::new(201, result)
     }
 
     /// Set the request id of the response
@@ -94,12 +151,26 @@ impl<T: Serialize> JSONRPCSuccess<T> {
         self.result = result;
         self
     }
+
+    /// The http code the response is sent with
+    pub fn http_code(&self) -> u16 {
+        self.http_code
+    }
+
+    /// Serializes the result, so results of different types can be handled alike
+    pub fn into_value(self) -> serde_json::Result<JSONRPCSuccess<Value>> {
+        Ok(JSONRPCSuccess {
+            id: self.id,
+            result: serde_json::to_value(self.result)?,
+            http_code: self.http_code,
+        })
+    }
 }
 
 /// Representation of a error response
 ///
 /// https://www.jsonrpc.org/specification#error_object
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug)]
 pub struct JSONRPCErrorObject<D = ()> {
     /// A String providing a short description of the error.
     pub message: String,
@@ -113,13 +184,26 @@ pub struct JSONRPCErrorObject<D = ()> {
 ///
 /// https://www.jsonrpc.org/specification#error_object
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct JSONRPCError<E: Serialize = JSONRPCErrorObject<()>> {
+pub struct JSONRPCError<E = JSONRPCErrorObject<AppError>> {
     /// The request identifier
     pub id: Option<Uuid>,
     error: E,
     http_code: u16,
 }
 
+impl<E> JSONRPCError<E> {
+    /// Takes the error object out of the response
+    pub fn into_error(self) -> E {
+        self.error
+    }
+
+    /// The http code the response is sent with
+    pub fn http_code(&self) -> u16 {
+        self.http_code
+    }
+}
+
+#[cfg(feature = "server")]
 impl<E: Serialize + DeserializeOwned> From<JSONRPCError<E>> for Response {
     fn from(value: JSONRPCError<E>) -> Self {
         Response::builder(value.http_code)
@@ -130,46 +214,47 @@ impl<E: Serialize + DeserializeOwned> From<JSONRPCError<E>> for Response {
 }
 
 impl<D: Serialize> JSONRPCError<JSONRPCErrorObject<D>> {
-    fn new(code: StatusCode, data: D) -> Self {
+    /// `message` is the reason phrase of the HTTP status code
+    pub(crate) fn new(code: u16, message: &str, data: D) -> Self {
         JSONRPCError {
             id: None,
             error: JSONRPCErrorObject {
-                code: code.into(),
-                message: String::from(code.canonical_reason()),
+                code,
+                message: String::from(message),
                 data,
             },
-            http_code: code.into(),
+            http_code: code,
         }
     }
 
     /// 400 Bad Request
     pub fn bad_request(data: D) -> Self {
-        Self::new(StatusCode::BadRequest, data)
+        Self::new(400, "Bad Request", data)
     }
 
     /// 401 Unauthorized
     pub fn unauthorized(data: D) -> Self {
-        Self::new(StatusCode::Unauthorized, data)
+        Self::new(401, "Unauthorized", data)
     }
 
     /// 403 Forbidden
     pub fn forbidden(data: D) -> Self {
-        Self::new(StatusCode::Forbidden, data)
+        Self::new(403, "Forbidden", data)
     }
 
     /// 409 Conflict
     pub fn conflict(data: D) -> Self {
-        Self::new(StatusCode::Conflict, data)
+        Self::new(409, "Conflict", data)
     }
 
     /// 500 Internal Server Error response
     pub fn internal(data: D) -> Self {
-        Self::new(StatusCode::InternalServerError, data)
+        Self::new(500, "Internal Server Error", data)
     }
 
     /// 404 Not Found
     pub fn not_found(data: D) -> Self {
-        Self::new(StatusCode::NotFound, data)
+        Self::new(404, "Not Found", data)
     }
 
     /// Sets the request identifier
@@ -196,3 +281,22 @@ impl<D: Serialize> JSONRPCError<JSONRPCErrorObject<D>> {
         self
     }
 }
+
+/// A response as clients read it, either kind
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum JSONRPCResponse<T, E = JSONRPCErrorObject<AppError>> {
+    // Errors come first: a result that is an `Option` would read an error as `None`
+    Error(JSONRPCError<E>),
+    Success(JSONRPCSuccess<T>),
+}
+
+impl<T, E> JSONRPCResponse<T, E> {
+    /// The result, or the error object
+    pub fn into_result(self) -> Result<T, E> {
+        match self {
+            JSONRPCResponse::Success(success) => Ok(success.result),
+            JSONRPCResponse::Error(error) => Err(error.into_error()),
+        }
+    }
+}
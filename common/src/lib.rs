@@ -1 +1,6 @@
+pub mod contacts;
+pub mod errors;
 pub mod jsonrpc;
+pub mod openrpc;
+pub mod tags;
+pub mod validation;
@@ -0,0 +1,310 @@
+/*!
+OpenRPC documents describing the JSON-RPC methods
+
+https://spec.open-rpc.org
+
+Params and results are described with the JSON schemas of the Rust types methods read and
+return, so the document cannot drift from what the server accepts. Params are always passed by
+name: each field of the params type is a param of its own.
+*/
+
+use std::collections::BTreeMap;
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{InstanceType, Metadata, ObjectValidation, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::AppError;
+use crate::jsonrpc::{NoParams, RpcMethod};
+
+/// Version of the specification documents follow
+pub const OPENRPC_VERSION: &str = "1.2.6";
+
+/// Where errors are defined, for references
+const ERRORS_PATH: &str = "#/components/errors/";
+
+/// Error of callers lacking a role
+const FORBIDDEN: &str = "forbidden";
+
+/// Definition of `rpc.discover`
+pub struct Discover;
+
+impl RpcMethod for Discover {
+    const NAME: &'static str = "rpc.discover";
+    type Params = NoParams;
+    type Result = OpenRpc;
+}
+
+/// An OpenRPC document, as served by `rpc.discover`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenRpc {
+    pub openrpc: String,
+    pub info: Info,
+    pub methods: Vec<Method>,
+    pub components: Components,
+}
+
+/// Documents describe themselves by pointing at the specification, not with their own schema
+impl JsonSchema for OpenRpc {
+    fn schema_name() -> String {
+        String::from("OpenRpc")
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            metadata: Some(Box::new(Metadata {
+                description: Some(String::from(
+                    "OpenRPC document, see https://spec.open-rpc.org",
+                )),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Info {
+    pub title: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Method {
+    pub name: String,
+    pub summary: String,
+    /// Always `by-name`
+    pub param_structure: String,
+    pub params: Vec<ContentDescriptor>,
+    pub result: ContentDescriptor,
+    /// References to the errors the method can return
+    pub errors: Vec<Reference>,
+    /// Realm roles the caller needs on top of being signed in
+    #[serde(rename = "x-roles")]
+    pub roles: Vec<String>,
+}
+
+impl Method {
+    /// Adds an error, named after the `code` of an [`AppError`]
+    pub fn error(&mut self, code: &str) -> &mut Self {
+        self.errors.push(Reference::error(code));
+        self
+    }
+
+    /// Adds a role the caller needs, callers without it get a `forbidden` error
+    pub fn role(&mut self, role: &str) -> &mut Self {
+        let forbidden = Reference::error(FORBIDDEN);
+        if !self.errors.contains(&forbidden) {
+            self.errors.push(forbidden);
+        }
+        self.roles.push(String::from(role));
+        self
+    }
+}
+
+/// A param or a result
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContentDescriptor {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    pub schema: Schema,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Reference {
+    #[serde(rename = "$ref")]
+    pub reference: String,
+}
+
+impl Reference {
+    fn error(name: &str) -> Self {
+        Reference {
+            reference: format!("{}{}", ERRORS_PATH, name),
+        }
+    }
+}
+
+/// Error object as defined by the specification, `code` and `message` are the ones of
+/// [`crate::jsonrpc::JSONRPCErrorObject`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorObject {
+    pub code: u16,
+    pub message: String,
+    /// Schema of the [`AppError`] sent as `data`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Schema>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Components {
+    /// Schemas of the named types params and results refer to
+    pub schemas: BTreeMap<String, Schema>,
+    pub errors: BTreeMap<String, ErrorObject>,
+}
+
+/// Collects methods into a document, with the schemas of their types
+pub struct Builder {
+    info: Info,
+    generator: SchemaGenerator,
+    methods: Vec<Method>,
+    errors: BTreeMap<String, ErrorObject>,
+    /// Errors every method can return
+    common: Vec<Reference>,
+}
+
+impl Builder {
+    pub fn new(title: &str, version: &str) -> Self {
+        Builder {
+            info: Info {
+                title: String::from(title),
+                version: String::from(version),
+            },
+            generator: SchemaSettings::openapi3().into_generator(),
+            methods: Vec::new(),
+            errors: BTreeMap::new(),
+            common: Vec::new(),
+        }
+    }
+
+    /// Defines an error for each code of [`AppError`], with the schema of its `data`. Errors
+    /// with a code in `common` can be returned by any method, they must be defined before the
+    /// methods.
+    pub fn errors(&mut self, common: &[&str]) -> &mut Self {
+        let variants = match AppError::json_schema(&mut self.generator) {
+            Schema::Object(SchemaObject {
+                subschemas: Some(subschemas),
+                ..
+            }) => subschemas.one_of.unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        for error in AppError::kinds() {
+            let code = error.code();
+            let (status, reason) = error.status();
+            let data = variants
+                .iter()
+                .find(|variant| tagged(variant, &code))
+                .cloned();
+
+            if common.contains(&code.as_str()) {
+                self.common.push(Reference::error(&code));
+            }
+            self.errors.insert(
+                code,
+                ErrorObject {
+                    code: status,
+                    message: String::from(reason),
+                    data,
+                },
+            );
+        }
+        self
+    }
+
+    /// Adds a method, described with the schemas of its params and result
+    pub fn method<M>(&mut self, summary: &str) -> &mut Method
+    where
+        M: RpcMethod,
+        M::Params: JsonSchema,
+        M::Result: JsonSchema,
+    {
+        let params = params(<M::Params as JsonSchema>::json_schema(&mut self.generator));
+        let result = ContentDescriptor {
+            name: String::from("result"),
+            description: None,
+            required: true,
+            schema: self.generator.subschema_for::<M::Result>(),
+        };
+
+        self.methods.push(Method {
+            name: String::from(M::NAME),
+            summary: String::from(summary),
+            param_structure: String::from("by-name"),
+            params,
+            result,
+            errors: self.common.clone(),
+            roles: Vec::new(),
+        });
+        self.methods.last_mut().unwrap()
+    }
+
+    pub fn build(mut self) -> OpenRpc {
+        OpenRpc {
+            openrpc: String::from(OPENRPC_VERSION),
+            info: self.info,
+            methods: self.methods,
+            components: Components {
+                schemas: self.generator.take_definitions().into_iter().collect(),
+                errors: self.errors,
+            },
+        }
+    }
+}
+
+/// Whether the schema of a variant of [`AppError`] is the one tagged with `code`
+fn tagged(variant: &Schema, code: &str) -> bool {
+    let tag = match variant {
+        Schema::Object(SchemaObject {
+            object: Some(object),
+            ..
+        }) => object.properties.get("code"),
+        _ => None,
+    };
+
+    matches!(
+        tag,
+        Some(Schema::Object(SchemaObject { enum_values: Some(values), .. }))
+            if values.iter().any(|value| value == code)
+    )
+}
+
+/// Splits the schema of a params struct into one descriptor per field
+fn params(schema: Schema) -> Vec<ContentDescriptor> {
+    let object = match schema {
+        Schema::Object(SchemaObject {
+            object: Some(object),
+            ..
+        }) => object,
+        _ => return Vec::new(),
+    };
+
+    let ObjectValidation {
+        properties,
+        required,
+        ..
+    } = *object;
+
+    properties
+        .into_iter()
+        .map(|(name, schema)| {
+            let (description, schema) = take_description(schema);
+            ContentDescriptor {
+                required: required.contains(&name),
+                name,
+                description,
+                schema,
+            }
+        })
+        .collect()
+}
+
+/// Moves the doc comment of a field from its schema to its descriptor
+fn take_description(schema: Schema) -> (Option<String>, Schema) {
+    match schema {
+        Schema::Object(mut object) => {
+            let description = object
+                .metadata
+                .as_mut()
+                .and_then(|metadata| metadata.description.take());
+            (description, Schema::Object(object))
+        }
+        schema => (None, schema),
+    }
+}
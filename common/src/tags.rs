@@ -0,0 +1,66 @@
+/*!
+Tags, the static groups of an address book
+*/
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH, MAX_TEXT_LENGTH};
+
+static COLOR: Lazy<Regex> = Lazy::new(|| Regex::new(r"^#[0-9a-f]{6}$").unwrap());
+
+/// Fields of a tag the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TagData {
+    pub name: String,
+    /// `#rrggbb`
+    pub color: String,
+    pub description: Option<String>,
+}
+
+/// A tag as stored in a contact
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct TagRef {
+    pub id: Uuid,
+    pub name: String,
+}
+
+/// Whether `color` is a lowercase `#rrggbb` color
+pub fn is_valid_color(color: &str) -> bool {
+    COLOR.is_match(color)
+}
+
+impl Validate for TagData {
+    /// Names must also be unique per owner regardless of case, which is for the server to check
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+        errors
+            .field("/description", &self.description)
+            .max_length(MAX_TEXT_LENGTH);
+        errors.field("/color", &self.color).required().satisfies(
+            |color| is_valid_color(&color.trim().to_lowercase()),
+            "invalid_color",
+            "Colors must be written as #rrggbb",
+        );
+    }
+}
+
+impl TagData {
+    /// Trims the name and description and lowercases the color of a valid tag
+    pub fn normalize(self) -> Self {
+        TagData {
+            name: self.name.trim().to_string(),
+            color: self.color.trim().to_lowercase(),
+            description: self
+                .description
+                .map(|description| description.trim().to_string())
+                .filter(|description| !description.is_empty()),
+        }
+    }
+}
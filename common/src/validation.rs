@@ -0,0 +1,573 @@
+/*!
+Structured validation errors reported back to the client
+
+The same rules run in the browser before a form is sent and on the server, and both report
+failed fields the same way. Params declare their rules by implementing [`Validate`]:
+
+```ignore
+impl Validate for SuggestParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/prefix", &self.prefix).required();
+        errors.field("/limit", &self.limit).range(1, MAX_SUGGESTIONS);
+    }
+}
+```
+
+Rules that need the data of the owner, like whether a name is taken, are left to the server.
+*/
+
+use std::fmt::Display;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::contacts::email;
+use crate::errors::AppError;
+use crate::jsonrpc::{JSONRPCError, JSONRPCErrorObject};
+
+/// Longest name of a contact, tag, group or anything else the owner names
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// Longest free text, like notes and descriptions
+pub const MAX_TEXT_LENGTH: usize = 10_000;
+
+/// A single field that failed validation
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct FieldError {
+    /// JSON pointer to the offending field, e.g. `/phones/0/number`
+    pub path: String,
+    /// Machine readable reason
+    pub code: String,
+    /// Human readable description
+    pub message: String,
+    /// 1-based column of the error within a text field, e.g. a search query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// Every field that failed validation in a request
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a failed field
+    pub fn add(&mut self, path: impl Into<String>, code: &'static str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            path: path.into(),
+            code: String::from(code),
+            message: message.into(),
+            column: None,
+        });
+    }
+
+    /// Records an error at a given column of a text field
+    pub fn add_at(
+        &mut self,
+        path: impl Into<String>,
+        column: usize,
+        code: &'static str,
+        message: impl Into<String>,
+    ) {
+        self.errors.push(FieldError {
+            path: path.into(),
+            code: String::from(code),
+            message: message.into(),
+            column: Some(column),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Message of the first error at `path`, for a form to show next to the field
+    pub fn message(&self, path: &str) -> Option<&str> {
+        self.errors
+            .iter()
+            .find(|error| error.path == path)
+            .map(|error| error.message.as_str())
+    }
+
+    /// Starts declaring the rules of the field at `path`
+    pub fn field<'a, T: ?Sized>(
+        &'a mut self,
+        path: impl Into<String>,
+        value: &'a T,
+    ) -> Field<'a, T> {
+        Field {
+            errors: self,
+            path: path.into(),
+            value,
+            failed: false,
+            last: None,
+        }
+    }
+
+    /// Declares the rules of each item of a list, found at `path/<index>`
+    pub fn items<T>(&mut self, path: &str, values: &[T], mut rules: impl FnMut(Field<'_, T>)) {
+        for (index, value) in values.iter().enumerate() {
+            rules(self.field(format!("{}/{}", path, index), value));
+        }
+    }
+
+    /// Checks a value with its own rules, the errors it reports moved under `path`
+    pub fn nested<T: Validate + ?Sized>(&mut self, path: &str, value: &T) {
+        let start = self.errors.len();
+        value.rules(self);
+        for error in &mut self.errors[start..] {
+            error.path.insert_str(0, path);
+        }
+    }
+
+    /// Checks each item of a list with its own rules, at `path/<index>`
+    pub fn each<T: Validate>(&mut self, path: &str, values: &[T]) {
+        for (index, value) in values.iter().enumerate() {
+            self.nested(&format!("{}/{}", path, index), value);
+        }
+    }
+
+    /// Records an error at `path` unless `holds`, for rules involving several fields
+    pub fn rule(
+        &mut self,
+        path: impl Into<String>,
+        holds: bool,
+        code: &'static str,
+        message: impl Into<String>,
+    ) {
+        if !holds {
+            self.add(path, code, message);
+        }
+    }
+
+    /// `Ok` when no errors were recorded
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<ValidationErrors> for JSONRPCError<JSONRPCErrorObject<AppError>> {
+    fn from(errors: ValidationErrors) -> Self {
+        log::debug!("Validation failed: {:?}", errors);
+        AppError::from(errors).into()
+    }
+}
+
+/// Values checked against declared rules, see the [module](self) documentation
+pub trait Validate {
+    /// Records every rule the value breaks in `errors`. Values without rules keep the default.
+    fn rules(&self, _errors: &mut ValidationErrors) {}
+
+    /// `Ok` when the value follows all of its rules
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        self.rules(&mut errors);
+        errors.into_result()
+    }
+}
+
+/// A field rules are declared on, see [`ValidationErrors::field`]
+///
+/// Rules are checked in the order they are declared and only the first one the value breaks
+/// is recorded, so `required` goes first.
+pub struct Field<'a, T: ?Sized> {
+    errors: &'a mut ValidationErrors,
+    path: String,
+    value: &'a T,
+    /// Whether a rule already failed
+    failed: bool,
+    /// Index of the error recorded by the last rule, when it failed
+    last: Option<usize>,
+}
+
+impl<'a, T: ?Sized> Field<'a, T> {
+    /// Fails with `code` and `message` unless `holds` for the value
+    pub fn rule(
+        mut self,
+        holds: impl FnOnce(&T) -> bool,
+        code: &'static str,
+        message: impl Into<String>,
+    ) -> Self {
+        self.last = None;
+        if !self.failed && !holds(self.value) {
+            self.failed = true;
+            self.last = Some(self.errors.errors.len());
+            self.errors.add(self.path.clone(), code, message);
+        }
+        self
+    }
+
+    /// Replaces the message of the rule declared last, when it failed
+    pub fn message(self, message: impl Into<String>) -> Self {
+        if let Some(index) = self.last {
+            self.errors.errors[index].message = message.into();
+        }
+        self
+    }
+}
+
+/// Text rules can be declared on. Blank text counts as missing, it only fails `required`.
+pub trait Text {
+    /// The text, `None` when missing or blank
+    fn text(&self) -> Option<&str>;
+}
+
+impl Text for str {
+    fn text(&self) -> Option<&str> {
+        Some(self).filter(|text| !text.trim().is_empty())
+    }
+}
+
+impl Text for String {
+    fn text(&self) -> Option<&str> {
+        self.as_str().text()
+    }
+}
+
+impl<T: Text> Text for Option<T> {
+    fn text(&self) -> Option<&str> {
+        self.as_ref().and_then(Text::text)
+    }
+}
+
+impl<'a, T: Text + ?Sized> Field<'a, T> {
+    /// Fails with `code` and `message` unless `holds` for the text, when there is one
+    pub fn satisfies(
+        self,
+        holds: impl FnOnce(&str) -> bool,
+        code: &'static str,
+        message: impl Into<String>,
+    ) -> Self {
+        self.rule(
+            |value| value.text().map(holds).unwrap_or(true),
+            code,
+            message,
+        )
+    }
+
+    /// Fails on missing or blank text
+    pub fn required(self) -> Self {
+        self.rule(
+            |value| value.text().is_some(),
+            "required",
+            "This field is required",
+        )
+    }
+
+    /// Fails on text shorter than `min` characters
+    pub fn min_length(self, min: usize) -> Self {
+        self.satisfies(
+            |text| text.trim().chars().count() >= min,
+            "too_short",
+            format!("At least {} characters are needed", min),
+        )
+    }
+
+    /// Fails on text longer than `max` characters
+    pub fn max_length(self, max: usize) -> Self {
+        self.satisfies(
+            |text| text.trim().chars().count() <= max,
+            "too_long",
+            format!("At most {} characters are allowed", max),
+        )
+    }
+
+    /// Fails on text that is not an email address
+    pub fn email(self) -> Self {
+        self.satisfies(
+            email::is_valid_address,
+            "invalid_email",
+            "Not an email address",
+        )
+    }
+
+    /// Fails on text that is not a http or https URL
+    pub fn url(self) -> Self {
+        self.satisfies(
+            |text| {
+                matches!(Url::parse(text.trim()), Ok(url) if url.scheme() == "http" || url.scheme() == "https")
+            },
+            "invalid_url",
+            "Not a http or https URL",
+        )
+    }
+
+    /// Fails on text other than one of `values`
+    pub fn one_of(self, values: &[&str]) -> Self {
+        self.satisfies(
+            |text| values.contains(&text),
+            "invalid_value",
+            format!("Must be one of {}", values.join(", ")),
+        )
+    }
+}
+
+/// Lists rules can be declared on
+pub trait Items {
+    /// Number of items in the list
+    fn count(&self) -> usize;
+}
+
+impl<T> Items for [T] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> Items for Vec<T> {
+    fn count(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<'a, T: Items + ?Sized> Field<'a, T> {
+    /// Fails on an empty list
+    pub fn not_empty(self) -> Self {
+        self.rule(
+            |values| values.count() > 0,
+            "required",
+            "At least one is needed",
+        )
+    }
+
+    /// Fails on lists of more than `max` items
+    pub fn max_items(self, max: usize) -> Self {
+        self.rule(
+            |values| values.count() <= max,
+            "too_many",
+            format!("At most {} are allowed", max),
+        )
+    }
+}
+
+impl<'a, T: PartialOrd + Display> Field<'a, T> {
+    /// Fails on values outside of `min..=max`
+    pub fn range(self, min: T, max: T) -> Self {
+        let message = format!("Must be between {} and {}", min, max);
+        self.rule(
+            |value| min <= *value && *value <= max,
+            "out_of_range",
+            message,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Phone {
+        number: String,
+        label: Option<String>,
+    }
+
+    impl Validate for Phone {
+        fn rules(&self, errors: &mut ValidationErrors) {
+            errors
+                .field("/number", &self.number)
+                .required()
+                .max_length(5);
+            errors
+                .field("/label", &self.label)
+                .one_of(&["home", "work"]);
+        }
+    }
+
+    struct Person {
+        name: String,
+        website: Option<String>,
+        phones: Vec<Phone>,
+        tags: Vec<String>,
+        age: u32,
+    }
+
+    impl Validate for Person {
+        fn rules(&self, errors: &mut ValidationErrors) {
+            errors.field("/name", &self.name).required().min_length(2);
+            errors.field("/website", &self.website).url();
+            errors.each("/phones", &self.phones);
+            errors.field("/tags", &self.tags).max_items(2);
+            errors.items("/tags", &self.tags, |tag| {
+                tag.email().message("Tags are addresses");
+            });
+            errors.field("/age", &self.age).range(0, 150);
+        }
+    }
+
+    fn person() -> Person {
+        Person {
+            name: String::from("Ada"),
+            website: None,
+            phones: Vec::new(),
+            tags: Vec::new(),
+            age: 36,
+        }
+    }
+
+    fn codes(errors: &ValidationErrors) -> Vec<(&str, &str)> {
+        errors
+            .errors
+            .iter()
+            .map(|error| (error.path.as_str(), error.code.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn valid_value() {
+        assert_eq!(person().validate(), Ok(()));
+    }
+
+    #[test]
+    fn blank_text_is_missing() {
+        let errors = Person {
+            name: String::from("  "),
+            ..person()
+        }
+        .validate()
+        .unwrap_err();
+        // only the first rule broken is recorded
+        assert_eq!(codes(&errors), vec![("/name", "required")]);
+        assert_eq!(errors.message("/name"), Some("This field is required"));
+    }
+
+    #[test]
+    fn missing_optional_text_passes() {
+        let mut errors = ValidationErrors::new();
+        errors
+            .field("/website", &None::<String>)
+            .url()
+            .max_length(1);
+        errors.field("/website", &Some(String::new())).email();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn length_counts_characters() {
+        let mut errors = ValidationErrors::new();
+        errors.field("/a", "é").min_length(2);
+        errors.field("/b", "éé").min_length(2).max_length(2);
+        errors.field("/c", "ééé").max_length(2);
+        assert_eq!(
+            codes(&errors),
+            vec![("/a", "too_short"), ("/c", "too_long")]
+        );
+        assert_eq!(
+            errors.message("/c"),
+            Some("At most 2 characters are allowed")
+        );
+    }
+
+    #[test]
+    fn urls_must_be_web() {
+        let mut errors = ValidationErrors::new();
+        errors.field("/a", "https://example.com").url();
+        errors.field("/b", "ftp://example.com").url();
+        errors.field("/c", "example.com").url();
+        assert_eq!(
+            codes(&errors),
+            vec![("/b", "invalid_url"), ("/c", "invalid_url")]
+        );
+    }
+
+    #[test]
+    fn nested_errors_are_pointed_to() {
+        let errors = Person {
+            phones: vec![
+                Phone {
+                    number: String::from("123"),
+                    label: Some(String::from("home")),
+                },
+                Phone {
+                    number: String::from("123456"),
+                    label: Some(String::from("boat")),
+                },
+            ],
+            ..person()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            codes(&errors),
+            vec![
+                ("/phones/1/number", "too_long"),
+                ("/phones/1/label", "invalid_value"),
+            ]
+        );
+        assert_eq!(
+            errors.message("/phones/1/label"),
+            Some("Must be one of home, work")
+        );
+    }
+
+    #[test]
+    fn list_rules() {
+        let errors = Person {
+            tags: vec![
+                String::from("ada@example.com"),
+                String::from("nope"),
+                String::from("x@example.com"),
+            ],
+            ..person()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            codes(&errors),
+            vec![("/tags", "too_many"), ("/tags/1", "invalid_email")]
+        );
+        assert_eq!(errors.message("/tags/1"), Some("Tags are addresses"));
+    }
+
+    #[test]
+    fn message_only_replaces_a_failed_rule() {
+        let mut errors = ValidationErrors::new();
+        errors.field("/a", "ok").required().message("Unused");
+        errors
+            .field("/b", "")
+            .required()
+            .message("Say something")
+            .min_length(3)
+            .message("Unused");
+        assert_eq!(codes(&errors), vec![("/b", "required")]);
+        assert_eq!(errors.message("/b"), Some("Say something"));
+        assert_eq!(errors.message("/a"), None);
+    }
+
+    #[test]
+    fn ranges_are_inclusive() {
+        let mut errors = ValidationErrors::new();
+        errors.field("/a", &1).range(1, 10);
+        errors.field("/b", &10).range(1, 10);
+        errors.field("/c", &11).range(1, 10);
+        assert_eq!(codes(&errors), vec![("/c", "out_of_range")]);
+        assert_eq!(errors.message("/c"), Some("Must be between 1 and 10"));
+    }
+
+    #[test]
+    fn rules_on_several_fields() {
+        let mut errors = ValidationErrors::new();
+        errors.rule("/end", true, "before_start", "Ends before it starts");
+        assert!(errors.is_empty());
+        errors.rule("/end", false, "before_start", "Ends before it starts");
+        assert_eq!(codes(&errors), vec![("/end", "before_start")]);
+        assert!(errors.into_result().is_err());
+    }
+
+    #[test]
+    fn not_empty_lists() {
+        let mut errors = ValidationErrors::new();
+        errors.field("/a", &Vec::<u8>::new()).not_empty();
+        errors.field("/b", &vec![1]).not_empty();
+        assert_eq!(codes(&errors), vec![("/a", "required")]);
+    }
+}
@@ -0,0 +1,22 @@
+create table user_settings (
+    owner uuid primary key,
+    default_region text
+);
+
+create table contacts (
+    id uuid primary key,
+    owner uuid not null,
+    given_name text,
+    family_name text,
+    country text,
+    notes text,
+    emails jsonb not null default '[]',
+    phones jsonb not null default '[]',
+    -- Normalized values used for search and duplicate detection
+    email_addresses text[] not null default '{}',
+    phone_numbers text[] not null default '{}'
+);
+
+create index contacts_owner_idx on contacts (owner);
+create index contacts_email_addresses_idx on contacts using gin (email_addresses);
+create index contacts_phone_numbers_idx on contacts using gin (phone_numbers);
@@ -0,0 +1 @@
+alter table contacts add column addresses jsonb not null default '[]';
@@ -0,0 +1,10 @@
+create table custom_field_definitions (
+    id uuid primary key,
+    owner uuid not null,
+    key text not null,
+    name text not null,
+    kind jsonb not null,
+    unique (owner, key)
+);
+
+alter table contacts add column custom_fields jsonb not null default '{}';
@@ -0,0 +1,8 @@
+alter table contacts add column photo text;
+
+create table contact_photos (
+    contact_id uuid not null references contacts (id) on delete cascade,
+    size integer not null,
+    data bytea not null,
+    primary key (contact_id, size)
+);
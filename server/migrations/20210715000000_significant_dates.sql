@@ -0,0 +1,3 @@
+alter table contacts add column dates jsonb not null default '[]';
+
+alter table user_settings add column time_zone text;
@@ -0,0 +1,16 @@
+create type relationship_kind as enum (
+    'spouse', 'parent', 'child', 'manager', 'report', 'colleague', 'introduced_by', 'introduced'
+);
+
+create table relationships (
+    id uuid primary key,
+    owner uuid not null,
+    from_contact uuid not null references contacts (id) on delete cascade,
+    to_contact uuid not null references contacts (id) on delete cascade,
+    kind relationship_kind not null,
+    reciprocal boolean not null,
+    unique (from_contact, to_contact, kind),
+    check (from_contact <> to_contact)
+);
+
+create index relationships_to_contact on relationships (to_contact);
@@ -0,0 +1,15 @@
+create table organizations (
+    id uuid primary key,
+    owner uuid not null,
+    name text not null,
+    domains text[] not null default '{}',
+    address jsonb,
+    notes text
+);
+
+create index organizations_owner_idx on organizations (owner);
+create index organizations_domains_idx on organizations using gin (domains);
+
+alter table contacts add column affiliations jsonb not null default '[]';
+
+create index contacts_affiliations_idx on contacts using gin (affiliations jsonb_path_ops);
@@ -0,0 +1,22 @@
+create type interaction_kind as enum ('call', 'meeting', 'email', 'message');
+
+create table interactions (
+    id uuid primary key,
+    owner uuid not null,
+    kind interaction_kind not null,
+    channel text,
+    occurred_at timestamptz not null,
+    duration integer,
+    notes text
+);
+
+create table interaction_contacts (
+    interaction_id uuid not null references interactions (id) on delete cascade,
+    contact_id uuid not null references contacts (id) on delete cascade,
+    primary key (interaction_id, contact_id)
+);
+
+create index interaction_contacts_contact_idx on interaction_contacts (contact_id);
+create index interactions_occurred_at_idx on interactions (owner, occurred_at desc);
+
+alter table contacts add column last_contacted timestamptz;
@@ -0,0 +1,19 @@
+create table tags (
+    id uuid primary key,
+    owner uuid not null,
+    name text not null,
+    color text not null,
+    description text
+);
+
+create unique index tags_owner_name_idx on tags (owner, lower(name));
+
+create table contact_tags (
+    contact_id uuid not null references contacts (id) on delete cascade,
+    tag_id uuid not null references tags (id) on delete cascade,
+    primary key (contact_id, tag_id)
+);
+
+create index contact_tags_tag_idx on contact_tags (tag_id);
+
+alter table contacts add column tags jsonb not null default '[]';
@@ -0,0 +1,8 @@
+create table smart_groups (
+    id uuid primary key,
+    owner uuid not null,
+    name text not null,
+    query text not null
+);
+
+create unique index smart_groups_owner_name_idx on smart_groups (owner, lower(name));
@@ -0,0 +1,9 @@
+create extension if not exists pg_trgm;
+
+-- Folded names and their Double Metaphone keys, computed by the server when a contact is
+-- projected. Contacts projected before these existed are filled in at startup.
+alter table contacts add column search_name text;
+alter table contacts add column name_keys text[] not null default '{}';
+
+create index contacts_search_name_idx on contacts using gin (search_name gin_trgm_ops);
+create index contacts_name_keys_idx on contacts using gin (name_keys);
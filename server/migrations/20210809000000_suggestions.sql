@@ -0,0 +1,39 @@
+-- Ranking of contacts by how often and how recently they were interacted with, see the
+-- suggest module of the server
+alter table contacts add column frecency double precision;
+
+update contacts set frecency = (
+    select ln(nullif(sum(power(2, extract(epoch from
+        interactions.occurred_at - timestamptz '2021-01-01') / 2592000)), 0)) / ln(2)
+    from interactions
+    join interaction_contacts on interaction_id = interactions.id
+    where contact_id = contacts.id
+);
+
+create index contacts_frecency_idx on contacts (owner, frecency desc nulls last);
+
+-- Prefixes contacts can be suggested for
+create table contact_terms (
+    owner uuid not null,
+    contact_id uuid not null references contacts (id) on delete cascade,
+    field text not null,
+    term text not null,
+    -- Email address the term was taken from
+    value text,
+    primary key (contact_id, field, term)
+);
+
+create index contact_terms_prefix_idx on contact_terms (owner, term text_pattern_ops);
+
+insert into contact_terms (owner, contact_id, field, term, value)
+select owner, id, 'name', term, null
+from contacts, unnest(string_to_array(search_name, ' ') || search_name) term
+where search_name <> ''
+union
+select owner, id, 'email', term, email
+from contacts, unnest(email_addresses) email,
+    unnest(array[email, split_part(email, '@', 2)]) term
+where term <> ''
+on conflict do nothing;
+
+create index organizations_name_prefix_idx on organizations (owner, lower(name) text_pattern_ops);
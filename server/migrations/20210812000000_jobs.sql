@@ -0,0 +1,16 @@
+create type job_status as enum ('queued', 'running', 'succeeded', 'failed');
+
+create table jobs (
+    id uuid primary key,
+    owner uuid not null,
+    kind text not null,
+    params jsonb not null,
+    status job_status not null default 'queued',
+    progress smallint not null default 0,
+    result jsonb,
+    error text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create index jobs_owner_idx on jobs (owner, created_at desc);
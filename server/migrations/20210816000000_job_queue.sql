@@ -0,0 +1,10 @@
+alter type job_status add value 'cancelled';
+
+alter table jobs add column attempts integer not null default 0;
+alter table jobs add column max_attempts integer not null default 5;
+alter table jobs add column run_at timestamptz not null default now();
+alter table jobs add column locked_at timestamptz;
+alter table jobs add column cancel_requested boolean not null default false;
+
+create index jobs_queue_idx on jobs (run_at) where status = 'queued';
+create index jobs_running_idx on jobs (locked_at) where status = 'running';
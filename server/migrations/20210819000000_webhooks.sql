@@ -0,0 +1,34 @@
+create table webhooks (
+    id uuid primary key,
+    owner uuid not null,
+    url text not null,
+    events text[] not null,
+    description text,
+    secret text not null,
+    consecutive_failures integer not null default 0,
+    disabled_at timestamptz
+);
+
+create index webhooks_owner_idx on webhooks (owner);
+
+create type webhook_delivery_status as enum ('pending', 'succeeded', 'failed');
+
+-- Outbox of webhook deliveries, written in the transaction of the change they announce
+create table webhook_deliveries (
+    webhook_id uuid not null references webhooks (id) on delete cascade,
+    event_id uuid not null,
+    event_type text not null,
+    payload jsonb not null,
+    status webhook_delivery_status not null default 'pending',
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    response_status smallint,
+    error text,
+    created_at timestamptz not null default now(),
+    delivered_at timestamptz,
+    primary key (webhook_id, event_id)
+);
+
+create index webhook_deliveries_due_idx on webhook_deliveries (next_attempt_at)
+    where status = 'pending';
+create index webhook_deliveries_log_idx on webhook_deliveries (webhook_id, created_at desc);
@@ -0,0 +1,19 @@
+-- Changes of contacts in commit order per owner, replayed by live streams
+create table contact_changes (
+    sequence bigserial primary key,
+    owner uuid not null,
+    contact_id uuid not null,
+    event_type text not null,
+    contact jsonb not null,
+    created_at timestamptz not null default now()
+);
+
+create index contact_changes_owner_idx on contact_changes (owner, sequence);
+
+create table stream_tickets (
+    ticket_hash text primary key,
+    owner uuid not null,
+    -- Streams opened without Last-Event-ID start after this change
+    after_sequence bigint not null,
+    expires_at timestamptz not null
+);
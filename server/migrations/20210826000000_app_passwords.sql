@@ -0,0 +1,13 @@
+-- Passwords of the CardDAV clients of a user, which cannot sign in with OpenID Connect
+create table app_passwords (
+    id uuid primary key,
+    owner uuid not null,
+    -- Basic auth user name, the preferred username of the owner when it was created
+    username text not null,
+    name text not null,
+    password_hash text not null unique,
+    created_at timestamptz not null default now(),
+    last_used_at timestamptz
+);
+
+create index app_passwords_owner_idx on app_passwords (owner);
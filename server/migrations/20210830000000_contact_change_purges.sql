@@ -0,0 +1,8 @@
+-- Highest sequence number purged from contact_changes for each owner. Sync tokens before it
+-- cannot be answered exactly anymore.
+create table contact_change_horizons (
+    owner uuid primary key,
+    purged_through bigint not null
+);
+
+create index contact_changes_created_at_idx on contact_changes (created_at);
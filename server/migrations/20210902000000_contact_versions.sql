@@ -0,0 +1,2 @@
+-- Number of changes made to each contact, so updates based on an older one can be refused
+alter table contacts add column version bigint not null default 1;
@@ -0,0 +1,129 @@
+/*!
+App passwords
+
+CardDAV clients such as phones and desktop address books only know Basic auth, so users
+create a password for each of them with `app_passwords.create` and revoke it when the device
+is gone. Passwords are random and shown once, only their SHA-256 hash is stored. They are
+random enough that a fast hash keeps them as safe as a slow one would.
+
+App passwords are not event sourced: they are credentials rather than address book data.
+*/
+
+pub mod rpc;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::util::hash_hex;
+
+/// An app password, without the password
+#[derive(Serialize, JsonSchema, Debug, FromRow)]
+pub struct AppPassword {
+    pub id: Uuid,
+    /// User name to sign in with, next to the password
+    pub username: String,
+    /// What the password is for, e.g. "Phone"
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A new app password, the only time the password itself is returned
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct CreatedAppPassword {
+    #[serde(flatten)]
+    pub app_password: AppPassword,
+    pub password: String,
+}
+
+fn generate_password() -> String {
+    format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+impl AppPassword {
+    pub async fn create(
+        pool: &PgPool,
+        owner: Uuid,
+        username: &str,
+        name: &str,
+    ) -> Result<CreatedAppPassword, sqlx::Error> {
+        let password = generate_password();
+
+        let app_password = sqlx::query_as(
+            "insert into app_passwords (id, owner, username, name, password_hash)
+            values ($1, $2, $3, $4, $5)
+            returning id, username, name, created_at, last_used_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(owner)
+        .bind(username)
+        .bind(name)
+        .bind(hash_hex(&password))
+        .fetch_one(pool)
+        .await?;
+
+        Ok(CreatedAppPassword {
+            app_password,
+            password,
+        })
+    }
+
+    /// App passwords of `owner`, newest first
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(
+            "select id, username, name, created_at, last_used_at from app_passwords
+            where owner = $1
+            order by created_at desc",
+        )
+        .bind(owner)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Revokes an app password, `false` when `owner` has no such password
+    pub async fn delete(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query("delete from app_passwords where owner = $1 and id = $2")
+            .bind(owner)
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    /// Owner of the app password signed in with, and notes when it was last used
+    pub async fn verify(
+        pool: &PgPool,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let owner: Option<(Uuid,)> = sqlx::query_as(
+            "select owner from app_passwords where password_hash = $1 and username = $2",
+        )
+        .bind(hash_hex(password))
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+        if owner.is_some() {
+            // Clients sync often, a minute is precise enough and saves most writes
+            sqlx::query(
+                "update app_passwords set last_used_at = now()
+                where password_hash = $1
+                    and (last_used_at is null or last_used_at < now() - interval '1 minute')",
+            )
+            .bind(hash_hex(password))
+            .execute(pool)
+            .await?;
+        }
+
+        Ok(owner.map(|(owner,)| owner))
+    }
+}
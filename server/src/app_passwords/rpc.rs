@@ -0,0 +1,93 @@
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{AppPassword, CreatedAppPassword};
+use crate::rpc::{self, Context, MethodResult};
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct CreateParams {
+    pub name: String,
+}
+
+impl Validate for CreateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+    }
+}
+
+/// Definition of `app_passwords.create`
+pub struct CreateAppPassword;
+
+impl RpcMethod for CreateAppPassword {
+    const NAME: &'static str = "app_passwords.create";
+    type Params = CreateParams;
+    type Result = CreatedAppPassword;
+}
+
+/// `app_passwords.create`
+///
+/// The password is only returned here, it cannot be read again
+pub async fn create(
+    ctx: &Context<'_>,
+    CreateParams { name }: CreateParams,
+) -> MethodResult<CreatedAppPassword> {
+    let name = name.trim();
+
+    let created = AppPassword::create(
+        &ctx.state.postgres,
+        ctx.owner(),
+        &ctx.actor.preferred_username,
+        name,
+    )
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(created)
+}
+
+/// Definition of `app_passwords.list`
+pub struct ListAppPasswords;
+
+impl RpcMethod for ListAppPasswords {
+    const NAME: &'static str = "app_passwords.list";
+    type Params = NoParams;
+    type Result = Vec<AppPassword>;
+}
+
+/// `app_passwords.list`
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<AppPassword>> {
+    let app_passwords = AppPassword::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(app_passwords)
+}
+
+/// Definition of `app_passwords.delete`
+pub struct DeleteAppPassword;
+
+impl RpcMethod for DeleteAppPassword {
+    const NAME: &'static str = "app_passwords.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `app_passwords.delete`
+///
+/// Clients using the password are signed out on their next request
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    if !AppPassword::delete(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+    {
+        return Err(rpc::not_found());
+    }
+
+    rpc::ok(id)
+}
@@ -0,0 +1,464 @@
+/*!
+Bulk operations on contacts
+
+An operation is applied to contacts picked by id or by a query, and reports the outcome for
+each of them as either a success or the error object a single call would have returned.
+
+In [`Mode::AllOrNothing`] every change is staged in one transaction, committed only when all
+contacts succeed. After the first failure the remaining contacts are still checked, without
+being changed, so the caller learns about every problem at once. In [`Mode::BestEffort`] each
+contact is committed on its own. Exports change nothing and never open a transaction.
+
+Operations on more than [`JOB_THRESHOLD`] contacts run as a background [job](crate::jobs).
+Changes all or nothing are limited to [`JOB_THRESHOLD`] contacts: their transaction records
+every change for the [streams](crate::streams), which locks the streams of the owner until it
+commits, so it must stay as short as a request.
+*/
+
+pub mod rpc;
+
+use std::collections::{BTreeMap, HashMap};
+
+use common::contacts::{phone, Affiliation, ContactData, ContactInput};
+use common::errors::AppError;
+use common::jsonrpc::JSONRPCErrorObject;
+use common::validation::{Validate, ValidationErrors, MAX_TEXT_LENGTH};
+use event_sauce::prelude::*;
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    contacts::{self, vcard, Contact, ContactTagsChanged, ContactUpdated},
+    custom_fields::FieldDefinition,
+    jobs::{Job, Progress},
+    organizations::Organization,
+    rpc::{internal, not_found, RpcError},
+    settings::UserSettings,
+    state::State,
+    tags::{self, Tag},
+};
+
+/// Kind of the jobs running bulk operations
+pub const JOB_KIND: &str = "bulk";
+
+/// Most contacts an operation handles within the request, larger ones run as a job
+pub const JOB_THRESHOLD: usize = 2000;
+
+/// Contacts loaded at once while running an operation
+const CHUNK: usize = 500;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Changes are only kept when every contact succeeds. Changes are limited to
+    /// [`JOB_THRESHOLD`] contacts in this mode, exports are not.
+    #[default]
+    AllOrNothing,
+    /// Contacts that succeed are changed even when others fail
+    BestEffort,
+}
+
+/// Reads a field that is present, even as `null`, as `Some`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Changes applied to every contact of a bulk update, fields left out are kept
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ContactPatch {
+    /// New country, `null` removes it
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub country: Option<Option<String>>,
+    /// New notes, `null` removes them
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub notes: Option<Option<String>>,
+    /// Custom field values to set, `null` removes a value
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom_fields: BTreeMap<String, Value>,
+    /// Organizations to affiliate the contacts with, unless they already are
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub affiliations: Vec<Affiliation>,
+}
+
+impl Validate for ContactPatch {
+    /// Custom fields and organizations are for the handler to check against those of the owner
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.rule(
+            "",
+            !self.is_empty(),
+            "required",
+            "The patch does not change anything",
+        );
+        errors.field("/country", &self.country).satisfies(
+            |country| phone::parse_region(country.trim()).is_some(),
+            "invalid_country",
+            "Unknown country",
+        );
+        errors
+            .field("/notes", &self.notes)
+            .max_length(MAX_TEXT_LENGTH);
+    }
+}
+
+impl ContactPatch {
+    pub fn is_empty(&self) -> bool {
+        self.country.is_none()
+            && self.notes.is_none()
+            && self.custom_fields.is_empty()
+            && self.affiliations.is_empty()
+    }
+
+    /// Input replacing `data` with the patch applied
+    fn apply(&self, data: ContactData) -> ContactInput {
+        let mut input = ContactInput::from(data);
+
+        if let Some(country) = &self.country {
+            input.country = country.clone();
+        }
+        if let Some(notes) = &self.notes {
+            input.notes = notes.clone();
+        }
+        input.custom_fields.extend(self.custom_fields.clone());
+        for affiliation in &self.affiliations {
+            if !input
+                .affiliations
+                .iter()
+                .any(|other| other.organization_id == affiliation.organization_id)
+            {
+                input.affiliations.push(affiliation.clone());
+            }
+        }
+
+        input
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+    Update { patch: ContactPatch },
+    Delete,
+    Tag { tag_ids: Vec<Uuid>, remove: bool },
+    Export,
+}
+
+/// An operation with the contacts it applies to, stored as the params of its job
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BulkJob {
+    pub operation: Operation,
+    pub ids: Vec<Uuid>,
+    pub mode: Mode,
+}
+
+/// Outcome of an operation for one contact
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct ItemResult {
+    pub id: Uuid,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JSONRPCErrorObject<AppError>>,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct BulkResult {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// One item per contact, in the order the contacts were given
+    pub items: Vec<ItemResult>,
+    /// vCards of the exported contacts, for `bulk.export`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vcard: Option<String>,
+}
+
+/// Error of the contacts that succeeded in an all or nothing operation that failed
+fn rolled_back() -> JSONRPCErrorObject<AppError> {
+    RpcError::from(AppError::RolledBack)
+        .message("Rolled back because another contact failed")
+        .into_error()
+}
+
+/// Stops a job whose owner cancelled it, changes staged in an all or nothing operation are
+/// rolled back
+fn cancelled() -> RpcError {
+    RpcError::from(AppError::Cancelled).message("Cancelled")
+}
+
+/// An operation with what its contacts are checked against, loaded once per run
+enum Prepared {
+    Update {
+        patch: ContactPatch,
+        region: Option<String>,
+        fields: Vec<FieldDefinition>,
+        organizations: Vec<Uuid>,
+    },
+    Delete,
+    Tag {
+        tags: Vec<Tag>,
+        add: bool,
+    },
+    Export,
+}
+
+impl Prepared {
+    /// Whether the operation changes contacts, exports only read them
+    fn changes(&self) -> bool {
+        !matches!(self, Prepared::Export)
+    }
+
+    async fn load(pool: &PgPool, owner: Uuid, operation: &Operation) -> Result<Self, sqlx::Error> {
+        Ok(match operation {
+            Operation::Update { patch } => Prepared::Update {
+                patch: patch.clone(),
+                region: UserSettings::load(pool, owner).await?.default_region,
+                fields: FieldDefinition::list(pool, owner).await?,
+                organizations: Organization::list(pool, owner)
+                    .await?
+                    .into_iter()
+                    .map(|organization| organization.id)
+                    .collect(),
+            },
+            Operation::Delete => Prepared::Delete,
+            Operation::Tag { tag_ids, remove } => Prepared::Tag {
+                tags: Tag::find_many(pool, owner, tag_ids).await?,
+                add: !remove,
+            },
+            Operation::Export => Prepared::Export,
+        })
+    }
+
+    /// Applies the operation to a contact, returning its vCard for exports. Without a
+    /// transaction the contact is only checked.
+    async fn apply(
+        &self,
+        tx: Option<&mut SqlxPgStoreTransaction>,
+        contact: Contact,
+    ) -> Result<Option<String>, RpcError> {
+        match self {
+            Prepared::Update {
+                patch,
+                region,
+                fields,
+                organizations,
+            } => {
+                let data = contacts::validate(
+                    patch.apply(contact.data.clone()),
+                    region.as_deref(),
+                    fields,
+                    organizations,
+                )?;
+                if let Some(tx) = tx {
+                    contact
+                        .try_update(ContactUpdated { data })
+                        .map_err(internal)?
+                        .stage_persist(tx)
+                        .await
+                        .map_err(internal)?;
+                }
+                Ok(None)
+            }
+            Prepared::Delete => {
+                if let Some(tx) = tx {
+                    contacts::rpc::stage_delete(tx, contact).await?;
+                }
+                Ok(None)
+            }
+            Prepared::Tag { tags, add } => {
+                let changed = tags::assign(&contact.tags, tags, *add);
+                if let Some(tx) = tx.filter(|_| changed != contact.tags) {
+                    contact
+                        .try_update(ContactTagsChanged { tags: changed })
+                        .map_err(internal)?
+                        .stage_persist(tx)
+                        .await
+                        .map_err(internal)?;
+                }
+                Ok(None)
+            }
+            Prepared::Export => Ok(Some(vcard::to_vcard(&contact))),
+        }
+    }
+}
+
+/// Contacts of `owner` among `ids`, by id
+async fn load<'c, E>(
+    executor: E,
+    owner: Uuid,
+    ids: &[Uuid],
+) -> Result<HashMap<Uuid, Contact>, RpcError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(Contact::find_many(executor, owner, ids)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(|contact| (contact.id, contact))
+        .collect())
+}
+
+/// Runs an operation on contacts of `owner`, reporting to `progress` when it runs as a job
+pub async fn run(
+    state: &State,
+    owner: Uuid,
+    job: &BulkJob,
+    mut progress: Option<&mut Progress>,
+) -> Result<BulkResult, RpcError> {
+    let prepared = Prepared::load(&state.postgres, owner, &job.operation)
+        .await
+        .map_err(internal)?;
+
+    let mut outcomes = Vec::with_capacity(job.ids.len());
+    match job.mode {
+        Mode::AllOrNothing => {
+            let mut tx = if prepared.changes() {
+                Some(state.store.transaction().await.map_err(internal)?)
+            } else {
+                None
+            };
+            let mut failed = false;
+
+            for chunk in job.ids.chunks(CHUNK) {
+                // A failed statement aborts the transaction, later contacts are read outside
+                let mut contacts = match tx.as_mut().filter(|_| !failed) {
+                    Some(tx) => load(tx.get(), owner, chunk).await?,
+                    None => load(&state.postgres, owner, chunk).await?,
+                };
+
+                for id in chunk {
+                    let outcome = match contacts.remove(id) {
+                        Some(contact) => {
+                            prepared
+                                .apply(tx.as_mut().filter(|_| !failed), contact)
+                                .await
+                        }
+                        None => Err(not_found()),
+                    };
+                    failed |= outcome.is_err();
+                    outcomes.push((*id, outcome));
+                }
+
+                if let Some(progress) = progress.as_mut() {
+                    progress.report(outcomes.len()).await.map_err(internal)?;
+                    if progress.cancelled() {
+                        return Err(cancelled());
+                    }
+                }
+            }
+
+            if failed {
+                let items = outcomes
+                    .into_iter()
+                    .map(|(id, outcome)| ItemResult {
+                        id,
+                        ok: false,
+                        error: Some(match outcome {
+                            Ok(_) => rolled_back(),
+                            Err(err) => err.into_error(),
+                        }),
+                    })
+                    .collect();
+                return Ok(summarize(items, None));
+            }
+
+            if let Some(tx) = tx {
+                tx.commit().await.map_err(internal)?;
+            }
+        }
+        Mode::BestEffort => {
+            for chunk in job.ids.chunks(CHUNK) {
+                let mut contacts = load(&state.postgres, owner, chunk).await?;
+
+                for id in chunk {
+                    let outcome = match contacts.remove(id) {
+                        Some(contact) if prepared.changes() => {
+                            let mut tx = state.store.transaction().await.map_err(internal)?;
+                            let outcome = prepared.apply(Some(&mut tx), contact).await;
+                            if outcome.is_ok() {
+                                tx.commit().await.map_err(internal)?;
+                            }
+                            outcome
+                        }
+                        Some(contact) => prepared.apply(None, contact).await,
+                        None => Err(not_found()),
+                    };
+                    outcomes.push((*id, outcome));
+                }
+
+                if let Some(progress) = progress.as_mut() {
+                    progress.report(outcomes.len()).await.map_err(internal)?;
+                    if progress.cancelled() {
+                        return Err(cancelled());
+                    }
+                }
+            }
+        }
+    }
+
+    let mut vcards = Vec::new();
+    let items = outcomes
+        .into_iter()
+        .map(|(id, outcome)| match outcome {
+            Ok(vcard) => {
+                vcards.extend(vcard);
+                ItemResult {
+                    id,
+                    ok: true,
+                    error: None,
+                }
+            }
+            Err(err) => ItemResult {
+                id,
+                ok: false,
+                error: Some(err.into_error()),
+            },
+        })
+        .collect();
+
+    let vcard = match job.operation {
+        Operation::Export => Some(vcards.concat()),
+        _ => None,
+    };
+
+    Ok(summarize(items, vcard))
+}
+
+fn summarize(items: Vec<ItemResult>, vcard: Option<String>) -> BulkResult {
+    let succeeded = items.iter().filter(|item| item.ok).count();
+
+    BulkResult {
+        succeeded,
+        failed: items.len() - succeeded,
+        items,
+        vcard,
+    }
+}
+
+/// Runs a bulk operation queued as a job
+pub async fn run_job(state: &State, job: &Job, progress: &mut Progress) -> Result<Value, String> {
+    let params: BulkJob =
+        serde_json::from_value(job.params.clone()).map_err(|err| err.to_string())?;
+    progress.total(params.ids.len());
+
+    let result = run(state, job.owner, &params, Some(progress))
+        .await
+        .map_err(|err| err.into_error().message)?;
+
+    serde_json::to_value(result).map_err(|err| err.to_string())
+}
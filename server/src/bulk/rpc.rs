@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+
+use common::jsonrpc::RpcMethod;
+use common::validation::{Validate, ValidationErrors};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{run, BulkJob, BulkResult, ContactPatch, Mode, Operation, JOB_KIND, JOB_THRESHOLD};
+use crate::{
+    contacts::Contact,
+    custom_fields::{FieldDefinition, FieldValueError},
+    groups,
+    jobs::Job,
+    organizations::Organization,
+    rpc::{self, Context, MethodResult},
+    tags::Tag,
+};
+
+/// Most contacts a single bulk operation can handle
+const MAX_CONTACTS: usize = 50_000;
+
+/// Contacts an operation applies to, given either by id or as a query
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct Target {
+    pub ids: Option<Vec<Uuid>>,
+    pub query: Option<String>,
+}
+
+impl Validate for Target {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.rule(
+            "/ids",
+            self.ids.is_some() != self.query.is_some(),
+            "target_required",
+            "Either ids or a query is needed, not both",
+        );
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    #[serde(flatten)]
+    pub target: Target,
+    #[serde(default)]
+    pub mode: Mode,
+    pub patch: ContactPatch,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.target.rules(errors);
+        errors.nested("/patch", &self.patch);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct TagParams {
+    #[serde(flatten)]
+    pub target: Target,
+    #[serde(default)]
+    pub mode: Mode,
+    pub tag_ids: Vec<Uuid>,
+    /// Removes the tags instead of adding them
+    #[serde(default)]
+    pub remove: bool,
+}
+
+impl Validate for TagParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.target.rules(errors);
+        errors.field("/tag_ids", &self.tag_ids).not_empty();
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct TargetParams {
+    #[serde(flatten)]
+    pub target: Target,
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+impl Validate for TargetParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.target.rules(errors);
+    }
+}
+
+/// Reply to an operation too large to run within the request
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Started {
+    pub job: Job,
+}
+
+/// Reply to an operation, its result or the job running it
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+pub enum Outcome {
+    Done(BulkResult),
+    Started(Started),
+}
+
+/// Ids of the contacts targeted, duplicates removed. Ids are not checked here, unknown ones
+/// fail individually.
+async fn resolve(ctx: &Context<'_>, target: Target) -> Result<Vec<Uuid>, rpc::RpcError> {
+    // The rules of a target leave it with either ids or a query
+    let ids = match target.query {
+        Some(query) => {
+            let compiled = groups::rpc::compile(ctx, "/query", &query, None).await?;
+            Contact::matching_ids(&ctx.state.postgres, ctx.owner(), &compiled)
+                .await
+                .map_err(rpc::internal)?
+        }
+        None => target.ids.unwrap_or_default(),
+    };
+
+    let mut seen = HashSet::with_capacity(ids.len());
+    let unique: Vec<Uuid> = ids.into_iter().filter(|id| seen.insert(*id)).collect();
+
+    if unique.len() > MAX_CONTACTS {
+        let mut errors = ValidationErrors::new();
+        errors.add(
+            "/ids",
+            "too_many",
+            format!("At most {} contacts can be changed at once", MAX_CONTACTS),
+        );
+        return Err(errors.into());
+    }
+
+    Ok(unique)
+}
+
+/// Runs an operation within the request, or as a job when it targets many contacts. Changes
+/// all or nothing are refused past [`JOB_THRESHOLD`] contacts, see the [module](super)
+/// documentation.
+async fn start(ctx: &Context<'_>, job: BulkJob) -> MethodResult<Outcome> {
+    let changes = !matches!(job.operation, Operation::Export);
+    if changes && job.mode == Mode::AllOrNothing && job.ids.len() > JOB_THRESHOLD {
+        let mut errors = ValidationErrors::new();
+        errors.add(
+            "/mode",
+            "too_many",
+            format!(
+                "At most {} contacts can be changed all or nothing, use best_effort for more",
+                JOB_THRESHOLD
+            ),
+        );
+        return Err(errors.into());
+    }
+
+    if job.ids.len() <= JOB_THRESHOLD {
+        let result = run(ctx.state, ctx.owner(), &job, None).await?;
+        return rpc::ok(Outcome::Done(result));
+    }
+
+    let params = serde_json::to_value(&job).map_err(rpc::internal)?;
+    let job = Job::create(&ctx.state.postgres, ctx.owner(), JOB_KIND, params)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::created(Outcome::Started(Started { job }))
+}
+
+/// Checks the custom fields and organizations of a patch against those of the caller
+async fn validate_patch(ctx: &Context<'_>, patch: &ContactPatch) -> Result<(), rpc::RpcError> {
+    let mut errors = ValidationErrors::new();
+
+    let fields = FieldDefinition::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+    for (key, value) in &patch.custom_fields {
+        if value.is_null() {
+            continue;
+        }
+
+        let checked = fields
+            .iter()
+            .find(|field| field.key == *key)
+            .ok_or(FieldValueError::UnknownField)
+            .and_then(|field| field.check(value));
+        if let Err(err) = checked {
+            errors.add(
+                format!("/patch/custom_fields/{}", key),
+                err.code(),
+                err.message(),
+            );
+        }
+    }
+
+    let ids: Vec<Uuid> = patch
+        .affiliations
+        .iter()
+        .map(|affiliation| affiliation.organization_id)
+        .collect();
+    let organizations = Organization::existing(&ctx.state.postgres, ctx.owner(), &ids)
+        .await
+        .map_err(rpc::internal)?;
+    for (index, id) in ids.iter().enumerate() {
+        if !organizations.contains(id) {
+            errors.add(
+                format!("/patch/affiliations/{}/organization_id", index),
+                "unknown_organization",
+                "There is no such organization",
+            );
+        }
+    }
+
+    errors.into_result()?;
+    Ok(())
+}
+
+/// Definition of `bulk.update`
+pub struct UpdateMany;
+
+impl RpcMethod for UpdateMany {
+    const NAME: &'static str = "bulk.update";
+    type Params = UpdateParams;
+    type Result = Outcome;
+}
+
+/// `bulk.update`
+///
+/// Applies a patch to the contacts. Country and notes are replaced when present, custom
+/// field values are merged and affiliations are added.
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams {
+        target,
+        mode,
+        patch,
+    }: UpdateParams,
+) -> MethodResult<Outcome> {
+    validate_patch(ctx, &patch).await?;
+    let ids = resolve(ctx, target).await?;
+
+    start(
+        ctx,
+        BulkJob {
+            operation: Operation::Update { patch },
+            ids,
+            mode,
+        },
+    )
+    .await
+}
+
+/// Definition of `bulk.delete`
+pub struct DeleteMany;
+
+impl RpcMethod for DeleteMany {
+    const NAME: &'static str = "bulk.delete";
+    type Params = TargetParams;
+    type Result = Outcome;
+}
+
+/// `bulk.delete`
+///
+/// Deletes the contacts with their relationships
+pub async fn delete(
+    ctx: &Context<'_>,
+    TargetParams { target, mode }: TargetParams,
+) -> MethodResult<Outcome> {
+    let ids = resolve(ctx, target).await?;
+
+    start(
+        ctx,
+        BulkJob {
+            operation: Operation::Delete,
+            ids,
+            mode,
+        },
+    )
+    .await
+}
+
+/// Definition of `bulk.tag`
+pub struct TagMany;
+
+impl RpcMethod for TagMany {
+    const NAME: &'static str = "bulk.tag";
+    type Params = TagParams;
+    type Result = Outcome;
+}
+
+/// `bulk.tag`
+///
+/// Adds tags to the contacts, or removes them with `remove`
+pub async fn tag(
+    ctx: &Context<'_>,
+    TagParams {
+        target,
+        mode,
+        tag_ids,
+        remove,
+    }: TagParams,
+) -> MethodResult<Outcome> {
+    let mut errors = ValidationErrors::new();
+    let tags = Tag::find_many(&ctx.state.postgres, ctx.owner(), &tag_ids)
+        .await
+        .map_err(rpc::internal)?;
+    for (index, id) in tag_ids.iter().enumerate() {
+        if !tags.iter().any(|tag| tag.id == *id) {
+            errors.add(
+                format!("/tag_ids/{}", index),
+                "unknown_tag",
+                "There is no such tag",
+            );
+        }
+    }
+    errors.into_result()?;
+
+    let ids = resolve(ctx, target).await?;
+
+    start(
+        ctx,
+        BulkJob {
+            operation: Operation::Tag { tag_ids, remove },
+            ids,
+            mode,
+        },
+    )
+    .await
+}
+
+/// Definition of `bulk.export`
+pub struct ExportMany;
+
+impl RpcMethod for ExportMany {
+    const NAME: &'static str = "bulk.export";
+    type Params = TargetParams;
+    type Result = Outcome;
+}
+
+/// `bulk.export`
+///
+/// vCards of the contacts, concatenated in the order of the contacts
+pub async fn export(
+    ctx: &Context<'_>,
+    TargetParams { target, mode }: TargetParams,
+) -> MethodResult<Outcome> {
+    let ids = resolve(ctx, target).await?;
+
+    start(
+        ctx,
+        BulkJob {
+            operation: Operation::Export,
+            ids,
+            mode,
+        },
+    )
+    .await
+}
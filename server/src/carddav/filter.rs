@@ -0,0 +1,354 @@
+/*!
+`C:filter` of `addressbook-query` reports
+
+https://datatracker.ietf.org/doc/html/rfc6352#section-10.5
+
+Filters are evaluated against the content lines of the vCard of each contact, so they see
+exactly what clients get. Parameter filters are not supported.
+*/
+
+use roxmltree::Node;
+
+use super::xml::{child, is, CARDDAV};
+use crate::contacts::vcard::unescape;
+
+/// A filter the server cannot evaluate, reported with the precondition it fails
+#[derive(Debug)]
+pub enum FilterError {
+    /// `C:supported-collation`
+    UnsupportedCollation,
+    /// `C:supported-filter`
+    UnsupportedFilter,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Collation {
+    /// `i;octet`
+    Octet,
+    /// `i;unicode-casemap` and `i;ascii-casemap`, case insensitive
+    CaseMap,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MatchType {
+    Equals,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug)]
+struct TextMatch {
+    text: String,
+    collation: Collation,
+    match_type: MatchType,
+    negate: bool,
+}
+
+impl TextMatch {
+    fn parse(node: Node) -> Result<Self, FilterError> {
+        let collation = match node.attribute("collation").unwrap_or("i;unicode-casemap") {
+            "i;octet" => Collation::Octet,
+            "i;unicode-casemap" | "i;ascii-casemap" => Collation::CaseMap,
+            _ => return Err(FilterError::UnsupportedCollation),
+        };
+        let match_type = match node.attribute("match-type").unwrap_or("contains") {
+            "equals" => MatchType::Equals,
+            "contains" => MatchType::Contains,
+            "starts-with" => MatchType::StartsWith,
+            "ends-with" => MatchType::EndsWith,
+            _ => return Err(FilterError::UnsupportedFilter),
+        };
+        let text = node.text().unwrap_or_default();
+
+        Ok(TextMatch {
+            text: match collation {
+                Collation::Octet => String::from(text),
+                Collation::CaseMap => text.to_lowercase(),
+            },
+            collation,
+            match_type,
+            negate: node.attribute("negate-condition") == Some("yes"),
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        let value = match self.collation {
+            Collation::Octet => String::from(value),
+            Collation::CaseMap => value.to_lowercase(),
+        };
+        let matched = match self.match_type {
+            MatchType::Equals => value == self.text,
+            MatchType::Contains => value.contains(&self.text),
+            MatchType::StartsWith => value.starts_with(&self.text),
+            MatchType::EndsWith => value.ends_with(&self.text),
+        };
+
+        matched != self.negate
+    }
+}
+
+/// Whether every condition must hold, `test="allof"`, or any of them, `test="anyof"`
+fn all_of(node: Node) -> Result<bool, FilterError> {
+    match node.attribute("test").unwrap_or("anyof") {
+        "anyof" => Ok(false),
+        "allof" => Ok(true),
+        _ => Err(FilterError::UnsupportedFilter),
+    }
+}
+
+#[derive(Debug)]
+struct PropFilter {
+    /// Property name, upper case
+    name: String,
+    all_of: bool,
+    is_not_defined: bool,
+    text_matches: Vec<TextMatch>,
+}
+
+impl PropFilter {
+    fn parse(node: Node) -> Result<Self, FilterError> {
+        if child(node, CARDDAV, "param-filter").is_some() {
+            return Err(FilterError::UnsupportedFilter);
+        }
+
+        Ok(PropFilter {
+            name: node.attribute("name").unwrap_or_default().to_uppercase(),
+            all_of: all_of(node)?,
+            is_not_defined: child(node, CARDDAV, "is-not-defined").is_some(),
+            text_matches: node
+                .children()
+                .filter(|child| is(*child, CARDDAV, "text-match"))
+                .map(TextMatch::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn matches(&self, lines: &[String]) -> bool {
+        let values: Vec<String> = lines
+            .iter()
+            .filter_map(|line| property(line))
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+            .map(|(_, value)| unescape(value))
+            .collect();
+
+        if self.is_not_defined {
+            return values.is_empty();
+        }
+        if self.text_matches.is_empty() {
+            return !values.is_empty();
+        }
+
+        values.iter().any(|value| {
+            let mut matches = self.text_matches.iter().map(|text| text.matches(value));
+            if self.all_of {
+                matches.all(|matched| matched)
+            } else {
+                matches.any(|matched| matched)
+            }
+        })
+    }
+}
+
+/// A `C:filter`, the default one matches every card
+#[derive(Debug, Default)]
+pub struct Filter {
+    all_of: bool,
+    props: Vec<PropFilter>,
+}
+
+impl Filter {
+    pub fn parse(node: Node) -> Result<Self, FilterError> {
+        Ok(Filter {
+            all_of: all_of(node)?,
+            props: node
+                .children()
+                .filter(|child| is(*child, CARDDAV, "prop-filter"))
+                .map(PropFilter::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether a card matches, given the content lines of its vCard
+    pub fn matches(&self, lines: &[String]) -> bool {
+        if self.props.is_empty() {
+            return true;
+        }
+
+        let mut matches = self.props.iter().map(|prop| prop.matches(lines));
+        if self.all_of {
+            matches.all(|matched| matched)
+        } else {
+            matches.any(|matched| matched)
+        }
+    }
+}
+
+/// Name and value of a content line, parameters left out
+pub fn property(line: &str) -> Option<(&str, &str)> {
+    let colon = line.find(':')?;
+    let name = line[..colon].split(';').next().unwrap_or_default();
+
+    Some((name, &line[colon + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use roxmltree::Document;
+
+    use super::*;
+
+    fn filter(body: &str) -> Result<Filter, FilterError> {
+        let body = format!(
+            "<C:filter xmlns:C=\"urn:ietf:params:xml:ns:carddav\" {}</C:filter>",
+            body
+        );
+        Filter::parse(Document::parse(&body).unwrap().root_element())
+    }
+
+    fn lines() -> Vec<String> {
+        vec![
+            String::from("BEGIN:VCARD"),
+            String::from("FN:Ada Lovelace"),
+            String::from("EMAIL;TYPE=work:ada@Example.com"),
+            String::from("NOTE:Analyst\\, poet"),
+            String::from("END:VCARD"),
+        ]
+    }
+
+    fn matches(body: &str) -> bool {
+        filter(body).unwrap().matches(&lines())
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(matches(">"));
+        assert!(Filter::default().matches(&lines()));
+    }
+
+    #[test]
+    fn case_insensitive_contains_by_default() {
+        assert!(matches(
+            "><C:prop-filter name=\"fn\"><C:text-match>LOVE</C:text-match></C:prop-filter>"
+        ));
+        assert!(!matches(
+            "><C:prop-filter name=\"FN\"><C:text-match>Babbage</C:text-match></C:prop-filter>"
+        ));
+    }
+
+    #[test]
+    fn octet_collation_is_case_sensitive() {
+        let text_match = |text: &str| {
+            format!(
+                "><C:prop-filter name=\"EMAIL\"><C:text-match collation=\"i;octet\">{}\
+                 </C:text-match></C:prop-filter>",
+                text
+            )
+        };
+        assert!(matches(&text_match("Example")));
+        assert!(!matches(&text_match("example")));
+    }
+
+    #[test]
+    fn match_types() {
+        let text_match = |match_type: &str, text: &str| {
+            matches(&format!(
+                "><C:prop-filter name=\"FN\"><C:text-match match-type=\"{}\">{}\
+                 </C:text-match></C:prop-filter>",
+                match_type, text
+            ))
+        };
+        assert!(text_match("equals", "ada lovelace"));
+        assert!(!text_match("equals", "ada"));
+        assert!(text_match("starts-with", "ada"));
+        assert!(!text_match("starts-with", "lovelace"));
+        assert!(text_match("ends-with", "lovelace"));
+        assert!(!text_match("ends-with", "ada"));
+    }
+
+    #[test]
+    fn negated_text_match() {
+        assert!(!matches(
+            "><C:prop-filter name=\"FN\"><C:text-match negate-condition=\"yes\">ada\
+             </C:text-match></C:prop-filter>"
+        ));
+        assert!(matches(
+            "><C:prop-filter name=\"FN\"><C:text-match negate-condition=\"yes\">babbage\
+             </C:text-match></C:prop-filter>"
+        ));
+    }
+
+    #[test]
+    fn values_are_unescaped() {
+        assert!(matches(
+            "><C:prop-filter name=\"NOTE\"><C:text-match match-type=\"equals\">analyst, poet\
+             </C:text-match></C:prop-filter>"
+        ));
+    }
+
+    #[test]
+    fn defined_and_not_defined_properties() {
+        assert!(matches("><C:prop-filter name=\"EMAIL\"/>"));
+        assert!(!matches("><C:prop-filter name=\"TEL\"/>"));
+        assert!(matches(
+            "><C:prop-filter name=\"TEL\"><C:is-not-defined/></C:prop-filter>"
+        ));
+        assert!(!matches(
+            "><C:prop-filter name=\"EMAIL\"><C:is-not-defined/></C:prop-filter>"
+        ));
+    }
+
+    #[test]
+    fn any_of_and_all_of() {
+        let props = "<C:prop-filter name=\"EMAIL\"/><C:prop-filter name=\"TEL\"/>";
+        assert!(matches(&format!(">{}", props)));
+        assert!(!matches(&format!(" test=\"allof\">{}", props)));
+
+        let text_matches = "<C:prop-filter name=\"FN\" test=\"allof\">\
+             <C:text-match>ada</C:text-match><C:text-match>babbage</C:text-match>\
+             </C:prop-filter>";
+        assert!(!matches(&format!(">{}", text_matches)));
+        assert!(matches(&format!(
+            ">{}",
+            text_matches.replace("allof", "anyof")
+        )));
+    }
+
+    #[test]
+    fn unsupported_filters() {
+        assert!(matches!(
+            filter(
+                "><C:prop-filter name=\"FN\"><C:text-match collation=\"i;klingon\">x\
+                 </C:text-match></C:prop-filter>"
+            ),
+            Err(FilterError::UnsupportedCollation)
+        ));
+        assert!(matches!(
+            filter(
+                "><C:prop-filter name=\"FN\"><C:text-match match-type=\"sounds-like\">x\
+                 </C:text-match></C:prop-filter>"
+            ),
+            Err(FilterError::UnsupportedFilter)
+        ));
+        assert!(matches!(
+            filter(
+                "><C:prop-filter name=\"EMAIL\"><C:param-filter name=\"TYPE\"/></C:prop-filter>"
+            ),
+            Err(FilterError::UnsupportedFilter)
+        ));
+        assert!(matches!(
+            filter(" test=\"noneof\">"),
+            Err(FilterError::UnsupportedFilter)
+        ));
+    }
+
+    #[test]
+    fn content_line_properties() {
+        assert_eq!(
+            property("EMAIL;TYPE=work:ada@example.com"),
+            Some(("EMAIL", "ada@example.com"))
+        );
+        assert_eq!(property("NOTE:a:b"), Some(("NOTE", "a:b")));
+        assert_eq!(property("garbage"), None);
+    }
+}
@@ -0,0 +1,926 @@
+/*!
+CardDAV server
+
+https://datatracker.ietf.org/doc/html/rfc6352
+
+Native address books such as iOS Contacts, Thunderbird and DAVx5 sync with the contacts of a
+user through a single address book:
+
+- `/dav/principal/`: the user, pointing to the address book home
+- `/dav/addressbooks/`: the address book home
+- `/dav/addressbooks/contacts/`: the address book
+- `/dav/addressbooks/contacts/<id>.vcf`: a contact, as served by `contacts.vcard`
+
+`/.well-known/carddav` redirects to `/dav/`, which points to the principal, so clients only
+need the host name. Clients sign in with Basic auth and an app password, see
+[`crate::app_passwords`].
+
+`PROPFIND`, `GET`, `PUT`, `DELETE` and the `addressbook-multiget`, `addressbook-query` and
+`sync-collection` reports are supported. The ETag of a contact is the hash of its vCard. Sync
+tokens are the sequence numbers of the change stream, so `sync-collection` reports the contacts
+changed or deleted since any earlier token, unless the changes after it were purged.
+
+Writes go through the contact events like edits in the app, and honour `If-Match` and
+`If-None-Match` so a client never overwrites a change it has not seen. The contact is locked
+while its ETag is compared, until the write commits. `PUT` replaces the fields a vCard holds,
+custom fields included, see [`vcard::read`]. Contacts get their id from the server, so a card
+`PUT` at a new name is created under its id, given in the `Location` header. Responses to `PUT`
+carry no ETag, the stored vCard differs from the one sent, so clients fetch it again. Other
+writes are refused with the `DAV:need-privileges` precondition.
+*/
+
+pub mod filter;
+pub mod xml;
+
+use std::collections::HashMap;
+
+use event_sauce::prelude::*;
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use sqlx::PgPool;
+use tide::{
+    http::{auth::BasicAuth, Method, Url},
+    Redirect, Request, Response, StatusCode,
+};
+use uuid::Uuid;
+
+use crate::{
+    app_passwords::AppPassword,
+    contacts::{self, rpc::stage_delete, vcard, Contact, ContactCreated, ContactUpdated, SortBy},
+    custom_fields::FieldDefinition,
+    state::State,
+    streams,
+    util::{hash_hex, xml_escape},
+    webhooks,
+};
+use filter::FilterError;
+use xml::{
+    AddressData, Multistatus, Name, Props, Report, RequestError, CALENDARSERVER, CARDDAV, DAV,
+};
+
+pub const ROOT: &str = "/dav/";
+const PRINCIPAL: &str = "/dav/principal/";
+const HOME: &str = "/dav/addressbooks/";
+const ADDRESS_BOOK: &str = "/dav/addressbooks/contacts/";
+
+/// Sync tokens are this prefix followed by a sequence number of the change stream
+const SYNC_TOKEN_PREFIX: &str = "urn:x-contacts:sync:";
+
+const XML: &str = "application/xml; charset=utf-8";
+const VCARD: &str = "text/vcard; charset=utf-8";
+
+/// Methods of the address book
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT";
+
+/// Properties returned for `DAV:allprop`
+const ALL_PROPS: &[(&str, &str)] = &[
+    (DAV, "resourcetype"),
+    (DAV, "displayname"),
+    (DAV, "getetag"),
+    (DAV, "getcontenttype"),
+    (DAV, "getcontentlength"),
+];
+
+/// Properties listed for `DAV:propname`, on the resources they apply to
+const PROP_NAMES: &[(&str, &str)] = &[
+    (DAV, "resourcetype"),
+    (DAV, "displayname"),
+    (DAV, "getetag"),
+    (DAV, "getcontenttype"),
+    (DAV, "getcontentlength"),
+    (DAV, "current-user-principal"),
+    (DAV, "current-user-privilege-set"),
+    (DAV, "principal-URL"),
+    (DAV, "owner"),
+    (DAV, "supported-report-set"),
+    (DAV, "sync-token"),
+    (CARDDAV, "addressbook-home-set"),
+    (CARDDAV, "supported-address-data"),
+    (CALENDARSERVER, "getctag"),
+];
+
+/// A path under `/dav/`
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Target {
+    Root,
+    Principal,
+    Home,
+    AddressBook,
+    Card(Uuid),
+    /// A card name that is not the id of a contact, where clients create cards
+    NewCard,
+}
+
+impl Target {
+    /// Target of a request path or of an href, which may be a full URL
+    fn parse(href: &str) -> Option<Self> {
+        let url;
+        let path = if href.starts_with("http://") || href.starts_with("https://") {
+            url = Url::parse(href).ok()?;
+            url.path()
+        } else {
+            href
+        };
+
+        match path.trim_end_matches('/') {
+            "/dav" => Some(Target::Root),
+            "/dav/principal" => Some(Target::Principal),
+            "/dav/addressbooks" => Some(Target::Home),
+            "/dav/addressbooks/contacts" => Some(Target::AddressBook),
+            path => path
+                .strip_prefix(ADDRESS_BOOK)
+                .and_then(|name| name.strip_suffix(".vcf"))
+                .filter(|name| !name.is_empty() && !name.contains('/'))
+                .map(|name| match name.parse() {
+                    Ok(id) => Target::Card(id),
+                    Err(_) => Target::NewCard,
+                }),
+        }
+    }
+}
+
+/// A contact with its vCard
+struct Card {
+    contact: Contact,
+    lines: Vec<String>,
+    vcard: String,
+    etag: String,
+}
+
+impl From<Contact> for Card {
+    fn from(contact: Contact) -> Self {
+        let lines = vcard::properties(&contact);
+        let vcard = vcard::from_properties(&lines);
+        let etag = format!("\"{}\"", hash_hex(&vcard));
+
+        Card {
+            contact,
+            lines,
+            vcard,
+            etag,
+        }
+    }
+}
+
+impl Card {
+    fn href(&self) -> String {
+        format!("{}{}.vcf", ADDRESS_BOOK, self.contact.id)
+    }
+
+    /// The vCard, with only the properties asked for when there is a list of them
+    fn address_data(&self, address_data: &AddressData) -> String {
+        match address_data {
+            None => self.vcard.clone(),
+            Some(properties) => {
+                let lines: Vec<String> = self
+                    .lines
+                    .iter()
+                    .filter(|line| match filter::property(line) {
+                        Some((name, _)) => {
+                            ["BEGIN", "END", "VERSION", "UID"].contains(&name)
+                                || properties.iter().any(|property| property == name)
+                        }
+                        None => false,
+                    })
+                    .cloned()
+                    .collect();
+                vcard::from_properties(&lines)
+            }
+        }
+    }
+}
+
+enum Resource {
+    Root,
+    Principal,
+    Home,
+    AddressBook,
+    Card(Box<Card>),
+}
+
+impl Resource {
+    fn href(&self) -> String {
+        match self {
+            Resource::Root => String::from(ROOT),
+            Resource::Principal => String::from(PRINCIPAL),
+            Resource::Home => String::from(HOME),
+            Resource::AddressBook => String::from(ADDRESS_BOOK),
+            Resource::Card(card) => card.href(),
+        }
+    }
+
+    /// Value of a property as XML, `None` when the resource does not have it
+    fn property(
+        &self,
+        name: &Name,
+        sync_token: &str,
+        address_data: &AddressData,
+    ) -> Option<String> {
+        let href = |href: &str| format!("<D:href>{}</D:href>", href);
+        let card = match self {
+            Resource::Card(card) => Some(card),
+            _ => None,
+        };
+
+        let value = match (name.namespace.as_str(), name.local.as_str()) {
+            (DAV, "resourcetype") => match self {
+                Resource::Root | Resource::Home => String::from("<D:collection/>"),
+                Resource::Principal => String::from("<D:collection/><D:principal/>"),
+                Resource::AddressBook => String::from("<D:collection/><C:addressbook/>"),
+                Resource::Card(_) => String::new(),
+            },
+            (DAV, "displayname") => match self {
+                Resource::Home => String::from("Address books"),
+                Resource::AddressBook => String::from("Contacts"),
+                Resource::Card(card) => xml_escape(&card.contact.display_name()),
+                _ => return None,
+            },
+            (DAV, "current-user-principal") => href(PRINCIPAL),
+            (DAV, "current-user-privilege-set") => String::from(
+                "<D:privilege><D:read/></D:privilege>\
+                <D:privilege><D:write-content/></D:privilege>\
+                <D:privilege><D:bind/></D:privilege>\
+                <D:privilege><D:unbind/></D:privilege>\
+                <D:privilege><D:read-current-user-privilege-set/></D:privilege>",
+            ),
+            (DAV, "principal-URL") => match self {
+                Resource::Principal => href(PRINCIPAL),
+                _ => return None,
+            },
+            (CARDDAV, "addressbook-home-set") => match self {
+                Resource::Root | Resource::Principal => href(HOME),
+                _ => return None,
+            },
+            (DAV, "owner") => match self {
+                Resource::AddressBook => href(PRINCIPAL),
+                _ => return None,
+            },
+            (DAV, "supported-report-set") => match self {
+                Resource::AddressBook => [
+                    "<C:addressbook-multiget/>",
+                    "<C:addressbook-query/>",
+                    "<D:sync-collection/>",
+                ]
+                .iter()
+                .map(|report| {
+                    format!(
+                        "<D:supported-report><D:report>{}</D:report></D:supported-report>",
+                        report
+                    )
+                })
+                .collect(),
+                _ => return None,
+            },
+            (CARDDAV, "supported-address-data") => match self {
+                Resource::AddressBook => String::from(
+                    "<C:address-data-type content-type=\"text/vcard\" version=\"3.0\"/>",
+                ),
+                _ => return None,
+            },
+            (DAV, "sync-token") | (CALENDARSERVER, "getctag") => match self {
+                Resource::AddressBook => xml_escape(sync_token),
+                _ => return None,
+            },
+            (DAV, "getetag") => xml_escape(&card?.etag),
+            (DAV, "getcontenttype") => card.map(|_| String::from(VCARD))?,
+            (DAV, "getcontentlength") => card?.vcard.len().to_string(),
+            (CARDDAV, "address-data") => xml_escape(&card?.address_data(address_data)),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// Adds the properties asked for to a multistatus response
+    fn propstat(
+        &self,
+        multistatus: &mut Multistatus,
+        props: &Props,
+        sync_token: &str,
+        address_data: &AddressData,
+    ) {
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+
+        match props {
+            Props::All => {
+                for (namespace, local) in ALL_PROPS {
+                    let name = Name::new(namespace, local);
+                    if let Some(value) = self.property(&name, sync_token, address_data) {
+                        found.push((name, value));
+                    }
+                }
+            }
+            Props::Names => {
+                for (namespace, local) in PROP_NAMES {
+                    let name = Name::new(namespace, local);
+                    if self.property(&name, sync_token, address_data).is_some() {
+                        found.push((name, String::new()));
+                    }
+                }
+            }
+            Props::Only(names) => {
+                for name in names {
+                    match self.property(name, sync_token, address_data) {
+                        Some(value) => found.push((name.clone(), value)),
+                        None => missing.push(name.clone()),
+                    }
+                }
+            }
+        }
+
+        multistatus.propstat(&self.href(), &found, &missing);
+    }
+}
+
+/// Logs an unexpected error and hides the details from the client
+fn internal(err: impl std::fmt::Debug) -> tide::Error {
+    log::error!("CardDAV error: {:?}", err);
+    tide::Error::from_str(StatusCode::InternalServerError, "Internal Server Error")
+}
+
+fn unauthorized() -> Response {
+    Response::builder(StatusCode::Unauthorized)
+        .header(
+            "WWW-Authenticate",
+            "Basic realm=\"Contacts\", charset=\"UTF-8\"",
+        )
+        .build()
+}
+
+/// Error with the precondition that failed
+fn precondition(status: StatusCode, namespace: &str, name: &str) -> Response {
+    Response::builder(status)
+        .content_type(XML)
+        .body(xml::error(namespace, name))
+        .build()
+}
+
+fn multistatus_response(body: String) -> Response {
+    Response::builder(StatusCode::MultiStatus)
+        .content_type(XML)
+        .body(body)
+        .build()
+}
+
+fn request_error(err: RequestError) -> Response {
+    log::debug!("Invalid CardDAV request: {:?}", err);
+
+    match err {
+        RequestError::Malformed(message) => Response::builder(StatusCode::BadRequest)
+            .body(message)
+            .build(),
+        RequestError::Filter(FilterError::UnsupportedCollation) => {
+            precondition(StatusCode::Forbidden, CARDDAV, "supported-collation")
+        }
+        RequestError::Filter(FilterError::UnsupportedFilter) => {
+            precondition(StatusCode::Forbidden, CARDDAV, "supported-filter")
+        }
+        RequestError::UnsupportedReport => {
+            precondition(StatusCode::Forbidden, DAV, "supported-report")
+        }
+    }
+}
+
+fn sync_token(sequence: i64) -> String {
+    format!("{}{}", SYNC_TOKEN_PREFIX, sequence)
+}
+
+/// `/.well-known/carddav`
+pub async fn well_known(_req: Request<State>) -> tide::Result {
+    Ok(Redirect::permanent(ROOT).into())
+}
+
+/// Every method on `/dav/`
+pub async fn handler(req: Request<State>) -> tide::Result {
+    if req.method() == Method::Options {
+        return Ok(Response::builder(StatusCode::Ok)
+            .header("DAV", "1, 3, addressbook")
+            .header("Allow", ALLOW)
+            .build());
+    }
+
+    let credentials = match BasicAuth::from_headers(&req) {
+        Ok(Some(credentials)) => credentials,
+        _ => return Ok(unauthorized()),
+    };
+    let owner = match AppPassword::verify(
+        &req.state().postgres,
+        credentials.username(),
+        credentials.password(),
+    )
+    .await
+    .map_err(internal)?
+    {
+        Some(owner) => owner,
+        None => {
+            log::debug!("Invalid app password for {}", credentials.username());
+            return Ok(unauthorized());
+        }
+    };
+
+    let target = match Target::parse(req.url().path()) {
+        Some(target) => target,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    match req.method() {
+        Method::PropFind => propfind(req, owner, target).await,
+        Method::Report => report(req, owner, target).await,
+        Method::Get | Method::Head => get(req, owner, target).await,
+        Method::Put => put(req, owner, target).await,
+        Method::Delete => delete(req, owner, target).await,
+        Method::Post
+        | Method::Patch
+        | Method::PropPatch
+        | Method::MkCol
+        | Method::Copy
+        | Method::Move => Ok(precondition(StatusCode::Forbidden, DAV, "need-privileges")),
+        _ => Ok(Response::builder(StatusCode::MethodNotAllowed)
+            .header("Allow", ALLOW)
+            .build()),
+    }
+}
+
+/// The resource at a target, `None` when it does not exist
+async fn load(pool: &PgPool, owner: Uuid, target: Target) -> Result<Option<Resource>, sqlx::Error> {
+    Ok(Some(match target {
+        Target::Root => Resource::Root,
+        Target::Principal => Resource::Principal,
+        Target::Home => Resource::Home,
+        Target::AddressBook => Resource::AddressBook,
+        Target::Card(id) => match Contact::find(pool, owner, id).await? {
+            Some(contact) => Resource::Card(Box::new(Card::from(contact))),
+            None => return Ok(None),
+        },
+        Target::NewCard => return Ok(None),
+    }))
+}
+
+async fn cards(pool: &PgPool, owner: Uuid) -> Result<Vec<Card>, sqlx::Error> {
+    let contacts = Contact::list(pool, owner, &SortBy::Name, false).await?;
+
+    Ok(contacts.into_iter().map(Card::from).collect())
+}
+
+async fn propfind(mut req: Request<State>, owner: Uuid, target: Target) -> tide::Result {
+    // A missing Depth means infinity, the tree is shallow enough to allow it
+    let depth = match req.header("Depth").map(|values| values.last().as_str()) {
+        Some("0") => 0,
+        Some("1") => 1,
+        _ => usize::MAX,
+    };
+
+    let body = req.body_string().await?;
+    let props = match xml::propfind(&body) {
+        Ok(props) => props,
+        Err(err) => return Ok(request_error(err)),
+    };
+
+    let pool = &req.state().postgres;
+    let resource = match load(pool, owner, target).await.map_err(internal)? {
+        Some(resource) => resource,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let sync_token = sync_token(streams::latest(pool, owner).await.map_err(internal)?);
+
+    let mut multistatus = Multistatus::default();
+    resource.propstat(&mut multistatus, &props, &sync_token, &None);
+
+    let mut level = vec![target];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for target in level {
+            match target {
+                Target::Root => {
+                    next.push(Target::Principal);
+                    next.push(Target::Home);
+                }
+                Target::Home => next.push(Target::AddressBook),
+                Target::AddressBook => {
+                    for card in cards(pool, owner).await.map_err(internal)? {
+                        Resource::Card(Box::new(card)).propstat(
+                            &mut multistatus,
+                            &props,
+                            &sync_token,
+                            &None,
+                        );
+                    }
+                }
+                Target::Principal | Target::Card(_) | Target::NewCard => {}
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+
+        for target in &next {
+            if let Some(resource) = load(pool, owner, *target).await.map_err(internal)? {
+                resource.propstat(&mut multistatus, &props, &sync_token, &None);
+            }
+        }
+        level = next;
+    }
+
+    Ok(multistatus_response(multistatus.finish(None)))
+}
+
+/// Whether the ETags of an `If-Match` or `If-None-Match` header match `card`, `None` without
+/// the header. `*` matches any card that exists.
+fn etag_matches(req: &Request<State>, header: &str, card: Option<&Card>) -> Option<bool> {
+    let values = req.header(header)?;
+    let card = match card {
+        Some(card) => card,
+        None => return Some(false),
+    };
+
+    Some(
+        values
+            .iter()
+            .flat_map(|value| value.as_str().split(','))
+            .map(str::trim)
+            .any(|etag| etag == "*" || etag == card.etag),
+    )
+}
+
+/// Whether the `If-Match` and `If-None-Match` preconditions of a write hold for `card`, the
+/// current state of the target
+fn write_allowed(req: &Request<State>, card: Option<&Card>) -> bool {
+    etag_matches(req, "If-Match", card) != Some(false)
+        && etag_matches(req, "If-None-Match", card) != Some(true)
+}
+
+/// The card at a target, `None` when it does not exist. The contact is locked until the
+/// transaction ends, so the card cannot change before it is written.
+async fn find_card(
+    tx: &mut SqlxPgStoreTransaction,
+    owner: Uuid,
+    target: Target,
+) -> Result<Option<Card>, sqlx::Error> {
+    Ok(match target {
+        Target::Card(id) => Contact::find_for_update(tx, owner, id)
+            .await?
+            .map(Card::from),
+        _ => None,
+    })
+}
+
+async fn get(req: Request<State>, owner: Uuid, target: Target) -> tide::Result {
+    let id = match target {
+        Target::Card(id) => id,
+        Target::NewCard => return Ok(Response::new(StatusCode::NotFound)),
+        _ => {
+            return Ok(Response::builder(StatusCode::MethodNotAllowed)
+                .header("Allow", "OPTIONS, PROPFIND, REPORT")
+                .build())
+        }
+    };
+
+    let card = match Contact::find(&req.state().postgres, owner, id)
+        .await
+        .map_err(internal)?
+    {
+        Some(contact) => Card::from(contact),
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    if etag_matches(&req, "If-None-Match", Some(&card)) == Some(true) {
+        return Ok(Response::builder(StatusCode::NotModified)
+            .header("ETag", card.etag.as_str())
+            .build());
+    }
+
+    let mut response = Response::builder(StatusCode::Ok)
+        .header("ETag", card.etag.as_str())
+        .content_type(VCARD)
+        .build();
+    if req.method() == Method::Get {
+        response.set_body(card.vcard);
+    }
+
+    Ok(response)
+}
+
+/// Creates or replaces a card
+async fn put(mut req: Request<State>, owner: Uuid, target: Target) -> tide::Result {
+    if !matches!(target, Target::Card(_) | Target::NewCard) {
+        return Ok(precondition(StatusCode::Forbidden, DAV, "need-privileges"));
+    }
+    let is_vcard = req
+        .content_type()
+        .is_none_or(|mime| mime.essence() == "text/vcard");
+    if !is_vcard {
+        return Ok(precondition(
+            StatusCode::Forbidden,
+            CARDDAV,
+            "supported-address-data",
+        ));
+    }
+
+    let body = req.body_string().await?;
+    let state = req.state();
+    let pool = &state.postgres;
+    let mut tx = state.store.transaction().await.map_err(internal)?;
+    let card = find_card(&mut tx, owner, target).await.map_err(internal)?;
+    if !write_allowed(&req, card.as_ref()) {
+        return Ok(Response::new(StatusCode::PreconditionFailed));
+    }
+
+    let input = card
+        .as_ref()
+        .map(|card| card.contact.data.clone().into())
+        .unwrap_or_default();
+    let invalid = || precondition(StatusCode::Forbidden, CARDDAV, "valid-address-data");
+    let fields = FieldDefinition::list(pool, owner).await.map_err(internal)?;
+    let input = match vcard::read(&body, input, &fields) {
+        Some(input) => input,
+        None => return Ok(invalid()),
+    };
+    let data = match contacts::validate_for(pool, owner, input)
+        .await
+        .map_err(internal)?
+    {
+        Ok(data) => data,
+        Err(errors) => {
+            log::debug!("Invalid vCard: {:?}", errors);
+            return Ok(invalid());
+        }
+    };
+
+    match card {
+        Some(card) => {
+            card.contact
+                .try_update(ContactUpdated { data })
+                .map_err(internal)?
+                .stage_persist(&mut tx)
+                .await
+                .map_err(internal)?;
+            tx.commit().await.map_err(internal)?;
+
+            Ok(Response::new(StatusCode::NoContent))
+        }
+        None => {
+            let contact = Contact::try_create(ContactCreated { owner, data })
+                .map_err(internal)?
+                .stage_persist(&mut tx)
+                .await
+                .map_err(internal)?;
+            tx.commit().await.map_err(internal)?;
+
+            Ok(Response::builder(StatusCode::Created)
+                .header("Location", Card::from(contact).href())
+                .build())
+        }
+    }
+}
+
+/// Deletes a card with its relationships
+async fn delete(req: Request<State>, owner: Uuid, target: Target) -> tide::Result {
+    if !matches!(target, Target::Card(_) | Target::NewCard) {
+        return Ok(precondition(StatusCode::Forbidden, DAV, "need-privileges"));
+    }
+
+    let mut tx = req.state().store.transaction().await.map_err(internal)?;
+    let card = match find_card(&mut tx, owner, target).await.map_err(internal)? {
+        Some(card) => card,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    if !write_allowed(&req, Some(&card)) {
+        return Ok(Response::new(StatusCode::PreconditionFailed));
+    }
+
+    stage_delete(&mut tx, card.contact)
+        .await
+        .map_err(internal)?;
+    tx.commit().await.map_err(internal)?;
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn report(mut req: Request<State>, owner: Uuid, target: Target) -> tide::Result {
+    if target != Target::AddressBook {
+        return Ok(precondition(StatusCode::Forbidden, DAV, "supported-report"));
+    }
+
+    let body = req.body_string().await?;
+    let report = match xml::report(&body) {
+        Ok(report) => report,
+        Err(err) => return Ok(request_error(err)),
+    };
+
+    let pool = &req.state().postgres;
+    let latest = streams::latest(pool, owner).await.map_err(internal)?;
+    let token = sync_token(latest);
+    let mut multistatus = Multistatus::default();
+
+    match report {
+        Report::Multiget {
+            props,
+            address_data,
+            hrefs,
+        } => {
+            let ids: Vec<Uuid> = hrefs
+                .iter()
+                .filter_map(|href| match Target::parse(href) {
+                    Some(Target::Card(id)) => Some(id),
+                    _ => None,
+                })
+                .collect();
+            let mut cards: HashMap<Uuid, Card> = Contact::find_many(pool, owner, &ids)
+                .await
+                .map_err(internal)?
+                .into_iter()
+                .map(|contact| (contact.id, Card::from(contact)))
+                .collect();
+
+            for href in &hrefs {
+                let card = match Target::parse(href) {
+                    Some(Target::Card(id)) => cards.remove(&id),
+                    _ => None,
+                };
+                match card {
+                    Some(card) => Resource::Card(Box::new(card)).propstat(
+                        &mut multistatus,
+                        &props,
+                        &token,
+                        &address_data,
+                    ),
+                    None => multistatus.status(href, StatusCode::NotFound),
+                }
+            }
+
+            Ok(multistatus_response(multistatus.finish(None)))
+        }
+        Report::Query {
+            props,
+            address_data,
+            filter,
+            limit,
+        } => {
+            let mut matched = 0;
+            for card in cards(pool, owner).await.map_err(internal)? {
+                if !filter.matches(&card.lines) {
+                    continue;
+                }
+                if limit.is_some_and(|limit| matched >= limit) {
+                    // Truncated results, RFC 6352 section 8.6.1
+                    multistatus.status(ADDRESS_BOOK, StatusCode::InsufficientStorage);
+                    break;
+                }
+                matched += 1;
+                Resource::Card(Box::new(card)).propstat(
+                    &mut multistatus,
+                    &props,
+                    &token,
+                    &address_data,
+                );
+            }
+
+            Ok(multistatus_response(multistatus.finish(None)))
+        }
+        Report::SyncCollection {
+            props,
+            address_data,
+            sync_token: None,
+            limit,
+        } => {
+            // The initial sync lists every card, it cannot be split
+            let cards = cards(pool, owner).await.map_err(internal)?;
+            if limit.is_some_and(|limit| cards.len() > limit) {
+                return Ok(precondition(
+                    StatusCode::InsufficientStorage,
+                    DAV,
+                    "number-of-matches-within-limits",
+                ));
+            }
+
+            for card in cards {
+                Resource::Card(Box::new(card)).propstat(
+                    &mut multistatus,
+                    &props,
+                    &token,
+                    &address_data,
+                );
+            }
+
+            Ok(multistatus_response(multistatus.finish(Some(&token))))
+        }
+        Report::SyncCollection {
+            props,
+            address_data,
+            sync_token: Some(since),
+            limit,
+        } => {
+            // Tokens from before a purge make clients start over with an initial sync
+            let purged = streams::purged_through(pool, owner)
+                .await
+                .map_err(internal)?;
+            let after = match since
+                .strip_prefix(SYNC_TOKEN_PREFIX)
+                .and_then(|sequence| sequence.parse::<i64>().ok())
+                .filter(|sequence| (purged..=latest).contains(sequence))
+            {
+                Some(after) => after,
+                None => return Ok(precondition(StatusCode::Forbidden, DAV, "valid-sync-token")),
+            };
+
+            // One change more than the limit tells whether the response is truncated
+            let fetch = limit.map(|limit| limit as i64 + 1);
+            let mut changes = streams::changed_contacts(pool, owner, after, latest, fetch)
+                .await
+                .map_err(internal)?;
+            let mut token = token;
+            let truncated = match limit {
+                Some(limit) if changes.len() > limit => {
+                    changes.truncate(limit);
+                    token = sync_token(changes.last().map_or(after, |change| change.sequence));
+                    true
+                }
+                _ => false,
+            };
+
+            let ids: Vec<Uuid> = changes
+                .iter()
+                .filter(|change| change.event_type != webhooks::CONTACT_DELETED)
+                .map(|change| change.contact_id)
+                .collect();
+            let mut cards: HashMap<Uuid, Card> = Contact::find_many(pool, owner, &ids)
+                .await
+                .map_err(internal)?
+                .into_iter()
+                .map(|contact| (contact.id, Card::from(contact)))
+                .collect();
+
+            for change in &changes {
+                match cards.remove(&change.contact_id) {
+                    Some(card) => Resource::Card(Box::new(card)).propstat(
+                        &mut multistatus,
+                        &props,
+                        &token,
+                        &address_data,
+                    ),
+                    None => multistatus.status(
+                        &format!("{}{}.vcf", ADDRESS_BOOK, change.contact_id),
+                        StatusCode::NotFound,
+                    ),
+                }
+            }
+            if truncated {
+                multistatus.status(ADDRESS_BOOK, StatusCode::InsufficientStorage);
+            }
+
+            Ok(multistatus_response(multistatus.finish(Some(&token))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_targets() {
+        assert_eq!(Target::parse("/dav"), Some(Target::Root));
+        assert_eq!(Target::parse("/dav/"), Some(Target::Root));
+        assert_eq!(Target::parse("/dav/principal/"), Some(Target::Principal));
+        assert_eq!(Target::parse("/dav/addressbooks"), Some(Target::Home));
+        assert_eq!(
+            Target::parse("/dav/addressbooks/contacts/"),
+            Some(Target::AddressBook)
+        );
+        assert_eq!(Target::parse("/dav/calendars/"), None);
+        assert_eq!(Target::parse("/"), None);
+    }
+
+    #[test]
+    fn card_targets() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            Target::parse(&format!("/dav/addressbooks/contacts/{}.vcf", id)),
+            Some(Target::Card(id))
+        );
+        assert_eq!(
+            Target::parse("/dav/addressbooks/contacts/ABC-123.vcf"),
+            Some(Target::NewCard)
+        );
+        assert_eq!(Target::parse("/dav/addressbooks/contacts/.vcf"), None);
+        assert_eq!(Target::parse("/dav/addressbooks/contacts/a/b.vcf"), None);
+        assert_eq!(Target::parse("/dav/addressbooks/contacts/a.ics"), None);
+    }
+
+    #[test]
+    fn full_url_hrefs() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            Target::parse(&format!(
+                "https://contacts.example.com/dav/addressbooks/contacts/{}.vcf",
+                id
+            )),
+            Some(Target::Card(id))
+        );
+        assert_eq!(
+            Target::parse("http://contacts.example.com/dav/principal/"),
+            Some(Target::Principal)
+        );
+        assert_eq!(Target::parse("https://"), None);
+    }
+}
@@ -0,0 +1,564 @@
+/*!
+WebDAV XML bodies
+
+Requests are parsed with namespaces resolved, so clients can use whatever prefixes they like.
+Responses always use `D` for `DAV:`, `C` for CardDAV and `CS` for the calendarserver
+extensions.
+*/
+
+use roxmltree::{Document, Node};
+use tide::StatusCode;
+
+use super::filter::{Filter, FilterError};
+use crate::util::xml_escape;
+
+pub const DAV: &str = "DAV:";
+pub const CARDDAV: &str = "urn:ietf:params:xml:ns:carddav";
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// Name of a property, namespace included
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Name {
+    pub namespace: String,
+    pub local: String,
+}
+
+impl Name {
+    pub fn new(namespace: &str, local: &str) -> Self {
+        Name {
+            namespace: String::from(namespace),
+            local: String::from(local),
+        }
+    }
+
+    fn of(node: Node) -> Self {
+        Name::new(
+            node.tag_name().namespace().unwrap_or_default(),
+            node.tag_name().name(),
+        )
+    }
+
+    /// Opening tag, declaring the namespace when it has no prefix of its own
+    fn open(&self) -> String {
+        match prefix(&self.namespace) {
+            Some(prefix) => format!("<{}:{}>", prefix, self.local),
+            None => format!("<{} xmlns=\"{}\">", self.local, xml_escape(&self.namespace)),
+        }
+    }
+
+    fn close(&self) -> String {
+        match prefix(&self.namespace) {
+            Some(prefix) => format!("</{}:{}>", prefix, self.local),
+            None => format!("</{}>", self.local),
+        }
+    }
+
+    fn empty(&self) -> String {
+        match prefix(&self.namespace) {
+            Some(prefix) => format!("<{}:{}/>", prefix, self.local),
+            None => format!(
+                "<{} xmlns=\"{}\"/>",
+                self.local,
+                xml_escape(&self.namespace)
+            ),
+        }
+    }
+}
+
+fn prefix(namespace: &str) -> Option<&'static str> {
+    match namespace {
+        DAV => Some("D"),
+        CARDDAV => Some("C"),
+        CALENDARSERVER => Some("CS"),
+        _ => None,
+    }
+}
+
+/// A body that could not be read
+#[derive(Debug)]
+pub enum RequestError {
+    /// Not XML, or not the expected elements
+    Malformed(String),
+    /// A filter the server cannot evaluate
+    Filter(FilterError),
+    /// A report the server does not implement
+    UnsupportedReport,
+}
+
+impl From<roxmltree::Error> for RequestError {
+    fn from(err: roxmltree::Error) -> Self {
+        RequestError::Malformed(err.to_string())
+    }
+}
+
+impl From<FilterError> for RequestError {
+    fn from(err: FilterError) -> Self {
+        RequestError::Filter(err)
+    }
+}
+
+pub fn is(node: Node, namespace: &str, local: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(namespace)
+        && node.tag_name().name() == local
+}
+
+pub fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    local: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| is(*child, namespace, local))
+}
+
+fn malformed(message: &str) -> RequestError {
+    RequestError::Malformed(String::from(message))
+}
+
+/// Properties asked for by `PROPFIND` or a report
+#[derive(Debug)]
+pub enum Props {
+    /// Every property with a value, the default when the body is empty
+    All,
+    /// Names of the properties with a value, without the values
+    Names,
+    Only(Vec<Name>),
+}
+
+/// vCard properties asked for in `C:address-data`, `None` for the whole vCard
+pub type AddressData = Option<Vec<String>>;
+
+fn props(node: Node) -> (Props, AddressData) {
+    if child(node, DAV, "allprop").is_some() {
+        return (Props::All, None);
+    }
+    if child(node, DAV, "propname").is_some() {
+        return (Props::Names, None);
+    }
+
+    let mut address_data = None;
+    let names = child(node, DAV, "prop")
+        .map(|prop| {
+            prop.children()
+                .filter(|child| child.is_element())
+                .map(|child| {
+                    if is(child, CARDDAV, "address-data") {
+                        let properties: Vec<String> = child
+                            .children()
+                            .filter(|property| is(*property, CARDDAV, "prop"))
+                            .filter_map(|property| property.attribute("name"))
+                            .map(|name| name.to_uppercase())
+                            .collect();
+                        if !properties.is_empty() {
+                            address_data = Some(properties);
+                        }
+                    }
+                    Name::of(child)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    (Props::Only(names), address_data)
+}
+
+/// Body of a `PROPFIND`
+pub fn propfind(body: &str) -> Result<Props, RequestError> {
+    if body.trim().is_empty() {
+        return Ok(Props::All);
+    }
+
+    let document = Document::parse(body)?;
+    let root = document.root_element();
+    if !is(root, DAV, "propfind") {
+        return Err(malformed("Expected a DAV:propfind element"));
+    }
+
+    Ok(props(root).0)
+}
+
+/// Body of a `REPORT`
+#[derive(Debug)]
+pub enum Report {
+    /// `C:addressbook-multiget`, cards by href
+    Multiget {
+        props: Props,
+        address_data: AddressData,
+        hrefs: Vec<String>,
+    },
+    /// `C:addressbook-query`, cards matching a filter
+    Query {
+        props: Props,
+        address_data: AddressData,
+        filter: Filter,
+        limit: Option<usize>,
+    },
+    /// `D:sync-collection`, cards changed since a sync token
+    SyncCollection {
+        props: Props,
+        address_data: AddressData,
+        sync_token: Option<String>,
+        limit: Option<usize>,
+    },
+}
+
+/// `nresults` of a `limit` element
+fn limit(node: Node, namespace: &str) -> Result<Option<usize>, RequestError> {
+    match child(node, namespace, "limit").and_then(|limit| child(limit, namespace, "nresults")) {
+        Some(nresults) => nresults
+            .text()
+            .and_then(|text| text.trim().parse().ok())
+            .map(Some)
+            .ok_or_else(|| malformed("nresults must be a number")),
+        None => Ok(None),
+    }
+}
+
+pub fn report(body: &str) -> Result<Report, RequestError> {
+    let document = Document::parse(body)?;
+    let root = document.root_element();
+    let (props, address_data) = props(root);
+
+    if is(root, CARDDAV, "addressbook-multiget") {
+        let hrefs = root
+            .children()
+            .filter(|child| is(*child, DAV, "href"))
+            .filter_map(|href| href.text())
+            .map(|href| href.trim().to_string())
+            .collect();
+
+        Ok(Report::Multiget {
+            props,
+            address_data,
+            hrefs,
+        })
+    } else if is(root, CARDDAV, "addressbook-query") {
+        let filter = match child(root, CARDDAV, "filter") {
+            Some(filter) => Filter::parse(filter)?,
+            None => Filter::default(),
+        };
+
+        Ok(Report::Query {
+            props,
+            address_data,
+            filter,
+            limit: limit(root, CARDDAV)?,
+        })
+    } else if is(root, DAV, "sync-collection") {
+        let sync_token = child(root, DAV, "sync-token")
+            .and_then(|token| token.text())
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty());
+
+        Ok(Report::SyncCollection {
+            props,
+            address_data,
+            sync_token,
+            limit: limit(root, DAV)?,
+        })
+    } else {
+        Err(RequestError::UnsupportedReport)
+    }
+}
+
+/// Body of a `D:error` response, with the precondition that failed
+pub fn error(namespace: &str, precondition: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <D:error xmlns:D=\"DAV:\" xmlns:C=\"{}\">{}</D:error>",
+        CARDDAV,
+        Name::new(namespace, precondition).empty()
+    )
+}
+
+fn status_line(status: StatusCode) -> String {
+    format!(
+        "<D:status>HTTP/1.1 {} {}</D:status>",
+        u16::from(status),
+        status.canonical_reason()
+    )
+}
+
+/// `207 Multi-Status` body, built one response at a time
+pub struct Multistatus {
+    body: String,
+}
+
+impl Default for Multistatus {
+    fn default() -> Self {
+        Multistatus {
+            body: format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                <D:multistatus xmlns:D=\"DAV:\" xmlns:C=\"{}\" xmlns:CS=\"{}\">",
+                CARDDAV, CALENDARSERVER
+            ),
+        }
+    }
+}
+
+impl Multistatus {
+    /// Properties of a resource, `found` with their values as XML and `missing` without
+    pub fn propstat(&mut self, href: &str, found: &[(Name, String)], missing: &[Name]) {
+        self.body.push_str("<D:response><D:href>");
+        self.body.push_str(&xml_escape(href));
+        self.body.push_str("</D:href>");
+
+        if !found.is_empty() {
+            self.body.push_str("<D:propstat><D:prop>");
+            for (name, value) in found {
+                if value.is_empty() {
+                    self.body.push_str(&name.empty());
+                } else {
+                    self.body.push_str(&name.open());
+                    self.body.push_str(value);
+                    self.body.push_str(&name.close());
+                }
+            }
+            self.body.push_str("</D:prop>");
+            self.body.push_str(&status_line(StatusCode::Ok));
+            self.body.push_str("</D:propstat>");
+        }
+
+        if !missing.is_empty() {
+            self.body.push_str("<D:propstat><D:prop>");
+            for name in missing {
+                self.body.push_str(&name.empty());
+            }
+            self.body.push_str("</D:prop>");
+            self.body.push_str(&status_line(StatusCode::NotFound));
+            self.body.push_str("</D:propstat>");
+        }
+
+        self.body.push_str("</D:response>");
+    }
+
+    /// A resource as a whole, e.g. a card that does not exist
+    pub fn status(&mut self, href: &str, status: StatusCode) {
+        self.body.push_str("<D:response><D:href>");
+        self.body.push_str(&xml_escape(href));
+        self.body.push_str("</D:href>");
+        self.body.push_str(&status_line(status));
+        self.body.push_str("</D:response>");
+    }
+
+    /// The body, with the sync token of a `D:sync-collection` report
+    pub fn finish(mut self, sync_token: Option<&str>) -> String {
+        if let Some(token) = sync_token {
+            self.body.push_str("<D:sync-token>");
+            self.body.push_str(&xml_escape(token));
+            self.body.push_str("</D:sync-token>");
+        }
+        self.body.push_str("</D:multistatus>");
+        self.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(props: Props) -> Vec<Name> {
+        match props {
+            Props::Only(names) => names,
+            props => panic!("{:?}", props),
+        }
+    }
+
+    #[test]
+    fn empty_propfind_asks_for_everything() {
+        assert!(matches!(propfind("").unwrap(), Props::All));
+        assert!(matches!(
+            propfind("<propfind xmlns=\"DAV:\"><allprop/></propfind>").unwrap(),
+            Props::All
+        ));
+        assert!(matches!(
+            propfind("<d:propfind xmlns:d=\"DAV:\"><d:propname/></d:propfind>").unwrap(),
+            Props::Names
+        ));
+    }
+
+    #[test]
+    fn propfind_resolves_namespaces() {
+        let props = propfind(
+            "<x:propfind xmlns:x=\"DAV:\" xmlns:y=\"http://calendarserver.org/ns/\">\
+             <x:prop><x:getetag/><y:getctag/><other xmlns=\"urn:other\"/></x:prop>\
+             </x:propfind>",
+        )
+        .unwrap();
+
+        assert_eq!(
+            names(props),
+            vec![
+                Name::new(DAV, "getetag"),
+                Name::new(CALENDARSERVER, "getctag"),
+                Name::new("urn:other", "other"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_propfinds() {
+        assert!(matches!(
+            propfind("<propfind>"),
+            Err(RequestError::Malformed(_))
+        ));
+        assert!(matches!(
+            propfind("<prop xmlns=\"DAV:\"/>"),
+            Err(RequestError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn multiget() {
+        let parsed = report(
+            "<C:addressbook-multiget xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:carddav\">\
+             <D:prop><D:getetag/><C:address-data><C:prop name=\"fn\"/><C:prop name=\"EMAIL\"/>\
+             </C:address-data></D:prop>\
+             <D:href> /dav/addressbooks/contacts/a.vcf </D:href>\
+             <D:href>/dav/addressbooks/contacts/b.vcf</D:href>\
+             </C:addressbook-multiget>",
+        )
+        .unwrap();
+
+        match parsed {
+            Report::Multiget {
+                props,
+                address_data,
+                hrefs,
+            } => {
+                assert_eq!(
+                    names(props),
+                    vec![
+                        Name::new(DAV, "getetag"),
+                        Name::new(CARDDAV, "address-data")
+                    ]
+                );
+                assert_eq!(
+                    address_data,
+                    Some(vec![String::from("FN"), String::from("EMAIL")])
+                );
+                assert_eq!(
+                    hrefs,
+                    vec![
+                        "/dav/addressbooks/contacts/a.vcf",
+                        "/dav/addressbooks/contacts/b.vcf"
+                    ]
+                );
+            }
+            parsed => panic!("{:?}", parsed),
+        }
+    }
+
+    #[test]
+    fn query_with_a_limit() {
+        let parsed = report(
+            "<C:addressbook-query xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:carddav\">\
+             <D:prop><C:address-data/></D:prop>\
+             <C:filter><C:prop-filter name=\"FN\"><C:text-match>ada</C:text-match>\
+             </C:prop-filter></C:filter>\
+             <C:limit><C:nresults>10</C:nresults></C:limit>\
+             </C:addressbook-query>",
+        )
+        .unwrap();
+
+        match parsed {
+            Report::Query {
+                address_data,
+                filter,
+                limit,
+                ..
+            } => {
+                assert_eq!(address_data, None);
+                assert_eq!(limit, Some(10));
+                assert!(filter.matches(&[String::from("FN:Ada")]));
+                assert!(!filter.matches(&[String::from("FN:Grace")]));
+            }
+            parsed => panic!("{:?}", parsed),
+        }
+    }
+
+    #[test]
+    fn sync_collection() {
+        let parsed = report(
+            "<D:sync-collection xmlns:D=\"DAV:\"><D:sync-token> 42 </D:sync-token>\
+             <D:prop><D:getetag/></D:prop><D:limit><D:nresults>5</D:nresults></D:limit>\
+             </D:sync-collection>",
+        )
+        .unwrap();
+        assert!(matches!(
+            parsed,
+            Report::SyncCollection { sync_token: Some(token), limit: Some(5), .. } if token == "42"
+        ));
+
+        let initial = report(
+            "<D:sync-collection xmlns:D=\"DAV:\"><D:sync-token/><D:prop/></D:sync-collection>",
+        )
+        .unwrap();
+        assert!(matches!(
+            initial,
+            Report::SyncCollection {
+                sync_token: None,
+                limit: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn bad_reports() {
+        assert!(matches!(
+            report("<D:expand-property xmlns:D=\"DAV:\"/>"),
+            Err(RequestError::UnsupportedReport)
+        ));
+        assert!(matches!(
+            report(
+                "<D:sync-collection xmlns:D=\"DAV:\"><D:limit><D:nresults>many</D:nresults>\
+                 </D:limit></D:sync-collection>"
+            ),
+            Err(RequestError::Malformed(_))
+        ));
+        assert!(matches!(
+            report(
+                "<C:addressbook-query xmlns:C=\"urn:ietf:params:xml:ns:carddav\">\
+                 <C:filter><C:prop-filter name=\"FN\"><C:param-filter name=\"X\"/>\
+                 </C:prop-filter></C:filter></C:addressbook-query>"
+            ),
+            Err(RequestError::Filter(FilterError::UnsupportedFilter))
+        ));
+    }
+
+    #[test]
+    fn multistatus_body() {
+        let mut multistatus = Multistatus::default();
+        multistatus.propstat(
+            "/dav/a&b.vcf",
+            &[
+                (Name::new(DAV, "getetag"), String::from("\"1\"")),
+                (Name::new(DAV, "resourcetype"), String::new()),
+            ],
+            &[Name::new("urn:other", "x")],
+        );
+        multistatus.status("/dav/gone.vcf", StatusCode::NotFound);
+        let body = multistatus.finish(Some("7"));
+
+        assert!(body.contains(
+            "<D:response><D:href>/dav/a&amp;b.vcf</D:href><D:propstat><D:prop>\
+             <D:getetag>\"1\"</D:getetag><D:resourcetype/></D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status></D:propstat>\
+             <D:propstat><D:prop><x xmlns=\"urn:other\"/></D:prop>\
+             <D:status>HTTP/1.1 404 Not Found</D:status></D:propstat></D:response>"
+        ));
+        assert!(body.contains(
+            "<D:response><D:href>/dav/gone.vcf</D:href>\
+             <D:status>HTTP/1.1 404 Not Found</D:status></D:response>"
+        ));
+        assert!(body.ends_with("<D:sync-token>7</D:sync-token></D:multistatus>"));
+        // The body is well formed
+        roxmltree::Document::parse(&body).unwrap();
+    }
+
+    #[test]
+    fn error_body() {
+        assert!(error(CARDDAV, "valid-address-data").ends_with("<C:valid-address-data/></D:error>"));
+    }
+}
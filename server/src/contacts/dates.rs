@@ -0,0 +1,120 @@
+/*!
+Upcoming birthdays, anniversaries and other dates worth remembering
+*/
+
+use chrono::{Datelike, NaiveDate};
+use common::contacts::dates::{DateKind, SignificantDate};
+use schemars::JsonSchema;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// An upcoming occurrence of a significant date
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct UpcomingEvent {
+    pub contact_id: Uuid,
+    pub display_name: String,
+    pub kind: DateKind,
+    pub label: Option<String>,
+    pub date: NaiveDate,
+    /// Days from today, 0 when the event is today
+    pub days_until: i64,
+    /// Age turned or years celebrated, when the original year is known
+    pub years: Option<i32>,
+}
+
+impl UpcomingEvent {
+    /// The occurrence of `date` between `today` and `today + days`, if there is one
+    pub fn new(
+        contact_id: Uuid,
+        display_name: &str,
+        date: &SignificantDate,
+        today: NaiveDate,
+        days: i64,
+    ) -> Option<Self> {
+        let next = date.next_occurrence(today)?;
+        let days_until = (next - today).num_days();
+        if days_until > days {
+            return None;
+        }
+
+        Some(UpcomingEvent {
+            contact_id,
+            display_name: display_name.to_string(),
+            kind: date.kind,
+            label: date.label.clone(),
+            date: next,
+            days_until,
+            years: date.year.map(|year| next.year() - year),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn birthday(year: Option<i32>, month: u32, day: u32) -> SignificantDate {
+        SignificantDate {
+            kind: DateKind::Birthday,
+            label: None,
+            year,
+            month,
+            day,
+        }
+    }
+
+    fn upcoming(date: &SignificantDate, today: NaiveDate, days: i64) -> Option<UpcomingEvent> {
+        UpcomingEvent::new(Uuid::nil(), "Ada", date, today, days)
+    }
+
+    #[test]
+    fn counts_days_and_years_until_the_next_occurrence() {
+        let event = upcoming(&birthday(Some(1990), 3, 10), day(2023, 3, 1), 30).unwrap();
+
+        assert_eq!(event.date, day(2023, 3, 10));
+        assert_eq!(event.days_until, 9);
+        assert_eq!(event.years, Some(33));
+    }
+
+    #[test]
+    fn includes_events_today_and_on_the_last_day() {
+        let date = birthday(None, 3, 10);
+
+        assert_eq!(upcoming(&date, day(2023, 3, 10), 0).unwrap().days_until, 0);
+        assert_eq!(upcoming(&date, day(2023, 3, 1), 9).unwrap().days_until, 9);
+        assert!(upcoming(&date, day(2023, 3, 1), 8).is_none());
+    }
+
+    #[test]
+    fn wraps_around_the_end_of_the_year() {
+        let event = upcoming(&birthday(Some(2000), 1, 2), day(2023, 12, 30), 7).unwrap();
+
+        assert_eq!(event.date, day(2024, 1, 2));
+        assert_eq!(event.days_until, 3);
+        assert_eq!(event.years, Some(24));
+    }
+
+    #[test]
+    fn leap_day_birthdays_fall_on_february_28_on_common_years() {
+        let date = birthday(Some(2000), 2, 29);
+
+        let common = upcoming(&date, day(2023, 2, 20), 30).unwrap();
+        assert_eq!(common.date, day(2023, 2, 28));
+        assert_eq!(common.years, Some(23));
+
+        let leap = upcoming(&date, day(2024, 2, 20), 30).unwrap();
+        assert_eq!(leap.date, day(2024, 2, 29));
+        assert_eq!(leap.years, Some(24));
+    }
+
+    #[test]
+    fn no_years_without_the_original_year() {
+        let event = upcoming(&birthday(None, 5, 1), day(2023, 4, 30), 7).unwrap();
+
+        assert_eq!(event.years, None);
+    }
+}
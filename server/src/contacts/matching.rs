@@ -0,0 +1,547 @@
+/*!
+Fuzzy and phonetic matching of contacts
+
+Names are folded to lowercase ASCII words when a contact is projected. The `contacts` table
+keeps them as `search_name`, indexed for `pg_trgm` similarity, and keeps the Double Metaphone
+keys of every word as `name_keys`. The database narrows down the candidates, the reasons
+each candidate matched are then worked out here.
+*/
+
+use std::collections::BTreeSet;
+
+use common::contacts::email::EmailAddress;
+use event_sauce::prelude::*;
+use event_sauce_storage_sqlx::SqlxPgStore;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use uuid::Uuid;
+
+use super::{phonetic, suggest, Contact};
+
+/// Lowest trigram similarity of two words considered a fuzzy match, the default threshold of
+/// `pg_trgm`
+pub const SIMILARITY_THRESHOLD: f32 = 0.3;
+
+/// Score of words that sound alike but are spelled too differently to be similar
+const PHONETIC_SCORE: f32 = 0.5;
+
+/// How closely names must match
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Values must contain the query
+    #[default]
+    Strict,
+    /// Names may also be misspelled or spelled differently but sound alike
+    Fuzzy,
+}
+
+/// How a value matched
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    Exact,
+    Contains,
+    Similar,
+    Phonetic,
+}
+
+/// Why a contact matched
+#[derive(Serialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct MatchReason {
+    /// JSON pointer to the matching value in the contact, as `/family_name` or `/emails/0`
+    pub field: String,
+    pub kind: MatchKind,
+    /// From 0 to 1, 1 for exact and contained values
+    pub score: f32,
+}
+
+impl MatchReason {
+    fn new(field: impl Into<String>, kind: MatchKind, score: f32) -> Self {
+        MatchReason {
+            field: field.into(),
+            kind,
+            score,
+        }
+    }
+}
+
+/// A contact with the reasons it matched a search or another contact
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Match {
+    #[serde(flatten)]
+    pub contact: Contact,
+    /// Best score of the reasons
+    pub score: f32,
+    pub reasons: Vec<MatchReason>,
+}
+
+impl Match {
+    /// `None` when there is no reason the contact matched
+    fn new(contact: Contact, reasons: Vec<MatchReason>) -> Option<Self> {
+        if reasons.is_empty() {
+            return None;
+        }
+        let score = reasons
+            .iter()
+            .map(|reason| reason.score)
+            .fold(0.0, f32::max);
+
+        Some(Match {
+            contact,
+            score,
+            reasons,
+        })
+    }
+}
+
+/// Lowercase ASCII words of a text, with accents removed and apostrophes dropped so
+/// `O'Brien` is one word
+pub fn words(text: &str) -> Vec<String> {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.nfd().filter(|c| !is_combining_mark(*c)) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'æ' | 'Æ' => folded.push_str("ae"),
+            'œ' | 'Œ' => folded.push_str("oe"),
+            'ø' | 'Ø' => folded.push('o'),
+            'ł' | 'Ł' => folded.push('l'),
+            'đ' | 'Đ' => folded.push('d'),
+            '\'' | '’' => {}
+            c if c.is_ascii_alphanumeric() => folded.push(c.to_ascii_lowercase()),
+            _ => folded.push(' '),
+        }
+    }
+
+    folded.split_whitespace().map(String::from).collect()
+}
+
+/// Folded given and family names, as kept in `search_name`
+pub fn search_name(given_name: Option<&str>, family_name: Option<&str>) -> String {
+    let mut folded = words(given_name.unwrap_or_default());
+    folded.extend(words(family_name.unwrap_or_default()));
+    folded.join(" ")
+}
+
+/// Distinct phonetic keys of some words
+pub fn phonetic_keys<S: AsRef<str>>(words: &[S]) -> Vec<String> {
+    let mut keys = BTreeSet::new();
+    for word in words {
+        let (primary, alternate) = phonetic::double_metaphone(word.as_ref());
+        keys.insert(primary);
+        keys.insert(alternate);
+    }
+    keys.remove("");
+
+    keys.into_iter().collect()
+}
+
+/// Trigrams of a text the way `pg_trgm` extracts them, each word padded with two spaces
+/// before and one after
+fn trigrams(text: &str) -> BTreeSet<String> {
+    let mut trigrams = BTreeSet::new();
+    for word in words(text) {
+        let padded: Vec<char> = format!("  {} ", word).chars().collect();
+        for window in padded.windows(3) {
+            trigrams.insert(window.iter().collect());
+        }
+    }
+    trigrams
+}
+
+/// Trigram similarity of two texts, as `similarity()` of `pg_trgm` computes it
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let a = trigrams(a);
+    let b = trigrams(b);
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+/// Whether two different spellings are similar or sound alike, `keys` being the phonetic
+/// keys of `a`. Sounding alike wins over a lower similarity.
+fn fuzzy_match(a: &str, keys: &[String], b: &str) -> Option<(MatchKind, f32)> {
+    let score = similarity(a, b);
+    let other_keys = phonetic_keys(&words(b));
+    let phonetic = keys.iter().any(|key| other_keys.contains(key));
+
+    if phonetic && score < PHONETIC_SCORE {
+        Some((MatchKind::Phonetic, PHONETIC_SCORE))
+    } else if score >= SIMILARITY_THRESHOLD {
+        Some((MatchKind::Similar, score))
+    } else {
+        None
+    }
+}
+
+/// How well a single word matches a name field, `None` when it does not
+fn word_match(word: &str, field: &str) -> Option<(MatchKind, f32)> {
+    let field_words = words(field);
+    let keys = phonetic_keys(&[word]);
+
+    field_words
+        .iter()
+        .filter_map(|other| {
+            if other == word {
+                return Some((MatchKind::Exact, 1.0));
+            }
+            if other.contains(word) {
+                return Some((MatchKind::Contains, 1.0));
+            }
+
+            fuzzy_match(word, &keys, other)
+        })
+        .fold(None, |best: Option<(MatchKind, f32)>, found| match best {
+            Some(best) if best.1 >= found.1 => Some(best),
+            _ => Some(found),
+        })
+}
+
+/// Name fields of a contact with their JSON pointer
+fn names(contact: &Contact) -> Vec<(&'static str, &str)> {
+    let mut names = Vec::new();
+    if let Some(given_name) = contact.data.given_name.as_deref() {
+        names.push(("/given_name", given_name));
+    }
+    if let Some(family_name) = contact.data.family_name.as_deref() {
+        names.push(("/family_name", family_name));
+    }
+    names
+}
+
+/// Textual value of a custom field
+fn custom_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Keeps the best reason of each field
+fn push_reason(reasons: &mut Vec<MatchReason>, reason: MatchReason) {
+    match reasons.iter_mut().find(|other| other.field == reason.field) {
+        Some(other) if other.score >= reason.score => {}
+        Some(other) => *other = reason,
+        None => reasons.push(reason),
+    }
+}
+
+/// Reasons a contact matches a search for `text`, or the phone number `e164`
+fn search_reasons(
+    contact: &Contact,
+    text: &str,
+    e164: Option<&str>,
+    mode: MatchMode,
+) -> Vec<MatchReason> {
+    let mut reasons = Vec::new();
+    let needle = text.to_lowercase();
+
+    let contains = |value: &str| {
+        let value = value.to_lowercase();
+        if value == needle {
+            Some(MatchKind::Exact)
+        } else if value.contains(&needle) {
+            Some(MatchKind::Contains)
+        } else {
+            None
+        }
+    };
+
+    for (field, name) in names(contact) {
+        if let Some(kind) = contains(name) {
+            push_reason(&mut reasons, MatchReason::new(field, kind, 1.0));
+        }
+    }
+    for (index, email) in contact.data.emails.iter().enumerate() {
+        if let Some(kind) = contains(&email.normalized()) {
+            push_reason(
+                &mut reasons,
+                MatchReason::new(format!("/emails/{}", index), kind, 1.0),
+            );
+        }
+    }
+    for (key, value) in &contact.data.custom_fields {
+        if let Some(kind) = contains(&custom_text(value)) {
+            push_reason(
+                &mut reasons,
+                MatchReason::new(format!("/custom_fields/{}", key), kind, 1.0),
+            );
+        }
+    }
+    if let Some(e164) = e164 {
+        for (index, phone) in contact.data.phones.iter().enumerate() {
+            if phone.e164 == e164 {
+                push_reason(
+                    &mut reasons,
+                    MatchReason::new(format!("/phones/{}", index), MatchKind::Exact, 1.0),
+                );
+            }
+        }
+    }
+
+    // Every word of the query must match a name for the names to match fuzzily
+    if mode == MatchMode::Fuzzy {
+        let names = names(contact);
+        let mut fuzzy = Vec::new();
+        let query = words(text);
+        let all = query.iter().all(|word| {
+            let best = names
+                .iter()
+                .filter_map(|(field, name)| {
+                    word_match(word, name)
+                        .map(|(kind, score)| MatchReason::new(*field, kind, score))
+                })
+                .fold(None, |best: Option<MatchReason>, found| match best {
+                    Some(best) if best.score >= found.score => Some(best),
+                    _ => Some(found),
+                });
+            match best {
+                Some(reason) => {
+                    push_reason(&mut fuzzy, reason);
+                    true
+                }
+                None => false,
+            }
+        });
+
+        if all && !query.is_empty() {
+            for reason in fuzzy {
+                push_reason(&mut reasons, reason);
+            }
+        }
+    }
+
+    reasons
+}
+
+/// Reasons `other` may be the same person as `contact`
+fn duplicate_reasons(contact: &Contact, other: &Contact, mode: MatchMode) -> Vec<MatchReason> {
+    let mut reasons = Vec::new();
+
+    let emails: Vec<String> = contact
+        .data
+        .emails
+        .iter()
+        .map(EmailAddress::normalized)
+        .collect();
+    for (index, email) in other.data.emails.iter().enumerate() {
+        if emails.contains(&email.normalized()) {
+            reasons.push(MatchReason::new(
+                format!("/emails/{}", index),
+                MatchKind::Exact,
+                1.0,
+            ));
+        }
+    }
+    for (index, phone) in other.data.phones.iter().enumerate() {
+        if contact.data.phones.iter().any(|own| own.e164 == phone.e164) {
+            reasons.push(MatchReason::new(
+                format!("/phones/{}", index),
+                MatchKind::Exact,
+                1.0,
+            ));
+        }
+    }
+
+    // Both names must be known on both contacts and match, a shared given name alone says
+    // little
+    if mode == MatchMode::Fuzzy {
+        let pairs = [
+            (
+                "/given_name",
+                &contact.data.given_name,
+                &other.data.given_name,
+            ),
+            (
+                "/family_name",
+                &contact.data.family_name,
+                &other.data.family_name,
+            ),
+        ];
+        let names: Option<Vec<MatchReason>> = pairs
+            .iter()
+            .map(|(field, own, theirs)| {
+                let own = words(own.as_deref()?).join(" ");
+                let theirs = words(theirs.as_deref()?).join(" ");
+                if own.is_empty() || theirs.is_empty() {
+                    return None;
+                }
+                if own == theirs {
+                    return Some(MatchReason::new(*field, MatchKind::Exact, 1.0));
+                }
+
+                let keys = phonetic_keys(&words(&own));
+                fuzzy_match(&own, &keys, &theirs)
+                    .map(|(kind, score)| MatchReason::new(*field, kind, score))
+            })
+            .collect();
+
+        if let Some(names) = names {
+            reasons.extend(names);
+        }
+    }
+
+    reasons
+}
+
+/// Keeps the candidates with a reason to match, best matches first
+fn rank(candidates: Vec<Contact>, reasons: impl Fn(&Contact) -> Vec<MatchReason>) -> Vec<Match> {
+    let mut matches: Vec<Match> = candidates
+        .into_iter()
+        .filter_map(|contact| {
+            let reasons = reasons(&contact);
+            Match::new(contact, reasons)
+        })
+        .collect();
+
+    // Stable, so equally good matches keep the order of the query
+    matches.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    matches
+}
+
+/// Contacts of `owner` whose name, email or custom field values contain `text`, or that have
+/// the phone number `e164`. Fuzzy searches also find names spelled differently.
+pub async fn search(
+    pool: &PgPool,
+    owner: Uuid,
+    text: &str,
+    e164: Option<&str>,
+    mode: MatchMode,
+) -> Result<Vec<Match>, sqlx::Error> {
+    let query_words = words(text);
+    let fuzzy = match mode {
+        MatchMode::Strict => None,
+        MatchMode::Fuzzy => Some((&query_words[..], phonetic_keys(&query_words))),
+    };
+    let candidates = Contact::search_candidates(pool, owner, text, e164, fuzzy).await?;
+
+    Ok(rank(candidates, |contact| {
+        search_reasons(contact, text, e164, mode)
+    }))
+}
+
+/// Other contacts of the same owner that may be the same person as `contact`: sharing a
+/// phone number or an email address, or in fuzzy mode with a similar name
+pub async fn duplicates(
+    pool: &PgPool,
+    contact: &Contact,
+    mode: MatchMode,
+) -> Result<Vec<Match>, sqlx::Error> {
+    let name = search_name(
+        contact.data.given_name.as_deref(),
+        contact.data.family_name.as_deref(),
+    );
+    let fuzzy = match mode {
+        MatchMode::Strict => None,
+        MatchMode::Fuzzy => Some((name.as_str(), phonetic_keys(&words(&name)))),
+    };
+    let candidates = contact.duplicate_candidates(pool, fuzzy).await?;
+
+    Ok(rank(candidates, |other| {
+        duplicate_reasons(contact, other, mode)
+    }))
+}
+
+/// Computes the folded names, phonetic keys and suggestion terms of contacts projected before
+/// names were folded, returns how many contacts were updated
+pub async fn backfill(store: &SqlxPgStore) -> Result<usize, sqlx::Error> {
+    let mut tx = store.transaction().await?;
+
+    let rows: Vec<(Uuid, Option<String>, Option<String>)> = sqlx::query_as(
+        "select id, given_name, family_name from contacts where search_name is null",
+    )
+    .fetch_all(tx.get())
+    .await?;
+
+    for (id, given_name, family_name) in &rows {
+        let name = search_name(given_name.as_deref(), family_name.as_deref());
+        sqlx::query("update contacts set search_name = $2, name_keys = $3 where id = $1")
+            .bind(id)
+            .bind(&name)
+            .bind(phonetic_keys(&words(&name)))
+            .execute(tx.get())
+            .await?;
+    }
+
+    let ids: Vec<Uuid> = rows.iter().map(|(id, _, _)| *id).collect();
+    suggest::index(&mut tx, &ids).await?;
+
+    tx.commit().await?;
+
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_folded() {
+        assert_eq!(words("O'Brien"), vec!["obrien"]);
+        assert_eq!(
+            words("José  Müller-Lüdenscheidt"),
+            vec!["jose", "muller", "ludenscheidt"]
+        );
+        assert_eq!(words("Straße Ærø Łódź"), vec!["strasse", "aero", "lodz"]);
+        assert!(words(" -- ").is_empty());
+    }
+
+    #[test]
+    fn search_name_joins_given_and_family_names() {
+        assert_eq!(search_name(Some("Zoë"), Some("D'Arcy")), "zoe darcy");
+        assert_eq!(search_name(None, Some("Smith")), "smith");
+        assert_eq!(search_name(None, None), "");
+    }
+
+    #[test]
+    fn phonetic_keys_are_distinct() {
+        assert_eq!(phonetic_keys(&["smith"]), vec!["SM0", "XMT"]);
+        assert_eq!(phonetic_keys(&["smith", "smyth"]), vec!["SM0", "XMT"]);
+        assert!(phonetic_keys::<&str>(&[]).is_empty());
+    }
+
+    #[test]
+    fn trigram_similarity() {
+        assert_eq!(similarity("Smith", "smith"), 1.0);
+        assert_eq!(similarity("abc", "xyz"), 0.0);
+        assert_eq!(similarity("", ""), 0.0);
+        // 3 shared out of 9 distinct trigrams
+        assert!((similarity("smith", "smyth") - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn word_match_kinds() {
+        assert_eq!(word_match("smith", "Smith"), Some((MatchKind::Exact, 1.0)));
+        assert_eq!(
+            word_match("smith", "Goldsmith"),
+            Some((MatchKind::Contains, 1.0))
+        );
+        assert_eq!(
+            word_match("jon", "John"),
+            Some((MatchKind::Phonetic, PHONETIC_SCORE))
+        );
+        assert!(matches!(
+            word_match("jonathan", "Jonathon"),
+            Some((MatchKind::Similar, _))
+        ));
+        assert_eq!(word_match("smith", "Jones"), None);
+    }
+
+    #[test]
+    fn best_word_wins() {
+        assert_eq!(
+            word_match("anne", "Mary Anne"),
+            Some((MatchKind::Exact, 1.0))
+        );
+    }
+}
@@ -0,0 +1,782 @@
+/*!
+Contacts of an address book
+
+Contacts are event sourced, every change is stored as an event and the `contacts` table holds
+the latest state of each contact so it can be queried. The fields of a contact and the rules
+they follow are shared with the client, see [`common::contacts`].
+*/
+
+pub mod dates;
+pub mod matching;
+pub mod phonetic;
+pub mod rpc;
+pub mod suggest;
+pub mod sync;
+pub mod vcard;
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use common::contacts::{
+    address::PostalAddress, dates::SignificantDate, email::EmailAddress, phone::PhoneNumber,
+    Affiliation, ContactData, ContactInput,
+};
+use common::tags::TagRef;
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    custom_fields::{FieldDefinition, FieldKind, FieldValueError},
+    organizations::Organization,
+    query::sql::{bind_compiled, Compiled},
+    settings::UserSettings,
+    streams,
+    util::like_escape,
+    webhooks,
+};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "contacts")]
+pub struct Contact {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    /// `sub` of the user whose address book holds the contact
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: ContactData,
+    /// Hash of the current photo, `None` when the contact has no photo
+    #[serde(default)]
+    pub photo: Option<String>,
+    /// Tags on the contact, sorted by name
+    #[serde(default)]
+    pub tags: Vec<TagRef>,
+    /// Time of the latest interaction with the contact. Kept up to date by the interaction log
+    /// rather than by contact events.
+    #[serde(default)]
+    pub last_contacted: Option<DateTime<Utc>>,
+    /// Number of changes made to the contact, see [`rpc::UpdateParams`]
+    #[serde(default)]
+    pub version: i64,
+}
+
+/// Validates and normalizes a contact with its [`Validate`] rules, the phone numbers read by
+/// [`ContactInput::check`] and the data of its owner.
+///
+/// Custom field values are checked against the `fields` defined by the owner. Affiliations
+/// must point to one of the `organizations` of the owner.
+pub fn validate(
+    input: ContactInput,
+    default_region: Option<&str>,
+    fields: &[FieldDefinition],
+    organizations: &[Uuid],
+) -> Result<ContactData, ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    input.rules(&mut errors);
+    let mut data = input.check(default_region, &mut errors);
+
+    for (index, affiliation) in data.affiliations.iter().enumerate() {
+        if !organizations.contains(&affiliation.organization_id) {
+            errors.add(
+                format!("/affiliations/{}/organization_id", index),
+                "unknown_organization",
+                "There is no such organization",
+            );
+        }
+    }
+
+    let values = std::mem::take(&mut data.custom_fields);
+    for (key, value) in values {
+        let checked = fields
+            .iter()
+            .find(|field| field.key == key)
+            .ok_or(FieldValueError::UnknownField)
+            .and_then(|field| field.check(&value));
+        match checked {
+            Ok(value) => {
+                data.custom_fields.insert(key, value);
+            }
+            Err(err) => errors.add(format!("/custom_fields/{}", key), err.code(), err.message()),
+        }
+    }
+
+    errors.into_result()?;
+    Ok(data)
+}
+
+/// Validates a contact of `owner` with [`validate`], against their default region, custom
+/// fields and organizations
+pub async fn validate_for(
+    pool: &PgPool,
+    owner: Uuid,
+    input: ContactInput,
+) -> Result<Result<ContactData, ValidationErrors>, sqlx::Error> {
+    let settings = UserSettings::load(pool, owner).await?;
+    let fields = FieldDefinition::list(pool, owner).await?;
+    let ids: Vec<Uuid> = input
+        .affiliations
+        .iter()
+        .map(|affiliation| affiliation.organization_id)
+        .collect();
+    let organizations = Organization::existing(pool, owner, &ids).await?;
+
+    Ok(validate(
+        input,
+        settings.default_region.as_deref(),
+        &fields,
+        &organizations,
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Contact)]
+pub struct ContactCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: ContactData,
+}
+
+/// Replaces every editable field of the contact
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Contact)]
+pub struct ContactUpdated {
+    #[serde(flatten)]
+    pub data: ContactData,
+}
+
+/// A photo was uploaded or removed. Only the hash is stored in the event, the thumbnails live
+/// in the `contact_photos` table.
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Contact)]
+pub struct ContactPhotoChanged {
+    pub photo: Option<String>,
+}
+
+/// Tags were added, removed or renamed. Holds every tag the contact has after the change.
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Contact)]
+pub struct ContactTagsChanged {
+    pub tags: Vec<TagRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Contact)]
+pub struct ContactDeleted {}
+
+impl AggregateCreate<ContactCreated> for Contact {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<ContactCreated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to create Contact from ContactCreated event")?;
+
+        Ok(Contact {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+            photo: None,
+            tags: Vec::new(),
+            last_contacted: None,
+            version: 1,
+        })
+    }
+}
+
+impl AggregateUpdate<ContactUpdated> for Contact {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<ContactUpdated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to update Contact from ContactUpdated event")?;
+
+        Ok(Contact {
+            data: data.data.clone(),
+            version: self.version + 1,
+            ..self
+        })
+    }
+}
+
+impl AggregateUpdate<ContactPhotoChanged> for Contact {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<ContactPhotoChanged>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update Contact from ContactPhotoChanged event",
+        )?;
+
+        Ok(Contact {
+            photo: data.photo.clone(),
+            version: self.version + 1,
+            ..self
+        })
+    }
+}
+
+impl AggregateUpdate<ContactTagsChanged> for Contact {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<ContactTagsChanged>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update Contact from ContactTagsChanged event",
+        )?;
+
+        Ok(Contact {
+            tags: data.tags.clone(),
+            version: self.version + 1,
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<ContactDeleted> for Contact {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(self, _event: &Event<ContactDeleted>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+/// Announces a change of a contact to webhooks and live streams, in the transaction of the
+/// change
+async fn announce(
+    tx: &mut SqlxPgStoreTransaction,
+    event_type: &str,
+    contact: &Contact,
+) -> Result<(), sqlx::Error> {
+    webhooks::enqueue(tx, contact.owner, event_type, contact).await?;
+    streams::record(tx, contact.owner, contact.id, event_type, contact).await
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Contact> for Contact {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        let email_addresses: Vec<String> = self
+            .data
+            .emails
+            .iter()
+            .map(EmailAddress::normalized)
+            .collect();
+        let phone_numbers: Vec<String> = self
+            .data
+            .phones
+            .iter()
+            .map(|phone| phone.e164.clone())
+            .collect();
+        let search_name = matching::search_name(
+            self.data.given_name.as_deref(),
+            self.data.family_name.as_deref(),
+        );
+        let name_keys = matching::phonetic_keys(&matching::words(&search_name));
+
+        // xmax is only set on rows that existed before
+        let (inserted,): (bool,) = sqlx::query_as(
+            "insert into contacts
+                (id, owner, given_name, family_name, country, notes, emails, phones, addresses, dates, affiliations, custom_fields, photo, tags, email_addresses, phone_numbers, search_name, name_keys, version)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+            on conflict (id) do update set
+                given_name = excluded.given_name,
+                family_name = excluded.family_name,
+                country = excluded.country,
+                notes = excluded.notes,
+                emails = excluded.emails,
+                phones = excluded.phones,
+                addresses = excluded.addresses,
+                dates = excluded.dates,
+                affiliations = excluded.affiliations,
+                custom_fields = excluded.custom_fields,
+                photo = excluded.photo,
+                tags = excluded.tags,
+                email_addresses = excluded.email_addresses,
+                phone_numbers = excluded.phone_numbers,
+                search_name = excluded.search_name,
+                name_keys = excluded.name_keys,
+                version = excluded.version
+            returning xmax = 0",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.data.given_name)
+        .bind(&self.data.family_name)
+        .bind(&self.data.country)
+        .bind(&self.data.notes)
+        .bind(Json(&self.data.emails))
+        .bind(Json(&self.data.phones))
+        .bind(Json(&self.data.addresses))
+        .bind(Json(&self.data.dates))
+        .bind(Json(&self.data.affiliations))
+        .bind(Json(&self.data.custom_fields))
+        .bind(&self.photo)
+        .bind(Json(&self.tags))
+        .bind(&email_addresses)
+        .bind(&phone_numbers)
+        .bind(&search_name)
+        .bind(&name_keys)
+        .bind(self.version)
+        .fetch_one(tx.get())
+        .await?;
+
+        suggest::index(tx, &[self.id]).await?;
+
+        let tags: Vec<Uuid> = self.tags.iter().map(|tag| tag.id).collect();
+        sqlx::query("delete from contact_tags where contact_id = $1 and tag_id <> all($2)")
+            .bind(self.id)
+            .bind(&tags)
+            .execute(tx.get())
+            .await?;
+        sqlx::query(
+            "insert into contact_tags (contact_id, tag_id)
+            select $1, unnest($2::uuid[])
+            on conflict do nothing",
+        )
+        .bind(self.id)
+        .bind(&tags)
+        .execute(tx.get())
+        .await?;
+
+        let event_type = if inserted {
+            webhooks::CONTACT_CREATED
+        } else {
+            webhooks::CONTACT_UPDATED
+        };
+        announce(tx, event_type, &self).await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Contact {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        announce(tx, webhooks::CONTACT_DELETED, &self).await?;
+
+        sqlx::query("delete from contacts where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Row of the `contacts` read model
+#[derive(FromRow)]
+struct ContactRow {
+    id: Uuid,
+    owner: Uuid,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    country: Option<String>,
+    notes: Option<String>,
+    emails: Json<Vec<EmailAddress>>,
+    phones: Json<Vec<PhoneNumber>>,
+    addresses: Json<Vec<PostalAddress>>,
+    dates: Json<Vec<SignificantDate>>,
+    affiliations: Json<Vec<Affiliation>>,
+    custom_fields: Json<BTreeMap<String, Value>>,
+    photo: Option<String>,
+    tags: Json<Vec<TagRef>>,
+    last_contacted: Option<DateTime<Utc>>,
+    version: i64,
+}
+
+impl From<ContactRow> for Contact {
+    fn from(row: ContactRow) -> Self {
+        Contact {
+            id: row.id,
+            owner: row.owner,
+            data: ContactData {
+                given_name: row.given_name,
+                family_name: row.family_name,
+                country: row.country,
+                emails: row.emails.0,
+                phones: row.phones.0,
+                addresses: row.addresses.0,
+                notes: row.notes,
+                dates: row.dates.0,
+                affiliations: row.affiliations.0,
+                custom_fields: row.custom_fields.0,
+            },
+            photo: row.photo,
+            tags: row.tags.0,
+            last_contacted: row.last_contacted,
+            version: row.version,
+        }
+    }
+}
+
+const COLUMNS: &str = "id, owner, given_name, family_name, country, notes, emails, phones, \
+    addresses, dates, affiliations, custom_fields, photo, tags, last_contacted, version";
+
+/// Order of contact listings
+pub enum SortBy {
+    /// Family name, then given name
+    Name,
+    /// Value of a custom field, contacts without a value go last
+    CustomField(FieldDefinition),
+}
+
+impl SortBy {
+    /// `order by` clause, the key of a custom field is bound to `$2`
+    fn order_by(&self, descending: bool) -> String {
+        let direction = if descending { "desc" } else { "asc" };
+
+        match self {
+            SortBy::Name => format!("family_name {0}, given_name {0}", direction),
+            SortBy::CustomField(field) => {
+                let value = match field.kind {
+                    FieldKind::Number => "(custom_fields->>$2)::numeric",
+                    FieldKind::Date => "(custom_fields->>$2)::date",
+                    FieldKind::Boolean => "(custom_fields->>$2)::boolean",
+                    _ => "lower(custom_fields->>$2)",
+                };
+                format!(
+                    "{} {} nulls last, family_name, given_name",
+                    value, direction
+                )
+            }
+        }
+    }
+}
+
+impl Contact {
+    /// Full name of the contact, given name first
+    pub fn display_name(&self) -> String {
+        let names: Vec<&str> = [&self.data.given_name, &self.data.family_name]
+            .iter()
+            .filter_map(|name| name.as_deref())
+            .collect();
+
+        names.join(" ")
+    }
+
+    /// Finds a contact in the address book of `owner`
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Contact::from))
+    }
+
+    /// A contact of `owner`, locked until the transaction ends so it cannot change between
+    /// reading it and writing it back
+    pub async fn find_for_update(
+        tx: &mut SqlxPgStoreTransaction,
+        owner: Uuid,
+        id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and id = $2 for update",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(tx.get())
+        .await?;
+
+        Ok(row.map(Contact::from))
+    }
+
+    /// Contacts of `owner` among `ids`, ids of other owners are ignored
+    pub async fn find_many<'c, E>(
+        executor: E,
+        owner: Uuid,
+        ids: &[Uuid],
+    ) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and id = any($2)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(ids)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// At most `limit` contacts of `owner` in the order of their ids, starting after the
+    /// contact `after`
+    pub async fn page(
+        pool: &PgPool,
+        owner: Uuid,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts
+            where owner = $1 and ($2::uuid is null or id > $2)
+            order by id
+            limit $3",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Every contact in the address book of `owner`
+    pub async fn list(
+        pool: &PgPool,
+        owner: Uuid,
+        sort: &SortBy,
+        descending: bool,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = format!(
+            "select {} from contacts where owner = $1 order by {}",
+            COLUMNS,
+            sort.order_by(descending)
+        );
+        let mut query = sqlx::query_as(&sql).bind(owner);
+        if let SortBy::CustomField(field) = sort {
+            query = query.bind(&field.key);
+        }
+        let rows: Vec<ContactRow> = query.fetch_all(pool).await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Contacts of `owner` with a value for the custom field `key`
+    pub async fn with_custom_field(
+        tx: &mut SqlxPgStoreTransaction,
+        owner: Uuid,
+        key: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and custom_fields ? $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(key)
+        .fetch_all(tx.get())
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Contacts of `owner` affiliated with the organization `organization`, sorted by name
+    pub async fn affiliated_with<'c, E>(
+        executor: E,
+        owner: Uuid,
+        organization: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and affiliations @> $2
+            order by family_name, given_name",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(Json(
+            serde_json::json!([{ "organization_id": organization }]),
+        ))
+        .fetch_all(executor)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Contacts of `owner` with the tag `tag`
+    pub async fn tagged_with<'c, E>(
+        executor: E,
+        owner: Uuid,
+        tag: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts
+            where owner = $1 and id in (select contact_id from contact_tags where tag_id = $2)
+            order by family_name, given_name",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(tag)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Contacts of `owner` matching a compiled query, whose placeholders start at `$2`
+    pub async fn matching<'c, E>(
+        executor: E,
+        owner: Uuid,
+        query: &Compiled,
+    ) -> Result<Vec<Self>, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        let sql = format!(
+            "select {} from contacts where owner = $1 and {} order by family_name, given_name",
+            COLUMNS, query.sql
+        );
+        let statement = bind_compiled(sqlx::query_as(&sql).bind(owner), &query.params);
+        let rows: Vec<ContactRow> = statement.fetch_all(executor).await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Ids of the contacts of `owner` matching a compiled query, sorted by name
+    pub async fn matching_ids(
+        pool: &PgPool,
+        owner: Uuid,
+        query: &Compiled,
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        let sql = format!(
+            "select id from contacts where owner = $1 and {} order by family_name, given_name",
+            query.sql
+        );
+        let statement = bind_compiled(sqlx::query_as(&sql).bind(owner), &query.params);
+        let rows: Vec<(Uuid,)> = statement.fetch_all(pool).await?;
+
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Number of contacts of `owner` matching a compiled query
+    pub async fn count_matching(
+        pool: &PgPool,
+        owner: Uuid,
+        query: &Compiled,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = format!(
+            "select count(*) from contacts where owner = $1 and {}",
+            query.sql
+        );
+        let statement = bind_compiled(sqlx::query_as(&sql).bind(owner), &query.params);
+        let (count,): (i64,) = statement.fetch_one(pool).await?;
+
+        Ok(count)
+    }
+
+    /// Contacts of `owner` with at least one significant date
+    pub async fn with_dates(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts where owner = $1 and jsonb_array_length(dates) > 0",
+            COLUMNS
+        ))
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Contacts that may match a search, see [`matching::search`]. `fuzzy` holds the folded
+    /// words of the query and their phonetic keys.
+    async fn search_candidates(
+        pool: &PgPool,
+        owner: Uuid,
+        text: &str,
+        e164: Option<&str>,
+        fuzzy: Option<(&[String], Vec<String>)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let pattern = format!("%{}%", like_escape(text));
+        let fuzzy_enabled = fuzzy.is_some();
+        let (words, keys) = fuzzy.unwrap_or_default();
+
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts
+            where owner = $1 and (
+                given_name ilike $2
+                or family_name ilike $2
+                or exists (select 1 from unnest(email_addresses) email where email ilike $2)
+                or exists (select 1 from jsonb_each_text(custom_fields) field where field.value ilike $2)
+                or $3 = any(phone_numbers)
+                or ($4 and (
+                    name_keys && $5
+                    or exists (select 1 from unnest($6::text[]) word
+                        where word_similarity(word, search_name) >= $7)
+                ))
+            )
+            order by family_name, given_name",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(pattern)
+        .bind(e164)
+        .bind(fuzzy_enabled)
+        .bind(&keys)
+        .bind(words)
+        .bind(matching::SIMILARITY_THRESHOLD)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+
+    /// Other contacts that may be duplicates, see [`matching::duplicates`]. `fuzzy` holds the
+    /// folded name of the contact and its phonetic keys.
+    async fn duplicate_candidates(
+        &self,
+        pool: &PgPool,
+        fuzzy: Option<(&str, Vec<String>)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let email_addresses: Vec<String> = self
+            .data
+            .emails
+            .iter()
+            .map(EmailAddress::normalized)
+            .collect();
+        let phone_numbers: Vec<String> = self
+            .data
+            .phones
+            .iter()
+            .map(|phone| phone.e164.clone())
+            .collect();
+        let fuzzy_enabled = fuzzy.is_some();
+        let (name, keys) = fuzzy.unwrap_or_default();
+
+        let rows: Vec<ContactRow> = sqlx::query_as(&format!(
+            "select {} from contacts
+            where owner = $1 and id <> $2
+            and (
+                phone_numbers && $3
+                or email_addresses && $4
+                or ($5 and (name_keys && $6 or similarity(search_name, $7) >= $8))
+            )
+            order by family_name, given_name",
+            COLUMNS
+        ))
+        .bind(self.owner)
+        .bind(self.id)
+        .bind(&phone_numbers)
+        .bind(&email_addresses)
+        .bind(fuzzy_enabled)
+        .bind(&keys)
+        .bind(name)
+        .bind(matching::SIMILARITY_THRESHOLD)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Contact::from).collect())
+    }
+}
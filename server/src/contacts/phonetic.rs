@@ -0,0 +1,705 @@
+/*!
+Double Metaphone phonetic keys
+
+Port of Lawrence Philips' Double Metaphone, which gives each word a primary key and an
+alternate key for a second common pronunciation. Names sounding alike, such as Smith and
+Smyth, get the same keys.
+*/
+
+/// Length of the keys, as in the original algorithm
+const MAX_LENGTH: usize = 4;
+
+/// Uppercase letters of a word, read with positions outside the word allowed
+struct Word {
+    chars: Vec<char>,
+    slavo_germanic: bool,
+}
+
+impl Word {
+    fn new(word: &str) -> Self {
+        let chars: Vec<char> = word
+            .chars()
+            .filter(char::is_ascii_alphabetic)
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let text: String = chars.iter().collect();
+        let slavo_germanic = text.contains('W')
+            || text.contains('K')
+            || text.contains("CZ")
+            || text.contains("WITZ");
+
+        Word {
+            chars,
+            slavo_germanic,
+        }
+    }
+
+    fn len(&self) -> isize {
+        self.chars.len() as isize
+    }
+
+    /// Letter at `pos`, a space past the end of the word
+    fn at(&self, pos: isize) -> char {
+        if pos < 0 {
+            return '\0';
+        }
+        self.chars.get(pos as usize).copied().unwrap_or(' ')
+    }
+
+    fn is_vowel(&self, pos: isize) -> bool {
+        matches!(self.at(pos), 'A' | 'E' | 'I' | 'O' | 'U' | 'Y')
+    }
+
+    /// Whether the `len` letters from `start` are one of `options`
+    fn is_at(&self, start: isize, len: usize, options: &[&str]) -> bool {
+        if start < 0 {
+            return false;
+        }
+        let text: String = (0..len as isize).map(|i| self.at(start + i)).collect();
+        options.contains(&text.as_str())
+    }
+
+    /// Whether the word starts like a germanic name
+    fn is_germanic(&self) -> bool {
+        self.is_at(0, 4, &["VAN ", "VON "]) || self.is_at(0, 3, &["SCH"])
+    }
+}
+
+#[derive(Default)]
+struct Keys {
+    primary: String,
+    alternate: String,
+}
+
+impl Keys {
+    fn add(&mut self, key: &str) {
+        self.primary.push_str(key);
+        self.alternate.push_str(key);
+    }
+
+    fn add2(&mut self, primary: &str, alternate: &str) {
+        self.primary.push_str(primary);
+        self.alternate.push_str(alternate);
+    }
+
+    fn is_full(&self) -> bool {
+        self.primary.len() >= MAX_LENGTH && self.alternate.len() >= MAX_LENGTH
+    }
+}
+
+/// Primary and alternate keys of a word. Letters other than A to Z are ignored, fold
+/// accented letters beforehand.
+pub fn double_metaphone(word: &str) -> (String, String) {
+    let w = Word::new(word);
+    let last = w.len() - 1;
+    let mut keys = Keys::default();
+    let mut current: isize = 0;
+
+    // Silent first letter
+    if w.is_at(0, 2, &["GN", "KN", "PN", "WR", "PS"]) {
+        current += 1;
+    }
+
+    // Initial X is pronounced Z, as in Xavier
+    if w.at(0) == 'X' {
+        keys.add("S");
+        current += 1;
+    }
+
+    while !keys.is_full() && current < w.len() {
+        match w.at(current) {
+            'A' | 'E' | 'I' | 'O' | 'U' | 'Y' => {
+                // Only initial vowels are kept
+                if current == 0 {
+                    keys.add("A");
+                }
+                current += 1;
+            }
+            'B' => {
+                keys.add("P");
+                current += if w.at(current + 1) == 'B' { 2 } else { 1 };
+            }
+            'C' => current = letter_c(&w, current, &mut keys),
+            'D' => {
+                if w.is_at(current, 2, &["DG"]) {
+                    if w.is_at(current + 2, 1, &["I", "E", "Y"]) {
+                        // Edge
+                        keys.add("J");
+                        current += 3;
+                    } else {
+                        // Edgar
+                        keys.add("TK");
+                        current += 2;
+                    }
+                } else if w.is_at(current, 2, &["DT", "DD"]) {
+                    keys.add("T");
+                    current += 2;
+                } else {
+                    keys.add("T");
+                    current += 1;
+                }
+            }
+            'F' => {
+                keys.add("F");
+                current += if w.at(current + 1) == 'F' { 2 } else { 1 };
+            }
+            'G' => current = letter_g(&w, current, &mut keys),
+            'H' => {
+                // Only kept when first or between vowels, and before a vowel
+                if (current == 0 || w.is_vowel(current - 1)) && w.is_vowel(current + 1) {
+                    keys.add("H");
+                    current += 2;
+                } else {
+                    current += 1;
+                }
+            }
+            'J' => current = letter_j(&w, current, &mut keys),
+            'K' => {
+                keys.add("K");
+                current += if w.at(current + 1) == 'K' { 2 } else { 1 };
+            }
+            'L' => {
+                if w.at(current + 1) == 'L' {
+                    // Spanish, as in Cabrillo and Gallegos
+                    let spanish = (current == w.len() - 3
+                        && w.is_at(current - 1, 4, &["ILLO", "ILLA", "ALLE"]))
+                        || ((w.is_at(last - 1, 2, &["AS", "OS"]) || w.is_at(last, 1, &["A", "O"]))
+                            && w.is_at(current - 1, 4, &["ALLE"]));
+                    if spanish {
+                        keys.add2("L", "");
+                    } else {
+                        keys.add("L");
+                    }
+                    current += 2;
+                } else {
+                    keys.add("L");
+                    current += 1;
+                }
+            }
+            'M' => {
+                // Silent B, as in Dumb and Thumb
+                let umb = w.is_at(current - 1, 3, &["UMB"])
+                    && (current + 1 == last || w.is_at(current + 2, 2, &["ER"]));
+                keys.add("M");
+                current += if umb || w.at(current + 1) == 'M' {
+                    2
+                } else {
+                    1
+                };
+            }
+            'N' => {
+                keys.add("N");
+                current += if w.at(current + 1) == 'N' { 2 } else { 1 };
+            }
+            'P' => {
+                if w.at(current + 1) == 'H' {
+                    keys.add("F");
+                    current += 2;
+                } else {
+                    // Campbell, Raspberry
+                    keys.add("P");
+                    current += if w.is_at(current + 1, 1, &["P", "B"]) {
+                        2
+                    } else {
+                        1
+                    };
+                }
+            }
+            'Q' => {
+                keys.add("K");
+                current += if w.at(current + 1) == 'Q' { 2 } else { 1 };
+            }
+            'R' => {
+                // French, as in Rogier, but not Hochmeier
+                if current == last
+                    && !w.slavo_germanic
+                    && w.is_at(current - 2, 2, &["IE"])
+                    && !w.is_at(current - 4, 2, &["ME", "MA"])
+                {
+                    keys.add2("", "R");
+                } else {
+                    keys.add("R");
+                }
+                current += if w.at(current + 1) == 'R' { 2 } else { 1 };
+            }
+            'S' => current = letter_s(&w, current, &mut keys),
+            'T' => {
+                if w.is_at(current, 4, &["TION"]) || w.is_at(current, 3, &["TIA", "TCH"]) {
+                    keys.add("X");
+                    current += 3;
+                } else if w.is_at(current, 2, &["TH"]) || w.is_at(current, 3, &["TTH"]) {
+                    // Thomas and Thames, or germanic
+                    if w.is_at(current + 2, 2, &["OM", "AM"]) || w.is_germanic() {
+                        keys.add("T");
+                    } else {
+                        keys.add2("0", "T");
+                    }
+                    current += 2;
+                } else {
+                    keys.add("T");
+                    current += if w.is_at(current + 1, 1, &["T", "D"]) {
+                        2
+                    } else {
+                        1
+                    };
+                }
+            }
+            'V' => {
+                keys.add("F");
+                current += if w.at(current + 1) == 'V' { 2 } else { 1 };
+            }
+            'W' => current = letter_w(&w, current, &mut keys),
+            'X' => {
+                // French, as in Breaux
+                let silent = current == last
+                    && (w.is_at(current - 3, 3, &["IAU", "EAU"])
+                        || w.is_at(current - 2, 2, &["AU", "OU"]));
+                if !silent {
+                    keys.add("KS");
+                }
+                current += if w.is_at(current + 1, 1, &["C", "X"]) {
+                    2
+                } else {
+                    1
+                };
+            }
+            'Z' => {
+                if w.at(current + 1) == 'H' {
+                    // Chinese pinyin, as in Zhao
+                    keys.add("J");
+                    current += 2;
+                } else {
+                    if w.is_at(current + 1, 2, &["ZO", "ZI", "ZA"])
+                        || (w.slavo_germanic && current > 0 && w.at(current - 1) != 'T')
+                    {
+                        keys.add2("S", "TS");
+                    } else {
+                        keys.add("S");
+                    }
+                    current += if w.at(current + 1) == 'Z' { 2 } else { 1 };
+                }
+            }
+            _ => current += 1,
+        }
+    }
+
+    keys.primary.truncate(MAX_LENGTH);
+    keys.alternate.truncate(MAX_LENGTH);
+    (keys.primary, keys.alternate)
+}
+
+/// Keys for a C, returns the position of the next letter to read
+fn letter_c(w: &Word, current: isize, keys: &mut Keys) -> isize {
+    // Germanic, as in Bacher and Macher
+    if current > 1
+        && !w.is_vowel(current - 2)
+        && w.is_at(current - 1, 3, &["ACH"])
+        && w.at(current + 2) != 'I'
+        && (w.at(current + 2) != 'E' || w.is_at(current - 2, 6, &["BACHER", "MACHER"]))
+    {
+        keys.add("K");
+        return current + 2;
+    }
+
+    if current == 0 && w.is_at(current, 6, &["CAESAR"]) {
+        keys.add("S");
+        return current + 2;
+    }
+
+    // Italian, as in Chianti
+    if w.is_at(current, 4, &["CHIA"]) {
+        keys.add("K");
+        return current + 2;
+    }
+
+    if w.is_at(current, 2, &["CH"]) {
+        // Michael
+        if current > 0 && w.is_at(current, 4, &["CHAE"]) {
+            keys.add2("K", "X");
+            return current + 2;
+        }
+
+        // Greek roots, as in Chemistry and Chorus
+        if current == 0
+            && (w.is_at(current + 1, 5, &["HARAC", "HARIS"])
+                || w.is_at(current + 1, 3, &["HOR", "HYM", "HIA", "HEM"]))
+            && !w.is_at(0, 5, &["CHORE"])
+        {
+            keys.add("K");
+            return current + 2;
+        }
+
+        // Germanic, greek or otherwise CH for a KH sound
+        if w.is_germanic()
+            || w.is_at(current - 2, 6, &["ORCHES", "ARCHIT", "ORCHID"])
+            || w.is_at(current + 2, 1, &["T", "S"])
+            || ((w.is_at(current - 1, 1, &["A", "O", "U", "E"]) || current == 0)
+                && w.is_at(
+                    current + 2,
+                    1,
+                    &["L", "R", "N", "M", "B", "H", "F", "V", "W", " "],
+                ))
+        {
+            keys.add("K");
+        } else if current > 0 {
+            if w.is_at(0, 2, &["MC"]) {
+                keys.add("K");
+            } else {
+                keys.add2("X", "K");
+            }
+        } else {
+            keys.add("X");
+        }
+        return current + 2;
+    }
+
+    // Czerny
+    if w.is_at(current, 2, &["CZ"]) && !w.is_at(current - 2, 4, &["WICZ"]) {
+        keys.add2("S", "X");
+        return current + 2;
+    }
+
+    // Focaccia
+    if w.is_at(current + 1, 3, &["CIA"]) {
+        keys.add("X");
+        return current + 3;
+    }
+
+    // Double C, but not as in McClellan
+    if w.is_at(current, 2, &["CC"]) && !(current == 1 && w.at(0) == 'M') {
+        // Bellocchio, but not Bacchus
+        if w.is_at(current + 2, 1, &["I", "E", "H"]) && !w.is_at(current + 2, 2, &["HU"]) {
+            // Accident, Accede and Succeed
+            if (current == 1 && w.at(current - 1) == 'A')
+                || w.is_at(current - 1, 5, &["UCCEE", "UCCES"])
+            {
+                keys.add("KS");
+            } else {
+                // Bacci, Bertucci
+                keys.add("X");
+            }
+            return current + 3;
+        }
+
+        keys.add("K");
+        return current + 2;
+    }
+
+    if w.is_at(current, 2, &["CK", "CG", "CQ"]) {
+        keys.add("K");
+        return current + 2;
+    }
+
+    if w.is_at(current, 2, &["CI", "CE", "CY"]) {
+        // Italian or english
+        if w.is_at(current, 3, &["CIO", "CIE", "CIA"]) {
+            keys.add2("S", "X");
+        } else {
+            keys.add("S");
+        }
+        return current + 2;
+    }
+
+    keys.add("K");
+
+    // Mac Caffrey, Mac Gregor
+    if w.is_at(current + 1, 2, &[" C", " Q", " G"]) {
+        current + 3
+    } else if w.is_at(current + 1, 1, &["C", "K", "Q"]) && !w.is_at(current + 1, 2, &["CE", "CI"]) {
+        current + 2
+    } else {
+        current + 1
+    }
+}
+
+/// Keys for a G, returns the position of the next letter to read
+fn letter_g(w: &Word, current: isize, keys: &mut Keys) -> isize {
+    if w.at(current + 1) == 'H' {
+        if current > 0 && !w.is_vowel(current - 1) {
+            keys.add("K");
+            return current + 2;
+        }
+
+        // Ghislane, Ghiradelli
+        if current == 0 {
+            if w.at(current + 2) == 'I' {
+                keys.add("J");
+            } else {
+                keys.add("K");
+            }
+            return current + 2;
+        }
+
+        // Parker's rule, as in Hugh, Bough and Broughton
+        if (current > 1 && w.is_at(current - 2, 1, &["B", "H", "D"]))
+            || (current > 2 && w.is_at(current - 3, 1, &["B", "H", "D"]))
+            || (current > 3 && w.is_at(current - 4, 1, &["B", "H"]))
+        {
+            return current + 2;
+        }
+
+        // Laugh, McLaughlin, Cough, Rough
+        if current > 2
+            && w.at(current - 1) == 'U'
+            && w.is_at(current - 3, 1, &["C", "G", "L", "R", "T"])
+        {
+            keys.add("F");
+        } else if current > 0 && w.at(current - 1) != 'I' {
+            keys.add("K");
+        }
+        return current + 2;
+    }
+
+    if w.at(current + 1) == 'N' {
+        if current == 1 && w.is_vowel(0) && !w.slavo_germanic {
+            keys.add2("KN", "N");
+        } else if !w.is_at(current + 2, 2, &["EY"]) && w.at(current + 1) != 'Y' && !w.slavo_germanic
+        {
+            // Not as in Cagney
+            keys.add2("N", "KN");
+        } else {
+            keys.add("KN");
+        }
+        return current + 2;
+    }
+
+    // Tagliaro
+    if w.is_at(current + 1, 2, &["LI"]) && !w.slavo_germanic {
+        keys.add2("KL", "L");
+        return current + 2;
+    }
+
+    // -ges-, -gep-, -gel- and -gie- at the beginning
+    if current == 0
+        && (w.at(current + 1) == 'Y'
+            || w.is_at(
+                current + 1,
+                2,
+                &[
+                    "ES", "EP", "EB", "EL", "EY", "IB", "IL", "IN", "IE", "EI", "ER",
+                ],
+            ))
+    {
+        keys.add2("K", "J");
+        return current + 2;
+    }
+
+    // -ger- and -gy-
+    if (w.is_at(current + 1, 2, &["ER"]) || w.at(current + 1) == 'Y')
+        && !w.is_at(0, 6, &["DANGER", "RANGER", "MANGER"])
+        && !w.is_at(current - 1, 1, &["E", "I"])
+        && !w.is_at(current - 1, 3, &["RGY", "OGY"])
+    {
+        keys.add2("K", "J");
+        return current + 2;
+    }
+
+    // Italian, as in Biaggi
+    if w.is_at(current + 1, 1, &["E", "I", "Y"]) || w.is_at(current - 1, 4, &["AGGI", "OGGI"]) {
+        if w.is_germanic() || w.is_at(current + 1, 2, &["ET"]) {
+            keys.add("K");
+        } else if w.is_at(current + 1, 4, &["IER "]) {
+            keys.add("J");
+        } else {
+            keys.add2("J", "K");
+        }
+        return current + 2;
+    }
+
+    keys.add("K");
+    if w.at(current + 1) == 'G' {
+        current + 2
+    } else {
+        current + 1
+    }
+}
+
+/// Keys for a J, returns the position of the next letter to read
+fn letter_j(w: &Word, current: isize, keys: &mut Keys) -> isize {
+    // Spanish, as in Jose and San Jacinto
+    if w.is_at(current, 4, &["JOSE"]) || w.is_at(0, 4, &["SAN "]) {
+        if (current == 0 && w.at(current + 4) == ' ') || w.is_at(0, 4, &["SAN "]) {
+            keys.add("H");
+        } else {
+            keys.add2("J", "H");
+        }
+        return current + 1;
+    }
+
+    if current == 0 {
+        // Yankelovich and Jankelowicz
+        keys.add2("J", "A");
+    } else if w.is_vowel(current - 1)
+        && !w.slavo_germanic
+        && (w.at(current + 1) == 'A' || w.at(current + 1) == 'O')
+    {
+        // Spanish, as in Bajador
+        keys.add2("J", "H");
+    } else if current == w.len() - 1 {
+        keys.add2("J", "");
+    } else if !w.is_at(current + 1, 1, &["L", "T", "K", "S", "N", "M", "B", "Z"])
+        && !w.is_at(current - 1, 1, &["S", "K", "L"])
+    {
+        keys.add("J");
+    }
+
+    if w.at(current + 1) == 'J' {
+        current + 2
+    } else {
+        current + 1
+    }
+}
+
+/// Keys for an S, returns the position of the next letter to read
+fn letter_s(w: &Word, current: isize, keys: &mut Keys) -> isize {
+    // Island, Isle, Carlisle and Carlysle
+    if w.is_at(current - 1, 3, &["ISL", "YSL"]) {
+        return current + 1;
+    }
+
+    if current == 0 && w.is_at(current, 5, &["SUGAR"]) {
+        keys.add2("X", "S");
+        return current + 1;
+    }
+
+    if w.is_at(current, 2, &["SH"]) {
+        // Germanic
+        if w.is_at(current + 1, 4, &["HEIM", "HOEK", "HOLM", "HOLZ"]) {
+            keys.add("S");
+        } else {
+            keys.add("X");
+        }
+        return current + 2;
+    }
+
+    // Italian and armenian
+    if w.is_at(current, 3, &["SIO", "SIA"]) || w.is_at(current, 4, &["SIAN"]) {
+        if w.slavo_germanic {
+            keys.add("S");
+        } else {
+            keys.add2("S", "X");
+        }
+        return current + 3;
+    }
+
+    // German and anglicisations, so Smith matches Schmidt and Snider matches Schneider. Also
+    // -sz- in slavic languages, although hungarian pronounces it S.
+    if (current == 0 && w.is_at(current + 1, 1, &["M", "N", "L", "W"]))
+        || w.is_at(current + 1, 1, &["Z"])
+    {
+        keys.add2("S", "X");
+        return if w.is_at(current + 1, 1, &["Z"]) {
+            current + 2
+        } else {
+            current + 1
+        };
+    }
+
+    if w.is_at(current, 2, &["SC"]) {
+        // Schlesinger's rule
+        if w.at(current + 2) == 'H' {
+            // Dutch, as in School and Schooner
+            if w.is_at(current + 3, 2, &["OO", "ER", "EN", "UY", "ED", "EM"]) {
+                // Schermerhorn, Schenker
+                if w.is_at(current + 3, 2, &["ER", "EN"]) {
+                    keys.add2("X", "SK");
+                } else {
+                    keys.add("SK");
+                }
+            } else if current == 0 && !w.is_vowel(3) && w.at(3) != 'W' {
+                keys.add2("X", "S");
+            } else {
+                keys.add("X");
+            }
+            return current + 3;
+        }
+
+        if w.is_at(current + 2, 1, &["I", "E", "Y"]) {
+            keys.add("S");
+        } else {
+            keys.add("SK");
+        }
+        return current + 3;
+    }
+
+    // French, as in Resnais and Artois
+    if current == w.len() - 1 && w.is_at(current - 2, 2, &["AI", "OI"]) {
+        keys.add2("", "S");
+    } else {
+        keys.add("S");
+    }
+
+    if w.is_at(current + 1, 1, &["S", "Z"]) {
+        current + 2
+    } else {
+        current + 1
+    }
+}
+
+/// Keys for a W, returns the position of the next letter to read
+fn letter_w(w: &Word, current: isize, keys: &mut Keys) -> isize {
+    if w.is_at(current, 2, &["WR"]) {
+        keys.add("R");
+        return current + 2;
+    }
+
+    if current == 0 && (w.is_vowel(current + 1) || w.is_at(current, 2, &["WH"])) {
+        // Wasserman matches Vasserman, Uomo matches Womo
+        if w.is_vowel(current + 1) {
+            keys.add2("A", "F");
+        } else {
+            keys.add("A");
+        }
+    }
+
+    // Arnow matches Arnoff
+    if (current == w.len() - 1 && w.is_vowel(current - 1))
+        || w.is_at(current - 1, 5, &["EWSKI", "EWSKY", "OWSKI", "OWSKY"])
+        || w.is_at(0, 3, &["SCH"])
+    {
+        keys.add2("", "F");
+        return current + 1;
+    }
+
+    // Polish, as in Filipowicz
+    if w.is_at(current, 4, &["WICZ", "WITZ"]) {
+        keys.add2("TS", "FX");
+        return current + 4;
+    }
+
+    current + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spelling_variants_share_keys() {
+        assert_eq!(double_metaphone("Smith"), double_metaphone("Smyth"));
+        assert_eq!(double_metaphone("Catherine"), double_metaphone("Kathryn"));
+        assert_eq!(double_metaphone("Philip"), double_metaphone("Filip"));
+        assert_eq!(double_metaphone("Stephen"), double_metaphone("Steven"));
+        assert_eq!(double_metaphone("Jon"), double_metaphone("John"));
+    }
+
+    #[test]
+    fn alternate_keys() {
+        assert_eq!(double_metaphone("Smith"), ("SM0".into(), "XMT".into()));
+        assert_eq!(double_metaphone("Schmidt"), ("XMT".into(), "SMT".into()));
+        assert_eq!(double_metaphone("Michael"), ("MKL".into(), "MXL".into()));
+        assert_eq!(double_metaphone("Jose"), ("HS".into(), "HS".into()));
+    }
+
+    #[test]
+    fn silent_letters() {
+        assert_eq!(double_metaphone("Knight").0, "NT");
+        assert_eq!(double_metaphone("Gnome").0, "NM");
+        assert_eq!(double_metaphone("Wright").0, "RT");
+    }
+
+    #[test]
+    fn empty_word() {
+        assert_eq!(double_metaphone(""), (String::new(), String::new()));
+    }
+}
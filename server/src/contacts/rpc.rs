@@ -0,0 +1,554 @@
+use common::contacts::{address::PostalAddress, phone, ContactData, ContactInput};
+use common::errors::AppError;
+use common::jsonrpc::{ById, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    dates::UpcomingEvent,
+    matching::{self, Match, MatchMode},
+    suggest::{self, Suggestion},
+    sync::{self, SyncResult},
+    vcard, Contact, ContactCreated, ContactDeleted, ContactUpdated, SortBy,
+};
+use crate::{
+    custom_fields::FieldDefinition,
+    relationships::{Relationship, RelationshipDeleted},
+    rpc::{self, Context, MethodResult},
+    settings::UserSettings,
+    streams,
+};
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    /// `version` of the contact the change is based on. When given, the update fails with
+    /// `version_conflict` if the contact changed since.
+    #[serde(default)]
+    pub version: Option<i64>,
+    #[serde(flatten)]
+    pub contact: ContactInput,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.contact.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Default)]
+#[serde(default)]
+pub struct ListParams {
+    /// `name` or the key of a custom field
+    pub sort: Option<String>,
+    pub descending: bool,
+}
+
+impl Validate for ListParams {}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct FormatAddressParams {
+    pub address: PostalAddress,
+}
+
+impl Validate for FormatAddressParams {}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct SearchParams {
+    pub query: String,
+    #[serde(default)]
+    pub mode: MatchMode,
+}
+
+impl Validate for SearchParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/query", &self.query).required();
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct DuplicatesParams {
+    pub id: Uuid,
+    #[serde(default)]
+    pub mode: MatchMode,
+}
+
+impl Validate for DuplicatesParams {}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpcomingParams {
+    /// Number of days to look ahead, today included
+    #[serde(default = "UpcomingParams::default_days")]
+    pub days: i64,
+}
+
+impl UpcomingParams {
+    fn default_days() -> i64 {
+        30
+    }
+}
+
+impl Validate for UpcomingParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/days", &self.days).range(0, 366);
+    }
+}
+
+/// Most suggestions a single `contacts.suggest` call returns
+const MAX_SUGGESTIONS: i64 = 50;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct SuggestParams {
+    pub prefix: String,
+    #[serde(default = "SuggestParams::default_limit")]
+    pub limit: i64,
+}
+
+impl SuggestParams {
+    fn default_limit() -> i64 {
+        10
+    }
+}
+
+impl Validate for SuggestParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/prefix", &self.prefix).required();
+        errors
+            .field("/limit", &self.limit)
+            .range(1, MAX_SUGGESTIONS);
+    }
+}
+
+/// Most changes a single `contacts.sync` call returns
+const MAX_SYNC_LIMIT: usize = 1000;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct SyncParams {
+    /// Token returned by the previous sync, none for the first one
+    pub token: Option<String>,
+    #[serde(default = "SyncParams::default_limit")]
+    pub limit: usize,
+}
+
+impl SyncParams {
+    fn default_limit() -> usize {
+        500
+    }
+}
+
+impl Validate for SyncParams {
+    /// Whether the token is one the server returned is for the handler to check
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/limit", &self.limit).range(1, MAX_SYNC_LIMIT);
+    }
+}
+
+async fn default_region(ctx: &Context<'_>) -> Result<Option<String>, rpc::RpcError> {
+    let settings = UserSettings::load(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    Ok(settings.default_region)
+}
+
+/// Validates a contact with the settings, custom fields and organizations of the caller
+async fn validate(ctx: &Context<'_>, input: ContactInput) -> Result<ContactData, rpc::RpcError> {
+    let data = super::validate_for(&ctx.state.postgres, ctx.owner(), input)
+        .await
+        .map_err(rpc::internal)??;
+
+    Ok(data)
+}
+
+/// Loads a contact of the caller, 404 if it does not exist
+pub async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Contact, rpc::RpcError> {
+    Contact::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `contacts.create`
+pub struct CreateContact;
+
+impl RpcMethod for CreateContact {
+    const NAME: &'static str = "contacts.create";
+    type Params = ContactInput;
+    type Result = Contact;
+}
+
+/// `contacts.create`
+pub async fn create(ctx: &Context<'_>, input: ContactInput) -> MethodResult<Contact> {
+    let data = validate(ctx, input).await?;
+
+    let contact = Contact::try_create(ContactCreated {
+        owner: ctx.owner(),
+        data,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(contact)
+}
+
+/// Definition of `contacts.get`
+pub struct GetContact;
+
+impl RpcMethod for GetContact {
+    const NAME: &'static str = "contacts.get";
+    type Params = ById;
+    type Result = Contact;
+}
+
+/// `contacts.get`
+pub async fn get(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Contact> {
+    rpc::ok(find(ctx, id).await?)
+}
+
+/// Definition of `contacts.list`
+pub struct ListContacts;
+
+impl RpcMethod for ListContacts {
+    const NAME: &'static str = "contacts.list";
+    type Params = ListParams;
+    type Result = Vec<Contact>;
+}
+
+/// `contacts.list`
+pub async fn list(
+    ctx: &Context<'_>,
+    ListParams { sort, descending }: ListParams,
+) -> MethodResult<Vec<Contact>> {
+    let sort = match sort.as_deref() {
+        None | Some("name") => SortBy::Name,
+        Some(key) => FieldDefinition::find_by_key(&ctx.state.postgres, ctx.owner(), key)
+            .await
+            .map_err(rpc::internal)?
+            .map(SortBy::CustomField)
+            .ok_or_else(|| {
+                let mut errors = ValidationErrors::new();
+                errors.add(
+                    "/sort",
+                    "unknown_field",
+                    "There is no custom field with this key",
+                );
+                errors
+            })?,
+    };
+
+    let contacts = Contact::list(&ctx.state.postgres, ctx.owner(), &sort, descending)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(contacts)
+}
+
+/// Definition of `contacts.update`
+pub struct UpdateContact;
+
+impl RpcMethod for UpdateContact {
+    const NAME: &'static str = "contacts.update";
+    type Params = UpdateParams;
+    type Result = Contact;
+}
+
+/// `contacts.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams {
+        id,
+        version,
+        contact: input,
+    }: UpdateParams,
+) -> MethodResult<Contact> {
+    let data = validate(ctx, input).await?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+    let current = Contact::find_for_update(&mut tx, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)?;
+    if let Some(expected) = version.filter(|expected| *expected != current.version) {
+        return Err(AppError::VersionConflict {
+            expected: expected.to_string(),
+            actual: current.version.to_string(),
+        }
+        .into());
+    }
+
+    let contact = current
+        .try_update(ContactUpdated { data })
+        .map_err(rpc::internal)?
+        .stage_persist(&mut tx)
+        .await
+        .map_err(rpc::internal)?;
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(contact)
+}
+
+/// Deletes a contact and its relationships in a transaction
+pub async fn stage_delete(
+    tx: &mut SqlxPgStoreTransaction,
+    contact: Contact,
+) -> Result<(), rpc::RpcError> {
+    let relationships = Relationship::touching(tx, contact.id)
+        .await
+        .map_err(rpc::internal)?;
+    for relationship in relationships {
+        relationship
+            .try_delete(RelationshipDeleted {})
+            .map_err(rpc::internal)?
+            .stage_delete(tx)
+            .await
+            .map_err(rpc::internal)?;
+    }
+
+    contact
+        .try_delete(ContactDeleted {})
+        .map_err(rpc::internal)?
+        .stage_delete(tx)
+        .await
+        .map_err(rpc::internal)?;
+
+    Ok(())
+}
+
+/// Definition of `contacts.delete`
+pub struct DeleteContact;
+
+impl RpcMethod for DeleteContact {
+    const NAME: &'static str = "contacts.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `contacts.delete`
+///
+/// Relationships of the contact are deleted in the same transaction.
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    let contact = find(ctx, id).await?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+    stage_delete(&mut tx, contact).await?;
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `contacts.search`
+pub struct SearchContacts;
+
+impl RpcMethod for SearchContacts {
+    const NAME: &'static str = "contacts.search";
+    type Params = SearchParams;
+    type Result = Vec<Match>;
+}
+
+/// `contacts.search`
+///
+/// Phone numbers in the query are normalized with the default region of the caller, so
+/// `020 7946 0000` finds a contact saved as `+44 20 7946 0000`. In `fuzzy` mode names that are
+/// misspelled or sound alike also match, so `Jon Smyth` finds John Smith. Each contact comes
+/// with the fields that matched and how, best matches first.
+pub async fn search(
+    ctx: &Context<'_>,
+    SearchParams { query, mode }: SearchParams,
+) -> MethodResult<Vec<Match>> {
+    let query = query.trim();
+
+    let region = default_region(ctx).await?;
+    let e164 = phone::to_e164(query, region.as_deref()).ok();
+
+    let matches = matching::search(
+        &ctx.state.postgres,
+        ctx.owner(),
+        query,
+        e164.as_deref(),
+        mode,
+    )
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::ok(matches)
+}
+
+/// Definition of `contacts.duplicates`
+pub struct FindDuplicates;
+
+impl RpcMethod for FindDuplicates {
+    const NAME: &'static str = "contacts.duplicates";
+    type Params = DuplicatesParams;
+    type Result = Vec<Match>;
+}
+
+/// `contacts.duplicates`
+///
+/// Contacts sharing a phone number or an email address with the given contact, and in `fuzzy`
+/// mode contacts whose given and family names are similar or sound alike
+pub async fn duplicates(
+    ctx: &Context<'_>,
+    DuplicatesParams { id, mode }: DuplicatesParams,
+) -> MethodResult<Vec<Match>> {
+    let contact = find(ctx, id).await?;
+
+    let duplicates = matching::duplicates(&ctx.state.postgres, &contact, mode)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(duplicates)
+}
+
+/// Definition of `contacts.vcard`
+pub struct ExportVcard;
+
+impl RpcMethod for ExportVcard {
+    const NAME: &'static str = "contacts.vcard";
+    type Params = ById;
+    type Result = String;
+}
+
+/// `contacts.vcard`
+pub async fn to_vcard(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<String> {
+    rpc::ok(vcard::to_vcard(&find(ctx, id).await?))
+}
+
+/// Definition of `contacts.format_address`
+pub struct FormatAddress;
+
+impl RpcMethod for FormatAddress {
+    const NAME: &'static str = "contacts.format_address";
+    type Params = FormatAddressParams;
+    type Result = Vec<String>;
+}
+
+/// `contacts.format_address`
+///
+/// Lines of an address in the conventional order of its country
+pub async fn format_address(
+    _ctx: &Context<'_>,
+    FormatAddressParams { address }: FormatAddressParams,
+) -> MethodResult<Vec<String>> {
+    rpc::ok(address.normalize().format())
+}
+
+/// Definition of `contacts.upcoming`
+pub struct UpcomingDates;
+
+impl RpcMethod for UpcomingDates {
+    const NAME: &'static str = "contacts.upcoming";
+    type Params = UpcomingParams;
+    type Result = Vec<UpcomingEvent>;
+}
+
+/// `contacts.upcoming`
+///
+/// Birthdays, anniversaries and custom dates in the next `days` days, soonest first. "Today"
+/// is the current date in the time zone of the caller.
+pub async fn upcoming(
+    ctx: &Context<'_>,
+    UpcomingParams { days }: UpcomingParams,
+) -> MethodResult<Vec<UpcomingEvent>> {
+    let settings = UserSettings::load(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+    let today = settings.today();
+
+    let contacts = Contact::with_dates(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    let mut events: Vec<UpcomingEvent> =
+        contacts
+            .iter()
+            .flat_map(|contact| {
+                let name = contact.display_name();
+                contact.data.dates.iter().filter_map(move |date| {
+                    UpcomingEvent::new(contact.id, &name, date, today, days)
+                })
+            })
+            .collect();
+    events.sort_by(|a, b| {
+        a.days_until
+            .cmp(&b.days_until)
+            .then_with(|| a.display_name.cmp(&b.display_name))
+    });
+
+    rpc::ok(events)
+}
+
+/// Definition of `contacts.suggest`
+pub struct SuggestContacts;
+
+impl RpcMethod for SuggestContacts {
+    const NAME: &'static str = "contacts.suggest";
+    type Params = SuggestParams;
+    type Result = Vec<Suggestion>;
+}
+
+/// `contacts.suggest`
+///
+/// Contacts whose name, email address or organization starts with `prefix`, for recipient
+/// autocomplete. The most frequently and recently contacted come first.
+pub async fn suggest(
+    ctx: &Context<'_>,
+    SuggestParams { prefix, limit }: SuggestParams,
+) -> MethodResult<Vec<Suggestion>> {
+    let suggestions = suggest::suggest(&ctx.state.postgres, ctx.owner(), &prefix, limit)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(suggestions)
+}
+
+/// Definition of `contacts.sync`
+pub struct SyncContacts;
+
+impl RpcMethod for SyncContacts {
+    const NAME: &'static str = "contacts.sync";
+    type Params = SyncParams;
+    type Result = SyncResult;
+}
+
+/// `contacts.sync`
+///
+/// Contacts created, updated or deleted since `token`, at most `limit` of them, with the token
+/// of the next sync. Without a token, or with one older than the retention of changes, every
+/// contact is returned with `full_resync` set, in pages of `limit` contacts.
+pub async fn sync(
+    ctx: &Context<'_>,
+    SyncParams { token, limit }: SyncParams,
+) -> MethodResult<SyncResult> {
+    let latest = streams::latest(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    let mut errors = ValidationErrors::new();
+    let after = match token {
+        Some(token) => {
+            let after = sync::Token::parse(&token).filter(|after| after.sequence <= latest);
+            if after.is_none() {
+                errors.add(
+                    "/token",
+                    "invalid_token",
+                    "Not a token returned by contacts.sync",
+                );
+            }
+            after
+        }
+        None => None,
+    };
+    errors.into_result()?;
+
+    let result = sync::sync(&ctx.state.postgres, ctx.owner(), after, latest, limit)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(result)
+}
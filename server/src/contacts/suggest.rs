@@ -0,0 +1,130 @@
+/*!
+Typeahead suggestions for recipient autocomplete
+
+Suggestions are answered from a dedicated projection rather than by scanning contacts. The
+`contact_terms` table holds the prefixes a contact can be found by: each word of its folded
+name, the whole folded name, each email address and the domain of each address. It is
+rewritten with the contact, and indexed for prefix searches per owner.
+
+Contacts are ranked by frecency, which combines how often and how recently they were
+interacted with. Each interaction counts for half as much every [`HALF_LIFE_DAYS`] days.
+Since every score decays at the same rate, the order never changes as time passes, so the
+interaction log stores `log2(sum(2 ^ (t / half life)))` over the times `t` of the
+interactions of a contact and the ranking is a plain index order.
+*/
+
+use chrono::{DateTime, Utc};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use super::matching;
+use crate::util::like_escape;
+
+/// Days after which an interaction counts for half as much in the ranking
+pub const HALF_LIFE_DAYS: i64 = 30;
+
+/// A contact suggested for a prefix
+#[derive(Serialize, JsonSchema, Debug, FromRow)]
+#[schemars(rename = "ContactSuggestion")]
+pub struct Suggestion {
+    pub id: Uuid,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// The address matching the prefix, or else the first address of the contact
+    pub email: Option<String>,
+    /// The organization matching the prefix, or else the first organization of the contact
+    pub organization: Option<String>,
+    /// Hash of the photo, to show an avatar
+    pub photo: Option<String>,
+    pub last_contacted: Option<DateTime<Utc>>,
+}
+
+/// `like` pattern matching values starting with `prefix`
+fn prefix_pattern(prefix: &str) -> String {
+    format!("{}%", like_escape(prefix))
+}
+
+/// Rewrites the terms of contacts from their `search_name` and `email_addresses`
+pub async fn index(tx: &mut SqlxPgStoreTransaction, contacts: &[Uuid]) -> Result<(), sqlx::Error> {
+    sqlx::query("delete from contact_terms where contact_id = any($1)")
+        .bind(contacts)
+        .execute(tx.get())
+        .await?;
+
+    sqlx::query(
+        "insert into contact_terms (owner, contact_id, field, term, value)
+        select owner, id, 'name', term, null
+        from contacts, unnest(string_to_array(search_name, ' ') || search_name) term
+        where id = any($1) and search_name <> ''
+        union
+        select owner, id, 'email', term, email
+        from contacts, unnest(email_addresses) email,
+            unnest(array[email, split_part(email, '@', 2)]) term
+        where id = any($1) and term <> ''
+        on conflict do nothing",
+    )
+    .bind(contacts)
+    .execute(tx.get())
+    .await?;
+
+    Ok(())
+}
+
+/// Up to `limit` contacts of `owner` with a name, email address or organization starting
+/// with `prefix`, most frequently and recently contacted first
+pub async fn suggest(
+    pool: &PgPool,
+    owner: Uuid,
+    prefix: &str,
+    limit: i64,
+) -> Result<Vec<Suggestion>, sqlx::Error> {
+    // Names are matched folded like the projection, emails and organizations as typed
+    let name = matching::words(prefix).join(" ");
+    let name_pattern = Some(name)
+        .filter(|name| !name.is_empty())
+        .map(|name| prefix_pattern(&name));
+    let pattern = prefix_pattern(&prefix.trim().to_lowercase());
+
+    sqlx::query_as(
+        "with matched as (
+            select contact_id, field, value from contact_terms
+            where owner = $1
+            and ((field = 'name' and term like $2) or (field = 'email' and term like $3))
+            union all
+            select contacts.id, 'organization', organizations.name
+            from organizations
+            join contacts on contacts.owner = organizations.owner
+                and contacts.affiliations @> jsonb_build_array(
+                    jsonb_build_object('organization_id', organizations.id))
+            where organizations.owner = $1 and lower(organizations.name) like $3
+        ),
+        best as (
+            select contact_id,
+                min(value) filter (where field = 'email') as email,
+                min(value) filter (where field = 'organization') as organization
+            from matched
+            group by contact_id
+        )
+        select contacts.id, contacts.given_name, contacts.family_name, contacts.photo,
+            contacts.last_contacted,
+            coalesce(best.email, contacts.emails->0->>'address') as email,
+            coalesce(best.organization, (
+                select name from organizations
+                where id = (contacts.affiliations->0->>'organization_id')::uuid
+            )) as organization
+        from best
+        join contacts on contacts.id = best.contact_id
+        order by contacts.frecency desc nulls last, contacts.last_contacted desc nulls last,
+            contacts.family_name, contacts.given_name
+        limit $4",
+    )
+    .bind(owner)
+    .bind(name_pattern)
+    .bind(pattern)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,280 @@
+/*!
+Delta sync for offline clients
+
+`contacts.sync` returns the contacts created, updated or deleted since a sync token, with the
+token to pass next time. Tokens are sequence numbers of the change stream, see
+[`crate::streams`], so a change committed between two syncs is never missed. Contacts are
+returned as they are when the sync runs, so one changed again meanwhile can show up in the
+next sync as well.
+
+Changes are only kept for [`streams::RETENTION_DAYS`] days. A token older than that, or no
+token at all, gets a full resync: every contact, flagged so the client replaces its copy. A
+resync is paged like the changes are, by contact id, and its tokens carry the last contact sent
+so the next page starts after it.
+*/
+
+use std::{collections::HashMap, fmt};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::Contact;
+use crate::{streams, webhooks};
+
+/// Changes of an address book since a sync token
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct SyncResult {
+    /// Token of the next sync
+    pub token: String,
+    /// Page of a full resync: once the page without `has_more` is in, the client drops the
+    /// contacts it has that were not in the `updated` of any page
+    pub full_resync: bool,
+    /// Contacts created or updated, in the order they last changed
+    pub updated: Vec<Contact>,
+    pub deleted: Vec<Uuid>,
+    /// Only part of the changes were returned, the client syncs again with `token`
+    pub has_more: bool,
+}
+
+/// Where a sync resumes, what its token stands for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Token {
+    /// Sequence number of the change stream the client is up to
+    pub sequence: i64,
+    /// Last contact sent by a full resync that has more pages, the resync goes on after it
+    pub resync_after: Option<Uuid>,
+}
+
+impl Token {
+    fn changes(sequence: i64) -> Self {
+        Token {
+            sequence,
+            resync_after: None,
+        }
+    }
+
+    /// Reads a token, `None` when it is not one
+    pub fn parse(token: &str) -> Option<Self> {
+        let (sequence, resync_after) = match token.split_once(':') {
+            Some((sequence, id)) => (sequence, Some(id.parse().ok()?)),
+            None => (token, None),
+        };
+        let sequence = sequence.parse().ok().filter(|sequence| *sequence >= 0)?;
+
+        Some(Token {
+            sequence,
+            resync_after,
+        })
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.resync_after {
+            Some(id) => write!(f, "{}:{}", self.sequence, id),
+            None => write!(f, "{}", self.sequence),
+        }
+    }
+}
+
+/// What a sync sends
+#[derive(PartialEq, Debug)]
+enum Start {
+    /// The changes after a sequence number
+    Changes(i64),
+    /// A page of a full resync as of `through`, with the contacts after the contact `after`
+    Resync { through: i64, after: Option<Uuid> },
+}
+
+/// Where a sync from `since` starts when changes up to `purged` were purged and the latest
+/// change is `latest`. Resyncs go on as of the sequence number they started at.
+fn start(since: Option<Token>, purged: i64, latest: i64) -> Start {
+    match since {
+        Some(Token {
+            sequence,
+            resync_after: Some(after),
+        }) => Start::Resync {
+            through: sequence,
+            after: Some(after),
+        },
+        Some(Token {
+            sequence,
+            resync_after: None,
+        }) if sequence >= purged => Start::Changes(sequence),
+        _ => Start::Resync {
+            through: latest,
+            after: None,
+        },
+    }
+}
+
+/// Changes of the contacts of `owner` since `since` and up to `latest`, at most `limit` of
+/// them. A full resync when there is no token or the changes after it were purged.
+pub async fn sync(
+    pool: &PgPool,
+    owner: Uuid,
+    since: Option<Token>,
+    latest: i64,
+    limit: usize,
+) -> Result<SyncResult, sqlx::Error> {
+    let purged = streams::purged_through(pool, owner).await?;
+
+    let after = match start(since, purged, latest) {
+        Start::Changes(after) => after,
+        Start::Resync { through, after } => {
+            return resync(pool, owner, through, after, limit).await
+        }
+    };
+
+    // One change more than the limit tells whether there are more
+    let mut changes =
+        streams::changed_contacts(pool, owner, after, latest, Some(limit as i64 + 1)).await?;
+    let has_more = changes.len() > limit;
+    changes.truncate(limit);
+    let next = match changes.last() {
+        Some(change) if has_more => change.sequence,
+        _ => latest,
+    };
+
+    let ids: Vec<Uuid> = changes
+        .iter()
+        .filter(|change| change.event_type != webhooks::CONTACT_DELETED)
+        .map(|change| change.contact_id)
+        .collect();
+    let mut contacts: HashMap<Uuid, Contact> = Contact::find_many(pool, owner, &ids)
+        .await?
+        .into_iter()
+        .map(|contact| (contact.id, contact))
+        .collect();
+
+    let mut updated = Vec::with_capacity(contacts.len());
+    let mut deleted = Vec::new();
+    for change in changes {
+        // Contacts deleted after `latest` count as deleted already
+        match contacts.remove(&change.contact_id) {
+            Some(contact) => updated.push(contact),
+            None => deleted.push(change.contact_id),
+        }
+    }
+
+    Ok(SyncResult {
+        token: Token::changes(next).to_string(),
+        full_resync: false,
+        updated,
+        deleted,
+        has_more,
+    })
+}
+
+/// Page of a full resync as of the `through` sequence number, with the contacts after the
+/// contact `after` in the order of their ids. Once the last page is sent, the client syncs the
+/// changes after `through`, which covers the contacts changed while it was paging.
+async fn resync(
+    pool: &PgPool,
+    owner: Uuid,
+    through: i64,
+    after: Option<Uuid>,
+    limit: usize,
+) -> Result<SyncResult, sqlx::Error> {
+    let mut updated = Contact::page(pool, owner, after, limit as i64 + 1).await?;
+    let has_more = updated.len() > limit;
+    updated.truncate(limit);
+    let next = Token {
+        sequence: through,
+        resync_after: updated
+            .last()
+            .map(|contact| contact.id)
+            .filter(|_| has_more),
+    };
+
+    Ok(SyncResult {
+        token: next.to_string(),
+        full_resync: true,
+        updated,
+        deleted: Vec::new(),
+        has_more,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6f1c5a0e-8d2b-4c3a-9e7f-1a2b3c4d5e6f";
+
+    #[test]
+    fn tokens_round_trip() {
+        for text in &["0", "42", &format!("42:{}", ID)] {
+            assert_eq!(Token::parse(text).unwrap().to_string(), *text);
+        }
+        assert_eq!(
+            Token::parse(&format!("7:{}", ID)),
+            Some(Token {
+                sequence: 7,
+                resync_after: Some(ID.parse().unwrap()),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        for text in &[
+            "",
+            "-1",
+            "abc",
+            "1.5",
+            "42:",
+            "42:nope",
+            &format!("-1:{}", ID),
+        ] {
+            assert_eq!(Token::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn no_token_resyncs() {
+        assert_eq!(
+            start(None, 10, 20),
+            Start::Resync {
+                through: 20,
+                after: None
+            }
+        );
+    }
+
+    #[test]
+    fn token_within_retention_gets_changes() {
+        assert_eq!(start(Some(Token::changes(15)), 10, 20), Start::Changes(15));
+        assert_eq!(start(Some(Token::changes(10)), 10, 20), Start::Changes(10));
+        assert_eq!(start(Some(Token::changes(20)), 10, 20), Start::Changes(20));
+    }
+
+    #[test]
+    fn token_past_horizon_resyncs() {
+        assert_eq!(
+            start(Some(Token::changes(9)), 10, 20),
+            Start::Resync {
+                through: 20,
+                after: None
+            }
+        );
+    }
+
+    #[test]
+    fn resync_goes_on_as_of_its_start() {
+        let after = ID.parse().unwrap();
+        let token = Token {
+            sequence: 5,
+            resync_after: Some(after),
+        };
+        assert_eq!(
+            start(Some(token), 10, 20),
+            Start::Resync {
+                through: 5,
+                after: Some(after)
+            }
+        );
+    }
+}
@@ -0,0 +1,692 @@
+/*!
+vCard 3.0 serialization
+
+https://datatracker.ietf.org/doc/html/rfc2426
+
+vCards sent by CardDAV clients are read back with [`read`], which also accepts the common
+vCard 4.0 forms of the properties it knows. Custom fields are written as `X-` properties named
+after their key, see [`FieldDefinition::vcard_property`], and read back from them.
+*/
+
+use common::contacts::{
+    address::{self, AddressLabel, PostalAddress},
+    dates::{DateKind, SignificantDate},
+    email::{EmailAddress, EmailLabel},
+    phone::{PhoneLabel, PhoneNumberInput},
+    ContactInput,
+};
+
+use super::Contact;
+use crate::custom_fields::{self, FieldDefinition};
+
+/// Escapes a text value, `;` and `,` separate components and lists in structured values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Folds a content line so no line is longer than 75 octets
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / 74 * 3);
+    let mut length = 0;
+
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+
+    folded
+}
+
+fn address_type(label: AddressLabel) -> &'static str {
+    match label {
+        AddressLabel::Home => "home",
+        AddressLabel::Work => "work",
+        AddressLabel::Other => "postal",
+    }
+}
+
+/// Value of the `ADR` property, `;` separated: PO box, extended address, street, locality,
+/// region, postal code and country
+pub fn adr(address: &PostalAddress) -> String {
+    let street: Vec<String> = address.street.iter().map(|line| escape(line)).collect();
+
+    [
+        String::new(),
+        String::new(),
+        street.join("\\n"),
+        escape(address.locality.as_deref().unwrap_or_default()),
+        escape(address.region.as_deref().unwrap_or_default()),
+        escape(address.postal_code.as_deref().unwrap_or_default()),
+        escape(address.country_name()),
+    ]
+    .join(";")
+}
+
+/// Content lines of a contact, without folding or line terminators
+pub fn properties(contact: &Contact) -> Vec<String> {
+    let data = &contact.data;
+    let mut lines = vec![
+        String::from("BEGIN:VCARD"),
+        String::from("VERSION:3.0"),
+        format!("UID:{}", contact.id),
+        format!("FN:{}", escape(&contact.display_name())),
+        format!(
+            "N:{};{};;;",
+            escape(data.family_name.as_deref().unwrap_or_default()),
+            escape(data.given_name.as_deref().unwrap_or_default())
+        ),
+    ];
+
+    for email in &data.emails {
+        let kind = match email.label {
+            EmailLabel::Home => "internet,home",
+            EmailLabel::Work => "internet,work",
+            EmailLabel::Other => "internet",
+        };
+        lines.push(format!("EMAIL;TYPE={}:{}", kind, escape(&email.address)));
+    }
+
+    for phone in &data.phones {
+        let kind = match phone.label {
+            PhoneLabel::Mobile => "cell",
+            PhoneLabel::Home => "home",
+            PhoneLabel::Work => "work",
+            PhoneLabel::Fax => "fax",
+            PhoneLabel::Pager => "pager",
+            PhoneLabel::Other => "voice",
+        };
+        lines.push(format!("TEL;TYPE={}:{}", kind, phone.e164));
+    }
+
+    for address in &data.addresses {
+        let kind = address_type(address.label);
+        lines.push(format!("ADR;TYPE={}:{}", kind, adr(address)));
+        lines.push(format!(
+            "LABEL;TYPE={}:{}",
+            kind,
+            escape(&address.format().join("\n"))
+        ));
+    }
+
+    for date in &data.dates {
+        let property = match date.kind {
+            DateKind::Birthday => "BDAY",
+            DateKind::Anniversary => "X-ANNIVERSARY",
+            // vCard 3.0 has no property for arbitrary dates
+            DateKind::Custom => continue,
+        };
+        let (parameters, value) = date.to_vcard();
+        lines.push(format!("{}{}:{}", property, parameters, value));
+    }
+
+    if !contact.tags.is_empty() {
+        let names: Vec<String> = contact.tags.iter().map(|tag| escape(&tag.name)).collect();
+        lines.push(format!("CATEGORIES:{}", names.join(",")));
+    }
+
+    if let Some(notes) = &data.notes {
+        lines.push(format!("NOTE:{}", escape(notes)));
+    }
+
+    for (key, value) in &data.custom_fields {
+        lines.push(format!(
+            "{}:{}",
+            FieldDefinition::vcard_property(key),
+            escape(&custom_fields::to_text(value))
+        ));
+    }
+
+    lines.push(String::from("END:VCARD"));
+    lines
+}
+
+/// Joins content lines into a vCard, folded and terminated
+pub fn from_properties(lines: &[String]) -> String {
+    lines.iter().map(|line| fold(line) + "\r\n").collect()
+}
+
+/// Serializes a contact as a vCard
+pub fn to_vcard(contact: &Contact) -> String {
+    from_properties(&properties(contact))
+}
+
+/// Reverses the escaping of vCard text values
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// Components of a structured value, split on the `;` that are not escaped and unescaped
+fn components(value: &str) -> Vec<String> {
+    let mut components = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ';' => {
+                components.push(unescape(&value[start..index]));
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    components.push(unescape(&value[start..]));
+
+    components
+}
+
+/// A content line: its name without group, its parameters and its raw value
+struct Line<'a> {
+    name: String,
+    parameters: Vec<(String, &'a str)>,
+    value: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        // Parameter values may be quoted and hold colons
+        let mut quoted = false;
+        let colon = line.char_indices().find_map(|(index, c)| match c {
+            '"' => {
+                quoted = !quoted;
+                None
+            }
+            ':' if !quoted => Some(index),
+            _ => None,
+        })?;
+
+        let mut parts = line[..colon].split(';');
+        let name = parts.next()?.rsplit('.').next()?.to_uppercase();
+        let parameters = parts
+            .map(|parameter| match parameter.split_once('=') {
+                Some((key, value)) => (key.to_uppercase(), value.trim_matches('"')),
+                // vCard 2.1 types have no key
+                None => (String::from("TYPE"), parameter),
+            })
+            .collect();
+
+        Some(Line {
+            name,
+            parameters,
+            value: &line[colon + 1..],
+        })
+    }
+
+    /// Values of the `TYPE` parameters, lowercased
+    fn types(&self) -> Vec<String> {
+        self.parameters
+            .iter()
+            .filter(|(key, _)| key == "TYPE")
+            .flat_map(|(_, value)| value.split(','))
+            .map(|kind| kind.trim().to_lowercase())
+            .collect()
+    }
+
+    fn has_type(&self, kind: &str) -> bool {
+        self.types().iter().any(|value| value == kind)
+    }
+
+    fn has_parameter(&self, key: &str) -> bool {
+        self.parameters.iter().any(|(name, _)| name == key)
+    }
+}
+
+/// Content lines of a vCard, unfolded
+fn unfold(vcard: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in vcard.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push(String::from(line)),
+        }
+    }
+
+    lines
+}
+
+/// Date of a `BDAY` or anniversary: `1985-04-12`, `19850412`, `--0412` or `--04-12`, with an
+/// optional time. The year is left out when the client marked it as omitted.
+fn date(line: &Line, kind: DateKind) -> Option<SignificantDate> {
+    let value = line.value.split('T').next()?.trim();
+    let (year, rest) = match value.strip_prefix("--") {
+        Some(rest) => (None, rest.replace('-', "")),
+        None => {
+            let digits = value.replace('-', "");
+            if digits.len() != 8 {
+                return None;
+            }
+            (Some(digits[..4].parse().ok()?), String::from(&digits[4..]))
+        }
+    };
+    if rest.len() != 4 {
+        return None;
+    }
+    let omitted = line.has_parameter("X-APPLE-OMIT-YEAR");
+
+    Some(SignificantDate {
+        kind,
+        label: None,
+        year: year.filter(|_| !omitted),
+        month: rest[..2].parse().ok()?,
+        day: rest[2..].parse().ok()?,
+    })
+}
+
+/// Reads a vCard into `input`, replacing the fields a vCard holds: names, emails, phone
+/// numbers, addresses, notes, birthday and anniversary. Fields it does not hold, such as
+/// affiliations and custom dates, are kept, and so are tags. `None` when the text is not a
+/// vCard.
+///
+/// Custom fields among `fields` are set from their property, and removed when it is empty.
+/// Many clients drop the `X-` properties they do not know, so fields without a property keep
+/// their value, and so do values that cannot be read as the type of their field.
+pub fn read(
+    vcard: &str,
+    mut input: ContactInput,
+    fields: &[FieldDefinition],
+) -> Option<ContactInput> {
+    let lines = unfold(vcard);
+    let is = |line: Option<&String>, value: &str| {
+        line.is_some_and(|line| line.trim().eq_ignore_ascii_case(value))
+    };
+    if !is(lines.first(), "BEGIN:VCARD") || !is(lines.last(), "END:VCARD") {
+        return None;
+    }
+
+    let mut full_name = None;
+    input.given_name = None;
+    input.family_name = None;
+    input.emails.clear();
+    input.phones.clear();
+    input.addresses.clear();
+    input.notes = None;
+    input.dates.retain(|date| date.kind == DateKind::Custom);
+
+    let text = |value: &str| Some(unescape(value)).filter(|value| !value.trim().is_empty());
+    for line in lines.iter().filter_map(|line| Line::parse(line)) {
+        match line.name.as_str() {
+            "FN" => full_name = text(line.value),
+            "N" => {
+                let mut names = components(line.value).into_iter();
+                input.family_name = names.next().filter(|name| !name.trim().is_empty());
+                input.given_name = names.next().filter(|name| !name.trim().is_empty());
+            }
+            "EMAIL" => input.emails.push(EmailAddress {
+                address: unescape(line.value).trim().to_string(),
+                label: if line.has_type("work") {
+                    EmailLabel::Work
+                } else if line.has_type("home") {
+                    EmailLabel::Home
+                } else {
+                    EmailLabel::Other
+                },
+            }),
+            "TEL" => input.phones.push(PhoneNumberInput {
+                number: unescape(line.value.trim_start_matches("tel:")),
+                label: if line.has_type("fax") {
+                    PhoneLabel::Fax
+                } else if line.has_type("pager") {
+                    PhoneLabel::Pager
+                } else if line.has_type("cell") {
+                    PhoneLabel::Mobile
+                } else if line.has_type("work") {
+                    PhoneLabel::Work
+                } else if line.has_type("home") {
+                    PhoneLabel::Home
+                } else {
+                    PhoneLabel::Other
+                },
+            }),
+            "ADR" => {
+                let parts = components(line.value);
+                let part = |index: usize| parts.get(index).cloned().unwrap_or_default();
+                let country = match part(6).trim() {
+                    "" => input.country.clone().unwrap_or_default(),
+                    name => address::country_code(name).unwrap_or_else(|| String::from(name)),
+                };
+
+                input.addresses.push(
+                    PostalAddress {
+                        label: if line.has_type("work") {
+                            AddressLabel::Work
+                        } else if line.has_type("home") {
+                            AddressLabel::Home
+                        } else {
+                            AddressLabel::Other
+                        },
+                        street: [part(1), part(2)]
+                            .iter()
+                            .flat_map(|lines| lines.lines())
+                            .map(String::from)
+                            .collect(),
+                        locality: text(&part(3)),
+                        region: text(&part(4)),
+                        postal_code: text(&part(5)),
+                        country,
+                    }
+                    .normalize(),
+                );
+            }
+            "BDAY" => input.dates.extend(date(&line, DateKind::Birthday)),
+            "ANNIVERSARY" | "X-ANNIVERSARY" => {
+                input.dates.extend(date(&line, DateKind::Anniversary))
+            }
+            "NOTE" => input.notes = text(line.value),
+            name => {
+                let field = fields
+                    .iter()
+                    .find(|field| FieldDefinition::vcard_property(&field.key) == name);
+                if let Some(field) = field {
+                    match text(line.value) {
+                        None => {
+                            input.custom_fields.remove(&field.key);
+                        }
+                        Some(value) => {
+                            if let Ok(value) = field.parse_text(&value) {
+                                input.custom_fields.insert(field.key.clone(), value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Cards without a structured name keep their full name as the given name
+    if input.given_name.is_none() && input.family_name.is_none() {
+        input.given_name = full_name;
+    }
+
+    Some(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_fields::FieldKind;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn card(lines: &[&str]) -> String {
+        let mut vcard = String::from("BEGIN:VCARD\r\nVERSION:3.0\r\n");
+        for line in lines {
+            vcard.push_str(line);
+            vcard.push_str("\r\n");
+        }
+        vcard + "END:VCARD\r\n"
+    }
+
+    #[test]
+    fn rejects_text_that_is_not_a_vcard() {
+        assert!(read("FN:Ada Lovelace", ContactInput::default(), &[]).is_none());
+        assert!(read("BEGIN:VCARD\r\nFN:Ada\r\n", ContactInput::default(), &[]).is_none());
+    }
+
+    #[test]
+    fn reads_names_and_unfolds_lines() {
+        let input = read(
+            &card(&[
+                "N:Lovelace;Ada;;;",
+                "NOTE:First line\\nsecond\r\n  line, folded",
+            ]),
+            ContactInput::default(),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(input.family_name.as_deref(), Some("Lovelace"));
+        assert_eq!(input.given_name.as_deref(), Some("Ada"));
+        assert_eq!(
+            input.notes.as_deref(),
+            Some("First line\nsecond line, folded")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_full_name() {
+        let input = read(
+            &card(&["FN:Ada Lovelace", "N:;;;;"]),
+            ContactInput::default(),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(input.given_name.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(input.family_name, None);
+    }
+
+    #[test]
+    fn reads_labels_of_grouped_and_typed_properties() {
+        let input = read(
+            &card(&[
+                "item1.EMAIL;TYPE=INTERNET,WORK:ada@example.com",
+                "EMAIL;type=home;type=pref:ada@home.example",
+                "TEL;TYPE=cell:+44 20 7946 0000",
+                "TEL;TYPE=work,fax:+44 20 7946 0001",
+                "TEL;VALUE=uri:tel:+44-20-7946-0002",
+            ]),
+            ContactInput::default(),
+            &[],
+        )
+        .unwrap();
+
+        let emails: Vec<_> = input
+            .emails
+            .iter()
+            .map(|email| (email.address.as_str(), email.label))
+            .collect();
+        assert_eq!(
+            emails,
+            vec![
+                ("ada@example.com", EmailLabel::Work),
+                ("ada@home.example", EmailLabel::Home)
+            ]
+        );
+
+        let phones: Vec<_> = input
+            .phones
+            .iter()
+            .map(|phone| (phone.number.as_str(), phone.label))
+            .collect();
+        assert_eq!(
+            phones,
+            vec![
+                ("+44 20 7946 0000", PhoneLabel::Mobile),
+                ("+44 20 7946 0001", PhoneLabel::Fax),
+                ("+44-20-7946-0002", PhoneLabel::Other)
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_addresses_with_country_names() {
+        let input = read(
+            &card(&[
+                "ADR;TYPE=home:;Flat 2;10 Downing St\\nWestminster;London;;SW1A 2AA;United Kingdom",
+            ]),
+            ContactInput::default(),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(
+            input.addresses,
+            vec![PostalAddress {
+                label: AddressLabel::Home,
+                street: vec![
+                    String::from("Flat 2"),
+                    String::from("10 Downing St"),
+                    String::from("Westminster")
+                ],
+                locality: Some(String::from("London")),
+                region: None,
+                postal_code: Some(String::from("SW1A 2AA")),
+                country: String::from("GB"),
+            }]
+        );
+    }
+
+    #[test]
+    fn round_trips_written_addresses() {
+        let address = PostalAddress {
+            label: AddressLabel::Work,
+            street: vec![String::from("Unter den Linden 1")],
+            locality: Some(String::from("Berlin")),
+            region: None,
+            postal_code: Some(String::from("10117")),
+            country: String::from("DE"),
+        };
+        let line = format!("ADR;TYPE=work:{}", adr(&address));
+
+        let input = read(&card(&[&line]), ContactInput::default(), &[]).unwrap();
+
+        assert_eq!(input.addresses, vec![address]);
+    }
+
+    #[test]
+    fn reads_dates_and_omitted_years() {
+        let input = read(
+            &card(&[
+                "BDAY;X-APPLE-OMIT-YEAR=1604:1604-02-29",
+                "X-ANNIVERSARY:20100612",
+                "BDAY:--0412",
+                "BDAY:not a date",
+            ]),
+            ContactInput::default(),
+            &[],
+        )
+        .unwrap();
+
+        let dates: Vec<_> = input
+            .dates
+            .iter()
+            .map(|date| (date.kind, date.year, date.month, date.day))
+            .collect();
+        assert_eq!(
+            dates,
+            vec![
+                (DateKind::Birthday, None, 2, 29),
+                (DateKind::Anniversary, Some(2010), 6, 12),
+                (DateKind::Birthday, None, 4, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_fields_vcards_do_not_hold() {
+        let custom = SignificantDate {
+            kind: DateKind::Custom,
+            label: Some(String::from("Name day")),
+            year: None,
+            month: 7,
+            day: 26,
+        };
+        let existing = ContactInput {
+            given_name: Some(String::from("Old")),
+            notes: Some(String::from("Old notes")),
+            dates: vec![
+                custom.clone(),
+                SignificantDate {
+                    kind: DateKind::Birthday,
+                    label: None,
+                    year: Some(1815),
+                    month: 12,
+                    day: 10,
+                },
+            ],
+            custom_fields: vec![(String::from("shoe_size"), serde_json::json!(38))]
+                .into_iter()
+                .collect(),
+            ..ContactInput::default()
+        };
+
+        let input = read(&card(&["N:Lovelace;Ada;;;"]), existing, &[]).unwrap();
+
+        assert_eq!(input.given_name.as_deref(), Some("Ada"));
+        assert_eq!(input.notes, None);
+        assert_eq!(input.dates, vec![custom]);
+        assert_eq!(input.custom_fields["shoe_size"], serde_json::json!(38));
+    }
+
+    #[test]
+    fn reads_custom_fields_from_their_properties() {
+        let field = |key: &str, kind: FieldKind| FieldDefinition {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            key: String::from(key),
+            name: String::from(key),
+            kind,
+        };
+        let fields = vec![
+            field("shoe_size", FieldKind::Number),
+            field("renewal", FieldKind::Date),
+            field("nickname", FieldKind::Text),
+            field("account_manager", FieldKind::Text),
+        ];
+        let existing = ContactInput {
+            custom_fields: vec![
+                (String::from("renewal"), json!("2021-10-01")),
+                (String::from("nickname"), json!("Ada")),
+                (String::from("account_manager"), json!("Charles")),
+            ]
+            .into_iter()
+            .collect(),
+            ..ContactInput::default()
+        };
+
+        let input = read(
+            &card(&[
+                "X-SHOE-SIZE:39",
+                "X-RENEWAL:next year",
+                "X-NICKNAME:",
+                "X-UNKNOWN:ignored",
+            ]),
+            existing,
+            &fields,
+        )
+        .unwrap();
+
+        assert_eq!(
+            input.custom_fields,
+            vec![
+                (String::from("account_manager"), json!("Charles")),
+                (String::from("renewal"), json!("2021-10-01")),
+                (String::from("shoe_size"), json!(39)),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+}
@@ -0,0 +1,390 @@
+/*!
+User defined contact fields
+
+Each user defines the extra fields their contacts have, e.g. "account manager" or "contract
+renewal date". Values are stored in the `custom_fields` object of the contact, keyed by the
+`key` of the definition, and checked against the definition on every write.
+
+vCards are the only form values are written in as text, as `X-` properties read back by
+[`vcard::read`](crate::contacts::vcard::read). There is no CSV import or export.
+*/
+
+pub mod rpc;
+
+use chrono::NaiveDate;
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgPool};
+use tide::http::Url;
+use uuid::Uuid;
+
+static KEY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[a-z][a-z0-9_]{0,62}$").unwrap());
+
+/// Type of the values of a custom field
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldKind {
+    Text,
+    Number,
+    /// `YYYY-MM-DD`
+    Date,
+    Boolean,
+    /// One of a fixed list of options
+    Enum {
+        options: Vec<String>,
+    },
+    Url,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "custom_field_definitions")]
+pub struct FieldDefinition {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    /// Identifier of the field in the contact, e.g. `account_manager`. Cannot be changed.
+    pub key: String,
+    /// Name displayed to the user, e.g. `Account manager`
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+/// Reasons a custom field value can be rejected
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FieldValueError {
+    UnknownField,
+    WrongType,
+    InvalidDate,
+    InvalidUrl,
+    UnknownOption,
+}
+
+impl FieldValueError {
+    /// Machine readable reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            FieldValueError::UnknownField => "unknown_field",
+            FieldValueError::WrongType => "wrong_type",
+            FieldValueError::InvalidDate => "invalid_date",
+            FieldValueError::InvalidUrl => "invalid_url",
+            FieldValueError::UnknownOption => "unknown_option",
+        }
+    }
+
+    /// Human readable reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            FieldValueError::UnknownField => "There is no custom field with this key",
+            FieldValueError::WrongType => "The value does not match the type of the field",
+            FieldValueError::InvalidDate => "Dates must be written as YYYY-MM-DD",
+            FieldValueError::InvalidUrl => "Not a http or https URL",
+            FieldValueError::UnknownOption => "Not one of the options of the field",
+        }
+    }
+}
+
+/// Keys must be lowercase ASCII so they can be used as vCard property names
+pub fn is_valid_key(key: &str) -> bool {
+    KEY.is_match(key)
+}
+
+/// A value written as text, for vCard properties
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+impl FieldDefinition {
+    /// Checks a value sent by the client and returns it in canonical form
+    pub fn check(&self, value: &Value) -> Result<Value, FieldValueError> {
+        match (&self.kind, value) {
+            (FieldKind::Number, Value::Number(_)) | (FieldKind::Boolean, Value::Bool(_)) => {
+                Ok(value.clone())
+            }
+            (FieldKind::Text, Value::String(text)) => Ok(Value::String(text.trim().to_string())),
+            (FieldKind::Date, Value::String(text))
+            | (FieldKind::Enum { .. }, Value::String(text))
+            | (FieldKind::Url, Value::String(text)) => self.parse_text(text),
+            _ => Err(FieldValueError::WrongType),
+        }
+    }
+
+    /// Parses a value written as text, e.g. the `X-` property of the field in a vCard
+    pub fn parse_text(&self, text: &str) -> Result<Value, FieldValueError> {
+        let text = text.trim();
+
+        match &self.kind {
+            FieldKind::Text => Ok(Value::String(text.to_string())),
+            // Whole numbers stay integers, so they are written back the same way
+            FieldKind::Number => match text.parse::<i64>() {
+                Ok(number) => Ok(Value::from(number)),
+                Err(_) => text
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+                    .ok_or(FieldValueError::WrongType),
+            },
+            FieldKind::Date => NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .map(|date| Value::String(date.to_string()))
+                .map_err(|_| FieldValueError::InvalidDate),
+            FieldKind::Boolean => match text.to_lowercase().as_str() {
+                "true" | "yes" | "1" => Ok(Value::Bool(true)),
+                "false" | "no" | "0" => Ok(Value::Bool(false)),
+                _ => Err(FieldValueError::WrongType),
+            },
+            FieldKind::Enum { options } => options
+                .iter()
+                .find(|option| option.eq_ignore_ascii_case(text))
+                .map(|option| Value::String(option.clone()))
+                .ok_or(FieldValueError::UnknownOption),
+            FieldKind::Url => Url::parse(text)
+                .ok()
+                .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+                .map(|url| Value::String(url.to_string()))
+                .ok_or(FieldValueError::InvalidUrl),
+        }
+    }
+
+    /// Name of the vCard property holding the value of the field, e.g. `X-ACCOUNT-MANAGER`
+    pub fn vcard_property(key: &str) -> String {
+        format!("X-{}", key.replace('_', "-").to_uppercase())
+    }
+
+    /// Definitions of `owner`, sorted by name
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<FieldDefinitionRow> = sqlx::query_as(
+            "select id, owner, key, name, kind from custom_field_definitions
+            where owner = $1 order by name",
+        )
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(FieldDefinition::from).collect())
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<FieldDefinitionRow> = sqlx::query_as(
+            "select id, owner, key, name, kind from custom_field_definitions
+            where owner = $1 and id = $2",
+        )
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(FieldDefinition::from))
+    }
+
+    pub async fn find_by_key(
+        pool: &PgPool,
+        owner: Uuid,
+        key: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<FieldDefinitionRow> = sqlx::query_as(
+            "select id, owner, key, name, kind from custom_field_definitions
+            where owner = $1 and key = $2",
+        )
+        .bind(owner)
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(FieldDefinition::from))
+    }
+}
+
+#[derive(FromRow)]
+struct FieldDefinitionRow {
+    id: Uuid,
+    owner: Uuid,
+    key: String,
+    name: String,
+    kind: Json<FieldKind>,
+}
+
+impl From<FieldDefinitionRow> for FieldDefinition {
+    fn from(row: FieldDefinitionRow) -> Self {
+        FieldDefinition {
+            id: row.id,
+            owner: row.owner,
+            key: row.key,
+            name: row.name,
+            kind: row.kind.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(FieldDefinition)]
+pub struct FieldDefinitionCreated {
+    pub owner: Uuid,
+    pub key: String,
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+/// Renames the field or changes the options of an enum field. The key and type never change,
+/// so values already stored in contacts stay valid.
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(FieldDefinition)]
+pub struct FieldDefinitionUpdated {
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(FieldDefinition)]
+pub struct FieldDefinitionDeleted {}
+
+impl AggregateCreate<FieldDefinitionCreated> for FieldDefinition {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<FieldDefinitionCreated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to create FieldDefinition from FieldDefinitionCreated event",
+        )?;
+
+        Ok(FieldDefinition {
+            id: event.entity_id,
+            owner: data.owner,
+            key: data.key.clone(),
+            name: data.name.clone(),
+            kind: data.kind.clone(),
+        })
+    }
+}
+
+impl AggregateUpdate<FieldDefinitionUpdated> for FieldDefinition {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(
+        self,
+        event: &Event<FieldDefinitionUpdated>,
+    ) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update FieldDefinition from FieldDefinitionUpdated event",
+        )?;
+
+        Ok(FieldDefinition {
+            name: data.name.clone(),
+            kind: data.kind.clone(),
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<FieldDefinitionDeleted> for FieldDefinition {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(
+        self,
+        _event: &Event<FieldDefinitionDeleted>,
+    ) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, FieldDefinition> for FieldDefinition {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into custom_field_definitions (id, owner, key, name, kind)
+            values ($1, $2, $3, $4, $5)
+            on conflict (id) do update set name = excluded.name, kind = excluded.kind",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.key)
+        .bind(&self.name)
+        .bind(Json(&self.kind))
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for FieldDefinition {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from custom_field_definitions where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(kind: FieldKind) -> FieldDefinition {
+        FieldDefinition {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            key: String::from("field"),
+            name: String::from("Field"),
+            kind,
+        }
+    }
+
+    #[test]
+    fn parses_text_as_the_type_of_the_field() {
+        let number = field(FieldKind::Number);
+        assert_eq!(number.parse_text(" 39 "), Ok(json!(39)));
+        assert_eq!(number.parse_text("1.5"), Ok(json!(1.5)));
+        assert_eq!(number.parse_text("many"), Err(FieldValueError::WrongType));
+
+        let date = field(FieldKind::Date);
+        assert_eq!(date.parse_text("2021-10-01"), Ok(json!("2021-10-01")));
+        assert_eq!(
+            date.parse_text("2021-02-30"),
+            Err(FieldValueError::InvalidDate)
+        );
+
+        let boolean = field(FieldKind::Boolean);
+        assert_eq!(boolean.parse_text("Yes"), Ok(json!(true)));
+        assert_eq!(boolean.parse_text("0"), Ok(json!(false)));
+
+        let options = field(FieldKind::Enum {
+            options: vec![String::from("Gold"), String::from("Silver")],
+        });
+        assert_eq!(options.parse_text("gold"), Ok(json!("Gold")));
+        assert_eq!(
+            options.parse_text("Bronze"),
+            Err(FieldValueError::UnknownOption)
+        );
+
+        let url = field(FieldKind::Url);
+        assert_eq!(
+            url.parse_text("https://example.com"),
+            Ok(json!("https://example.com/"))
+        );
+        assert_eq!(
+            url.parse_text("ftp://example.com"),
+            Err(FieldValueError::InvalidUrl)
+        );
+    }
+
+    #[test]
+    fn names_vcard_properties_after_the_key() {
+        assert_eq!(
+            FieldDefinition::vcard_property("account_manager"),
+            "X-ACCOUNT-MANAGER"
+        );
+    }
+}
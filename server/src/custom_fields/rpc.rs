@@ -0,0 +1,215 @@
+use common::errors::AppError;
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    is_valid_key, FieldDefinition, FieldDefinitionCreated, FieldDefinitionDeleted,
+    FieldDefinitionUpdated, FieldKind,
+};
+use crate::{
+    contacts::{Contact, ContactUpdated},
+    rpc::{self, Context, MethodResult},
+};
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct CreateParams {
+    pub key: String,
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+impl Validate for CreateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/key", &self.key).rule(
+            |key| is_valid_key(key),
+            "invalid_key",
+            "Keys must start with a lowercase letter followed by lowercase letters, digits or _",
+        );
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+        if let FieldKind::Enum { options } = &self.kind {
+            options_rules("/kind/options", options, errors);
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    pub name: String,
+    /// New options of an enum field
+    pub options: Option<Vec<String>>,
+}
+
+impl Validate for UpdateParams {
+    /// Whether the field is an enum, and so has options, is for the handler to check
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+        if let Some(options) = &self.options {
+            options_rules("/options", options, errors);
+        }
+    }
+}
+
+/// Rules of the options of an enum field, found at `path`
+fn options_rules(path: &str, options: &[String], errors: &mut ValidationErrors) {
+    errors
+        .field(path, options)
+        .not_empty()
+        .message("Enum fields need at least one option");
+    errors.items(path, options, |option| {
+        option.required().max_length(MAX_NAME_LENGTH);
+    });
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<FieldDefinition, rpc::RpcError> {
+    FieldDefinition::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `custom_fields.create`
+pub struct CreateField;
+
+impl RpcMethod for CreateField {
+    const NAME: &'static str = "custom_fields.create";
+    type Params = CreateParams;
+    type Result = FieldDefinition;
+}
+
+/// `custom_fields.create`
+pub async fn create(
+    ctx: &Context<'_>,
+    CreateParams { key, name, kind }: CreateParams,
+) -> MethodResult<FieldDefinition> {
+    let existing = FieldDefinition::find_by_key(&ctx.state.postgres, ctx.owner(), &key)
+        .await
+        .map_err(rpc::internal)?;
+    if existing.is_some() {
+        return Err(AppError::AlreadyExists { value: key }.into());
+    }
+
+    let definition = FieldDefinition::try_create(FieldDefinitionCreated {
+        owner: ctx.owner(),
+        key,
+        name: name.trim().to_string(),
+        kind,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(definition)
+}
+
+/// Definition of `custom_fields.list`
+pub struct ListFields;
+
+impl RpcMethod for ListFields {
+    const NAME: &'static str = "custom_fields.list";
+    type Params = NoParams;
+    type Result = Vec<FieldDefinition>;
+}
+
+/// `custom_fields.list`
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<FieldDefinition>> {
+    let definitions = FieldDefinition::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(definitions)
+}
+
+/// Definition of `custom_fields.update`
+pub struct UpdateField;
+
+impl RpcMethod for UpdateField {
+    const NAME: &'static str = "custom_fields.update";
+    type Params = UpdateParams;
+    type Result = FieldDefinition;
+}
+
+/// `custom_fields.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams { id, name, options }: UpdateParams,
+) -> MethodResult<FieldDefinition> {
+    let definition = find(ctx, id).await?;
+
+    let kind = match (definition.kind.clone(), options) {
+        (FieldKind::Enum { .. }, Some(options)) => FieldKind::Enum { options },
+        (_, Some(_)) => {
+            let mut errors = ValidationErrors::new();
+            errors.add("/options", "not_enum", "Only enum fields have options");
+            return Err(errors.into());
+        }
+        (kind, None) => kind,
+    };
+
+    let definition = definition
+        .try_update(FieldDefinitionUpdated {
+            name: name.trim().to_string(),
+            kind,
+        })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(definition)
+}
+
+/// Definition of `custom_fields.delete`
+pub struct DeleteField;
+
+impl RpcMethod for DeleteField {
+    const NAME: &'static str = "custom_fields.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `custom_fields.delete`
+///
+/// The value of the field is removed from every contact in the same transaction.
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    let definition = find(ctx, id).await?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+
+    let contacts = Contact::with_custom_field(&mut tx, ctx.owner(), &definition.key)
+        .await
+        .map_err(rpc::internal)?;
+    for contact in contacts {
+        let mut data = contact.data.clone();
+        data.custom_fields.remove(&definition.key);
+
+        contact
+            .try_update(ContactUpdated { data })
+            .map_err(rpc::internal)?
+            .stage_persist(&mut tx)
+            .await
+            .map_err(rpc::internal)?;
+    }
+
+    definition
+        .try_delete(FieldDefinitionDeleted {})
+        .map_err(rpc::internal)?
+        .stage_delete(&mut tx)
+        .await
+        .map_err(rpc::internal)?;
+
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
@@ -0,0 +1,291 @@
+/*!
+OpenRPC description of the RPC methods, served by `rpc.discover`
+
+Every method of [`rpc::dispatch`] is listed here with the types it reads and returns, the
+errors it can fail with besides the common ones and the realm roles it needs. A method
+missing from this list cannot be called.
+*/
+
+use common::jsonrpc::NoParams;
+use common::openrpc::{Builder, Discover, OpenRpc};
+use once_cell::sync::Lazy;
+
+use crate::{
+    app_passwords, bulk, contacts, custom_fields, groups, interactions, jobs, organizations,
+    relationships,
+    rpc::{self, Context, MethodResult},
+    settings, streams, tags, webhooks, ws,
+};
+
+const NOT_FOUND: &str = "not_found";
+const ALREADY_EXISTS: &str = "already_exists";
+
+/// Realm role of the users who may change or export many contacts at once
+const BULK: &str = "bulk";
+/// Realm role of the users who may let CardDAV clients in with app passwords
+const CARDDAV: &str = "carddav";
+/// Realm role of the users who may send their contacts to external services
+const WEBHOOKS: &str = "webhooks";
+
+static DOCUMENT: Lazy<OpenRpc> = Lazy::new(build);
+
+fn build() -> OpenRpc {
+    let mut doc = Builder::new("Contacts", env!("CARGO_PKG_VERSION"));
+
+    doc.errors(&[
+        "invalid_params",
+        "validation_failed",
+        "unauthorized",
+        "internal",
+    ]);
+
+    doc.method::<app_passwords::rpc::CreateAppPassword>(
+        "Creates a password for CardDAV clients, only returned this once",
+    )
+    .role(CARDDAV);
+    doc.method::<app_passwords::rpc::ListAppPasswords>("App passwords of the caller")
+        .role(CARDDAV);
+    // Revoking needs no role, so users who lost it can still revoke their passwords
+    doc.method::<app_passwords::rpc::DeleteAppPassword>("Revokes an app password")
+        .error(NOT_FOUND);
+
+    doc.method::<bulk::rpc::UpdateMany>(
+        "Applies a patch to many contacts, as a job when they are too many",
+    )
+    .role(BULK);
+    doc.method::<bulk::rpc::DeleteMany>("Deletes many contacts, as a job when they are too many")
+        .role(BULK);
+    doc.method::<bulk::rpc::TagMany>(
+        "Adds or removes tags on many contacts, as a job when they are too many",
+    )
+    .role(BULK);
+    doc.method::<bulk::rpc::ExportMany>(
+        "Exports many contacts as vCards, as a job when they are too many",
+    )
+    .role(BULK);
+
+    doc.method::<contacts::rpc::CreateContact>("Creates a contact");
+    doc.method::<contacts::rpc::GetContact>("Loads a contact")
+        .error(NOT_FOUND);
+    doc.method::<contacts::rpc::ListContacts>("Contacts of the caller");
+    doc.method::<contacts::rpc::UpdateContact>("Replaces a contact")
+        .error(NOT_FOUND)
+        .error("version_conflict");
+    doc.method::<contacts::rpc::DeleteContact>("Deletes a contact")
+        .error(NOT_FOUND);
+    doc.method::<contacts::rpc::SearchContacts>(
+        "Contacts matching a text or phone number, best matches first",
+    );
+    doc.method::<contacts::rpc::FindDuplicates>(
+        "Contacts that may be the same person as a contact",
+    )
+    .error(NOT_FOUND);
+    doc.method::<contacts::rpc::ExportVcard>("A contact as a vCard")
+        .error(NOT_FOUND);
+    doc.method::<contacts::rpc::FormatAddress>(
+        "Lines of an address in the conventional order of its country",
+    );
+    doc.method::<contacts::rpc::UpcomingDates>(
+        "Birthdays, anniversaries and custom dates in the next days",
+    );
+    doc.method::<contacts::rpc::SuggestContacts>(
+        "Contacts starting with a prefix, for recipient autocomplete",
+    );
+    doc.method::<contacts::rpc::SyncContacts>(
+        "Contacts created, updated or deleted since a sync token",
+    );
+    doc.method::<ws::SubscribeContacts>(
+        "Pushes the changes to the contacts as `contacts.changed` notifications, only over a \
+         WebSocket",
+    )
+    .error("quota_exceeded")
+    .error("web_socket_only");
+    doc.method::<ws::UnsubscribeContacts>("Stops a subscription, only over a WebSocket")
+        .error(NOT_FOUND)
+        .error("web_socket_only");
+
+    doc.method::<custom_fields::rpc::CreateField>("Defines a custom field")
+        .error(ALREADY_EXISTS);
+    doc.method::<custom_fields::rpc::ListFields>("Custom fields of the caller");
+    doc.method::<custom_fields::rpc::UpdateField>("Renames a custom field or changes its options")
+        .error(NOT_FOUND);
+    doc.method::<custom_fields::rpc::DeleteField>("Deletes a custom field and its values")
+        .error(NOT_FOUND);
+
+    doc.method::<groups::rpc::CreateGroup>("Creates a smart group")
+        .error(ALREADY_EXISTS);
+    doc.method::<groups::rpc::ListGroups>(
+        "Smart groups of the caller with the number of contacts matching each",
+    );
+    doc.method::<groups::rpc::UpdateGroup>("Replaces a smart group")
+        .error(NOT_FOUND)
+        .error(ALREADY_EXISTS);
+    doc.method::<groups::rpc::DeleteGroup>("Deletes a smart group")
+        .error(NOT_FOUND);
+    doc.method::<groups::rpc::GroupContacts>("Contacts currently matching a smart group")
+        .error(NOT_FOUND);
+    doc.method::<groups::rpc::PreviewGroup>("Contacts matching a query");
+    doc.method::<groups::rpc::ParseQuery>("Syntax tree of a query");
+
+    doc.method::<interactions::rpc::CreateInteraction>("Logs an interaction");
+    doc.method::<interactions::rpc::GetInteraction>("Loads an interaction")
+        .error(NOT_FOUND);
+    doc.method::<interactions::rpc::UpdateInteraction>("Replaces an interaction")
+        .error(NOT_FOUND);
+    doc.method::<interactions::rpc::DeleteInteraction>("Deletes an interaction")
+        .error(NOT_FOUND);
+    doc.method::<interactions::rpc::ListInteractions>(
+        "Interactions with a contact, most recent first",
+    )
+    .error(NOT_FOUND);
+
+    doc.method::<jobs::rpc::GetJob>("Loads a job with its progress")
+        .error(NOT_FOUND);
+    doc.method::<jobs::rpc::ListJobs>("Jobs of the caller");
+    doc.method::<jobs::rpc::CancelJob>("Cancels a job that has not finished")
+        .error(NOT_FOUND)
+        .error("job_ended");
+
+    doc.method::<organizations::rpc::CreateOrganization>("Creates an organization");
+    doc.method::<organizations::rpc::GetOrganization>("Loads an organization")
+        .error(NOT_FOUND);
+    doc.method::<organizations::rpc::ListOrganizations>("Organizations of the caller");
+    doc.method::<organizations::rpc::UpdateOrganization>("Replaces an organization")
+        .error(NOT_FOUND);
+    doc.method::<organizations::rpc::DeleteOrganization>(
+        "Deletes an organization and the affiliations with it",
+    )
+    .error(NOT_FOUND);
+    doc.method::<organizations::rpc::OrganizationPeople>(
+        "Contacts affiliated with an organization",
+    )
+    .error(NOT_FOUND);
+    doc.method::<organizations::rpc::SuggestOrganizations>(
+        "Organizations guessed from the email domains of contacts",
+    )
+    .error(NOT_FOUND);
+
+    doc.method::<relationships::rpc::CreateRelationship>("Links two contacts")
+        .error(NOT_FOUND)
+        .error(ALREADY_EXISTS);
+    doc.method::<relationships::rpc::ListRelationships>(
+        "Relationships of a contact as seen from it",
+    )
+    .error(NOT_FOUND);
+    doc.method::<relationships::rpc::UpdateRelationship>("Changes the kind of a relationship")
+        .error(NOT_FOUND)
+        .error(ALREADY_EXISTS);
+    doc.method::<relationships::rpc::DeleteRelationship>("Deletes a relationship")
+        .error(NOT_FOUND);
+    doc.method::<relationships::rpc::RelationshipGraph>(
+        "Contacts a few relationships away from a contact, as JSON, DOT or GraphML",
+    )
+    .error(NOT_FOUND);
+
+    doc.method::<settings::rpc::GetSettings>("Preferences of the caller");
+    doc.method::<settings::rpc::UpdateSettings>("Replaces the preferences");
+
+    doc.method::<streams::rpc::IssueTicket>("Single use ticket to open an event stream with");
+
+    doc.method::<tags::rpc::CreateTag>("Creates a tag")
+        .error(ALREADY_EXISTS);
+    doc.method::<tags::rpc::ListTags>("Tags of the caller with the number of contacts having each");
+    doc.method::<tags::rpc::UpdateTag>("Renames or recolors a tag")
+        .error(NOT_FOUND)
+        .error(ALREADY_EXISTS);
+    doc.method::<tags::rpc::DeleteTag>("Deletes a tag and removes it from contacts")
+        .error(NOT_FOUND);
+    doc.method::<tags::rpc::TagContacts>("Contacts having a tag")
+        .error(NOT_FOUND);
+    doc.method::<tags::rpc::AddTags>("Adds tags to many contacts");
+    doc.method::<tags::rpc::RemoveTags>("Removes tags from many contacts");
+
+    doc.method::<webhooks::rpc::CreateWebhook>("Subscribes a URL to contact events")
+        .role(WEBHOOKS);
+    doc.method::<webhooks::rpc::ListWebhooks>("Webhooks of the caller")
+        .role(WEBHOOKS);
+    doc.method::<webhooks::rpc::UpdateWebhook>("Replaces a webhook")
+        .error(NOT_FOUND)
+        .role(WEBHOOKS);
+    // Deleting needs no role, so users who lost it can stop the deliveries
+    doc.method::<webhooks::rpc::DeleteWebhook>("Deletes a webhook")
+        .error(NOT_FOUND);
+    doc.method::<webhooks::rpc::ListDeliveries>("Delivery log of a webhook, most recent first")
+        .error(NOT_FOUND)
+        .role(WEBHOOKS);
+    doc.method::<webhooks::rpc::Redeliver>("Sends an event to a webhook again")
+        .error(NOT_FOUND)
+        .role(WEBHOOKS);
+
+    doc.method::<Discover>("This document");
+
+    doc.build()
+}
+
+/// Realm roles needed to call a method, `None` for methods that are not documented
+pub fn roles(method: &str) -> Option<&'static [String]> {
+    DOCUMENT
+        .methods
+        .iter()
+        .find(|described| described.name == method)
+        .map(|described| described.roles.as_slice())
+}
+
+/// The document, served without signing in as clients read it first
+pub fn document() -> OpenRpc {
+    DOCUMENT.clone()
+}
+
+/// `rpc.discover`
+pub async fn discover(_ctx: &Context<'_>, _params: NoParams) -> MethodResult<OpenRpc> {
+    rpc::ok(document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_generated_from_the_catalog() {
+        let errors = document().components.errors;
+
+        let conflict = &errors["version_conflict"];
+        assert_eq!(
+            (conflict.code, conflict.message.as_str()),
+            (409, "Conflict")
+        );
+        let data = serde_json::to_value(&conflict.data).unwrap();
+        assert_eq!(data["properties"]["code"]["enum"][0], "version_conflict");
+        assert!(data["properties"]["details"]["properties"]["expected"].is_object());
+
+        assert_eq!(errors["not_found"].code, 404);
+        assert_eq!(errors.len(), common::errors::AppError::kinds().len());
+    }
+
+    #[test]
+    fn referenced_errors_are_defined() {
+        let document = document();
+        for method in &document.methods {
+            for error in &method.errors {
+                let name = error.reference.trim_start_matches("#/components/errors/");
+                assert!(
+                    document.components.errors.contains_key(name),
+                    "{} of {}",
+                    name,
+                    method.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn methods_needing_roles_can_be_forbidden() {
+        for method in document().methods {
+            let forbidden = method
+                .errors
+                .iter()
+                .any(|error| error.reference.ends_with("/forbidden"));
+            assert_eq!(forbidden, !method.roles.is_empty(), "{}", method.name);
+        }
+    }
+}
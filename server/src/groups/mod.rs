@@ -0,0 +1,217 @@
+/*!
+Smart groups, the dynamic groups of an address book
+
+A smart group is a saved query, its contacts are whoever matches the query at the time it is
+run. Queries can include other groups with `group:<name>`.
+*/
+
+pub mod rpc;
+
+use common::validation::{FieldError, Validate, ValidationErrors, MAX_NAME_LENGTH};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Fields of a smart group the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SmartGroupData {
+    pub name: String,
+    /// Query in the language of [`crate::query`]
+    pub query: String,
+}
+
+impl Validate for SmartGroupData {
+    /// Whether the name is taken and the query compiles is for the handler to check
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+        errors.field("/query", &self.query).required();
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "smart_groups")]
+pub struct SmartGroup {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: SmartGroupData,
+}
+
+/// A smart group with the number of contacts matching it
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct SmartGroupWithCount {
+    #[serde(flatten)]
+    pub group: SmartGroup,
+    /// 0 when the query no longer compiles
+    pub contacts: i64,
+    /// Why the query no longer compiles, for example because a group it includes was deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<FieldError>,
+}
+
+const COLUMNS: &str = "id, owner, name, query";
+
+impl SmartGroup {
+    /// Groups of `owner`, sorted by name
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<SmartGroupRow> = sqlx::query_as(&format!(
+            "select {} from smart_groups where owner = $1 order by lower(name)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(SmartGroup::from).collect())
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<SmartGroupRow> = sqlx::query_as(&format!(
+            "select {} from smart_groups where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(SmartGroup::from))
+    }
+
+    /// Group of `owner` with the same name, ignoring case
+    pub async fn find_by_name(
+        pool: &PgPool,
+        owner: Uuid,
+        name: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<SmartGroupRow> = sqlx::query_as(&format!(
+            "select {} from smart_groups where owner = $1 and lower(name) = lower($2)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(SmartGroup::from))
+    }
+}
+
+#[derive(FromRow)]
+struct SmartGroupRow {
+    id: Uuid,
+    owner: Uuid,
+    name: String,
+    query: String,
+}
+
+impl From<SmartGroupRow> for SmartGroup {
+    fn from(row: SmartGroupRow) -> Self {
+        SmartGroup {
+            id: row.id,
+            owner: row.owner,
+            data: SmartGroupData {
+                name: row.name,
+                query: row.query,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(SmartGroup)]
+pub struct SmartGroupCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: SmartGroupData,
+}
+
+/// Renames the group or changes its query
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(SmartGroup)]
+pub struct SmartGroupUpdated {
+    #[serde(flatten)]
+    pub data: SmartGroupData,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(SmartGroup)]
+pub struct SmartGroupDeleted {}
+
+impl AggregateCreate<SmartGroupCreated> for SmartGroup {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<SmartGroupCreated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to create SmartGroup from SmartGroupCreated event",
+        )?;
+
+        Ok(SmartGroup {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+        })
+    }
+}
+
+impl AggregateUpdate<SmartGroupUpdated> for SmartGroup {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<SmartGroupUpdated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update SmartGroup from SmartGroupUpdated event",
+        )?;
+
+        Ok(SmartGroup {
+            data: data.data.clone(),
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<SmartGroupDeleted> for SmartGroup {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(self, _event: &Event<SmartGroupDeleted>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, SmartGroup> for SmartGroup {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into smart_groups (id, owner, name, query)
+            values ($1, $2, $3, $4)
+            on conflict (id) do update set name = excluded.name, query = excluded.query",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.data.name)
+        .bind(&self.data.query)
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for SmartGroup {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from smart_groups where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
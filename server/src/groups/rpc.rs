@@ -0,0 +1,332 @@
+use chrono::NaiveDate;
+use common::errors::AppError;
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::validation::{FieldError, Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    SmartGroup, SmartGroupCreated, SmartGroupData, SmartGroupDeleted, SmartGroupUpdated,
+    SmartGroupWithCount,
+};
+use crate::{
+    contacts::Contact,
+    custom_fields::FieldDefinition,
+    query::{
+        self,
+        sql::{self as query_sql, Compiled, Scope},
+        Expr, QueryError,
+    },
+    rpc::{self, Context, MethodResult},
+    settings::UserSettings,
+};
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub group: SmartGroupData,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.group.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct QueryParams {
+    pub query: String,
+}
+
+/// The query is checked by parsing it, so its errors come with their column
+impl Validate for QueryParams {}
+
+/// Reports a query error at `path`, with the column it was found at
+fn invalid(path: &str, err: QueryError) -> rpc::RpcError {
+    let mut errors = ValidationErrors::new();
+    errors.add_at(path, err.column, err.code, err.message);
+    errors.into()
+}
+
+/// What the queries of the caller are compiled against, loaded once per call
+struct Loaded {
+    today: NaiveDate,
+    custom_fields: Vec<FieldDefinition>,
+    groups: Vec<(String, String)>,
+}
+
+impl Loaded {
+    async fn load(ctx: &Context<'_>) -> Result<Self, rpc::RpcError> {
+        let settings = UserSettings::load(&ctx.state.postgres, ctx.owner())
+            .await
+            .map_err(rpc::internal)?;
+        let custom_fields = FieldDefinition::list(&ctx.state.postgres, ctx.owner())
+            .await
+            .map_err(rpc::internal)?;
+        let groups = SmartGroup::list(&ctx.state.postgres, ctx.owner())
+            .await
+            .map_err(rpc::internal)?
+            .into_iter()
+            .map(|group| (group.data.name, group.data.query))
+            .collect();
+
+        Ok(Loaded {
+            today: settings.today(),
+            custom_fields,
+            groups,
+        })
+    }
+
+    /// Compiles a query with the owner bound to `$1`. `within` names the group the query
+    /// belongs to, so it cannot include itself.
+    fn compile(&self, query: &str, within: Option<&str>) -> Result<Compiled, QueryError> {
+        let scope = Scope {
+            today: self.today,
+            custom_fields: &self.custom_fields,
+            groups: &self.groups,
+            within,
+        };
+
+        query_sql::compile_query(query, &scope, 2)
+    }
+}
+
+/// Compiles a query of the caller for [`Contact::matching`], reporting errors at `path`
+pub async fn compile(
+    ctx: &Context<'_>,
+    path: &str,
+    query: &str,
+    within: Option<&str>,
+) -> Result<Compiled, rpc::RpcError> {
+    // Syntax errors are reported without loading anything
+    query::parse(query).map_err(|err| invalid(path, err))?;
+
+    Loaded::load(ctx)
+        .await?
+        .compile(query, within)
+        .map_err(|err| invalid(path, err))
+}
+
+/// Normalizes a group that follows its [`Validate`] rules, names are unique per owner
+/// regardless of case and the query must compile
+async fn validate(
+    ctx: &Context<'_>,
+    input: SmartGroupData,
+    id: Option<Uuid>,
+) -> Result<SmartGroupData, rpc::RpcError> {
+    let name = input.name.trim().to_string();
+    let query = input.query.trim().to_string();
+
+    let existing = SmartGroup::find_by_name(&ctx.state.postgres, ctx.owner(), &name)
+        .await
+        .map_err(rpc::internal)?;
+    if existing.is_some_and(|group| Some(group.id) != id) {
+        return Err(AppError::AlreadyExists { value: name }.into());
+    }
+
+    compile(ctx, "/query", &query, Some(&name)).await?;
+
+    Ok(SmartGroupData { name, query })
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<SmartGroup, rpc::RpcError> {
+    SmartGroup::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `groups.create`
+pub struct CreateGroup;
+
+impl RpcMethod for CreateGroup {
+    const NAME: &'static str = "groups.create";
+    type Params = SmartGroupData;
+    type Result = SmartGroup;
+}
+
+/// `groups.create`
+pub async fn create(ctx: &Context<'_>, input: SmartGroupData) -> MethodResult<SmartGroup> {
+    let data = validate(ctx, input, None).await?;
+
+    let group = SmartGroup::try_create(SmartGroupCreated {
+        owner: ctx.owner(),
+        data,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(group)
+}
+
+/// Definition of `groups.list`
+pub struct ListGroups;
+
+impl RpcMethod for ListGroups {
+    const NAME: &'static str = "groups.list";
+    type Params = NoParams;
+    type Result = Vec<SmartGroupWithCount>;
+}
+
+/// `groups.list`
+///
+/// Groups of the caller with the number of contacts currently matching each. A group whose
+/// query no longer compiles, for example because a group it includes was deleted, has no
+/// contacts and the error at `/query`.
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<SmartGroupWithCount>> {
+    let groups = SmartGroup::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    let loaded = Loaded::load(ctx).await?;
+
+    let mut counted = Vec::with_capacity(groups.len());
+    for group in groups {
+        let (contacts, error) = match loaded.compile(&group.data.query, Some(&group.data.name)) {
+            Ok(compiled) => (
+                Contact::count_matching(&ctx.state.postgres, ctx.owner(), &compiled)
+                    .await
+                    .map_err(rpc::internal)?,
+                None,
+            ),
+            Err(err) => (
+                0,
+                Some(FieldError {
+                    path: String::from("/query"),
+                    code: String::from(err.code),
+                    message: err.message,
+                    column: Some(err.column),
+                }),
+            ),
+        };
+        counted.push(SmartGroupWithCount {
+            group,
+            contacts,
+            error,
+        });
+    }
+
+    rpc::ok(counted)
+}
+
+/// Definition of `groups.update`
+pub struct UpdateGroup;
+
+impl RpcMethod for UpdateGroup {
+    const NAME: &'static str = "groups.update";
+    type Params = UpdateParams;
+    type Result = SmartGroup;
+}
+
+/// `groups.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams { id, group: input }: UpdateParams,
+) -> MethodResult<SmartGroup> {
+    let group = find(ctx, id).await?;
+    let data = validate(ctx, input, Some(id)).await?;
+
+    let group = group
+        .try_update(SmartGroupUpdated { data })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(group)
+}
+
+/// Definition of `groups.delete`
+pub struct DeleteGroup;
+
+impl RpcMethod for DeleteGroup {
+    const NAME: &'static str = "groups.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `groups.delete`
+///
+/// Groups including the deleted one stop matching anything until their query is changed.
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    find(ctx, id)
+        .await?
+        .try_delete(SmartGroupDeleted {})
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `groups.contacts`
+pub struct GroupContacts;
+
+impl RpcMethod for GroupContacts {
+    const NAME: &'static str = "groups.contacts";
+    type Params = ById;
+    type Result = Vec<Contact>;
+}
+
+/// `groups.contacts`
+///
+/// Contacts currently matching the group
+pub async fn contacts(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Vec<Contact>> {
+    let group = find(ctx, id).await?;
+    let compiled = compile(ctx, "/query", &group.data.query, Some(&group.data.name)).await?;
+
+    let contacts = Contact::matching(&ctx.state.postgres, ctx.owner(), &compiled)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(contacts)
+}
+
+/// Definition of `groups.preview`
+pub struct PreviewGroup;
+
+impl RpcMethod for PreviewGroup {
+    const NAME: &'static str = "groups.preview";
+    type Params = QueryParams;
+    type Result = Vec<Contact>;
+}
+
+/// `groups.preview`
+///
+/// Contacts matching a query that is not saved yet
+pub async fn preview(
+    ctx: &Context<'_>,
+    QueryParams { query }: QueryParams,
+) -> MethodResult<Vec<Contact>> {
+    let compiled = compile(ctx, "/query", &query, None).await?;
+
+    let contacts = Contact::matching(&ctx.state.postgres, ctx.owner(), &compiled)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(contacts)
+}
+
+/// Definition of `groups.parse`
+pub struct ParseQuery;
+
+impl RpcMethod for ParseQuery {
+    const NAME: &'static str = "groups.parse";
+    type Params = QueryParams;
+    type Result = Expr;
+}
+
+/// `groups.parse`
+///
+/// Syntax tree of a query, for editors to highlight it
+pub async fn parse(_ctx: &Context<'_>, QueryParams { query }: QueryParams) -> MethodResult<Expr> {
+    let expr = query::parse(&query).map_err(|err| invalid("/query", err))?;
+
+    rpc::ok(expr)
+}
@@ -0,0 +1,314 @@
+/*!
+Log of calls, meetings, emails and messages with contacts
+
+Interactions are event sourced like contacts, so the log is auditable. An interaction can
+involve several contacts, the `interaction_contacts` table links them and the
+`last_contacted` and `frecency` columns of each contact are recomputed whenever an
+interaction changes.
+*/
+
+pub mod rpc;
+
+use chrono::{DateTime, Utc};
+use common::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH, MAX_TEXT_LENGTH};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::contacts::suggest;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "interaction_kind", rename_all = "lowercase")]
+pub enum InteractionKind {
+    Call,
+    Meeting,
+    Email,
+    Message,
+}
+
+/// Fields of an interaction the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct InteractionData {
+    /// Contacts involved, at least one
+    pub contacts: Vec<Uuid>,
+    pub kind: InteractionKind,
+    /// Medium used, e.g. `phone`, `zoom` or `in person`
+    pub channel: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    /// Length of the interaction in seconds
+    pub duration: Option<i32>,
+    pub notes: Option<String>,
+}
+
+impl Validate for InteractionData {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/contacts", &self.contacts)
+            .not_empty()
+            .message("An interaction needs at least one contact");
+        errors.field("/occurred_at", &self.occurred_at).rule(
+            |occurred_at| *occurred_at <= Utc::now(),
+            "in_future",
+            "Interactions can only be logged once they happened",
+        );
+        errors.rule(
+            "/duration",
+            self.duration.unwrap_or(0) >= 0,
+            "negative",
+            "Durations cannot be negative",
+        );
+        errors
+            .field("/channel", &self.channel)
+            .max_length(MAX_NAME_LENGTH);
+        errors
+            .field("/notes", &self.notes)
+            .max_length(MAX_TEXT_LENGTH);
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "interactions")]
+pub struct Interaction {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: InteractionData,
+}
+
+const COLUMNS: &str = "id, owner, kind, channel, occurred_at, duration, notes, \
+    array(select contact_id from interaction_contacts where interaction_id = interactions.id) as contacts";
+
+impl Interaction {
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<InteractionRow> = sqlx::query_as(&format!(
+            "select {} from interactions where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Interaction::from))
+    }
+
+    /// Interactions with `contact`, most recent first. `before` is the `occurred_at` of the
+    /// last interaction of the previous page.
+    pub async fn for_contact(
+        pool: &PgPool,
+        owner: Uuid,
+        contact: Uuid,
+        before: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<InteractionRow> = sqlx::query_as(&format!(
+            "select {} from interactions
+            where owner = $1
+            and exists (
+                select 1 from interaction_contacts
+                where interaction_id = interactions.id and contact_id = $2
+            )
+            and ($3::timestamptz is null or occurred_at < $3)
+            order by occurred_at desc
+            limit $4",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(contact)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Interaction::from).collect())
+    }
+}
+
+/// Recomputes `last_contacted` and `frecency` of `contacts` from the interactions they are
+/// linked to. Frecency is described in [`crate::contacts::suggest`], times are counted from
+/// 2021 to keep the powers of two within range.
+async fn update_activity(
+    tx: &mut SqlxPgStoreTransaction,
+    contacts: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "update contacts set (last_contacted, frecency) = (
+            select
+                max(interactions.occurred_at),
+                ln(nullif(sum(power(2, extract(epoch from
+                    interactions.occurred_at - timestamptz '2021-01-01') / $2)), 0)) / ln(2)
+            from interactions
+            join interaction_contacts on interaction_id = interactions.id
+            where contact_id = contacts.id
+        )
+        where id = any($1)",
+    )
+    .bind(contacts)
+    .bind((suggest::HALF_LIFE_DAYS * 24 * 60 * 60) as f64)
+    .execute(tx.get())
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the links of an interaction and returns the contacts it was linked to
+async fn unlink(tx: &mut SqlxPgStoreTransaction, id: Uuid) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows: Vec<(Uuid,)> = sqlx::query_as(
+        "delete from interaction_contacts where interaction_id = $1 returning contact_id",
+    )
+    .bind(id)
+    .fetch_all(tx.get())
+    .await?;
+
+    Ok(rows.into_iter().map(|(contact,)| contact).collect())
+}
+
+#[derive(FromRow)]
+struct InteractionRow {
+    id: Uuid,
+    owner: Uuid,
+    kind: InteractionKind,
+    channel: Option<String>,
+    occurred_at: DateTime<Utc>,
+    duration: Option<i32>,
+    notes: Option<String>,
+    contacts: Vec<Uuid>,
+}
+
+impl From<InteractionRow> for Interaction {
+    fn from(row: InteractionRow) -> Self {
+        Interaction {
+            id: row.id,
+            owner: row.owner,
+            data: InteractionData {
+                contacts: row.contacts,
+                kind: row.kind,
+                channel: row.channel,
+                occurred_at: row.occurred_at,
+                duration: row.duration,
+                notes: row.notes,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Interaction)]
+pub struct InteractionCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: InteractionData,
+}
+
+/// Replaces every editable field of the interaction
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Interaction)]
+pub struct InteractionUpdated {
+    #[serde(flatten)]
+    pub data: InteractionData,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Interaction)]
+pub struct InteractionDeleted {}
+
+impl AggregateCreate<InteractionCreated> for Interaction {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<InteractionCreated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to create Interaction from InteractionCreated event",
+        )?;
+
+        Ok(Interaction {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+        })
+    }
+}
+
+impl AggregateUpdate<InteractionUpdated> for Interaction {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<InteractionUpdated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update Interaction from InteractionUpdated event",
+        )?;
+
+        Ok(Interaction {
+            data: data.data.clone(),
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<InteractionDeleted> for Interaction {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(self, _event: &Event<InteractionDeleted>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Interaction> for Interaction {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into interactions (id, owner, kind, channel, occurred_at, duration, notes)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            on conflict (id) do update set
+                kind = excluded.kind,
+                channel = excluded.channel,
+                occurred_at = excluded.occurred_at,
+                duration = excluded.duration,
+                notes = excluded.notes",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(self.data.kind)
+        .bind(&self.data.channel)
+        .bind(self.data.occurred_at)
+        .bind(self.data.duration)
+        .bind(&self.data.notes)
+        .execute(tx.get())
+        .await?;
+
+        let mut contacts = unlink(tx, self.id).await?;
+        sqlx::query(
+            "insert into interaction_contacts (interaction_id, contact_id)
+            select $1, unnest($2::uuid[])",
+        )
+        .bind(self.id)
+        .bind(&self.data.contacts)
+        .execute(tx.get())
+        .await?;
+
+        contacts.extend_from_slice(&self.data.contacts);
+        update_activity(tx, &contacts).await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Interaction {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        let contacts = unlink(tx, self.id).await?;
+
+        sqlx::query("delete from interactions where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        update_activity(tx, &contacts).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use chrono::{DateTime, Utc};
+use common::jsonrpc::{ById, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    Interaction, InteractionCreated, InteractionData, InteractionDeleted, InteractionUpdated,
+};
+use crate::{
+    contacts::{self, Contact},
+    rpc::{self, Context, MethodResult},
+};
+
+/// Most interactions returned by a single `interactions.list` call
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub interaction: InteractionData,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.interaction.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct ListParams {
+    pub contact_id: Uuid,
+    /// Only interactions older than this, to fetch the next page
+    #[serde(default)]
+    pub before: Option<DateTime<Utc>>,
+    #[serde(default = "ListParams::default_limit")]
+    pub limit: i64,
+}
+
+impl ListParams {
+    fn default_limit() -> i64 {
+        50
+    }
+}
+
+impl Validate for ListParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/limit", &self.limit).range(1, MAX_LIMIT);
+    }
+}
+
+/// Normalizes an interaction that follows its [`Validate`] rules, every contact must belong
+/// to the caller
+async fn validate(
+    ctx: &Context<'_>,
+    input: InteractionData,
+) -> Result<InteractionData, rpc::RpcError> {
+    let mut errors = ValidationErrors::new();
+
+    let mut contacts: Vec<Uuid> = Vec::new();
+    for contact in input.contacts {
+        if !contacts.contains(&contact) {
+            contacts.push(contact);
+        }
+    }
+    let found = Contact::find_many(&ctx.state.postgres, ctx.owner(), &contacts)
+        .await
+        .map_err(rpc::internal)?;
+    for (index, id) in contacts.iter().enumerate() {
+        if !found.iter().any(|contact| contact.id == *id) {
+            errors.add(
+                format!("/contacts/{}", index),
+                "unknown_contact",
+                "There is no such contact",
+            );
+        }
+    }
+
+    errors.into_result()?;
+
+    let non_empty = |value: Option<String>| {
+        value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    Ok(InteractionData {
+        contacts,
+        channel: non_empty(input.channel),
+        notes: non_empty(input.notes),
+        ..input
+    })
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Interaction, rpc::RpcError> {
+    Interaction::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `interactions.create`
+pub struct CreateInteraction;
+
+impl RpcMethod for CreateInteraction {
+    const NAME: &'static str = "interactions.create";
+    type Params = InteractionData;
+    type Result = Interaction;
+}
+
+/// `interactions.create`
+pub async fn create(ctx: &Context<'_>, input: InteractionData) -> MethodResult<Interaction> {
+    let data = validate(ctx, input).await?;
+
+    let interaction = Interaction::try_create(InteractionCreated {
+        owner: ctx.owner(),
+        data,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(interaction)
+}
+
+/// Definition of `interactions.get`
+pub struct GetInteraction;
+
+impl RpcMethod for GetInteraction {
+    const NAME: &'static str = "interactions.get";
+    type Params = ById;
+    type Result = Interaction;
+}
+
+/// `interactions.get`
+pub async fn get(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Interaction> {
+    rpc::ok(find(ctx, id).await?)
+}
+
+/// Definition of `interactions.update`
+pub struct UpdateInteraction;
+
+impl RpcMethod for UpdateInteraction {
+    const NAME: &'static str = "interactions.update";
+    type Params = UpdateParams;
+    type Result = Interaction;
+}
+
+/// `interactions.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams {
+        id,
+        interaction: input,
+    }: UpdateParams,
+) -> MethodResult<Interaction> {
+    let interaction = find(ctx, id).await?;
+    let data = validate(ctx, input).await?;
+
+    let interaction = interaction
+        .try_update(InteractionUpdated { data })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(interaction)
+}
+
+/// Definition of `interactions.delete`
+pub struct DeleteInteraction;
+
+impl RpcMethod for DeleteInteraction {
+    const NAME: &'static str = "interactions.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `interactions.delete`
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    find(ctx, id)
+        .await?
+        .try_delete(InteractionDeleted {})
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `interactions.list`
+pub struct ListInteractions;
+
+impl RpcMethod for ListInteractions {
+    const NAME: &'static str = "interactions.list";
+    type Params = ListParams;
+    type Result = Vec<Interaction>;
+}
+
+/// `interactions.list`
+///
+/// Interactions with a contact, most recent first. Pass the `occurred_at` of the last
+/// interaction as `before` to get the next page.
+pub async fn list(
+    ctx: &Context<'_>,
+    ListParams {
+        contact_id,
+        before,
+        limit,
+    }: ListParams,
+) -> MethodResult<Vec<Interaction>> {
+    contacts::rpc::find(ctx, contact_id).await?;
+
+    let interactions =
+        Interaction::for_contact(&ctx.state.postgres, ctx.owner(), contact_id, before, limit)
+            .await
+            .map_err(rpc::internal)?;
+
+    rpc::ok(interactions)
+}
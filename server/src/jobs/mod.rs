@@ -0,0 +1,460 @@
+/*!
+Background jobs
+
+Work too long for a request, such as a bulk operation on thousands of contacts, is queued in
+the `jobs` table. The caller gets the queued job right away and polls it with `jobs.get` for
+its progress and, once it is done, its result.
+
+Workers claim queued jobs with `for update skip locked`, so any number of them can share the
+table, whether they run in the server process or in a process of their own started with
+`server worker`. A claimed job holds a lease that is renewed every time it reports progress.
+Every process with workers looks for leases that ran out every [`EXPIRE_INTERVAL`], whether or
+not the queue is empty. Their jobs are retried with the same backoff as failed attempts, as the
+job itself may be what stopped the worker. Progress and outcomes are only recorded while the
+job is still running the attempt the worker claimed, so a worker that lost its lease stops at
+its next report and cannot overwrite the attempt of another worker.
+
+A job that fails is retried with an exponential backoff until it reaches its maximum number
+of attempts. A job is cancelled right away while it is queued. A running job is only asked to
+stop, and notices it the next time it reports progress.
+
+Jobs are not event sourced: they are bookkeeping around the events the work itself emits.
+*/
+
+pub mod rpc;
+
+use std::time::Duration;
+
+use async_std::task;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::{bulk, state::State};
+
+/// Time a worker waits before looking for jobs again when the queue is empty
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds a running job can go without reporting progress before it is considered stopped
+const LEASE_SECONDS: i64 = 300;
+
+/// Time between two looks for running jobs whose lease ran out
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When a job whose latest attempt failed is retried: after 30 seconds, doubling every attempt
+/// up to an hour
+const RETRY_AT: &str =
+    "now() + least(interval '30 seconds' * power(2, attempts - 1), interval '1 hour')";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "job_status", rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a worker, possibly to be retried at `run_at`
+    Queued,
+    Running,
+    Succeeded,
+    /// Failed on its last attempt
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    pub owner: Uuid,
+    /// What the job does, decides how `params` are read
+    pub kind: String,
+    #[serde(skip)]
+    pub params: Value,
+    pub status: JobStatus,
+    /// Percentage of the work done
+    pub progress: i16,
+    /// Result of a succeeded job
+    pub result: Option<Value>,
+    /// Why the latest attempt failed
+    pub error: Option<String>,
+    /// Attempts started so far
+    pub attempts: i32,
+    pub max_attempts: i32,
+    /// Earliest time a queued job runs
+    pub run_at: DateTime<Utc>,
+    /// Whether the owner asked to cancel the job while it was running
+    pub cancel_requested: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct JobRow {
+    id: Uuid,
+    owner: Uuid,
+    kind: String,
+    params: Json<Value>,
+    status: JobStatus,
+    progress: i16,
+    result: Option<Json<Value>>,
+    error: Option<String>,
+    attempts: i32,
+    max_attempts: i32,
+    run_at: DateTime<Utc>,
+    cancel_requested: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<JobRow> for Job {
+    fn from(row: JobRow) -> Self {
+        Job {
+            id: row.id,
+            owner: row.owner,
+            kind: row.kind,
+            params: row.params.0,
+            status: row.status,
+            progress: row.progress,
+            result: row.result.map(|result| result.0),
+            error: row.error,
+            attempts: row.attempts,
+            max_attempts: row.max_attempts,
+            run_at: row.run_at,
+            cancel_requested: row.cancel_requested,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+const COLUMNS: &str = "id, owner, kind, params, status, progress, result, error, attempts, \
+    max_attempts, run_at, cancel_requested, created_at, updated_at";
+
+impl Job {
+    /// Queues a job of `owner`, workers pick it up right away
+    pub async fn create(
+        pool: &PgPool,
+        owner: Uuid,
+        kind: &str,
+        params: Value,
+    ) -> Result<Self, sqlx::Error> {
+        let row: JobRow = sqlx::query_as(&format!(
+            "insert into jobs (id, owner, kind, params) values ($1, $2, $3, $4) returning {}",
+            COLUMNS
+        ))
+        .bind(Uuid::new_v4())
+        .bind(owner)
+        .bind(kind)
+        .bind(Json(params))
+        .fetch_one(pool)
+        .await?;
+
+        Ok(Job::from(row))
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<JobRow> = sqlx::query_as(&format!(
+            "select {} from jobs where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Job::from))
+    }
+
+    /// Latest jobs of `owner`, only those with `status` when given
+    pub async fn list(
+        pool: &PgPool,
+        owner: Uuid,
+        status: Option<JobStatus>,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<JobRow> = sqlx::query_as(&format!(
+            "select {} from jobs
+            where owner = $1 and ($2::job_status is null or status = $2)
+            order by created_at desc
+            limit $3",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Job::from).collect())
+    }
+
+    /// Cancels a queued job, or asks a running one to stop. `None` when the job is not queued
+    /// or running anymore.
+    pub async fn cancel(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<JobRow> = sqlx::query_as(&format!(
+            "update jobs set
+                status = (case when status = 'queued' then 'cancelled' else status end)
+                    ::job_status,
+                cancel_requested = true,
+                updated_at = now()
+            where owner = $1 and id = $2 and status in ('queued', 'running')
+            returning {}",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Job::from))
+    }
+
+    /// Claims the next queued job that is due, skipping the ones other workers are claiming
+    async fn claim(pool: &PgPool) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<JobRow> = sqlx::query_as(&format!(
+            "update jobs set
+                status = 'running', attempts = attempts + 1, progress = 0,
+                locked_at = now(), updated_at = now()
+            where id = (
+                select id from jobs
+                where status = 'queued' and run_at <= now()
+                order by run_at
+                limit 1
+                for update skip locked
+            )
+            returning {}",
+            COLUMNS
+        ))
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Job::from))
+    }
+
+    /// Ends the attempts of running jobs whose lease ran out, as if they had failed
+    async fn expire(pool: &PgPool) -> Result<u64, sqlx::Error> {
+        let expired = sqlx::query(&format!(
+            "update jobs set
+                status = (case
+                    when cancel_requested then 'cancelled'
+                    when attempts < max_attempts then 'queued'
+                    else 'failed'
+                end)::job_status,
+                run_at = {},
+                error = 'The worker running the job stopped',
+                locked_at = null,
+                updated_at = now()
+            where status = 'running' and locked_at < now() - $1 * interval '1 second'",
+            RETRY_AT
+        ))
+        .bind(LEASE_SECONDS as f64)
+        .execute(pool)
+        .await?;
+
+        Ok(expired.rows_affected())
+    }
+
+    /// Records the outcome of the `attempt` of a job. Failed attempts are retried at
+    /// [`RETRY_AT`], unless the job was cancelled or has no attempts left. `false` when the attempt is not running anymore, its lease ran out.
+    async fn finish(
+        pool: &PgPool,
+        id: Uuid,
+        attempt: i32,
+        outcome: Result<Value, String>,
+    ) -> Result<bool, sqlx::Error> {
+        let finished = match outcome {
+            Ok(result) => {
+                sqlx::query(
+                    "update jobs set
+                        status = 'succeeded', progress = 100, result = $2, error = null,
+                        locked_at = null, updated_at = now()
+                    where id = $1 and status = 'running' and attempts = $3",
+                )
+                .bind(id)
+                .bind(Json(result))
+                .bind(attempt)
+                .execute(pool)
+                .await?
+            }
+            Err(error) => {
+                sqlx::query(&format!(
+                    "update jobs set
+                        status = (case
+                            when cancel_requested then 'cancelled'
+                            when attempts < max_attempts then 'queued'
+                            else 'failed'
+                        end)::job_status,
+                        run_at = {},
+                        error = $2,
+                        locked_at = null,
+                        updated_at = now()
+                    where id = $1 and status = 'running' and attempts = $3",
+                    RETRY_AT
+                ))
+                .bind(id)
+                .bind(error)
+                .bind(attempt)
+                .execute(pool)
+                .await?
+            }
+        };
+
+        Ok(finished.rows_affected() > 0)
+    }
+}
+
+/// Records the progress of a running job, renews its lease and tells whether it should stop
+pub struct Progress {
+    pool: PgPool,
+    id: Uuid,
+    /// Attempt the worker claimed
+    attempt: i32,
+    total: usize,
+    cancelled: bool,
+    /// Whether the lease of the attempt ran out
+    lost: bool,
+}
+
+impl Progress {
+    fn new(pool: PgPool, job: &Job) -> Self {
+        Progress {
+            pool,
+            id: job.id,
+            attempt: job.attempts,
+            total: 0,
+            cancelled: false,
+            lost: false,
+        }
+    }
+
+    /// Sets the number of items the job works on
+    pub fn total(&mut self, total: usize) {
+        self.total = total;
+    }
+
+    /// Records that `done` items out of the total are done
+    pub async fn report(&mut self, done: usize) -> Result<(), sqlx::Error> {
+        // Only a finished job is at 100%
+        let percent = match self.total {
+            0 => 0,
+            total => (done * 100 / total).min(99) as i16,
+        };
+
+        let cancelled: Option<(bool,)> = sqlx::query_as(
+            "update jobs set progress = $2, locked_at = now(), updated_at = now()
+            where id = $1 and status = 'running' and attempts = $3
+            returning cancel_requested",
+        )
+        .bind(self.id)
+        .bind(percent)
+        .bind(self.attempt)
+        .fetch_optional(&self.pool)
+        .await?;
+        match cancelled {
+            Some((cancelled,)) => self.cancelled = cancelled,
+            None => self.lost = true,
+        }
+
+        Ok(())
+    }
+
+    /// Whether the job should stop, as of the latest report: the owner asked to cancel it or
+    /// its lease ran out. A stopped job should return an error.
+    pub fn cancelled(&self) -> bool {
+        self.cancelled || self.lost
+    }
+}
+
+/// Starts `count` workers in tasks of the current process, and the expiry of leases when there
+/// is at least one
+pub fn start(state: &State, count: usize) {
+    for worker in 0..count {
+        task::spawn(work(state.clone(), worker));
+    }
+    if count > 0 {
+        task::spawn(expire_periodically(state.postgres.clone()));
+    }
+}
+
+/// Ends the attempts whose lease ran out every [`EXPIRE_INTERVAL`], for as long as the process
+/// runs
+async fn expire_periodically(pool: PgPool) {
+    loop {
+        match Job::expire(&pool).await {
+            Ok(0) => {}
+            Ok(expired) => log::warn!(
+                "Ended the attempts of {} jobs whose worker stopped",
+                expired
+            ),
+            Err(err) => log::error!("Error ending the attempts of stopped jobs: {:?}", err),
+        }
+        task::sleep(EXPIRE_INTERVAL).await;
+    }
+}
+
+/// Runs jobs one after the other, forever
+async fn work(state: State, worker: usize) {
+    log::debug!("Job worker {} started", worker);
+
+    loop {
+        match next(&state).await {
+            Ok(true) => continue,
+            Ok(false) => task::sleep(POLL_INTERVAL).await,
+            Err(err) => {
+                log::error!("Job worker {} could not reach the queue: {:?}", worker, err);
+                task::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Runs the next job that is due, `false` when there is none
+async fn next(state: &State) -> Result<bool, sqlx::Error> {
+    let job = match Job::claim(&state.postgres).await? {
+        Some(job) => job,
+        None => return Ok(false),
+    };
+
+    log::debug!(
+        "Running job {} ({}), attempt {}",
+        job.id,
+        job.kind,
+        job.attempts
+    );
+
+    let mut progress = Progress::new(state.postgres.clone(), &job);
+    let outcome = match job.kind.as_str() {
+        bulk::JOB_KIND => bulk::run_job(state, &job, &mut progress).await,
+        kind => Err(format!("Unknown job kind {}", kind)),
+    };
+
+    if let Err(error) = &outcome {
+        log::warn!(
+            "Attempt {} of job {} failed: {}",
+            job.attempts,
+            job.id,
+            error
+        );
+    }
+
+    if !Job::finish(&state.postgres, job.id, job.attempts, outcome).await? {
+        log::warn!(
+            "Attempt {} of job {} ran out of its lease, its outcome is dropped",
+            job.attempts,
+            job.id
+        );
+    }
+    Ok(true)
+}
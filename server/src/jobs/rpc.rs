@@ -0,0 +1,106 @@
+use common::errors::AppError;
+use common::jsonrpc::{ById, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{Job, JobStatus};
+use crate::rpc::{self, Context, MethodResult};
+
+/// Most jobs a single `jobs.list` call returns
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct ListParams {
+    pub status: Option<JobStatus>,
+    #[serde(default = "ListParams::default_limit")]
+    pub limit: i64,
+}
+
+impl ListParams {
+    fn default_limit() -> i64 {
+        50
+    }
+}
+
+impl Validate for ListParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/limit", &self.limit).range(1, MAX_LIMIT);
+    }
+}
+
+/// Definition of `jobs.get`
+pub struct GetJob;
+
+impl RpcMethod for GetJob {
+    const NAME: &'static str = "jobs.get";
+    type Params = ById;
+    type Result = Job;
+}
+
+/// `jobs.get`
+///
+/// Status and progress of a job of the caller, with its result once it succeeded
+pub async fn get(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Job> {
+    let job = Job::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)?;
+
+    rpc::ok(job)
+}
+
+/// Definition of `jobs.list`
+pub struct ListJobs;
+
+impl RpcMethod for ListJobs {
+    const NAME: &'static str = "jobs.list";
+    type Params = ListParams;
+    type Result = Vec<Job>;
+}
+
+/// `jobs.list`
+///
+/// Latest jobs of the caller, optionally only those with a status
+pub async fn list(
+    ctx: &Context<'_>,
+    ListParams { status, limit }: ListParams,
+) -> MethodResult<Vec<Job>> {
+    let jobs = Job::list(&ctx.state.postgres, ctx.owner(), status, limit)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(jobs)
+}
+
+/// Definition of `jobs.cancel`
+pub struct CancelJob;
+
+impl RpcMethod for CancelJob {
+    const NAME: &'static str = "jobs.cancel";
+    type Params = ById;
+    type Result = Job;
+}
+
+/// `jobs.cancel`
+///
+/// Queued jobs are cancelled right away, running ones stop at their next progress report.
+/// Jobs that already ended cannot be cancelled.
+pub async fn cancel(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Job> {
+    if let Some(job) = Job::cancel(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+    {
+        return rpc::ok(job);
+    }
+
+    let job = Job::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)?;
+
+    Err(AppError::JobEnded {
+        status: job.status.as_str().to_string(),
+    }
+    .into())
+}
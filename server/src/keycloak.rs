@@ -1,6 +1,7 @@
 use std::{future::Future, ops::Deref, pin::Pin};
 
 use crate::state::State;
+use common::errors::AppError;
 use common::jsonrpc::JSONRPCError;
 use jsonwebtoken::{decode, errors::ErrorKind, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
@@ -23,7 +24,7 @@ pub struct JWK {
     x5t: String,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct JWKS {
     keys: Vec<JWK>,
 }
@@ -46,7 +47,7 @@ pub enum AuthErrors {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct KeycloakRealmAccess {
-    roles: Vec<String>,
+    pub roles: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -77,9 +78,9 @@ pub struct KeycloakClaims {
 /// Extracts a JWT from a [`tide::Request`] and return a [`User`] struct on success
 ///
 /// The verification uses [JWKS](https://auth0.com/docs/tokens/concepts/jwks) to verify a JWT
-/// token that must be present in the `Authorization` header or `auth_token` query parameter.
-/// The value in the `Authorization` header will always take precedence over the query
-/// parameter.
+/// token that must be present in the `Authorization` header. Tokens are never read from the
+/// query string, where they would end up in access logs; streams authenticate with a ticket
+/// instead.
 pub fn from_request(req: &Request<State>) -> Result<KeycloakClaims, AuthErrors> {
     let (token_type, header_token_value) = req
         .header(&"Authorization".parse::<HeaderName>().unwrap())
@@ -94,39 +95,28 @@ pub fn from_request(req: &Request<State>) -> Result<KeycloakClaims, AuthErrors>
         })
         .unwrap_or((None, None));
 
-    #[derive(Deserialize, Debug)]
-    struct QueryParam {
-        access_token: Option<String>,
-    }
-
-    // Look for an optional access token in the query params.
-    let query_param = req.query().ok().and_then(|q: QueryParam| q.access_token);
+    log::debug!("Auth header type: {:?}", token_type);
 
-    log::debug!(
-        "Auth header: {:?} {:?}, auth query param: {:?}",
-        token_type,
-        header_token_value,
-        query_param
-    );
-
-    let token = match (token_type, query_param) {
-        (Some(token_type), _) if token_type.to_lowercase() == "bearer" => {
+    let token = match token_type {
+        Some(token_type) if token_type.to_lowercase() == "bearer" => {
             header_token_value.ok_or(AuthErrors::InvalidHeader)
         }
-        (Some(_), _) => Err(AuthErrors::InvalidHeader),
-        (None, Some(query_param)) => Ok(query_param),
-        (None, None) => Err(AuthErrors::EmptyHeader),
+        Some(_) => Err(AuthErrors::InvalidHeader),
+        None => Err(AuthErrors::EmptyHeader),
     }?;
 
-    log::debug!("Validating token {}", token);
+    decode_token(&req.state().auth_keys, &token)
+}
 
-    let key = req.state().auth_keys.keys.get(0).unwrap();
+/// Verifies a JWT with the JWKS and returns its claims
+pub fn decode_token(keys: &JWKS, token: &str) -> Result<KeycloakClaims, AuthErrors> {
+    let key = keys.keys.get(0).ok_or(AuthErrors::InvalidToken)?;
     let decoding_key = DecodingKey::from_rsa_components(&key.n, &key.e);
     let validating_algo = Validation::new(Algorithm::RS256);
-    let credentials = decode::<KeycloakClaims>(&token, &decoding_key, &validating_algo);
+    let credentials = decode::<KeycloakClaims>(token, &decoding_key, &validating_algo);
     match credentials {
         Ok(token_data) => {
-            log::debug!("Decoded credentials: {:?}", token_data);
+            log::debug!("Validated token of {}", token_data.claims.sub);
             Ok(token_data.claims)
         }
         Err(err) => {
@@ -142,7 +132,7 @@ pub fn from_request(req: &Request<State>) -> Result<KeycloakClaims, AuthErrors>
                 | ErrorKind::InvalidIssuer
                 | ErrorKind::InvalidSignature
                 | ErrorKind::ImmatureSignature
-                | ErrorKind::Base64(_) => log::warn!("{:?} decoding {:?}", err, token),
+                | ErrorKind::Base64(_) => log::warn!("Token error: {:?}", err),
                 _ => log::error!("Token error: {:?}", err),
             };
 
@@ -174,7 +164,7 @@ async fn inner_auth_middleware(
             // Could not get the credentials from the token, can be a bug in the client
             // application, the credentials may have expired or someone is trying to tamper with token.
             log::error!("Error retrieving credentials from token: {:?}", err);
-            Err(JSONRPCError::unauthorized(()))
+            Err(AppError::Unauthorized.into())
         }
     }
 }
@@ -4,10 +4,32 @@
 )]
 #![deny(broken_intra_doc_links)]
 
+mod app_passwords;
+mod bulk;
+mod carddav;
+mod contacts;
+mod custom_fields;
+mod discover;
+mod groups;
+mod interactions;
+mod jobs;
 mod keycloak;
+mod organizations;
+mod photos;
+mod query;
+mod relationships;
+mod rpc;
+mod settings;
 mod state;
+mod streams;
+mod tags;
+mod util;
+mod webhooks;
+mod ws;
 
+use common::errors::AppError;
 use common::jsonrpc::{JSONRPCError, JSONRPCSuccess};
+use event_sauce_storage_sqlx::SqlxPgStore;
 use keycloak::RequestActor;
 use sqlx::PgPool;
 use state::State;
@@ -17,6 +39,7 @@ use tide::{
     security::{CorsMiddleware, Origin},
     Request,
 };
+use tide_websockets::WebSocket;
 
 use crate::keycloak::{auth_middleware, JWKS};
 
@@ -32,12 +55,24 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let auth_server_url =
         std::env::var("AUTH_SERVER_URL").unwrap_or(String::from("http://localhost:8081"));
 
-    let auth_keys: JWKS = surf::get(&format!(
-        "{}/auth/realms/demo/protocol/openid-connect/certs",
-        auth_server_url
-    ))
-    .recv_json()
-    .await?;
+    // `server worker` only runs background jobs and webhook deliveries, for servers started
+    // with JOB_WORKERS=0
+    let worker_only = std::env::args().nth(1).as_deref() == Some("worker");
+    let job_workers: usize = std::env::var("JOB_WORKERS")
+        .ok()
+        .map(|workers| workers.parse().expect("JOB_WORKERS is not a number"))
+        .unwrap_or(2);
+
+    let auth_keys: JWKS = if worker_only {
+        JWKS::default()
+    } else {
+        surf::get(&format!(
+            "{}/auth/realms/demo/protocol/openid-connect/certs",
+            auth_server_url
+        ))
+        .recv_json()
+        .await?
+    };
 
     log::debug!("Loaded keys: {:?}", auth_keys);
 
@@ -47,25 +82,71 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     log::debug!("Postgres pool created");
 
+    let store = SqlxPgStore::new(postgres.clone()).await?;
+    store.create_event_table().await?;
+    sqlx::migrate!().run(&postgres).await?;
+
+    log::debug!("Database migrations completed");
+
+    let backfilled = contacts::matching::backfill(&store).await?;
+    if backfilled > 0 {
+        log::info!("Computed name keys of {} contacts", backfilled);
+    }
+
     let state = State {
         postgres,
+        store,
         auth_keys,
+        changes: Default::default(),
     };
 
-    log::debug!("Database migrations completed");
+    jobs::start(&state, job_workers);
+    if job_workers > 0 {
+        webhooks::delivery::start(&state);
+        async_std::task::spawn(streams::purge_periodically(state.postgres.clone()));
+    }
+
+    if worker_only {
+        log::info!("Running {} job workers", job_workers);
+        async_std::future::pending::<()>().await;
+    }
+
+    async_std::task::spawn(streams::listen(
+        state.postgres.clone(),
+        state.changes.clone(),
+    ));
 
     log::info!("Using port {}", port);
 
     let cors = CorsMiddleware::new()
-        .allow_methods("GET, POST, OPTIONS".parse::<HeaderValue>().unwrap())
+        .allow_methods(
+            "GET, POST, PUT, DELETE, OPTIONS"
+                .parse::<HeaderValue>()
+                .unwrap(),
+        )
         .allow_origin(Origin::from("*"))
         .allow_credentials(false);
 
     let mut app = tide::with_state(state);
     app.with(cors);
-    app.with(auth_middleware);
 
-    app.at("/").get(handler);
+    // CardDAV clients sign in with app passwords rather than tokens
+    app.at("/")
+        .with(auth_middleware)
+        .get(handler)
+        .post(rpc::handler);
+    app.at("/stream").get(streams::handler);
+    app.at("/ws").get(WebSocket::new(ws::handler));
+    app.at("/contacts/:id/photo")
+        .with(auth_middleware)
+        .get(photos::get)
+        .put(photos::upload)
+        .delete(photos::delete);
+
+    app.at("/.well-known/carddav").all(carddav::well_known);
+    app.at("/dav").all(carddav::handler);
+    app.at("/dav/").all(carddav::handler);
+    app.at("/dav/*path").all(carddav::handler);
 
     app.listen(format!("0.0.0.0:{}", port)).await?;
     Ok(())
@@ -79,7 +160,7 @@ pub async fn handler(req: Request<State>) -> tide::Result {
         .as_ref()
         .ok_or_else(|| {
             log::error!("Error retrieving user from request");
-            JSONRPCError::forbidden(())
+            JSONRPCError::from(AppError::Unauthorized)
         })
         .map(|actor| JSONRPCSuccess::ok(actor).try_into().unwrap())
         .or_else(|e| Ok(e.into()))
@@ -0,0 +1,440 @@
+/*!
+Companies and other organizations contacts belong to
+
+Contacts link to organizations through their `affiliations`, which carry the title and
+department of the contact within the organization. The domains of an organization are used to
+suggest it for contacts with a matching email address.
+*/
+
+pub mod rpc;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use common::contacts::{address::PostalAddress, Affiliation};
+use common::validation::{Validate, ValidationErrors, MAX_NAME_LENGTH, MAX_TEXT_LENGTH};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::contacts::Contact;
+
+static DOMAIN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^([a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?\.)+[a-z]{2,63}$").unwrap());
+
+/// Email providers whose domains say nothing about where someone works
+const FREE_MAIL_DOMAINS: &[&str] = &[
+    "aol.com",
+    "gmail.com",
+    "gmx.com",
+    "gmx.de",
+    "googlemail.com",
+    "hotmail.com",
+    "icloud.com",
+    "live.com",
+    "mac.com",
+    "mail.com",
+    "me.com",
+    "msn.com",
+    "outlook.com",
+    "proton.me",
+    "protonmail.com",
+    "web.de",
+    "yahoo.com",
+    "yandex.ru",
+    "zoho.com",
+];
+
+/// Second level labels used in country code domains, e.g. the `co` of `example.co.uk`
+const SECOND_LEVEL_LABELS: &[&str] = &["ac", "co", "com", "edu", "gob", "gov", "net", "org"];
+
+/// Fields of an organization the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct OrganizationData {
+    pub name: String,
+    /// Lowercased domains, e.g. `example.com`
+    pub domains: Vec<String>,
+    pub address: Option<PostalAddress>,
+    pub notes: Option<String>,
+}
+
+impl Validate for OrganizationData {
+    /// Domains are checked once normalized, whether another organization has them is for
+    /// the handler to check
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/name", &self.name)
+            .required()
+            .max_length(MAX_NAME_LENGTH);
+        errors.items("/domains", &self.domains, |domain| {
+            domain.required().satisfies(
+                |domain| is_valid_domain(&normalize_domain(domain)),
+                "invalid_domain",
+                "Not a domain name",
+            );
+        });
+        if let Some(address) = &self.address {
+            errors.nested("/address", address);
+        }
+        errors
+            .field("/notes", &self.notes)
+            .max_length(MAX_TEXT_LENGTH);
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "organizations")]
+pub struct Organization {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: OrganizationData,
+}
+
+/// A contact of an organization with their role in it
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Member {
+    pub contact_id: Uuid,
+    pub name: String,
+    pub title: Option<String>,
+    pub department: Option<String>,
+}
+
+/// An organization matching the email domain of some contacts. `organization_id` is `None`
+/// when no organization has the domain yet and one could be created with `name`.
+#[derive(Serialize, JsonSchema, Debug)]
+#[schemars(rename = "OrganizationSuggestion")]
+pub struct Suggestion {
+    pub organization_id: Option<Uuid>,
+    pub name: String,
+    pub domain: String,
+    /// Contacts with an email address on the domain that are not affiliated yet
+    pub contacts: Vec<Uuid>,
+}
+
+/// Whether `domain` looks like a lowercase DNS name
+pub fn is_valid_domain(domain: &str) -> bool {
+    DOMAIN.is_match(domain)
+}
+
+/// Domain as written by a user, lowercased and without a leading `@`
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_start_matches('@').to_lowercase()
+}
+
+/// Whether anyone can get an address on `domain`
+pub fn is_free_mail(domain: &str) -> bool {
+    FREE_MAIL_DOMAINS.contains(&domain)
+}
+
+/// Guesses the name of an organization from its domain, `acme-corp.co.uk` gives `Acme Corp`
+pub fn name_from_domain(domain: &str) -> String {
+    let labels: Vec<&str> = domain.split('.').collect();
+    let name = match labels.as_slice() {
+        [.., name, second, _] if SECOND_LEVEL_LABELS.contains(second) => name,
+        [.., name, _] => name,
+        _ => domain,
+    };
+
+    name.split('-')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+const COLUMNS: &str = "id, owner, name, domains, address, notes";
+
+impl Organization {
+    /// Organizations of `owner`, sorted by name
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<OrganizationRow> = sqlx::query_as(&format!(
+            "select {} from organizations where owner = $1 order by lower(name)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Organization::from).collect())
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<OrganizationRow> = sqlx::query_as(&format!(
+            "select {} from organizations where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Organization::from))
+    }
+
+    /// Which of `ids` are organizations of `owner`
+    pub async fn existing(
+        pool: &PgPool,
+        owner: Uuid,
+        ids: &[Uuid],
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        let rows: Vec<(Uuid,)> =
+            sqlx::query_as("select id from organizations where owner = $1 and id = any($2)")
+                .bind(owner)
+                .bind(ids)
+                .fetch_all(pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Domains of `domains` already used by another organization of `owner` than `except`
+    pub async fn taken_domains(
+        pool: &PgPool,
+        owner: Uuid,
+        domains: &[String],
+        except: Option<Uuid>,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "select distinct domain from organizations, unnest(domains) domain
+            where owner = $1 and domain = any($2) and id is distinct from $3",
+        )
+        .bind(owner)
+        .bind(domains)
+        .bind(except)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|(domain,)| domain).collect())
+    }
+
+    /// Contacts affiliated with the organization, sorted by name
+    pub async fn members(&self, pool: &PgPool) -> Result<Vec<Member>, sqlx::Error> {
+        let contacts = Contact::affiliated_with(pool, self.owner, self.id).await?;
+
+        let members = contacts
+            .iter()
+            .filter_map(|contact| {
+                let affiliation = contact
+                    .data
+                    .affiliations
+                    .iter()
+                    .find(|affiliation| affiliation.organization_id == self.id)?;
+
+                Some(Member {
+                    contact_id: contact.id,
+                    name: contact.display_name(),
+                    title: affiliation.title.clone(),
+                    department: affiliation.department.clone(),
+                })
+            })
+            .collect();
+
+        Ok(members)
+    }
+
+    /// Organizations matching the email domains of the contacts of `owner`, or of `contact`
+    /// only. Free mail providers are ignored, and contacts are only suggested for organizations
+    /// they are not affiliated with yet.
+    pub async fn suggestions(
+        pool: &PgPool,
+        owner: Uuid,
+        contact: Option<Uuid>,
+    ) -> Result<Vec<Suggestion>, sqlx::Error> {
+        let rows: Vec<(Uuid, String, Json<Vec<Affiliation>>)> = sqlx::query_as(
+            "select distinct id, split_part(email, '@', 2), affiliations
+            from contacts, unnest(email_addresses) email
+            where owner = $1 and ($2::uuid is null or id = $2)",
+        )
+        .bind(owner)
+        .bind(contact)
+        .fetch_all(pool)
+        .await?;
+
+        let organizations = Organization::list(pool, owner).await?;
+        let by_domain: BTreeMap<&str, &Organization> = organizations
+            .iter()
+            .flat_map(|organization| {
+                organization
+                    .data
+                    .domains
+                    .iter()
+                    .map(move |domain| (domain.as_str(), organization))
+            })
+            .collect();
+
+        let mut contacts_by_domain: BTreeMap<String, BTreeSet<Uuid>> = BTreeMap::new();
+        for (contact_id, domain, affiliations) in rows {
+            if is_free_mail(&domain) || !domain.contains('.') {
+                continue;
+            }
+
+            let affiliated = by_domain.get(domain.as_str()).is_some_and(|organization| {
+                affiliations
+                    .0
+                    .iter()
+                    .any(|affiliation| affiliation.organization_id == organization.id)
+            });
+            if !affiliated {
+                contacts_by_domain
+                    .entry(domain)
+                    .or_default()
+                    .insert(contact_id);
+            }
+        }
+
+        let suggestions = contacts_by_domain
+            .into_iter()
+            .map(|(domain, contacts)| {
+                let organization = by_domain.get(domain.as_str());
+                Suggestion {
+                    organization_id: organization.map(|organization| organization.id),
+                    name: organization.map_or_else(
+                        || name_from_domain(&domain),
+                        |organization| organization.data.name.clone(),
+                    ),
+                    domain,
+                    contacts: contacts.into_iter().collect(),
+                }
+            })
+            .collect();
+
+        Ok(suggestions)
+    }
+}
+
+#[derive(FromRow)]
+struct OrganizationRow {
+    id: Uuid,
+    owner: Uuid,
+    name: String,
+    domains: Vec<String>,
+    address: Option<Json<PostalAddress>>,
+    notes: Option<String>,
+}
+
+impl From<OrganizationRow> for Organization {
+    fn from(row: OrganizationRow) -> Self {
+        Organization {
+            id: row.id,
+            owner: row.owner,
+            data: OrganizationData {
+                name: row.name,
+                domains: row.domains,
+                address: row.address.map(|address| address.0),
+                notes: row.notes,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Organization)]
+pub struct OrganizationCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: OrganizationData,
+}
+
+/// Replaces every editable field of the organization
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Organization)]
+pub struct OrganizationUpdated {
+    #[serde(flatten)]
+    pub data: OrganizationData,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Organization)]
+pub struct OrganizationDeleted {}
+
+impl AggregateCreate<OrganizationCreated> for Organization {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<OrganizationCreated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to create Organization from OrganizationCreated event",
+        )?;
+
+        Ok(Organization {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+        })
+    }
+}
+
+impl AggregateUpdate<OrganizationUpdated> for Organization {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<OrganizationUpdated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update Organization from OrganizationUpdated event",
+        )?;
+
+        Ok(Organization {
+            data: data.data.clone(),
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<OrganizationDeleted> for Organization {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(
+        self,
+        _event: &Event<OrganizationDeleted>,
+    ) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Organization> for Organization {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into organizations (id, owner, name, domains, address, notes)
+            values ($1, $2, $3, $4, $5, $6)
+            on conflict (id) do update set
+                name = excluded.name,
+                domains = excluded.domains,
+                address = excluded.address,
+                notes = excluded.notes",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.data.name)
+        .bind(&self.data.domains)
+        .bind(self.data.address.as_ref().map(Json))
+        .bind(&self.data.notes)
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Organization {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from organizations where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    normalize_domain, Member, Organization, OrganizationCreated, OrganizationData,
+    OrganizationDeleted, OrganizationUpdated, Suggestion,
+};
+use crate::{
+    contacts::{self, Contact, ContactUpdated},
+    rpc::{self, Context, MethodResult},
+};
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub organization: OrganizationData,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.organization.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Default)]
+#[serde(default)]
+pub struct SuggestParams {
+    /// Only suggest organizations for this contact
+    pub contact_id: Option<Uuid>,
+}
+
+impl Validate for SuggestParams {}
+
+/// Normalizes an organization that follows its [`Validate`] rules. Domains are lowercased
+/// and cannot belong to another organization of the caller.
+async fn validate(
+    ctx: &Context<'_>,
+    input: OrganizationData,
+    id: Option<Uuid>,
+) -> Result<OrganizationData, rpc::RpcError> {
+    let mut errors = ValidationErrors::new();
+
+    let mut domains: Vec<String> = Vec::new();
+    for domain in &input.domains {
+        let domain = normalize_domain(domain);
+        if !domains.contains(&domain) {
+            domains.push(domain);
+        }
+    }
+
+    let taken = Organization::taken_domains(&ctx.state.postgres, ctx.owner(), &domains, id)
+        .await
+        .map_err(rpc::internal)?;
+    for (index, domain) in input.domains.iter().enumerate() {
+        if taken.contains(&normalize_domain(domain)) {
+            errors.add(
+                format!("/domains/{}", index),
+                "domain_taken",
+                "Another organization has this domain",
+            );
+        }
+    }
+
+    errors.into_result()?;
+
+    Ok(OrganizationData {
+        name: input.name.trim().to_string(),
+        domains,
+        address: input.address.map(|address| address.normalize()),
+        notes: input
+            .notes
+            .map(|notes| notes.trim().to_string())
+            .filter(|notes| !notes.is_empty()),
+    })
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Organization, rpc::RpcError> {
+    Organization::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `organizations.create`
+pub struct CreateOrganization;
+
+impl RpcMethod for CreateOrganization {
+    const NAME: &'static str = "organizations.create";
+    type Params = OrganizationData;
+    type Result = Organization;
+}
+
+/// `organizations.create`
+pub async fn create(ctx: &Context<'_>, input: OrganizationData) -> MethodResult<Organization> {
+    let data = validate(ctx, input, None).await?;
+
+    let organization = Organization::try_create(OrganizationCreated {
+        owner: ctx.owner(),
+        data,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(organization)
+}
+
+/// Definition of `organizations.get`
+pub struct GetOrganization;
+
+impl RpcMethod for GetOrganization {
+    const NAME: &'static str = "organizations.get";
+    type Params = ById;
+    type Result = Organization;
+}
+
+/// `organizations.get`
+pub async fn get(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Organization> {
+    rpc::ok(find(ctx, id).await?)
+}
+
+/// Definition of `organizations.list`
+pub struct ListOrganizations;
+
+impl RpcMethod for ListOrganizations {
+    const NAME: &'static str = "organizations.list";
+    type Params = NoParams;
+    type Result = Vec<Organization>;
+}
+
+/// `organizations.list`
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<Organization>> {
+    let organizations = Organization::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(organizations)
+}
+
+/// Definition of `organizations.update`
+pub struct UpdateOrganization;
+
+impl RpcMethod for UpdateOrganization {
+    const NAME: &'static str = "organizations.update";
+    type Params = UpdateParams;
+    type Result = Organization;
+}
+
+/// `organizations.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams {
+        id,
+        organization: input,
+    }: UpdateParams,
+) -> MethodResult<Organization> {
+    let organization = find(ctx, id).await?;
+    let data = validate(ctx, input, Some(id)).await?;
+
+    let organization = organization
+        .try_update(OrganizationUpdated { data })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(organization)
+}
+
+/// Definition of `organizations.delete`
+pub struct DeleteOrganization;
+
+impl RpcMethod for DeleteOrganization {
+    const NAME: &'static str = "organizations.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `organizations.delete`
+///
+/// Contacts affiliated with the organization lose the affiliation in the same transaction.
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    let organization = find(ctx, id).await?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+
+    let contacts = Contact::affiliated_with(tx.get(), ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?;
+    for contact in contacts {
+        let mut data = contact.data.clone();
+        data.affiliations
+            .retain(|affiliation| affiliation.organization_id != id);
+
+        contact
+            .try_update(ContactUpdated { data })
+            .map_err(rpc::internal)?
+            .stage_persist(&mut tx)
+            .await
+            .map_err(rpc::internal)?;
+    }
+
+    organization
+        .try_delete(OrganizationDeleted {})
+        .map_err(rpc::internal)?
+        .stage_delete(&mut tx)
+        .await
+        .map_err(rpc::internal)?;
+
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `organizations.people`
+pub struct OrganizationPeople;
+
+impl RpcMethod for OrganizationPeople {
+    const NAME: &'static str = "organizations.people";
+    type Params = ById;
+    type Result = Vec<Member>;
+}
+
+/// `organizations.people`
+///
+/// Contacts affiliated with the organization, with their title and department
+pub async fn people(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Vec<Member>> {
+    let members = find(ctx, id)
+        .await?
+        .members(&ctx.state.postgres)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(members)
+}
+
+/// Definition of `organizations.suggest`
+pub struct SuggestOrganizations;
+
+impl RpcMethod for SuggestOrganizations {
+    const NAME: &'static str = "organizations.suggest";
+    type Params = SuggestParams;
+    type Result = Vec<Suggestion>;
+}
+
+/// `organizations.suggest`
+///
+/// Organizations to create or link, guessed from the email domains of the contacts of the
+/// caller, or of a single contact
+pub async fn suggest(
+    ctx: &Context<'_>,
+    SuggestParams { contact_id }: SuggestParams,
+) -> MethodResult<Vec<Suggestion>> {
+    if let Some(contact_id) = contact_id {
+        contacts::rpc::find(ctx, contact_id).await?;
+    }
+
+    let suggestions = Organization::suggestions(&ctx.state.postgres, ctx.owner(), contact_id)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(suggestions)
+}
@@ -0,0 +1,593 @@
+/*!
+Contact photos
+
+Photos are uploaded with `PUT /contacts/:id/photo` and served with
+`GET /contacts/:id/photo?size=128`. Uploads are decoded and re-encoded as square JPEG
+thumbnails, which drops any EXIF metadata (location, camera...) the original file carried.
+Contacts without a photo are served a generated avatar with their initials.
+*/
+
+use std::io::Cursor;
+
+use async_std::io::ReadExt;
+use common::errors::AppError;
+use common::jsonrpc::JSONRPCSuccess;
+use common::validation::ValidationErrors;
+use event_sauce::prelude::*;
+use event_sauce_storage_sqlx::{SqlxPgStore, SqlxPgStoreTransaction};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, io::Reader, DynamicImage, ImageFormat, Rgb,
+    RgbImage,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tide::{http::mime, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use crate::{
+    contacts::{Contact, ContactPhotoChanged},
+    keycloak::RequestActor,
+    rpc::RpcError,
+    state::State,
+    util::{hash_hex, xml_escape},
+};
+
+/// Largest upload accepted, in bytes
+pub const MAX_UPLOAD_SIZE: usize = 5 * 1024 * 1024;
+
+/// Largest image accepted, in pixels, to avoid decompression bombs
+const MAX_PIXELS: u64 = 40_000_000;
+
+/// Side of the generated thumbnails, in pixels
+pub const SIZES: [u32; 4] = [64, 128, 256, 512];
+
+const DEFAULT_SIZE: u32 = 128;
+
+const CACHE_CONTROL: &str = "private, max-age=3600";
+
+/// Background colors of the generated avatars
+const AVATAR_COLORS: [&str; 8] = [
+    "#e57373", "#f06292", "#ba68c8", "#7986cb", "#4fc3f7", "#4db6ac", "#aed581", "#ffb74d",
+];
+
+/// A square JPEG version of the photo
+pub struct Thumbnail {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reasons an upload can be rejected
+#[derive(Debug)]
+pub enum PhotoError {
+    TooLarge,
+    UnsupportedFormat,
+    Corrupt,
+}
+
+impl PhotoError {
+    fn code(&self) -> &'static str {
+        match self {
+            PhotoError::TooLarge => "photo_too_large",
+            PhotoError::UnsupportedFormat => "unsupported_format",
+            PhotoError::Corrupt => "corrupt_photo",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            PhotoError::TooLarge => "The photo is too large",
+            PhotoError::UnsupportedFormat => "Photos must be JPEG, PNG or WebP images",
+            PhotoError::Corrupt => "The photo cannot be read",
+        }
+    }
+
+    /// Error response, the body of the request being the field that failed
+    fn into_error(self) -> RpcError {
+        let mut errors = ValidationErrors::new();
+        errors.add("", self.code(), self.message());
+        errors.into()
+    }
+}
+
+/// EXIF orientation of the photo, 1 when missing
+fn orientation(bytes: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+/// Rotates the image so it displays upright once the EXIF metadata is gone
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Blends transparent pixels onto a white background, JPEG has no alpha channel
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend =
+            |channel: u8| ((channel as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// Validates an upload and generates its thumbnails
+pub fn process(bytes: &[u8]) -> Result<Vec<Thumbnail>, PhotoError> {
+    if bytes.len() > MAX_UPLOAD_SIZE {
+        return Err(PhotoError::TooLarge);
+    }
+
+    let format = image::guess_format(bytes).map_err(|_| PhotoError::UnsupportedFormat)?;
+    if !matches!(
+        format,
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP
+    ) {
+        return Err(PhotoError::UnsupportedFormat);
+    }
+
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .map_err(|_| PhotoError::Corrupt)?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(PhotoError::TooLarge);
+    }
+
+    let image = image::load_from_memory_with_format(bytes, format).map_err(|err| {
+        log::debug!("Error decoding photo: {:?}", err);
+        PhotoError::Corrupt
+    })?;
+    let image = apply_orientation(image, orientation(bytes));
+
+    SIZES
+        .iter()
+        .map(|&size| {
+            let thumbnail = DynamicImage::ImageRgb8(flatten(&image.resize_to_fill(
+                size,
+                size,
+                FilterType::Lanczos3,
+            )));
+
+            let mut data = Vec::new();
+            JpegEncoder::new_with_quality(&mut data, 85)
+                .encode_image(&thumbnail)
+                .map_err(|err| {
+                    log::error!("Error encoding thumbnail: {:?}", err);
+                    PhotoError::Corrupt
+                })?;
+
+            Ok(Thumbnail { size, data })
+        })
+        .collect()
+}
+
+/// Up to two letters identifying the contact
+fn initials(contact: &Contact) -> String {
+    let first = |name: &Option<String>| name.as_deref().and_then(|name| name.chars().next());
+
+    let initials: String = match (
+        first(&contact.data.given_name),
+        first(&contact.data.family_name),
+    ) {
+        (None, None) => String::from("?"),
+        (given, family) => given.into_iter().chain(family).collect(),
+    };
+
+    initials.to_uppercase()
+}
+
+/// SVG avatar with the initials of the contact on a background picked from its id
+pub fn avatar(contact: &Contact) -> String {
+    let color = AVATAR_COLORS[contact.id.as_bytes()[0] as usize % AVATAR_COLORS.len()];
+    let initials = xml_escape(&initials(contact));
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100"><rect width="100" height="100" fill="{}"/><text x="50" y="50" dy=".35em" text-anchor="middle" font-family="sans-serif" font-size="40" fill="#fff">{}</text></svg>"##,
+        color, initials
+    )
+}
+
+async fn save_thumbnails(
+    tx: &mut SqlxPgStoreTransaction,
+    contact_id: Uuid,
+    thumbnails: &[Thumbnail],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("delete from contact_photos where contact_id = $1")
+        .bind(contact_id)
+        .execute(tx.get())
+        .await?;
+
+    for thumbnail in thumbnails {
+        sqlx::query("insert into contact_photos (contact_id, size, data) values ($1, $2, $3)")
+            .bind(contact_id)
+            .bind(thumbnail.size as i32)
+            .bind(&thumbnail.data)
+            .execute(tx.get())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the thumbnails of a contact and records the change in the contact events
+async fn change_photo(
+    store: &SqlxPgStore,
+    contact: Contact,
+    photo: Option<String>,
+    thumbnails: &[Thumbnail],
+) -> Result<Contact, Box<dyn std::error::Error + Send + Sync>> {
+    let mut tx = store.transaction().await?;
+
+    save_thumbnails(&mut tx, contact.id, thumbnails).await?;
+    let contact = contact
+        .try_update(ContactPhotoChanged { photo })?
+        .stage_persist(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(contact)
+}
+
+/// The smallest thumbnail at least `size` pixels wide, or the largest one
+async fn load_thumbnail(
+    pool: &PgPool,
+    contact_id: Uuid,
+    size: u32,
+) -> Result<Option<(i32, Vec<u8>)>, sqlx::Error> {
+    sqlx::query_as(
+        "select size, data from contact_photos where contact_id = $1
+        order by size < $2, case when size >= $2 then size else -size end
+        limit 1",
+    )
+    .bind(contact_id)
+    .bind(size as i32)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Finds the contact of the `:id` route param in the address book of the caller
+async fn find_contact(req: &Request<State>) -> Result<Contact, Response> {
+    let actor = req
+        .ext::<RequestActor>()
+        .and_then(|actor| actor.as_ref())
+        .ok_or_else(|| Response::from(RpcError::from(AppError::Unauthorized)))?;
+
+    let id: Uuid = req
+        .param("id")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| Response::from(RpcError::from(AppError::NotFound)))?;
+
+    Contact::find(&req.state().postgres, actor.sub, id)
+        .await
+        .map_err(|err| {
+            log::error!("Error loading contact {}: {:?}", id, err);
+            Response::from(RpcError::from(AppError::Internal))
+        })?
+        .ok_or_else(|| Response::from(RpcError::from(AppError::NotFound)))
+}
+
+/// `PUT /contacts/:id/photo` with the image as body
+pub async fn upload(mut req: Request<State>) -> tide::Result {
+    let contact = match find_contact(&req).await {
+        Ok(contact) => contact,
+        Err(response) => return Ok(response),
+    };
+
+    if req.len().is_some_and(|len| len > MAX_UPLOAD_SIZE) {
+        return Ok(PhotoError::TooLarge.into_error().into());
+    }
+
+    let mut bytes = Vec::new();
+    req.take_body()
+        .take(MAX_UPLOAD_SIZE as u64 + 1)
+        .read_to_end(&mut bytes)
+        .await?;
+
+    // The hash is the version of the photo
+    let photo = hash_hex(&bytes);
+    let thumbnails = match async_std::task::spawn_blocking(move || process(&bytes)).await {
+        Ok(thumbnails) => thumbnails,
+        Err(err) => {
+            log::debug!("Rejected photo for {}: {:?}", contact.id, err);
+            return Ok(err.into_error().into());
+        }
+    };
+
+    Ok(
+        match change_photo(&req.state().store, contact, Some(photo), &thumbnails).await {
+            Ok(contact) => JSONRPCSuccess::ok(contact).into(),
+            Err(err) => {
+                log::error!("Error saving photo: {:?}", err);
+                RpcError::from(AppError::Internal).into()
+            }
+        },
+    )
+}
+
+/// `DELETE /contacts/:id/photo`
+pub async fn delete(req: Request<State>) -> tide::Result {
+    let contact = match find_contact(&req).await {
+        Ok(contact) => contact,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(
+        match change_photo(&req.state().store, contact, None, &[]).await {
+            Ok(contact) => JSONRPCSuccess::ok(contact).into(),
+            Err(err) => {
+                log::error!("Error deleting photo: {:?}", err);
+                RpcError::from(AppError::Internal).into()
+            }
+        },
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotoQuery {
+    size: Option<u32>,
+}
+
+/// Builds a cacheable response, or a 304 if the client already has this version
+fn cached(req: &Request<State>, etag: String, body: Vec<u8>, content_type: mime::Mime) -> Response {
+    let etag = format!("\"{}\"", etag);
+
+    let fresh = req
+        .header("If-None-Match")
+        .is_some_and(|values| values.iter().any(|value| value.as_str() == etag));
+    if fresh {
+        return Response::builder(StatusCode::NotModified)
+            .header("ETag", etag)
+            .header("Cache-Control", CACHE_CONTROL)
+            .build();
+    }
+
+    Response::builder(StatusCode::Ok)
+        .body(body)
+        .content_type(content_type)
+        .header("ETag", etag)
+        .header("Cache-Control", CACHE_CONTROL)
+        .build()
+}
+
+/// `GET /contacts/:id/photo?size=128`
+///
+/// Serves the thumbnail closest to `size`, or the initials avatar if there is no photo
+pub async fn get(req: Request<State>) -> tide::Result {
+    let contact = match find_contact(&req).await {
+        Ok(contact) => contact,
+        Err(response) => return Ok(response),
+    };
+
+    let size = req
+        .query::<PhotoQuery>()
+        .ok()
+        .and_then(|query| query.size)
+        .unwrap_or(DEFAULT_SIZE);
+
+    let thumbnail = match &contact.photo {
+        Some(_) => load_thumbnail(&req.state().postgres, contact.id, size).await?,
+        None => None,
+    };
+
+    Ok(match (&contact.photo, thumbnail) {
+        (Some(photo), Some((size, data))) => {
+            cached(&req, format!("{}-{}", photo, size), data, mime::JPEG)
+        }
+        _ => {
+            let svg = avatar(&contact);
+            cached(&req, hash_hex(&svg), svg.into_bytes(), mime::SVG)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::contacts::ContactData;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    /// 20x10 image, red on the left half and blue on the right half
+    fn halves() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(
+            20,
+            10,
+            |x, _| {
+                if x < 10 {
+                    RED
+                } else {
+                    BLUE
+                }
+            },
+        ))
+    }
+
+    fn jpeg(image: &DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, 95)
+            .encode_image(image)
+            .unwrap();
+        data
+    }
+
+    fn png(image: &DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        image.write_to(&mut data, ImageOutputFormat::Png).unwrap();
+        data
+    }
+
+    /// A JPEG with an EXIF segment holding only its orientation
+    fn with_orientation(jpeg: &[u8], orientation: u8) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+        // One IFD entry: orientation, a single SHORT, and no next IFD
+        exif.extend_from_slice(&[0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xff, 0xe1]);
+        data.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&exif);
+        data.extend_from_slice(&jpeg[2..]);
+        data
+    }
+
+    fn decode(thumbnail: &Thumbnail) -> RgbImage {
+        image::load_from_memory(&thumbnail.data).unwrap().to_rgb8()
+    }
+
+    fn is_red(pixel: &Rgb<u8>) -> bool {
+        pixel[0] > 200 && pixel[2] < 60
+    }
+
+    fn is_blue(pixel: &Rgb<u8>) -> bool {
+        pixel[2] > 200 && pixel[0] < 60
+    }
+
+    fn contact(given_name: Option<&str>, family_name: Option<&str>) -> Contact {
+        Contact {
+            id: Uuid::nil(),
+            owner: Uuid::nil(),
+            data: ContactData {
+                given_name: given_name.map(String::from),
+                family_name: family_name.map(String::from),
+                ..ContactData::default()
+            },
+            photo: None,
+            tags: Vec::new(),
+            last_contacted: None,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn generates_square_jpeg_thumbnails() {
+        let thumbnails = process(&png(&halves())).unwrap();
+
+        let sizes: Vec<u32> = thumbnails.iter().map(|thumbnail| thumbnail.size).collect();
+        assert_eq!(sizes, SIZES.to_vec());
+        for thumbnail in &thumbnails {
+            assert_eq!(
+                image::guess_format(&thumbnail.data).unwrap(),
+                ImageFormat::Jpeg
+            );
+            assert_eq!(
+                decode(thumbnail).dimensions(),
+                (thumbnail.size, thumbnail.size)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_uploads_over_the_size_limit() {
+        let bytes = vec![0; MAX_UPLOAD_SIZE + 1];
+        assert!(matches!(process(&bytes), Err(PhotoError::TooLarge)));
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        assert!(matches!(
+            process(b"GIF89a\x01\0\x01\0\0\0\0;"),
+            Err(PhotoError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            process(b"not an image"),
+            Err(PhotoError::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_images() {
+        let mut bytes = png(&halves());
+        bytes.truncate(40);
+        assert!(matches!(process(&bytes), Err(PhotoError::Corrupt)));
+    }
+
+    #[test]
+    fn rejects_images_over_the_pixel_limit_before_decoding() {
+        let mut bytes = jpeg(&halves());
+        // Height and width of the frame header claim 10000x10000 pixels
+        let frame = bytes
+            .windows(2)
+            .position(|marker| marker == [0xff, 0xc0])
+            .unwrap();
+        bytes[frame + 5..frame + 9].copy_from_slice(&[0x27, 0x10, 0x27, 0x10]);
+
+        assert!(matches!(process(&bytes), Err(PhotoError::TooLarge)));
+    }
+
+    #[test]
+    fn rotates_photos_by_their_exif_orientation() {
+        let bytes = with_orientation(&jpeg(&halves()), 6);
+        assert_eq!(orientation(&bytes), 6);
+        assert_eq!(orientation(&jpeg(&halves())), 1);
+
+        // Turned a quarter clockwise, the left half ends up on top
+        let thumbnail = decode(&process(&bytes).unwrap()[0]);
+        assert!(is_red(thumbnail.get_pixel(32, 8)));
+        assert!(is_blue(thumbnail.get_pixel(32, 56)));
+
+        let upright = decode(&process(&jpeg(&halves())).unwrap()[0]);
+        assert!(is_red(upright.get_pixel(8, 32)));
+        assert!(is_blue(upright.get_pixel(56, 32)));
+    }
+
+    #[test]
+    fn flattens_transparency_onto_white() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([0, 0, 0, 128])
+            }
+        }));
+
+        let flat = flatten(&image);
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(flat.get_pixel(1, 0), &Rgb([127, 127, 127]));
+
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([0; 4])));
+        let thumbnail = decode(&process(&png(&transparent)).unwrap()[0]);
+        assert!(thumbnail
+            .pixels()
+            .all(|pixel| pixel.0.iter().all(|&c| c > 250)));
+    }
+
+    #[test]
+    fn initials_of_the_names() {
+        assert_eq!(initials(&contact(Some("ada"), Some("lovelace"))), "AL");
+        assert_eq!(initials(&contact(None, Some("Lovelace"))), "L");
+        assert_eq!(initials(&contact(Some("Élodie"), None)), "É");
+        assert_eq!(initials(&contact(None, None)), "?");
+    }
+
+    #[test]
+    fn avatars_show_escaped_initials_on_a_color_of_the_id() {
+        let svg = avatar(&contact(Some("<b>"), None));
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(">&lt;</text>"));
+        assert!(svg.contains(&format!("fill=\"{}\"", AVATAR_COLORS[0])));
+
+        let mut other = contact(Some("Ada"), None);
+        other.id = Uuid::from_bytes([1; 16]);
+        assert!(avatar(&other).contains(&format!("fill=\"{}\"", AVATAR_COLORS[1])));
+    }
+}
@@ -0,0 +1,621 @@
+/*!
+Contact query language
+
+Queries combine terms with `AND`, `OR`, `NOT` and parentheses. Terms next to each other are
+joined with `AND`. A term is either free text, matched against names and email addresses, or
+`field:value`:
+
+```text
+tag:customer AND city:"Berlin" AND NOT email:*@example.com
+(org:Acme OR org:"Acme Labs") has:phone
+birthday:next-30d
+```
+
+Values are compared ignoring case, `*` matches any run of characters. Fields that are not
+built in are looked up among the custom fields of the owner when the query is compiled.
+
+Queries are parsed into an [`Expr`] tree and compiled to parameterized SQL by [`sql`]. Errors
+carry the 1-based column they were found at.
+*/
+
+pub mod sql;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Longest query accepted, in characters
+const MAX_LENGTH: usize = 1000;
+/// Deepest nesting of parentheses and `NOT` accepted
+const MAX_DEPTH: usize = 32;
+/// Longest window of `birthday:next-<days>d`
+pub const MAX_WINDOW: u32 = 366;
+
+/// Parsed query
+#[derive(Serialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Expr {
+    And { left: Box<Expr>, right: Box<Expr> },
+    Or { left: Box<Expr>, right: Box<Expr> },
+    Not { expr: Box<Expr> },
+    Match { column: usize, predicate: Predicate },
+}
+
+/// A single term of a query
+#[derive(Serialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum Predicate {
+    /// Free text, part of a name or an email address
+    Text {
+        value: String,
+    },
+    Name {
+        pattern: String,
+    },
+    Email {
+        pattern: String,
+    },
+    Phone {
+        pattern: String,
+    },
+    /// Locality of one of the addresses
+    City {
+        pattern: String,
+    },
+    Country {
+        pattern: String,
+    },
+    /// Name of an organization the contact is affiliated with
+    Organization {
+        pattern: String,
+    },
+    Tag {
+        pattern: String,
+    },
+    /// Contacts matching the query of another smart group
+    Group {
+        name: String,
+    },
+    Birthday {
+        window: Window,
+    },
+    Anniversary {
+        window: Window,
+    },
+    Has {
+        what: Has,
+    },
+    /// Value of a custom field
+    Custom {
+        key: String,
+        pattern: String,
+    },
+}
+
+/// Days a recurring date must fall within, counting from today in the time zone of the owner
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Window {
+    Today,
+    Next { days: u32 },
+}
+
+/// Contacts having at least one of something
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Has {
+    Photo,
+    Email,
+    Phone,
+    Address,
+    Birthday,
+    Tag,
+    Organization,
+}
+
+/// Why a query could not be parsed or compiled
+#[derive(Clone, PartialEq, Debug)]
+pub struct QueryError {
+    /// 1-based column, in characters
+    pub column: usize,
+    /// Machine readable reason
+    pub code: &'static str,
+    /// Human readable reason
+    pub message: String,
+}
+
+impl QueryError {
+    fn new(column: usize, code: &'static str, message: impl Into<String>) -> Self {
+        QueryError {
+            column,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum TokenKind {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    /// Bare word
+    Word(String),
+    /// Double quoted string, without the quotes
+    Quoted(String),
+    /// `field:` immediately followed by a value
+    Field(String),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::LParen => String::from("`(`"),
+            TokenKind::RParen => String::from("`)`"),
+            TokenKind::And => String::from("`AND`"),
+            TokenKind::Or => String::from("`OR`"),
+            TokenKind::Not => String::from("`NOT`"),
+            TokenKind::Word(word) => format!("`{}`", word),
+            TokenKind::Quoted(text) => format!("`\"{}\"`", text),
+            TokenKind::Field(field) => format!("`{}:`", field),
+        }
+    }
+}
+
+fn is_field_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_lowercase() || first == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let column = i + 1;
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token {
+                    kind: TokenKind::LParen,
+                    column,
+                });
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token {
+                    kind: TokenKind::RParen,
+                    column,
+                });
+                i += 1;
+            }
+            '"' => {
+                let start = i + 1;
+                let end = (start..chars.len())
+                    .find(|&j| chars[j] == '"')
+                    .ok_or_else(|| {
+                        QueryError::new(column, "unterminated_string", "Missing closing quote")
+                    })?;
+                tokens.push(Token {
+                    kind: TokenKind::Quoted(chars[start..end].iter().collect()),
+                    column,
+                });
+                i = end + 1;
+            }
+            _ => {
+                let start = i;
+                while i < chars.len()
+                    && !chars[i].is_whitespace()
+                    && !matches!(chars[i], '(' | ')' | '"' | ':')
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+
+                if i < chars.len() && chars[i] == ':' {
+                    if !is_field_name(&word) {
+                        return Err(QueryError::new(
+                            column,
+                            "invalid_field",
+                            "Field names are lowercase letters, digits and _",
+                        ));
+                    }
+                    tokens.push(Token {
+                        kind: TokenKind::Field(word),
+                        column,
+                    });
+                    i += 1;
+
+                    // The value of a field can contain colons, e.g. a URL
+                    if i < chars.len() && !chars[i].is_whitespace() && chars[i] != '"' {
+                        let start = i;
+                        while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ')' {
+                            i += 1;
+                        }
+                        tokens.push(Token {
+                            kind: TokenKind::Word(chars[start..i].iter().collect()),
+                            column: start + 1,
+                        });
+                    }
+                    continue;
+                }
+
+                let kind = match word.as_str() {
+                    "AND" => TokenKind::And,
+                    "OR" => TokenKind::Or,
+                    "NOT" => TokenKind::Not,
+                    _ => TokenKind::Word(word),
+                };
+                tokens.push(Token { kind, column });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Column right after the end of the query, reported when it ends too early
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn enter(&mut self, column: usize) -> Result<(), QueryError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(QueryError::new(
+                column,
+                "too_deep",
+                "The query is nested too deeply",
+            ));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut left = self.and()?;
+        while matches!(
+            self.peek(),
+            Some(Token {
+                kind: TokenKind::Or,
+                ..
+            })
+        ) {
+            self.next();
+            let right = self.and()?;
+            left = Expr::Or {
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut left = self.not()?;
+        loop {
+            match self.peek().map(|token| &token.kind) {
+                Some(TokenKind::And) => {
+                    self.next();
+                }
+                // Terms next to each other are joined with AND
+                Some(TokenKind::Not)
+                | Some(TokenKind::LParen)
+                | Some(TokenKind::Word(_))
+                | Some(TokenKind::Quoted(_))
+                | Some(TokenKind::Field(_)) => {}
+                _ => return Ok(left),
+            }
+            let right = self.not()?;
+            left = Expr::And {
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+    }
+
+    fn not(&mut self) -> Result<Expr, QueryError> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Not,
+                column,
+            }) => {
+                let column = *column;
+                self.next();
+                self.enter(column)?;
+                let expr = self.not()?;
+                self.depth -= 1;
+                Ok(Expr::Not {
+                    expr: Box::new(expr),
+                })
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, QueryError> {
+        let token = self.next().ok_or_else(|| {
+            QueryError::new(self.end, "unexpected_end", "The query ends too early")
+        })?;
+
+        match token.kind {
+            TokenKind::LParen => {
+                self.enter(token.column)?;
+                let expr = self.or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    }) => Ok(expr),
+                    Some(other) => Err(QueryError::new(
+                        other.column,
+                        "unexpected_token",
+                        format!("Expected `)` but found {}", other.kind.describe()),
+                    )),
+                    None => Err(QueryError::new(
+                        token.column,
+                        "unclosed_paren",
+                        "This parenthesis is never closed",
+                    )),
+                }
+            }
+            TokenKind::Word(value) | TokenKind::Quoted(value) => Ok(Expr::Match {
+                column: token.column,
+                predicate: Predicate::Text { value },
+            }),
+            TokenKind::Field(field) => {
+                let (value, column) = match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Word(value),
+                        column,
+                    })
+                    | Some(Token {
+                        kind: TokenKind::Quoted(value),
+                        column,
+                    }) if column == token.column + field.chars().count() + 1 => (value, column),
+                    _ => {
+                        return Err(QueryError::new(
+                            token.column,
+                            "missing_value",
+                            format!("`{}:` needs a value right after the colon", field),
+                        ))
+                    }
+                };
+
+                Ok(Expr::Match {
+                    column: token.column,
+                    predicate: predicate(field, value, column)?,
+                })
+            }
+            other => Err(QueryError::new(
+                token.column,
+                "unexpected_token",
+                format!("Expected a term but found {}", other.describe()),
+            )),
+        }
+    }
+}
+
+/// Parses `next-<days>d` and `today`
+fn window(value: &str, column: usize) -> Result<Window, QueryError> {
+    if value == "today" {
+        return Ok(Window::Today);
+    }
+
+    let days = value
+        .strip_prefix("next-")
+        .and_then(|rest| rest.strip_suffix('d'))
+        .and_then(|days| days.parse::<u32>().ok())
+        .ok_or_else(|| {
+            QueryError::new(
+                column,
+                "invalid_value",
+                "Expected `today` or `next-<days>d`, e.g. `next-30d`",
+            )
+        })?;
+    if days == 0 || days > MAX_WINDOW {
+        return Err(QueryError::new(
+            column,
+            "invalid_value",
+            format!("The window must be between 1 and {} days", MAX_WINDOW),
+        ));
+    }
+
+    Ok(Window::Next { days })
+}
+
+fn has(value: &str, column: usize) -> Result<Has, QueryError> {
+    match value {
+        "photo" => Ok(Has::Photo),
+        "email" => Ok(Has::Email),
+        "phone" => Ok(Has::Phone),
+        "address" => Ok(Has::Address),
+        "birthday" => Ok(Has::Birthday),
+        "tag" => Ok(Has::Tag),
+        "org" | "organization" => Ok(Has::Organization),
+        _ => Err(QueryError::new(
+            column,
+            "invalid_value",
+            "Expected photo, email, phone, address, birthday, tag or organization",
+        )),
+    }
+}
+
+fn predicate(field: String, value: String, column: usize) -> Result<Predicate, QueryError> {
+    let pattern = value.clone();
+
+    Ok(match field.as_str() {
+        "name" => Predicate::Name { pattern },
+        "email" => Predicate::Email { pattern },
+        "phone" => Predicate::Phone { pattern },
+        "city" => Predicate::City { pattern },
+        "country" => Predicate::Country { pattern },
+        "org" | "organization" => Predicate::Organization { pattern },
+        "tag" => Predicate::Tag { pattern },
+        "group" => Predicate::Group { name: value },
+        "birthday" => Predicate::Birthday {
+            window: window(&value, column)?,
+        },
+        "anniversary" => Predicate::Anniversary {
+            window: window(&value, column)?,
+        },
+        "has" => Predicate::Has {
+            what: has(&value, column)?,
+        },
+        _ => Predicate::Custom {
+            key: field,
+            pattern,
+        },
+    })
+}
+
+/// Parses a query
+pub fn parse(query: &str) -> Result<Expr, QueryError> {
+    let length = query.chars().count();
+    if length > MAX_LENGTH {
+        return Err(QueryError::new(
+            MAX_LENGTH + 1,
+            "too_long",
+            format!("Queries are limited to {} characters", MAX_LENGTH),
+        ));
+    }
+
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Err(QueryError::new(1, "empty_query", "The query is empty"));
+    }
+
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: length + 1,
+        depth: 0,
+    };
+    let expr = parser.or()?;
+
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(QueryError::new(
+            token.column,
+            "unexpected_token",
+            format!("Unexpected {}", token.kind.describe()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(query: &str) -> (&'static str, usize) {
+        let err = parse(query).unwrap_err();
+        (err.code, err.column)
+    }
+
+    fn term(column: usize, predicate: Predicate) -> Expr {
+        Expr::Match { column, predicate }
+    }
+
+    #[test]
+    fn joins_adjacent_terms_with_and() {
+        assert_eq!(
+            parse("ann tag:vip OR NOT has:phone").unwrap(),
+            Expr::Or {
+                left: Box::new(Expr::And {
+                    left: Box::new(term(
+                        1,
+                        Predicate::Text {
+                            value: String::from("ann")
+                        }
+                    )),
+                    right: Box::new(term(
+                        5,
+                        Predicate::Tag {
+                            pattern: String::from("vip")
+                        }
+                    )),
+                }),
+                right: Box::new(Expr::Not {
+                    expr: Box::new(term(20, Predicate::Has { what: Has::Phone })),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn reads_quoted_values_and_windows() {
+        assert_eq!(
+            parse(r#"city:"New York""#).unwrap(),
+            term(
+                1,
+                Predicate::City {
+                    pattern: String::from("New York")
+                }
+            )
+        );
+        assert_eq!(
+            parse("birthday:next-30d").unwrap(),
+            term(
+                1,
+                Predicate::Birthday {
+                    window: Window::Next { days: 30 }
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn reports_errors_at_their_column() {
+        assert_eq!(error(""), ("empty_query", 1));
+        assert_eq!(error("tag:a \"open"), ("unterminated_string", 7));
+        assert_eq!(error("has:phone Tag:x"), ("invalid_field", 11));
+        assert_eq!(error("tag: x"), ("missing_value", 1));
+        assert_eq!(error("(tag:a OR"), ("unexpected_end", 10));
+        assert_eq!(error("(tag:a"), ("unclosed_paren", 1));
+        assert_eq!(error("tag:a )"), ("unexpected_token", 7));
+        assert_eq!(error("x birthday:next-400d"), ("invalid_value", 12));
+        assert_eq!(error("has:boat"), ("invalid_value", 5));
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        assert_eq!(error("émile has:boat"), ("invalid_value", 11));
+    }
+
+    #[test]
+    fn limits_length_and_depth() {
+        assert_eq!(error(&"a ".repeat(501)), ("too_long", MAX_LENGTH + 1));
+
+        let nested = format!(
+            "{}a{}",
+            "(".repeat(MAX_DEPTH + 1),
+            ")".repeat(MAX_DEPTH + 1)
+        );
+        assert_eq!(error(&nested), ("too_deep", MAX_DEPTH + 1));
+    }
+}
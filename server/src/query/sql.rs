@@ -0,0 +1,444 @@
+/*!
+Compiles parsed queries to SQL conditions on the `contacts` read model
+
+Every value from the query is bound as a parameter, only column names and fixed literals are
+written into the SQL text.
+*/
+
+use chrono::{Datelike, Duration, NaiveDate};
+use sqlx::{postgres::PgArguments, query::QueryAs, Postgres};
+
+use super::{parse, Expr, Has, Predicate, QueryError, Window};
+use crate::custom_fields::FieldDefinition;
+use crate::util::like_escape;
+
+/// Deepest chain of groups referencing other groups
+const MAX_GROUP_DEPTH: usize = 8;
+
+/// Most terms a query can expand to once the groups it refers to are inlined. Groups are
+/// inlined every time they are referred to, so a few groups referring to each other several
+/// times would otherwise grow exponentially. Terms bind at most two parameters, which keeps
+/// queries far below the 65535 parameters Postgres accepts.
+const MAX_TERMS: usize = 1000;
+
+/// Value bound to a placeholder of a compiled query
+#[derive(Clone, PartialEq, Debug)]
+pub enum SqlValue {
+    Text(String),
+    TextArray(Vec<String>),
+}
+
+/// A condition on the `contacts` table and the values of its placeholders
+#[derive(Clone, Debug)]
+pub struct Compiled {
+    pub sql: String,
+    pub params: Vec<SqlValue>,
+}
+
+/// Binds `params` to the placeholders of a compiled query, after those `statement` already
+/// binds
+pub fn bind_compiled<'q, O>(
+    statement: QueryAs<'q, Postgres, O, PgArguments>,
+    params: &[SqlValue],
+) -> QueryAs<'q, Postgres, O, PgArguments> {
+    params
+        .iter()
+        .fold(statement, |statement, param| match param {
+            SqlValue::Text(text) => statement.bind(text.clone()),
+            SqlValue::TextArray(texts) => statement.bind(texts.clone()),
+        })
+}
+
+/// What a query is compiled against
+pub struct Scope<'a> {
+    /// Current date of the owner, for birthday windows
+    pub today: NaiveDate,
+    pub custom_fields: &'a [FieldDefinition],
+    /// Names and queries of the smart groups of the owner
+    pub groups: &'a [(String, String)],
+    /// Name of the group whose query is compiled, it cannot refer to itself
+    pub within: Option<&'a str>,
+}
+
+/// Turns `*` wildcards into a `like` pattern, escaping the characters `like` gives a meaning to
+fn like_pattern(pattern: &str) -> String {
+    like_escape(pattern).replace('*', "%")
+}
+
+/// `MM-DD` of every day in the window. February 29 is included when the window contains
+/// February 28 of a common year, as that is when such dates are celebrated.
+fn month_days(today: NaiveDate, window: Window) -> Vec<String> {
+    let days = match window {
+        Window::Today => 0,
+        Window::Next { days } => days - 1,
+    };
+
+    let mut month_days = Vec::new();
+    for offset in 0..=i64::from(days) {
+        let date = today + Duration::days(offset);
+        month_days.push(format!("{:02}-{:02}", date.month(), date.day()));
+
+        let leap = NaiveDate::from_ymd_opt(date.year(), 2, 29).is_some();
+        if (date.month(), date.day()) == (2, 28) && !leap {
+            month_days.push(String::from("02-29"));
+        }
+    }
+    month_days
+}
+
+struct Compiler<'a> {
+    scope: &'a Scope<'a>,
+    /// Number of the first placeholder, the ones before are bound by the caller
+    first_param: usize,
+    params: Vec<SqlValue>,
+    /// Groups being compiled, to detect cycles
+    groups: Vec<String>,
+    /// Terms compiled so far, groups included
+    terms: usize,
+}
+
+impl<'a> Compiler<'a> {
+    /// Adds a parameter and returns its placeholder
+    fn bind(&mut self, value: SqlValue) -> String {
+        self.params.push(value);
+        format!("${}", self.first_param + self.params.len() - 1)
+    }
+
+    fn text(&mut self, value: &str) -> String {
+        self.bind(SqlValue::Text(value.to_string()))
+    }
+
+    fn pattern(&mut self, pattern: &str) -> String {
+        self.bind(SqlValue::Text(like_pattern(pattern)))
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<String, QueryError> {
+        Ok(match expr {
+            Expr::And { left, right } => {
+                format!("({} and {})", self.expr(left)?, self.expr(right)?)
+            }
+            Expr::Or { left, right } => format!("({} or {})", self.expr(left)?, self.expr(right)?),
+            Expr::Not { expr } => format!("not {}", self.expr(expr)?),
+            Expr::Match { column, predicate } => {
+                self.terms += 1;
+                if self.terms > MAX_TERMS {
+                    return Err(QueryError::new(
+                        *column,
+                        "too_large",
+                        format!(
+                            "With the groups it refers to the query has more than {} terms",
+                            MAX_TERMS
+                        ),
+                    ));
+                }
+                self.predicate(*column, predicate)?
+            }
+        })
+    }
+
+    fn predicate(&mut self, column: usize, predicate: &Predicate) -> Result<String, QueryError> {
+        Ok(match predicate {
+            Predicate::Text { value } => {
+                let pattern = self.pattern(&format!("*{}*", value));
+                format!(
+                    "(coalesce(given_name, '') ilike {0}
+                    or coalesce(family_name, '') ilike {0}
+                    or exists (select 1 from unnest(email_addresses) email where email ilike {0}))",
+                    pattern
+                )
+            }
+            Predicate::Name { pattern } => {
+                let pattern = self.pattern(pattern);
+                format!(
+                    "(coalesce(given_name, '') ilike {0}
+                    or coalesce(family_name, '') ilike {0}
+                    or concat_ws(' ', given_name, family_name) ilike {0})",
+                    pattern
+                )
+            }
+            Predicate::Email { pattern } => format!(
+                "exists (select 1 from unnest(email_addresses) email where email ilike {})",
+                self.pattern(pattern)
+            ),
+            Predicate::Phone { pattern } => {
+                let pattern: String = pattern.chars().filter(|c| !c.is_whitespace()).collect();
+                format!(
+                    "exists (select 1 from unnest(phone_numbers) phone where phone like {})",
+                    self.pattern(&pattern)
+                )
+            }
+            Predicate::City { pattern } => format!(
+                "exists (select 1 from jsonb_array_elements(addresses) address
+                where address->>'locality' ilike {})",
+                self.pattern(pattern)
+            ),
+            Predicate::Country { pattern } => {
+                format!("coalesce(country, '') ilike {}", self.pattern(pattern))
+            }
+            Predicate::Organization { pattern } => format!(
+                "exists (select 1 from organizations
+                where organizations.owner = contacts.owner
+                and organizations.name ilike {}
+                and contacts.affiliations @> jsonb_build_array(
+                    jsonb_build_object('organization_id', organizations.id)))",
+                self.pattern(pattern)
+            ),
+            Predicate::Tag { pattern } => format!(
+                "exists (select 1 from jsonb_array_elements(tags) tag where tag->>'name' ilike {})",
+                self.pattern(pattern)
+            ),
+            Predicate::Group { name } => self.group(column, name)?,
+            Predicate::Birthday { window } => self.date("birthday", *window),
+            Predicate::Anniversary { window } => self.date("anniversary", *window),
+            Predicate::Has { what } => String::from(match what {
+                Has::Photo => "photo is not null",
+                Has::Email => "cardinality(email_addresses) > 0",
+                Has::Phone => "cardinality(phone_numbers) > 0",
+                Has::Address => "jsonb_array_length(addresses) > 0",
+                Has::Birthday => "dates @> '[{\"kind\": \"birthday\"}]'",
+                Has::Tag => "jsonb_array_length(tags) > 0",
+                Has::Organization => "jsonb_array_length(affiliations) > 0",
+            }),
+            Predicate::Custom { key, pattern } => {
+                if !self
+                    .scope
+                    .custom_fields
+                    .iter()
+                    .any(|field| &field.key == key)
+                {
+                    return Err(QueryError::new(
+                        column,
+                        "unknown_field",
+                        format!("There is no built in or custom field `{}`", key),
+                    ));
+                }
+                let key = self.text(key);
+                format!("custom_fields->>{} ilike {}", key, self.pattern(pattern))
+            }
+        })
+    }
+
+    fn date(&mut self, kind: &'static str, window: Window) -> String {
+        let month_days = month_days(self.scope.today, window);
+        format!(
+            "exists (select 1 from jsonb_array_elements(dates) date
+            where date->>'kind' = '{}'
+            and lpad(date->>'month', 2, '0') || '-' || lpad(date->>'day', 2, '0') = any({}))",
+            kind,
+            self.bind(SqlValue::TextArray(month_days))
+        )
+    }
+
+    /// Inlines the query of another group
+    fn group(&mut self, column: usize, name: &str) -> Result<String, QueryError> {
+        let (name, query) = self
+            .scope
+            .groups
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                QueryError::new(
+                    column,
+                    "unknown_group",
+                    format!("There is no group named `{}`", name),
+                )
+            })?;
+
+        if self
+            .groups
+            .iter()
+            .any(|other| other.eq_ignore_ascii_case(name))
+        {
+            return Err(QueryError::new(
+                column,
+                "group_cycle",
+                format!("The group `{}` refers to itself", name),
+            ));
+        }
+        if self.groups.len() >= MAX_GROUP_DEPTH {
+            return Err(QueryError::new(
+                column,
+                "too_deep",
+                "Groups refer to each other too deeply",
+            ));
+        }
+
+        let expr = parse(query).map_err(|_| {
+            QueryError::new(
+                column,
+                "invalid_group",
+                format!("The query of the group `{}` is invalid", name),
+            )
+        })?;
+
+        self.groups.push(name.clone());
+        let sql = self.expr(&expr).map_err(|err| {
+            if matches!(err.code, "group_cycle" | "too_deep" | "too_large") {
+                QueryError { column, ..err }
+            } else {
+                QueryError::new(
+                    column,
+                    "invalid_group",
+                    format!("The query of the group `{}` is invalid", name),
+                )
+            }
+        })?;
+        self.groups.pop();
+
+        Ok(sql)
+    }
+}
+
+/// Compiles a parsed query. Placeholders are numbered from `first_param`, the values to bind
+/// are returned in order.
+pub fn compile(expr: &Expr, scope: &Scope, first_param: usize) -> Result<Compiled, QueryError> {
+    let mut compiler = Compiler {
+        scope,
+        first_param,
+        params: Vec::new(),
+        groups: scope.within.map(String::from).into_iter().collect(),
+        terms: 0,
+    };
+    let sql = compiler.expr(expr)?;
+
+    Ok(Compiled {
+        sql,
+        params: compiler.params,
+    })
+}
+
+/// Parses and compiles a query
+pub fn compile_query(
+    query: &str,
+    scope: &Scope,
+    first_param: usize,
+) -> Result<Compiled, QueryError> {
+    compile(&parse(query)?, scope, first_param)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(groups: &[(&str, &str)]) -> Vec<(String, String)> {
+        groups
+            .iter()
+            .map(|(name, query)| (name.to_string(), query.to_string()))
+            .collect()
+    }
+
+    fn compile_with(
+        query: &str,
+        groups: &[(String, String)],
+        within: Option<&str>,
+    ) -> Result<Compiled, QueryError> {
+        let scope = Scope {
+            today: NaiveDate::from_ymd_opt(2021, 2, 27).unwrap(),
+            custom_fields: &[],
+            groups,
+            within,
+        };
+        compile_query(query, &scope, 2)
+    }
+
+    #[test]
+    fn binds_values_from_first_param() {
+        let compiled = compile_with("name:Ann* tag:vip", &[], None).unwrap();
+
+        assert!(compiled.sql.contains("$2"));
+        assert!(compiled.sql.contains("$3"));
+        assert!(!compiled.sql.contains("$1"));
+        assert_eq!(
+            compiled.params,
+            vec![
+                SqlValue::Text(String::from("Ann%")),
+                SqlValue::Text(String::from("vip")),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_like_patterns() {
+        assert_eq!(like_pattern("50%_off*"), "50\\%\\_off%");
+    }
+
+    #[test]
+    fn includes_february_29_on_common_years() {
+        let today = NaiveDate::from_ymd_opt(2021, 2, 27).unwrap();
+        assert_eq!(
+            month_days(today, Window::Next { days: 3 }),
+            vec!["02-27", "02-28", "02-29", "03-01"]
+        );
+    }
+
+    #[test]
+    fn inlines_groups() {
+        let groups = groups(&[("Friends", "tag:friend")]);
+        let compiled = compile_with("group:friends has:phone", &groups, None).unwrap();
+
+        assert_eq!(
+            compiled.params,
+            vec![SqlValue::Text(String::from("friend"))]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_groups_at_their_column() {
+        let err = compile_with("has:phone group:nope", &[], None).unwrap_err();
+
+        assert_eq!(err.code, "unknown_group");
+        assert_eq!(err.column, 11);
+    }
+
+    #[test]
+    fn reports_unknown_fields_at_their_column() {
+        let err = compile_with("tag:x  shoe_size:42", &[], None).unwrap_err();
+
+        assert_eq!(err.code, "unknown_field");
+        assert_eq!(err.column, 8);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let groups = groups(&[("a", "group:b"), ("b", "tag:x OR group:a")]);
+
+        let err = compile_with("has:email group:a", &groups, None).unwrap_err();
+        assert_eq!(err.code, "group_cycle");
+        assert_eq!(err.column, 11);
+
+        let err = compile_with("group:a", &groups, Some("b")).unwrap_err();
+        assert_eq!(err.code, "group_cycle");
+        assert_eq!(err.column, 1);
+    }
+
+    #[test]
+    fn rejects_deep_chains() {
+        let queries: Vec<(String, String)> = (0..10)
+            .map(|level| (format!("g{}", level), format!("group:g{}", level + 1)))
+            .chain(std::iter::once((
+                String::from("g10"),
+                String::from("has:tag"),
+            )))
+            .collect();
+
+        let err = compile_with("group:g0", &queries, None).unwrap_err();
+        assert_eq!(err.code, "too_deep");
+        assert_eq!(err.column, 1);
+    }
+
+    #[test]
+    fn caps_the_expansion_of_groups() {
+        // Every level refers ten times to the one below, so g3 expands to 10000 terms
+        let mut queries = vec![(String::from("g0"), String::from("tag:x"))];
+        for level in 1..=3 {
+            let query = vec![format!("group:g{}", level - 1); 10].join(" OR ");
+            queries.push((format!("g{}", level), query));
+        }
+
+        assert!(compile_with("group:g2", &queries, None).is_ok());
+
+        let err = compile_with("has:tag group:g3", &queries, None).unwrap_err();
+        assert_eq!(err.code, "too_large");
+        assert_eq!(err.column, 9);
+    }
+}
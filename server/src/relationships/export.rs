@@ -0,0 +1,95 @@
+/*!
+Relationship graphs in formats understood by graph visualization tools
+
+- DOT, for Graphviz: https://graphviz.org/doc/info/lang.html
+- GraphML, for Gephi, yEd and Cytoscape: http://graphml.graphdrawing.org/
+*/
+
+use std::fmt::Write;
+
+use super::Graph;
+use crate::util::xml_escape;
+
+/// Escapes a DOT quoted string
+fn dot_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The graph as a Graphviz digraph. Reciprocal relationships are drawn with arrows on both
+/// ends.
+pub fn to_dot(graph: &Graph) -> String {
+    let mut dot = String::from("digraph relationships {\n");
+
+    for node in &graph.nodes {
+        let style = if node.id == graph.center {
+            ", style=bold"
+        } else {
+            ""
+        };
+        writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\"{}];",
+            node.id,
+            dot_escape(&node.name),
+            style
+        )
+        .unwrap();
+    }
+
+    for edge in &graph.edges {
+        let dir = if edge.reciprocal { ", dir=both" } else { "" };
+        writeln!(
+            dot,
+            "  \"{}\" -> \"{}\" [label=\"{}\"{}];",
+            edge.from,
+            edge.to,
+            edge.kind.as_str(),
+            dir
+        )
+        .unwrap();
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+/// The graph as a GraphML document, with the name and depth of each contact and the kind of
+/// each relationship as data attributes
+pub fn to_graphml(graph: &Graph) -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        "  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n",
+        "  <key id=\"depth\" for=\"node\" attr.name=\"depth\" attr.type=\"int\"/>\n",
+        "  <key id=\"kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>\n",
+        "  <key id=\"reciprocal\" for=\"edge\" attr.name=\"reciprocal\" attr.type=\"boolean\"/>\n",
+        "  <graph id=\"relationships\" edgedefault=\"directed\">\n",
+    ));
+
+    for node in &graph.nodes {
+        writeln!(
+            xml,
+            "    <node id=\"{}\"><data key=\"name\">{}</data><data key=\"depth\">{}</data></node>",
+            node.id,
+            xml_escape(&node.name),
+            node.depth
+        )
+        .unwrap();
+    }
+
+    for edge in &graph.edges {
+        writeln!(
+            xml,
+            "    <edge id=\"{}\" source=\"{}\" target=\"{}\"><data key=\"kind\">{}</data><data key=\"reciprocal\">{}</data></edge>",
+            edge.id,
+            edge.from,
+            edge.to,
+            edge.kind.as_str(),
+            edge.reciprocal
+        )
+        .unwrap();
+    }
+
+    xml.push_str("  </graph>\n</graphml>\n");
+    xml
+}
@@ -0,0 +1,346 @@
+/*!
+Typed links between contacts
+
+A relationship reads "`to` is the `kind` of `from`", e.g. a relationship from Alice to Bob of
+kind `manager` means Bob is the manager of Alice. Reciprocal relationships are also followed
+from the other end, where they read with the inverse kind: Alice is a report of Bob.
+*/
+
+pub mod export;
+pub mod rpc;
+
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::contacts::Contact;
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Hash, Debug, sqlx::Type,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "relationship_kind", rename_all = "snake_case")]
+pub enum RelationshipKind {
+    Spouse,
+    Parent,
+    Child,
+    Manager,
+    Report,
+    Colleague,
+    /// The contact who introduced `from` to the owner
+    IntroducedBy,
+    /// A contact `from` introduced to the owner
+    Introduced,
+}
+
+impl RelationshipKind {
+    /// Kind of the relationship read from the other end
+    pub fn inverse(self) -> Self {
+        match self {
+            RelationshipKind::Spouse => RelationshipKind::Spouse,
+            RelationshipKind::Parent => RelationshipKind::Child,
+            RelationshipKind::Child => RelationshipKind::Parent,
+            RelationshipKind::Manager => RelationshipKind::Report,
+            RelationshipKind::Report => RelationshipKind::Manager,
+            RelationshipKind::Colleague => RelationshipKind::Colleague,
+            RelationshipKind::IntroducedBy => RelationshipKind::Introduced,
+            RelationshipKind::Introduced => RelationshipKind::IntroducedBy,
+        }
+    }
+
+    /// Name of the kind as serialized, used as edge label in exports
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RelationshipKind::Spouse => "spouse",
+            RelationshipKind::Parent => "parent",
+            RelationshipKind::Child => "child",
+            RelationshipKind::Manager => "manager",
+            RelationshipKind::Report => "report",
+            RelationshipKind::Colleague => "colleague",
+            RelationshipKind::IntroducedBy => "introduced_by",
+            RelationshipKind::Introduced => "introduced",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity, FromRow)]
+#[event_sauce(entity_name = "relationships")]
+pub struct Relationship {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    pub from_contact: Uuid,
+    pub to_contact: Uuid,
+    pub kind: RelationshipKind,
+    /// Whether the relationship is also followed from `to_contact`
+    pub reciprocal: bool,
+}
+
+const COLUMNS: &str = "id, owner, from_contact, to_contact, kind, reciprocal";
+
+impl Relationship {
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "select {} from relationships where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Relationships starting at one of `contacts`, and reciprocal relationships ending at one
+    /// of them
+    pub async fn around(
+        pool: &PgPool,
+        owner: Uuid,
+        contacts: &[Uuid],
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "select {} from relationships
+            where owner = $1 and (from_contact = any($2) or (reciprocal and to_contact = any($2)))
+            order by kind",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(contacts)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Every relationship touching `contact`, reciprocal or not
+    pub async fn touching(
+        tx: &mut SqlxPgStoreTransaction,
+        contact: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "select {} from relationships where from_contact = $1 or to_contact = $1",
+            COLUMNS
+        ))
+        .bind(contact)
+        .fetch_all(tx.get())
+        .await
+    }
+
+    /// Whether the same link already exists
+    pub async fn exists(
+        pool: &PgPool,
+        from_contact: Uuid,
+        to_contact: Uuid,
+        kind: RelationshipKind,
+    ) -> Result<bool, sqlx::Error> {
+        let (exists,): (bool,) = sqlx::query_as(
+            "select exists (
+                select 1 from relationships
+                where from_contact = $1 and to_contact = $2 and kind = $3
+            )",
+        )
+        .bind(from_contact)
+        .bind(to_contact)
+        .bind(kind)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(exists)
+    }
+}
+
+/// A contact in a relationship graph
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Node {
+    pub id: Uuid,
+    pub name: String,
+    /// Number of relationships between the contact and the center of the graph
+    pub depth: u32,
+}
+
+/// A relationship as seen while walking the graph, `kind` is what `to` is of `from`
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Edge {
+    pub id: Uuid,
+    pub from: Uuid,
+    pub to: Uuid,
+    pub kind: RelationshipKind,
+    pub reciprocal: bool,
+}
+
+/// Contacts reachable from a contact and the relationships between them
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Graph {
+    pub center: Uuid,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Walks the relationships of `owner` breadth first, up to `depth` relationships away from
+    /// `center`
+    pub async fn around(
+        pool: &PgPool,
+        owner: Uuid,
+        center: &Contact,
+        depth: u32,
+    ) -> Result<Self, sqlx::Error> {
+        let mut depths: HashMap<Uuid, u32> = HashMap::new();
+        depths.insert(center.id, 0);
+        let mut seen_edges = HashSet::new();
+        let mut edges = Vec::new();
+        let mut frontier = vec![center.id];
+
+        for level in 1..=depth {
+            if frontier.is_empty() {
+                break;
+            }
+
+            let mut next = Vec::new();
+            for relationship in Relationship::around(pool, owner, &frontier).await? {
+                if seen_edges.insert(relationship.id) {
+                    edges.push(Edge {
+                        id: relationship.id,
+                        from: relationship.from_contact,
+                        to: relationship.to_contact,
+                        kind: relationship.kind,
+                        reciprocal: relationship.reciprocal,
+                    });
+                }
+
+                for contact in [relationship.from_contact, relationship.to_contact].iter() {
+                    if let Entry::Vacant(entry) = depths.entry(*contact) {
+                        entry.insert(level);
+                        next.push(*contact);
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        let ids: Vec<Uuid> = depths.keys().copied().collect();
+        let mut nodes: Vec<Node> = Contact::find_many(pool, owner, &ids)
+            .await?
+            .iter()
+            .map(|contact| Node {
+                id: contact.id,
+                name: contact.display_name(),
+                depth: depths[&contact.id],
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.depth.cmp(&b.depth).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(Graph {
+            center: center.id,
+            nodes,
+            edges,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Relationship)]
+pub struct RelationshipCreated {
+    pub owner: Uuid,
+    pub from_contact: Uuid,
+    pub to_contact: Uuid,
+    pub kind: RelationshipKind,
+    pub reciprocal: bool,
+}
+
+/// Changes the kind of a relationship or whether it is reciprocal. The contacts it links never
+/// change.
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Relationship)]
+pub struct RelationshipUpdated {
+    pub kind: RelationshipKind,
+    pub reciprocal: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Relationship)]
+pub struct RelationshipDeleted {}
+
+impl AggregateCreate<RelationshipCreated> for Relationship {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<RelationshipCreated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to create Relationship from RelationshipCreated event",
+        )?;
+
+        Ok(Relationship {
+            id: event.entity_id,
+            owner: data.owner,
+            from_contact: data.from_contact,
+            to_contact: data.to_contact,
+            kind: data.kind,
+            reciprocal: data.reciprocal,
+        })
+    }
+}
+
+impl AggregateUpdate<RelationshipUpdated> for Relationship {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<RelationshipUpdated>) -> Result<Self, Self::Error> {
+        let data = event.data.as_ref().ok_or(
+            "Event data must be populated to update Relationship from RelationshipUpdated event",
+        )?;
+
+        Ok(Relationship {
+            kind: data.kind,
+            reciprocal: data.reciprocal,
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<RelationshipDeleted> for Relationship {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(
+        self,
+        _event: &Event<RelationshipDeleted>,
+    ) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Relationship> for Relationship {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into relationships (id, owner, from_contact, to_contact, kind, reciprocal)
+            values ($1, $2, $3, $4, $5, $6)
+            on conflict (id) do update set
+                kind = excluded.kind,
+                reciprocal = excluded.reciprocal",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(self.from_contact)
+        .bind(self.to_contact)
+        .bind(self.kind)
+        .bind(self.reciprocal)
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Relationship {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from relationships where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
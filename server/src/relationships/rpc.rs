@@ -0,0 +1,307 @@
+use common::errors::AppError;
+use common::jsonrpc::{ById, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    export, Graph, Relationship, RelationshipCreated, RelationshipDeleted, RelationshipKind,
+    RelationshipUpdated,
+};
+use crate::{
+    contacts,
+    rpc::{self, Context, MethodResult},
+};
+
+/// Deepest graph a client can ask for, every level is one query
+const MAX_DEPTH: u32 = 5;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct CreateParams {
+    pub from_contact: Uuid,
+    pub to_contact: Uuid,
+    pub kind: RelationshipKind,
+    #[serde(default)]
+    pub reciprocal: bool,
+}
+
+impl Validate for CreateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.rule(
+            "/to_contact",
+            self.from_contact != self.to_contact,
+            "same_contact",
+            "A contact cannot be related to itself",
+        );
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    pub kind: RelationshipKind,
+    pub reciprocal: bool,
+}
+
+impl Validate for UpdateParams {}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct ListParams {
+    pub contact_id: Uuid,
+}
+
+impl Validate for ListParams {}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    #[default]
+    Json,
+    Dot,
+    Graphml,
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct GraphParams {
+    pub contact_id: Uuid,
+    #[serde(default = "GraphParams::default_depth")]
+    pub depth: u32,
+    #[serde(default)]
+    pub format: GraphFormat,
+}
+
+impl GraphParams {
+    fn default_depth() -> u32 {
+        1
+    }
+}
+
+impl Validate for GraphParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/depth", &self.depth).range(1, MAX_DEPTH);
+    }
+}
+
+/// Reply to `relationships.graph`, the graph itself or its export
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+pub enum GraphOutput {
+    Graph(Graph),
+    Text(String),
+}
+
+/// A relationship seen from one of the contacts it links
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Link {
+    pub relationship_id: Uuid,
+    /// The other contact
+    pub contact_id: Uuid,
+    /// What the other contact is of the contact the links were listed for
+    pub kind: RelationshipKind,
+    pub reciprocal: bool,
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Relationship, rpc::RpcError> {
+    Relationship::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `relationships.create`
+pub struct CreateRelationship;
+
+impl RpcMethod for CreateRelationship {
+    const NAME: &'static str = "relationships.create";
+    type Params = CreateParams;
+    type Result = Relationship;
+}
+
+/// `relationships.create`
+pub async fn create(
+    ctx: &Context<'_>,
+    CreateParams {
+        from_contact,
+        to_contact,
+        kind,
+        reciprocal,
+    }: CreateParams,
+) -> MethodResult<Relationship> {
+    contacts::rpc::find(ctx, from_contact).await?;
+    contacts::rpc::find(ctx, to_contact).await?;
+
+    let exists = Relationship::exists(&ctx.state.postgres, from_contact, to_contact, kind)
+        .await
+        .map_err(rpc::internal)?;
+    if exists {
+        return Err(AppError::AlreadyExists {
+            value: kind.as_str().to_string(),
+        }
+        .into());
+    }
+
+    let relationship = Relationship::try_create(RelationshipCreated {
+        owner: ctx.owner(),
+        from_contact,
+        to_contact,
+        kind,
+        reciprocal,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(relationship)
+}
+
+/// Definition of `relationships.list`
+pub struct ListRelationships;
+
+impl RpcMethod for ListRelationships {
+    const NAME: &'static str = "relationships.list";
+    type Params = ListParams;
+    type Result = Vec<Link>;
+}
+
+/// `relationships.list`
+///
+/// Relationships of a contact as seen from it: outgoing relationships as they were created
+/// and reciprocal incoming relationships with the inverse kind.
+pub async fn list(
+    ctx: &Context<'_>,
+    ListParams { contact_id }: ListParams,
+) -> MethodResult<Vec<Link>> {
+    contacts::rpc::find(ctx, contact_id).await?;
+
+    let relationships = Relationship::around(&ctx.state.postgres, ctx.owner(), &[contact_id])
+        .await
+        .map_err(rpc::internal)?;
+
+    let links: Vec<Link> = relationships
+        .into_iter()
+        .map(|relationship| {
+            if relationship.from_contact == contact_id {
+                Link {
+                    relationship_id: relationship.id,
+                    contact_id: relationship.to_contact,
+                    kind: relationship.kind,
+                    reciprocal: relationship.reciprocal,
+                }
+            } else {
+                Link {
+                    relationship_id: relationship.id,
+                    contact_id: relationship.from_contact,
+                    kind: relationship.kind.inverse(),
+                    reciprocal: relationship.reciprocal,
+                }
+            }
+        })
+        .collect();
+
+    rpc::ok(links)
+}
+
+/// Definition of `relationships.update`
+pub struct UpdateRelationship;
+
+impl RpcMethod for UpdateRelationship {
+    const NAME: &'static str = "relationships.update";
+    type Params = UpdateParams;
+    type Result = Relationship;
+}
+
+/// `relationships.update`
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams {
+        id,
+        kind,
+        reciprocal,
+    }: UpdateParams,
+) -> MethodResult<Relationship> {
+    let relationship = find(ctx, id).await?;
+
+    if kind != relationship.kind {
+        let exists = Relationship::exists(
+            &ctx.state.postgres,
+            relationship.from_contact,
+            relationship.to_contact,
+            kind,
+        )
+        .await
+        .map_err(rpc::internal)?;
+        if exists {
+            return Err(AppError::AlreadyExists {
+                value: kind.as_str().to_string(),
+            }
+            .into());
+        }
+    }
+
+    let relationship = relationship
+        .try_update(RelationshipUpdated { kind, reciprocal })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(relationship)
+}
+
+/// Definition of `relationships.delete`
+pub struct DeleteRelationship;
+
+impl RpcMethod for DeleteRelationship {
+    const NAME: &'static str = "relationships.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `relationships.delete`
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    find(ctx, id)
+        .await?
+        .try_delete(RelationshipDeleted {})
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `relationships.graph`
+pub struct RelationshipGraph;
+
+impl RpcMethod for RelationshipGraph {
+    const NAME: &'static str = "relationships.graph";
+    type Params = GraphParams;
+    type Result = GraphOutput;
+}
+
+/// `relationships.graph`
+///
+/// Contacts up to `depth` relationships away from a contact, as JSON or exported as DOT or
+/// GraphML text
+pub async fn graph(
+    ctx: &Context<'_>,
+    GraphParams {
+        contact_id,
+        depth,
+        format,
+    }: GraphParams,
+) -> MethodResult<GraphOutput> {
+    let center = contacts::rpc::find(ctx, contact_id).await?;
+    let graph = Graph::around(&ctx.state.postgres, ctx.owner(), &center, depth)
+        .await
+        .map_err(rpc::internal)?;
+
+    match format {
+        GraphFormat::Json => rpc::ok(GraphOutput::Graph(graph)),
+        GraphFormat::Dot => rpc::ok(GraphOutput::Text(export::to_dot(&graph))),
+        GraphFormat::Graphml => rpc::ok(GraphOutput::Text(export::to_graphml(&graph))),
+    }
+}
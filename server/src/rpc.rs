@@ -0,0 +1,426 @@
+/*!
+JSON-RPC endpoint and method dispatch
+*/
+
+use std::fmt::Debug;
+use std::future::Future;
+
+use common::errors::AppError;
+use common::jsonrpc::{
+    JSONRPCError, JSONRPCErrorObject, JSONRPCRequest, JSONRPCSuccess, RpcMethod,
+};
+use common::openrpc::Discover;
+use common::validation::{Validate, ValidationErrors};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use serde_path_to_error::Segment;
+use tide::{Request, Response};
+use uuid::Uuid;
+
+use crate::{
+    app_passwords, bulk, contacts, custom_fields, discover, groups, interactions, jobs,
+    keycloak::{KeycloakClaims, RequestActor},
+    organizations, relationships, settings,
+    state::State,
+    streams, tags, webhooks, ws,
+};
+
+/// Error returned by RPC methods
+pub type RpcError = JSONRPCError<JSONRPCErrorObject<AppError>>;
+
+/// Result returned by a method handler, with the result type of its [`RpcMethod`]
+pub type MethodResult<T> = Result<JSONRPCSuccess<T>, RpcError>;
+
+/// Result of any method, serialized
+pub type RpcResult = MethodResult<Value>;
+
+/// Everything a method needs to know about the request that invoked it
+pub struct Context<'a> {
+    pub state: &'a State,
+    pub actor: &'a KeycloakClaims,
+}
+
+impl<'a> Context<'a> {
+    /// The `sub` of the user that owns the data being accessed
+    pub fn owner(&self) -> Uuid {
+        self.actor.sub
+    }
+}
+
+/// Deserializes the method params into the type the method expects.
+///
+/// Missing params are read as an empty object so methods with optional params can be called
+/// without any. A missing or mistyped field is reported as a [`ValidationErrors`] at its JSON
+/// pointer, like the rules of the params; params that are not an object at all are
+/// `InvalidParams`.
+pub fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params,
+    };
+
+    serde_path_to_error::deserialize(params).map_err(|err| {
+        log::debug!("Invalid params: {:?}", err);
+        let mut path = pointer(err.path());
+        let message = err.into_inner().to_string();
+
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'));
+
+        let mut errors = ValidationErrors::new();
+        match missing {
+            Some(field) => {
+                path.push('/');
+                path.push_str(&escape_pointer(field));
+                errors.add(path, "required", "This field is required");
+            }
+            None if path.is_empty() => {
+                return RpcError::from(AppError::InvalidParams { message });
+            }
+            None => errors.add(path, "invalid_value", message),
+        }
+        RpcError::from(errors)
+    })
+}
+
+/// JSON pointer to the value a deserialization error was found at
+fn pointer(path: &serde_path_to_error::Path) -> String {
+    let mut pointer = String::new();
+    for segment in path.iter() {
+        let token = match segment {
+            Segment::Seq { index } => index.to_string(),
+            Segment::Map { key } => escape_pointer(key),
+            Segment::Enum { variant } => escape_pointer(variant),
+            Segment::Unknown => break,
+        };
+        pointer.push('/');
+        pointer.push_str(&token);
+    }
+    pointer
+}
+
+/// Escapes a key for use as a JSON pointer token, see RFC 6901
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// 200 OK with the result
+pub fn ok<T: Serialize>(result: T) -> MethodResult<T> {
+    Ok(JSONRPCSuccess::ok(result))
+}
+
+/// 201 Created with the result
+pub fn created<T: Serialize>(result: T) -> MethodResult<T> {
+    Ok(JSONRPCSuccess::created(result))
+}
+
+/// Serializes the result of a method
+pub fn into_value<T: Serialize>(result: MethodResult<T>) -> RpcResult {
+    result?.into_value().map_err(internal)
+}
+
+/// Logs an unexpected error and hides the details from the client
+pub fn internal(err: impl Debug) -> RpcError {
+    log::error!("Internal error: {:?}", err);
+    AppError::Internal.into()
+}
+
+/// 404 for entities that do not exist or belong to someone else
+pub fn not_found() -> RpcError {
+    AppError::NotFound.into()
+}
+
+/// Calls the handler of a method with the params read as its `Params`, the result of the
+/// handler has to be the `Result` of the method. Params that break their [`Validate`] rules
+/// fail with every field that broke one, before the handler runs.
+async fn call<M, F, Fut>(params: Value, handler: F) -> RpcResult
+where
+    M: RpcMethod,
+    M::Params: DeserializeOwned,
+    M::Result: Serialize,
+    F: FnOnce(M::Params) -> Fut,
+    Fut: Future<Output = MethodResult<M::Result>>,
+{
+    let params: M::Params = self::params(params)?;
+    params.validate()?;
+
+    into_value(handler(params).await)
+}
+
+/// Defines [`route`], matching a method name against the `NAME` of each [`RpcMethod`] to call
+/// the handler registered for it
+macro_rules! routes {
+    ($($definition:ty => $handler:path,)*) => {
+        /// Names of the methods [`route`] calls
+        #[cfg(test)]
+        const ROUTED: &[&str] = &[$(<$definition as RpcMethod>::NAME,)*];
+
+        /// Calls the handler of a method, once [`authorize`] let the caller in
+        pub async fn route(ctx: &Context<'_>, method: &str, params: Value) -> RpcResult {
+            match method {
+                $(
+                    <$definition as RpcMethod>::NAME => {
+                        call::<$definition, _, _>(params, |params| $handler(ctx, params)).await
+                    }
+                )*
+                method => Err(method_not_found(method)),
+            }
+        }
+    };
+}
+
+fn method_not_found(method: &str) -> RpcError {
+    RpcError::from(AppError::MethodNotFound {
+        method: String::from(method),
+    })
+    .message("Method not found")
+}
+
+/// Fails unless the method is documented and the caller has every role it needs
+pub fn authorize(ctx: &Context<'_>, method: &str) -> Result<(), RpcError> {
+    let needed = discover::roles(method).ok_or_else(|| method_not_found(method))?;
+
+    let roles = &ctx.actor.realm_access.roles;
+    let missing_roles: Vec<String> = needed
+        .iter()
+        .filter(|role| !roles.contains(role))
+        .cloned()
+        .collect();
+    if !missing_roles.is_empty() {
+        log::debug!("{} lacks a role to call {}", ctx.actor.sub, method);
+        return Err(AppError::Forbidden { missing_roles }.into());
+    }
+
+    Ok(())
+}
+
+/// Calls a method, for every transport
+pub async fn dispatch(ctx: &Context<'_>, method: &str, params: Value) -> RpcResult {
+    authorize(ctx, method)?;
+
+    if method == ws::SubscribeContacts::NAME || method == ws::UnsubscribeContacts::NAME {
+        return Err(RpcError::from(AppError::WebSocketOnly)
+            .message("Subscriptions need a WebSocket connection, see /ws"));
+    }
+
+    route(ctx, method, params).await
+}
+
+routes! {
+    app_passwords::rpc::CreateAppPassword => app_passwords::rpc::create,
+    app_passwords::rpc::ListAppPasswords => app_passwords::rpc::list,
+    app_passwords::rpc::DeleteAppPassword => app_passwords::rpc::delete,
+    bulk::rpc::UpdateMany => bulk::rpc::update,
+    bulk::rpc::DeleteMany => bulk::rpc::delete,
+    bulk::rpc::TagMany => bulk::rpc::tag,
+    bulk::rpc::ExportMany => bulk::rpc::export,
+    contacts::rpc::CreateContact => contacts::rpc::create,
+    contacts::rpc::GetContact => contacts::rpc::get,
+    contacts::rpc::ListContacts => contacts::rpc::list,
+    contacts::rpc::UpdateContact => contacts::rpc::update,
+    contacts::rpc::DeleteContact => contacts::rpc::delete,
+    contacts::rpc::SearchContacts => contacts::rpc::search,
+    contacts::rpc::FindDuplicates => contacts::rpc::duplicates,
+    contacts::rpc::ExportVcard => contacts::rpc::to_vcard,
+    contacts::rpc::FormatAddress => contacts::rpc::format_address,
+    contacts::rpc::UpcomingDates => contacts::rpc::upcoming,
+    contacts::rpc::SuggestContacts => contacts::rpc::suggest,
+    contacts::rpc::SyncContacts => contacts::rpc::sync,
+    custom_fields::rpc::CreateField => custom_fields::rpc::create,
+    custom_fields::rpc::ListFields => custom_fields::rpc::list,
+    custom_fields::rpc::UpdateField => custom_fields::rpc::update,
+    custom_fields::rpc::DeleteField => custom_fields::rpc::delete,
+    groups::rpc::CreateGroup => groups::rpc::create,
+    groups::rpc::ListGroups => groups::rpc::list,
+    groups::rpc::UpdateGroup => groups::rpc::update,
+    groups::rpc::DeleteGroup => groups::rpc::delete,
+    groups::rpc::GroupContacts => groups::rpc::contacts,
+    groups::rpc::PreviewGroup => groups::rpc::preview,
+    groups::rpc::ParseQuery => groups::rpc::parse,
+    interactions::rpc::CreateInteraction => interactions::rpc::create,
+    interactions::rpc::GetInteraction => interactions::rpc::get,
+    interactions::rpc::UpdateInteraction => interactions::rpc::update,
+    interactions::rpc::DeleteInteraction => interactions::rpc::delete,
+    interactions::rpc::ListInteractions => interactions::rpc::list,
+    jobs::rpc::GetJob => jobs::rpc::get,
+    jobs::rpc::ListJobs => jobs::rpc::list,
+    jobs::rpc::CancelJob => jobs::rpc::cancel,
+    organizations::rpc::CreateOrganization => organizations::rpc::create,
+    organizations::rpc::GetOrganization => organizations::rpc::get,
+    organizations::rpc::ListOrganizations => organizations::rpc::list,
+    organizations::rpc::UpdateOrganization => organizations::rpc::update,
+    organizations::rpc::DeleteOrganization => organizations::rpc::delete,
+    organizations::rpc::OrganizationPeople => organizations::rpc::people,
+    organizations::rpc::SuggestOrganizations => organizations::rpc::suggest,
+    relationships::rpc::CreateRelationship => relationships::rpc::create,
+    relationships::rpc::ListRelationships => relationships::rpc::list,
+    relationships::rpc::UpdateRelationship => relationships::rpc::update,
+    relationships::rpc::DeleteRelationship => relationships::rpc::delete,
+    relationships::rpc::RelationshipGraph => relationships::rpc::graph,
+    settings::rpc::GetSettings => settings::rpc::get,
+    settings::rpc::UpdateSettings => settings::rpc::update,
+    streams::rpc::IssueTicket => streams::rpc::ticket,
+    tags::rpc::CreateTag => tags::rpc::create,
+    tags::rpc::ListTags => tags::rpc::list,
+    tags::rpc::UpdateTag => tags::rpc::update,
+    tags::rpc::DeleteTag => tags::rpc::delete,
+    tags::rpc::TagContacts => tags::rpc::contacts,
+    tags::rpc::AddTags => tags::rpc::add,
+    tags::rpc::RemoveTags => tags::rpc::remove,
+    webhooks::rpc::CreateWebhook => webhooks::rpc::create,
+    webhooks::rpc::ListWebhooks => webhooks::rpc::list,
+    webhooks::rpc::UpdateWebhook => webhooks::rpc::update,
+    webhooks::rpc::DeleteWebhook => webhooks::rpc::delete,
+    webhooks::rpc::ListDeliveries => webhooks::rpc::deliveries,
+    webhooks::rpc::Redeliver => webhooks::rpc::redeliver,
+    Discover => discover::discover,
+}
+
+/// Entry point of every RPC call
+pub async fn handler(mut req: Request<State>) -> tide::Result {
+    let rpc: JSONRPCRequest = match req.body_json().await {
+        Ok(rpc) => rpc,
+        Err(err) => {
+            log::debug!("Invalid RPC request: {:?}", err);
+            return Ok(RpcError::from(AppError::InvalidRequest).into());
+        }
+    };
+
+    let result = if rpc.method == Discover::NAME {
+        // Clients read the description before signing in
+        into_value(ok(discover::document()))
+    } else {
+        let actor = match req.ext::<RequestActor>().and_then(|actor| actor.as_ref()) {
+            Some(actor) => actor,
+            None => {
+                log::debug!("Anonymous call to {}", rpc.method);
+                return Ok(Response::from(RpcError::from(AppError::Unauthorized)));
+            }
+        };
+
+        let ctx = Context {
+            state: req.state(),
+            actor,
+        };
+
+        log::debug!("Calling {} as {}", rpc.method, actor.sub);
+        dispatch(&ctx, &rpc.method, rpc.params).await
+    };
+
+    let response = match (result, rpc.id) {
+        (Ok(success), Some(id)) => success.id(id).into(),
+        (Ok(success), None) => success.into(),
+        (Err(error), Some(id)) => error.id(id).into(),
+        (Err(error), None) => error.into(),
+    };
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug)]
+    struct Person {
+        name: String,
+        #[serde(default)]
+        phones: Vec<Phone>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Phone {
+        #[allow(dead_code)]
+        number: String,
+    }
+
+    /// Path and code of every failed field
+    fn failed(value: Value) -> Vec<(String, String)> {
+        match params::<Person>(value).unwrap_err().into_error().data {
+            AppError::ValidationFailed(errors) => errors
+                .errors
+                .into_iter()
+                .map(|error| (error.path, error.code))
+                .collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn params_are_read() {
+        let person: Person = params(json!({ "name": "Ada" })).unwrap();
+        assert_eq!(person.name, "Ada");
+        assert!(person.phones.is_empty());
+    }
+
+    #[test]
+    fn missing_fields_are_required() {
+        assert_eq!(
+            failed(Value::Null),
+            vec![(String::from("/name"), String::from("required"))]
+        );
+        assert_eq!(
+            failed(json!({ "name": "Ada", "phones": [{}] })),
+            vec![(String::from("/phones/0/number"), String::from("required"))]
+        );
+    }
+
+    #[test]
+    fn mistyped_fields_are_invalid() {
+        assert_eq!(
+            failed(json!({ "name": 1 })),
+            vec![(String::from("/name"), String::from("invalid_value"))]
+        );
+        assert_eq!(
+            failed(json!({ "name": "Ada", "phones": [{ "number": "1" }, { "number": false }] })),
+            vec![(
+                String::from("/phones/1/number"),
+                String::from("invalid_value")
+            )]
+        );
+    }
+
+    #[test]
+    fn params_other_than_an_object_are_invalid() {
+        let error = params::<Person>(json!("Ada")).unwrap_err().into_error();
+        assert!(matches!(error.data, AppError::InvalidParams { .. }));
+    }
+
+    #[test]
+    fn pointer_tokens_are_escaped() {
+        assert_eq!(escape_pointer("a/b~c"), "a~1b~0c");
+    }
+
+    #[test]
+    fn routed_methods_are_documented() {
+        let documented: Vec<String> = discover::document()
+            .methods
+            .into_iter()
+            .map(|method| method.name)
+            .collect();
+
+        for method in ROUTED
+            .iter()
+            .chain(&[ws::SubscribeContacts::NAME, ws::UnsubscribeContacts::NAME])
+        {
+            assert!(documented.iter().any(|name| name == method), "{}", method);
+        }
+        assert_eq!(documented.len(), ROUTED.len() + 2);
+    }
+
+    #[test]
+    fn roles_of_methods() {
+        assert_eq!(discover::roles("contacts.drop_all"), None);
+        assert_eq!(
+            discover::roles("contacts.get").map(<[String]>::len),
+            Some(0)
+        );
+        assert_eq!(
+            discover::roles("webhooks.create"),
+            Some(&[String::from("webhooks")][..])
+        );
+    }
+}
@@ -0,0 +1,133 @@
+/*!
+Per user preferences
+*/
+
+pub mod rpc;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use common::contacts::phone;
+use common::validation::{Validate, ValidationErrors};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Preferences of a single user, identified by its `sub`
+#[derive(Serialize, Deserialize, JsonSchema, FromRow, Clone, Debug, Default)]
+pub struct UserSettings {
+    /// ISO 3166-1 alpha-2 region used to parse phone numbers written in national format
+    pub default_region: Option<String>,
+    /// IANA time zone, e.g. `Europe/Madrid`, used to decide which day it is for the user
+    pub time_zone: Option<String>,
+}
+
+impl Validate for UserSettings {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors
+            .field("/default_region", &self.default_region)
+            .satisfies(
+                |region| phone::parse_region(&region.trim().to_uppercase()).is_some(),
+                "invalid_region",
+                "Unknown region",
+            );
+        errors.field("/time_zone", &self.time_zone).satisfies(
+            |time_zone| time_zone.parse::<Tz>().is_ok(),
+            "invalid_time_zone",
+            "Unknown time zone",
+        );
+    }
+}
+
+impl UserSettings {
+    /// Time zone of the user, UTC when none was set
+    pub fn tz(&self) -> Tz {
+        self.time_zone
+            .as_deref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    /// Current date in the time zone of the user
+    pub fn today(&self) -> NaiveDate {
+        self.date_at(Utc::now())
+    }
+
+    /// Date it is for the user at `time`
+    pub fn date_at(&self, time: DateTime<Utc>) -> NaiveDate {
+        time.with_timezone(&self.tz()).naive_local().date()
+    }
+
+    /// Loads the settings of a user, falling back to the defaults if none were saved yet
+    pub async fn load(pool: &PgPool, owner: Uuid) -> Result<Self, sqlx::Error> {
+        let settings =
+            sqlx::query_as("select default_region, time_zone from user_settings where owner = $1")
+                .bind(owner)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(settings.unwrap_or_default())
+    }
+
+    pub async fn save(&self, pool: &PgPool, owner: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into user_settings (owner, default_region, time_zone) values ($1, $2, $3)
+            on conflict (owner) do update set
+                default_region = excluded.default_region,
+                time_zone = excluded.time_zone",
+        )
+        .bind(owner)
+        .bind(&self.default_region)
+        .bind(&self.time_zone)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn in_zone(time_zone: Option<&str>) -> UserSettings {
+        UserSettings {
+            default_region: None,
+            time_zone: time_zone.map(String::from),
+        }
+    }
+
+    #[test]
+    fn the_date_depends_on_the_time_zone() {
+        let time = Utc.with_ymd_and_hms(2024, 2, 28, 23, 30, 0).unwrap();
+
+        assert_eq!(
+            in_zone(Some("Pacific/Auckland")).date_at(time),
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        );
+        assert_eq!(
+            in_zone(Some("America/Los_Angeles")).date_at(time),
+            NaiveDate::from_ymd_opt(2024, 2, 28).unwrap()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_utc() {
+        let time = Utc.with_ymd_and_hms(2023, 12, 31, 23, 59, 59).unwrap();
+
+        assert_eq!(
+            in_zone(None).date_at(time),
+            NaiveDate::from_ymd_opt(2023, 12, 31).unwrap()
+        );
+        assert_eq!(
+            in_zone(Some("Not/AZone")).date_at(time),
+            NaiveDate::from_ymd_opt(2023, 12, 31).unwrap()
+        );
+        assert_eq!(
+            in_zone(Some("Asia/Tokyo")).date_at(time),
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use common::jsonrpc::{NoParams, RpcMethod};
+
+use super::UserSettings;
+use crate::rpc::{self, Context, MethodResult};
+
+/// Definition of `settings.get`
+pub struct GetSettings;
+
+impl RpcMethod for GetSettings {
+    const NAME: &'static str = "settings.get";
+    type Params = NoParams;
+    type Result = UserSettings;
+}
+
+/// `settings.get`
+pub async fn get(ctx: &Context<'_>, _params: NoParams) -> MethodResult<UserSettings> {
+    let settings = UserSettings::load(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(settings)
+}
+
+/// Definition of `settings.update`
+pub struct UpdateSettings;
+
+impl RpcMethod for UpdateSettings {
+    const NAME: &'static str = "settings.update";
+    type Params = UserSettings;
+    type Result = UserSettings;
+}
+
+/// `settings.update`
+pub async fn update(ctx: &Context<'_>, mut settings: UserSettings) -> MethodResult<UserSettings> {
+    settings.default_region = settings
+        .default_region
+        .map(|region| region.trim().to_uppercase())
+        .filter(|region| !region.is_empty());
+
+    settings
+        .save(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(settings)
+}
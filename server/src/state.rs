@@ -1,8 +1,12 @@
-use crate::keycloak::JWKS;
+use crate::{keycloak::JWKS, streams::Hub};
+use event_sauce_storage_sqlx::SqlxPgStore;
 use sqlx::PgPool;
 
 #[derive(Clone)]
 pub struct State {
     pub postgres: PgPool,
+    pub store: SqlxPgStore,
     pub auth_keys: JWKS,
+    /// Open live streams, woken when changes are committed
+    pub changes: Hub,
 }
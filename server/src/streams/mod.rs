@@ -0,0 +1,441 @@
+/*!
+Live change stream over Server-Sent Events
+
+`GET /stream?ticket=<ticket>` streams the changes to the contacts of the owner of the ticket
+as they are committed. Tickets come from `streams.ticket`, they are valid once and for a
+minute, so no long-lived token ends up in URLs or server logs.
+
+Changes are recorded in the `contact_changes` table in the transaction of the change, and a
+`contact_changes` notification wakes the streams of the owner once it is committed. Each change
+is sent as an event named after its type, e.g. `contact.updated`, with the contact as data and
+its sequence number as id. Browsers send the id of the last event they received in the
+`Last-Event-ID` header when they reconnect, and the stream resumes after it. When changes after
+it were already purged, a `resync` event comes first instead, telling the client to start over
+with a full `contacts.sync`, and the stream resumes after the latest change. Streams opened
+without it start after the changes committed when their ticket was issued. A `heartbeat`
+event is sent when nothing happened for [`HEARTBEAT_INTERVAL`], to keep proxies from closing
+the connection.
+
+Sequence numbers grow in commit order for each owner: recording a change takes a lock on the
+owner held until the transaction ends, so a stream never sees a change after one with a
+higher number.
+
+Changes are kept for [`RETENTION_DAYS`] days. [`purge`] deletes older ones and remembers the
+highest sequence number it deleted for each owner, so sync tokens from before it are known
+to be too old rather than silently missing changes.
+*/
+
+pub mod rpc;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future, task,
+};
+use chrono::{DateTime, Utc};
+use common::errors::AppError;
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{postgres::PgListener, types::Json, FromRow, PgPool};
+use tide::{sse, Request, Response};
+use uuid::Uuid;
+
+use crate::{rpc::RpcError, state::State, util::hash_hex};
+
+/// Time after which an idle stream sends a heartbeat
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Changes sent at once when a stream catches up
+const BATCH: i64 = 500;
+
+/// Notification channel of committed changes, the payload is the owner
+const CHANNEL: &str = "contact_changes";
+
+/// Time the listener waits before reconnecting after an error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Days changes are kept for
+pub const RETENTION_DAYS: i32 = 90;
+
+/// Time between purges of old changes
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Single use ticket opening a stream with `GET /stream?ticket=<ticket>`
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Ticket {
+    pub ticket: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Open streams by owner, woken by [`listen`] when a change of the owner is committed
+#[derive(Clone, Default)]
+pub struct Hub {
+    streams: Arc<Mutex<HashMap<Uuid, Vec<Sender<()>>>>>,
+}
+
+impl Hub {
+    /// Receives a wake whenever a change of `owner` is committed
+    pub(crate) fn subscribe(&self, owner: Uuid) -> Receiver<()> {
+        // A single pending wake is enough, the stream reads every change when it wakes
+        let (sender, receiver) = channel::bounded(1);
+        let mut streams = self.streams.lock().unwrap();
+        let senders = streams.entry(owner).or_default();
+        senders.retain(|sender| !sender.is_closed());
+        senders.push(sender);
+
+        receiver
+    }
+
+    fn wake(&self, owner: Uuid) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(senders) = streams.get_mut(&owner) {
+            senders.retain(|sender| !sender.is_closed());
+            for sender in senders.iter() {
+                let _ = sender.try_send(());
+            }
+            if senders.is_empty() {
+                streams.remove(&owner);
+            }
+        }
+    }
+}
+
+/// Records a change of a contact for the streams of its owner
+pub async fn record<T: Serialize + Sync>(
+    tx: &mut SqlxPgStoreTransaction,
+    owner: Uuid,
+    contact_id: Uuid,
+    event_type: &str,
+    contact: &T,
+) -> Result<(), sqlx::Error> {
+    // Keeps sequence numbers in commit order, see the module documentation
+    sqlx::query("select pg_advisory_xact_lock(hashtext($1::text))")
+        .bind(owner)
+        .execute(tx.get())
+        .await?;
+
+    sqlx::query(
+        "insert into contact_changes (owner, contact_id, event_type, contact)
+        values ($1, $2, $3, $4)",
+    )
+    .bind(owner)
+    .bind(contact_id)
+    .bind(event_type)
+    .bind(Json(contact))
+    .execute(tx.get())
+    .await?;
+
+    sqlx::query("select pg_notify($1, $2::text)")
+        .bind(CHANNEL)
+        .bind(owner)
+        .execute(tx.get())
+        .await?;
+
+    Ok(())
+}
+
+#[derive(FromRow)]
+pub(crate) struct Change {
+    pub sequence: i64,
+    pub event_type: String,
+    pub contact: Json<Value>,
+}
+
+/// Changes of `owner` after the `after` sequence number
+pub(crate) async fn changes(
+    pool: &PgPool,
+    owner: Uuid,
+    after: i64,
+) -> Result<Vec<Change>, sqlx::Error> {
+    sqlx::query_as(
+        "select sequence, event_type, contact from contact_changes
+        where owner = $1 and sequence > $2
+        order by sequence
+        limit $3",
+    )
+    .bind(owner)
+    .bind(after)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+}
+
+/// Sequence number of the latest change of `owner`, 0 when there is none. Purged changes
+/// count, so the number never goes back.
+pub(crate) async fn latest(pool: &PgPool, owner: Uuid) -> Result<i64, sqlx::Error> {
+    let (sequence,) = sqlx::query_as(
+        "select greatest(
+            (select coalesce(max(sequence), 0) from contact_changes where owner = $1),
+            (select coalesce(max(purged_through), 0) from contact_change_horizons
+                where owner = $1)
+        )",
+    )
+    .bind(owner)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(sequence)
+}
+
+/// Highest sequence number purged for `owner`, changes after it are all kept
+pub(crate) async fn purged_through(pool: &PgPool, owner: Uuid) -> Result<i64, sqlx::Error> {
+    let purged: Option<(i64,)> =
+        sqlx::query_as("select purged_through from contact_change_horizons where owner = $1")
+            .bind(owner)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(purged.map_or(0, |(sequence,)| sequence))
+}
+
+/// Latest change of a contact in a range of sequence numbers
+#[derive(FromRow, Debug)]
+pub(crate) struct ContactChange {
+    pub contact_id: Uuid,
+    pub sequence: i64,
+    pub event_type: String,
+}
+
+/// Latest change of each contact of `owner` changed after the `after` sequence number, up to
+/// `until`, in the order of their latest change. At most `limit` of them when given.
+pub(crate) async fn changed_contacts(
+    pool: &PgPool,
+    owner: Uuid,
+    after: i64,
+    until: i64,
+    limit: Option<i64>,
+) -> Result<Vec<ContactChange>, sqlx::Error> {
+    sqlx::query_as(
+        "select * from (
+            select distinct on (contact_id) contact_id, sequence, event_type
+            from contact_changes
+            where owner = $1 and sequence > $2 and sequence <= $3
+            order by contact_id, sequence desc
+        ) latest
+        order by sequence
+        limit $4",
+    )
+    .bind(owner)
+    .bind(after)
+    .bind(until)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Deletes the changes older than [`RETENTION_DAYS`], returns how many were deleted
+pub async fn purge(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "insert into contact_change_horizons (owner, purged_through)
+        select owner, max(sequence) from contact_changes
+        where created_at < now() - make_interval(days => $1)
+        group by owner
+        on conflict (owner) do update set purged_through = greatest(
+            contact_change_horizons.purged_through,
+            excluded.purged_through
+        )",
+    )
+    .bind(RETENTION_DAYS)
+    .execute(&mut tx)
+    .await?;
+
+    // Purging every change up to the horizon, a change committed late with an old
+    // timestamp must not outlive the ones before it
+    let deleted = sqlx::query(
+        "delete from contact_changes changes
+        using contact_change_horizons horizons
+        where horizons.owner = changes.owner and changes.sequence <= horizons.purged_through",
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(deleted.rows_affected())
+}
+
+/// Purges old changes every [`PURGE_INTERVAL`], for as long as the process runs
+pub async fn purge_periodically(pool: PgPool) {
+    loop {
+        match purge(&pool).await {
+            Ok(0) => {}
+            Ok(deleted) => log::info!("Purged {} old contact changes", deleted),
+            Err(err) => log::error!("Error purging old contact changes: {:?}", err),
+        }
+        task::sleep(PURGE_INTERVAL).await;
+    }
+}
+
+/// Wakes the streams of owners whose changes were committed, for as long as the server runs
+pub async fn listen(pool: PgPool, hub: Hub) {
+    loop {
+        if let Err(err) = listen_once(&pool, &hub).await {
+            log::error!("Lost the change notifications: {:?}", err);
+        }
+        task::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once(pool: &PgPool, hub: &Hub) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+        match notification.payload().parse() {
+            Ok(owner) => hub.wake(owner),
+            Err(_) => log::warn!("Unexpected change notification {:?}", notification),
+        }
+    }
+}
+
+/// Issues a ticket for a stream of `owner`. Unless it resumes from a `Last-Event-ID`, the
+/// stream starts with the changes committed after the ticket was issued.
+pub async fn issue_ticket(pool: &PgPool, owner: Uuid) -> Result<Ticket, sqlx::Error> {
+    let ticket = format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    );
+
+    sqlx::query("delete from stream_tickets where expires_at < now()")
+        .execute(pool)
+        .await?;
+
+    // Tickets are stored hashed, like passwords
+    let (expires_at,) = sqlx::query_as(
+        "insert into stream_tickets (ticket_hash, owner, after_sequence, expires_at)
+        select $1, $2, coalesce(max(sequence), 0), now() + interval '1 minute'
+        from contact_changes where owner = $2
+        returning expires_at",
+    )
+    .bind(hash_hex(&ticket))
+    .bind(owner)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Ticket { ticket, expires_at })
+}
+
+/// Owner of a ticket that is still valid and the sequence number its stream starts after.
+/// The ticket cannot be used again.
+async fn redeem_ticket(pool: &PgPool, ticket: &str) -> Result<Option<(Uuid, i64)>, sqlx::Error> {
+    sqlx::query_as(
+        "delete from stream_tickets where ticket_hash = $1 and expires_at > now()
+        returning owner, after_sequence",
+    )
+    .bind(hash_hex(ticket))
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct StreamQuery {
+    ticket: Option<String>,
+}
+
+/// `GET /stream?ticket=<ticket>`
+pub async fn handler(req: Request<State>) -> tide::Result {
+    let ticket = req.query::<StreamQuery>().unwrap_or_default().ticket;
+    let redeemed = match ticket {
+        Some(ticket) => redeem_ticket(&req.state().postgres, &ticket)
+            .await
+            .map_err(|err| {
+                log::error!("Error redeeming stream ticket: {:?}", err);
+                Response::from(RpcError::from(AppError::Internal))
+            }),
+        None => Ok(None),
+    };
+    let (owner, after) = match redeemed {
+        Ok(Some(ticket)) => ticket,
+        Ok(None) => return Ok(RpcError::from(AppError::Unauthorized).into()),
+        Err(response) => return Ok(response),
+    };
+
+    let resume = req
+        .header("Last-Event-ID")
+        .and_then(|values| values.last().as_str().parse().ok());
+    let (after, resync) = match resume {
+        Some(resume) => match resume_after(&req.state().postgres, owner, resume).await {
+            Ok(resumed) => resumed,
+            Err(err) => {
+                log::error!("Error resuming stream: {:?}", err);
+                return Ok(RpcError::from(AppError::Internal).into());
+            }
+        },
+        None => (after, false),
+    };
+
+    Ok(sse::upgrade(req, move |req, sender| async move {
+        if resync
+            && sender
+                .send("resync", "", Some(&after.to_string()))
+                .await
+                .is_err()
+        {
+            return Ok(());
+        }
+        stream(req.state(), owner, after, sender).await
+    }))
+}
+
+/// Sequence number a stream resuming after `resume` starts after, and whether the client must
+/// resync because changes it missed were purged. Resyncing streams start after the latest
+/// change, like sync tokens from before a purge.
+async fn resume_after(pool: &PgPool, owner: Uuid, resume: i64) -> Result<(i64, bool), sqlx::Error> {
+    let purged = purged_through(pool, owner).await?;
+    if resume >= purged {
+        return Ok((resume, false));
+    }
+
+    Ok((latest(pool, owner).await?, true))
+}
+
+/// Sends the changes of `owner` after the `after` sequence number, then every change as it
+/// is committed, until the client goes away
+async fn stream(
+    state: &State,
+    owner: Uuid,
+    mut after: i64,
+    sender: sse::Sender,
+) -> tide::Result<()> {
+    // Subscribing first, so no change committed while catching up is missed
+    let wakes = state.changes.subscribe(owner);
+
+    loop {
+        let changes = changes(&state.postgres, owner, after).await?;
+        let caught_up = (changes.len() as i64) < BATCH;
+        for change in changes {
+            let id = change.sequence.to_string();
+            let sent = sender
+                .send(&change.event_type, change.contact.0.to_string(), Some(&id))
+                .await;
+            if sent.is_err() {
+                return Ok(());
+            }
+            after = change.sequence;
+        }
+        if !caught_up {
+            continue;
+        }
+
+        // Changes are read again after heartbeats too, in case a notification was lost
+        // while the listener reconnected
+        if future::timeout(HEARTBEAT_INTERVAL, wakes.recv())
+            .await
+            .is_err()
+            && sender.send("heartbeat", "", None).await.is_err()
+        {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use common::jsonrpc::{NoParams, RpcMethod};
+
+use super::Ticket;
+use crate::rpc::{self, Context, MethodResult};
+
+/// Definition of `streams.ticket`
+pub struct IssueTicket;
+
+impl RpcMethod for IssueTicket {
+    const NAME: &'static str = "streams.ticket";
+    type Params = NoParams;
+    type Result = Ticket;
+}
+
+/// `streams.ticket`
+///
+/// Ticket for a live stream of the changes to the contacts of the caller, valid once and for
+/// a minute
+pub async fn ticket(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Ticket> {
+    let ticket = super::issue_ticket(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::created(ticket)
+}
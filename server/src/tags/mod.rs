@@ -0,0 +1,273 @@
+/*!
+Tags, the static groups of an address book
+
+Contacts keep the id and name of each of their tags, changed with `ContactTagsChanged`
+events, so the history of a contact shows which tags it had under which names. The
+`contact_tags` table mirrors the links to count and list the contacts of a tag.
+*/
+
+pub mod rpc;
+
+use common::tags::{TagData, TagRef};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "tags")]
+pub struct Tag {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: TagData,
+}
+
+/// A tag with the number of contacts it is on
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct TagWithCount {
+    #[serde(flatten)]
+    pub tag: Tag,
+    pub contacts: i64,
+}
+
+/// Sorts tags the way contacts keep them
+pub fn sorted(mut tags: Vec<TagRef>) -> Vec<TagRef> {
+    tags.sort_by_key(|tag| tag.name.to_lowercase());
+    tags
+}
+
+/// Tags of a contact after adding or removing `tags`
+pub fn assign(current: &[TagRef], tags: &[Tag], add: bool) -> Vec<TagRef> {
+    let mut changed: Vec<TagRef> = current
+        .iter()
+        .filter(|other| !tags.iter().any(|tag| tag.id == other.id))
+        .cloned()
+        .collect();
+    if add {
+        changed.extend(tags.iter().map(Tag::to_ref));
+    }
+
+    sorted(changed)
+}
+
+const COLUMNS: &str = "id, owner, name, color, description";
+
+impl Tag {
+    pub fn to_ref(&self) -> TagRef {
+        TagRef {
+            id: self.id,
+            name: self.data.name.clone(),
+        }
+    }
+
+    /// Tags of `owner` sorted by name, with the live number of contacts on each
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<TagWithCount>, sqlx::Error> {
+        let rows: Vec<TagCountRow> = sqlx::query_as(
+            "select id, owner, name, color, description,
+                (select count(*) from contact_tags where tag_id = tags.id) as contacts
+            from tags where owner = $1 order by lower(name)",
+        )
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| TagWithCount {
+                tag: Tag {
+                    id: row.id,
+                    owner: row.owner,
+                    data: TagData {
+                        name: row.name,
+                        color: row.color,
+                        description: row.description,
+                    },
+                },
+                contacts: row.contacts,
+            })
+            .collect())
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<TagRow> = sqlx::query_as(&format!(
+            "select {} from tags where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Tag::from))
+    }
+
+    /// Tags of `owner` among `ids`
+    pub async fn find_many(
+        pool: &PgPool,
+        owner: Uuid,
+        ids: &[Uuid],
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<TagRow> = sqlx::query_as(&format!(
+            "select {} from tags where owner = $1 and id = any($2)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Tag::from).collect())
+    }
+
+    /// Tag of `owner` with the same name, ignoring case
+    pub async fn find_by_name(
+        pool: &PgPool,
+        owner: Uuid,
+        name: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<TagRow> = sqlx::query_as(&format!(
+            "select {} from tags where owner = $1 and lower(name) = lower($2)",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Tag::from))
+    }
+}
+
+#[derive(FromRow)]
+struct TagRow {
+    id: Uuid,
+    owner: Uuid,
+    name: String,
+    color: String,
+    description: Option<String>,
+}
+
+#[derive(FromRow)]
+struct TagCountRow {
+    id: Uuid,
+    owner: Uuid,
+    name: String,
+    color: String,
+    description: Option<String>,
+    contacts: i64,
+}
+
+impl From<TagRow> for Tag {
+    fn from(row: TagRow) -> Self {
+        Tag {
+            id: row.id,
+            owner: row.owner,
+            data: TagData {
+                name: row.name,
+                color: row.color,
+                description: row.description,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Tag)]
+pub struct TagCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: TagData,
+}
+
+/// Replaces every editable field of the tag
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Tag)]
+pub struct TagUpdated {
+    #[serde(flatten)]
+    pub data: TagData,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Tag)]
+pub struct TagDeleted {}
+
+impl AggregateCreate<TagCreated> for Tag {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<TagCreated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to create Tag from TagCreated event")?;
+
+        Ok(Tag {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+        })
+    }
+}
+
+impl AggregateUpdate<TagUpdated> for Tag {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<TagUpdated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to update Tag from TagUpdated event")?;
+
+        Ok(Tag {
+            data: data.data.clone(),
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<TagDeleted> for Tag {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(self, _event: &Event<TagDeleted>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Tag> for Tag {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into tags (id, owner, name, color, description)
+            values ($1, $2, $3, $4, $5)
+            on conflict (id) do update set
+                name = excluded.name,
+                color = excluded.color,
+                description = excluded.description",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.data.name)
+        .bind(&self.data.color)
+        .bind(&self.data.description)
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Tag {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from tags where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+use common::errors::AppError;
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::tags::TagData;
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{sorted, Tag, TagCreated, TagDeleted, TagUpdated, TagWithCount};
+use crate::{
+    contacts::{Contact, ContactTagsChanged},
+    rpc::{self, Context, MethodResult},
+};
+
+/// Most contacts a single `tags.add` or `tags.remove` call can change
+const MAX_BATCH: usize = 1000;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub tag: TagData,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.tag.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct AssignParams {
+    pub tag_ids: Vec<Uuid>,
+    pub contact_ids: Vec<Uuid>,
+}
+
+impl Validate for AssignParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/tag_ids", &self.tag_ids).not_empty();
+        errors
+            .field("/contact_ids", &self.contact_ids)
+            .not_empty()
+            .max_items(MAX_BATCH);
+    }
+}
+
+/// Outcome of a bulk assignment
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct AssignResult {
+    /// Contacts whose tags changed, contacts that already had the requested tags are skipped
+    pub updated: Vec<Uuid>,
+}
+
+/// Normalizes a tag that follows its [`Validate`] rules, names are unique per owner
+/// regardless of case
+async fn validate(
+    ctx: &Context<'_>,
+    input: TagData,
+    id: Option<Uuid>,
+) -> Result<TagData, rpc::RpcError> {
+    let data = input.normalize();
+
+    let existing = Tag::find_by_name(&ctx.state.postgres, ctx.owner(), &data.name)
+        .await
+        .map_err(rpc::internal)?;
+    if existing.is_some_and(|tag| Some(tag.id) != id) {
+        return Err(AppError::AlreadyExists { value: data.name }.into());
+    }
+
+    Ok(data)
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Tag, rpc::RpcError> {
+    Tag::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `tags.create`
+pub struct CreateTag;
+
+impl RpcMethod for CreateTag {
+    const NAME: &'static str = "tags.create";
+    type Params = TagData;
+    type Result = Tag;
+}
+
+/// `tags.create`
+pub async fn create(ctx: &Context<'_>, input: TagData) -> MethodResult<Tag> {
+    let data = validate(ctx, input, None).await?;
+
+    let tag = Tag::try_create(TagCreated {
+        owner: ctx.owner(),
+        data,
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(tag)
+}
+
+/// Definition of `tags.list`
+pub struct ListTags;
+
+impl RpcMethod for ListTags {
+    const NAME: &'static str = "tags.list";
+    type Params = NoParams;
+    type Result = Vec<TagWithCount>;
+}
+
+/// `tags.list`
+///
+/// Tags of the caller with the number of contacts on each
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<TagWithCount>> {
+    let tags = Tag::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(tags)
+}
+
+/// Definition of `tags.update`
+pub struct UpdateTag;
+
+impl RpcMethod for UpdateTag {
+    const NAME: &'static str = "tags.update";
+    type Params = UpdateParams;
+    type Result = Tag;
+}
+
+/// `tags.update`
+///
+/// A new name is written to every tagged contact in the same transaction.
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams { id, tag: input }: UpdateParams,
+) -> MethodResult<Tag> {
+    let tag = find(ctx, id).await?;
+    let data = validate(ctx, input, Some(id)).await?;
+    let renamed = data.name != tag.data.name;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+
+    let tag = tag
+        .try_update(TagUpdated { data })
+        .map_err(rpc::internal)?
+        .stage_persist(&mut tx)
+        .await
+        .map_err(rpc::internal)?;
+
+    if renamed {
+        let contacts = Contact::tagged_with(tx.get(), ctx.owner(), id)
+            .await
+            .map_err(rpc::internal)?;
+        for contact in contacts {
+            let tags = contact
+                .tags
+                .iter()
+                .map(|other| {
+                    if other.id == id {
+                        tag.to_ref()
+                    } else {
+                        other.clone()
+                    }
+                })
+                .collect();
+
+            contact
+                .try_update(ContactTagsChanged { tags: sorted(tags) })
+                .map_err(rpc::internal)?
+                .stage_persist(&mut tx)
+                .await
+                .map_err(rpc::internal)?;
+        }
+    }
+
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(tag)
+}
+
+/// Definition of `tags.delete`
+pub struct DeleteTag;
+
+impl RpcMethod for DeleteTag {
+    const NAME: &'static str = "tags.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `tags.delete`
+///
+/// The tag is removed from every contact in the same transaction.
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    let tag = find(ctx, id).await?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+
+    let contacts = Contact::tagged_with(tx.get(), ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?;
+    for contact in contacts {
+        let tags = contact
+            .tags
+            .iter()
+            .filter(|other| other.id != id)
+            .cloned()
+            .collect();
+
+        contact
+            .try_update(ContactTagsChanged { tags })
+            .map_err(rpc::internal)?
+            .stage_persist(&mut tx)
+            .await
+            .map_err(rpc::internal)?;
+    }
+
+    tag.try_delete(TagDeleted {})
+        .map_err(rpc::internal)?
+        .stage_delete(&mut tx)
+        .await
+        .map_err(rpc::internal)?;
+
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `tags.contacts`
+pub struct TagContacts;
+
+impl RpcMethod for TagContacts {
+    const NAME: &'static str = "tags.contacts";
+    type Params = ById;
+    type Result = Vec<Contact>;
+}
+
+/// `tags.contacts`
+///
+/// Contacts with the tag
+pub async fn contacts(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Vec<Contact>> {
+    find(ctx, id).await?;
+
+    let contacts = Contact::tagged_with(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(contacts)
+}
+
+/// Adds or removes tags on many contacts in one transaction. Fails as a whole when a tag or
+/// contact does not exist.
+async fn assign(
+    ctx: &Context<'_>,
+    AssignParams {
+        tag_ids,
+        contact_ids,
+    }: AssignParams,
+    add: bool,
+) -> MethodResult<AssignResult> {
+    let tags = Tag::find_many(&ctx.state.postgres, ctx.owner(), &tag_ids)
+        .await
+        .map_err(rpc::internal)?;
+
+    let mut tx = ctx.state.store.transaction().await.map_err(rpc::internal)?;
+
+    let contacts = Contact::find_many(tx.get(), ctx.owner(), &contact_ids)
+        .await
+        .map_err(rpc::internal)?;
+
+    let mut errors = ValidationErrors::new();
+    for (index, id) in tag_ids.iter().enumerate() {
+        if !tags.iter().any(|tag| tag.id == *id) {
+            errors.add(
+                format!("/tag_ids/{}", index),
+                "unknown_tag",
+                "There is no such tag",
+            );
+        }
+    }
+    for (index, id) in contact_ids.iter().enumerate() {
+        if !contacts.iter().any(|contact| contact.id == *id) {
+            errors.add(
+                format!("/contact_ids/{}", index),
+                "unknown_contact",
+                "There is no such contact",
+            );
+        }
+    }
+    errors.into_result()?;
+
+    let mut updated = Vec::new();
+    for contact in contacts {
+        let changed = super::assign(&contact.tags, &tags, add);
+        if changed == contact.tags {
+            continue;
+        }
+
+        updated.push(contact.id);
+        contact
+            .try_update(ContactTagsChanged { tags: changed })
+            .map_err(rpc::internal)?
+            .stage_persist(&mut tx)
+            .await
+            .map_err(rpc::internal)?;
+    }
+
+    tx.commit().await.map_err(rpc::internal)?;
+
+    rpc::ok(AssignResult { updated })
+}
+
+/// Definition of `tags.add`
+pub struct AddTags;
+
+impl RpcMethod for AddTags {
+    const NAME: &'static str = "tags.add";
+    type Params = AssignParams;
+    type Result = AssignResult;
+}
+
+/// `tags.add`
+pub async fn add(ctx: &Context<'_>, params: AssignParams) -> MethodResult<AssignResult> {
+    assign(ctx, params, true).await
+}
+
+/// Definition of `tags.remove`
+pub struct RemoveTags;
+
+impl RpcMethod for RemoveTags {
+    const NAME: &'static str = "tags.remove";
+    type Params = AssignParams;
+    type Result = AssignResult;
+}
+
+/// `tags.remove`
+pub async fn remove(ctx: &Context<'_>, params: AssignParams) -> MethodResult<AssignResult> {
+    assign(ctx, params, false).await
+}
@@ -0,0 +1,57 @@
+/*!
+Helpers shared by modules that have nothing else in common
+*/
+
+use sha2::{Digest, Sha256};
+
+/// Hex encoded SHA-256 of some bytes
+pub fn hash_hex(bytes: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(bytes.as_ref()))
+}
+
+/// Escapes XML character data and attribute values
+pub fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Escapes the characters `like` gives a meaning to, so `value` only matches itself
+pub fn like_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_to_lowercase_hex() {
+        assert_eq!(
+            hash_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash_hex(b"abc"), hash_hex(String::from("abc")));
+    }
+
+    #[test]
+    fn escapes_markup_and_quotes() {
+        assert_eq!(
+            xml_escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(xml_escape("&amp;"), "&amp;amp;");
+    }
+
+    #[test]
+    fn escapes_like_wildcards_and_the_escape() {
+        assert_eq!(like_escape(r"50%_off\"), r"50\%\_off\\");
+        assert_eq!(like_escape("plain"), "plain");
+    }
+}
@@ -0,0 +1,379 @@
+/*!
+Workers sending the deliveries of the webhook outbox
+
+Each worker claims one due delivery at a time, with `for update skip locked`, by moving its
+next attempt [`LEASE`] ahead. It then posts the delivery without holding any lock and records
+the outcome in a transaction of its own. A worker that dies mid-delivery leaves the delivery
+due again once the lease is over, and an outcome is only recorded while the lease it was
+claimed with still holds, so a delivery is never recorded by two workers. Failed attempts are retried after 30 seconds, doubling every attempt up to 6 hours,
+until [`MAX_ATTEMPTS`]. A webhook that fails [`DISABLE_AFTER`] attempts in a row is disabled
+and its pending deliveries are given up.
+
+Deliveries are posted as JSON with these headers:
+
+- `Webhook-Id`: id of the event, the same for every attempt
+- `Webhook-Event`: type of the event, e.g. `contact.updated`
+- `Webhook-Signature`: `t=<unix time>,v1=<signature>`, where the signature is the hex
+  encoded HMAC-SHA256 of `<unix time>.<body>` keyed with the secret of the webhook
+
+The URL is checked again right before each attempt, so a host that started resolving to a
+private address fails instead of being posted to. The attempt then connects to the address
+that was checked, the host is only used for the `Host` header and TLS. Redirects are not
+followed, a receiver answering one fails the attempt.
+*/
+
+use std::{net::IpAddr, time::Duration};
+
+use async_std::{future, task};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use http_client::isahc::IsahcClient;
+use isahc::config::{Configurable, RedirectPolicy, ResolveMap};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{types::Json, FromRow, PgPool, Postgres, Transaction};
+use tide::http::url::{Host, Url};
+use uuid::Uuid;
+
+use super::target;
+use crate::state::State;
+
+/// Attempts of a delivery before it is given up
+pub const MAX_ATTEMPTS: i32 = 8;
+
+/// Failed attempts in a row after which a webhook is disabled
+pub const DISABLE_AFTER: i32 = 20;
+
+/// Time a receiver has to respond
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a claimed delivery is kept from other workers, well over [`TIMEOUT`]
+const LEASE: Duration = Duration::from_secs(60);
+
+/// Time a worker waits before looking for deliveries again when none is due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Workers started by [`start`]
+const WORKERS: usize = 4;
+
+#[derive(FromRow)]
+struct Due {
+    webhook_id: Uuid,
+    event_id: Uuid,
+    event_type: String,
+    payload: Json<Value>,
+    url: String,
+    secret: String,
+    /// End of the lease the delivery was claimed with
+    leased_until: DateTime<Utc>,
+}
+
+/// Hex encoded HMAC-SHA256 of `<timestamp>.<body>`
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Starts the delivery workers in tasks of the current process
+pub fn start(state: &State) {
+    for worker in 0..WORKERS {
+        task::spawn(work(state.postgres.clone(), worker));
+    }
+}
+
+async fn work(pool: PgPool, worker: usize) {
+    log::debug!("Webhook worker {} started", worker);
+
+    loop {
+        match deliver_next(&pool).await {
+            Ok(true) => continue,
+            Ok(false) => task::sleep(POLL_INTERVAL).await,
+            Err(err) => {
+                log::error!(
+                    "Webhook worker {} could not reach the outbox: {:?}",
+                    worker,
+                    err
+                );
+                task::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Sends the next due delivery, `false` when there is none
+async fn deliver_next(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let due = match claim(pool).await? {
+        Some(due) => due,
+        None => return Ok(false),
+    };
+
+    let outcome = send(&due).await;
+
+    let mut tx = pool.begin().await?;
+    let recorded = match outcome {
+        Ok(status) => succeeded(&mut tx, &due, status).await?,
+        Err((status, error)) => {
+            log::debug!(
+                "Delivery of {} to webhook {} failed: {}",
+                due.event_id,
+                due.webhook_id,
+                error
+            );
+            failed(&mut tx, &due, status, &error).await?
+        }
+    };
+    tx.commit().await?;
+
+    if !recorded {
+        log::warn!(
+            "Lease on the delivery of {} to webhook {} ended before its outcome was recorded",
+            due.event_id,
+            due.webhook_id
+        );
+    }
+    Ok(true)
+}
+
+/// Leases the next due delivery, `None` when there is none
+async fn claim(pool: &PgPool) -> Result<Option<Due>, sqlx::Error> {
+    sqlx::query_as(
+        "with due as (
+            select deliveries.webhook_id, deliveries.event_id
+            from webhook_deliveries deliveries
+            join webhooks on webhooks.id = deliveries.webhook_id
+            where deliveries.status = 'pending' and deliveries.next_attempt_at <= now()
+                and webhooks.disabled_at is null
+            order by deliveries.next_attempt_at
+            limit 1
+            for update of deliveries skip locked
+        )
+        update webhook_deliveries deliveries set
+            next_attempt_at = now() + make_interval(secs => $1)
+        from due, webhooks
+        where deliveries.webhook_id = due.webhook_id and deliveries.event_id = due.event_id
+            and webhooks.id = deliveries.webhook_id
+        returning deliveries.webhook_id, deliveries.event_id, deliveries.event_type,
+            deliveries.payload, webhooks.url, webhooks.secret,
+            deliveries.next_attempt_at as leased_until",
+    )
+    .bind(LEASE.as_secs_f64())
+    .fetch_optional(pool)
+    .await
+}
+
+/// A client connecting to `ip` for the host of `url`, whatever the host resolves to by then.
+/// Certificates are still verified against the host.
+fn pinned_client(url: &Url, ip: IpAddr) -> Result<surf::Client, String> {
+    let mut builder = isahc::HttpClient::builder().redirect_policy(RedirectPolicy::None);
+    if let Some(Host::Domain(domain)) = url.host() {
+        let port = url.port_or_known_default().unwrap_or(80);
+        builder = builder.dns_resolve(ResolveMap::new().add(domain, port, ip));
+    }
+    let client = builder.build().map_err(|err| err.to_string())?;
+
+    Ok(surf::Client::with_http_client(IsahcClient::from_client(
+        client,
+    )))
+}
+
+/// Posts a delivery, with the response status when the receiver answered
+async fn send(due: &Due) -> Result<u16, (Option<u16>, String)> {
+    let url = Url::parse(&due.url).map_err(|err| (None, err.to_string()))?;
+    let ip = target::check(&url)
+        .await
+        .map_err(|err| (None, String::from(err.message())))?;
+    let client = pinned_client(&url, ip).map_err(|err| (None, err))?;
+
+    let body = serde_json::to_string(&due.payload.0).map_err(|err| (None, err.to_string()))?;
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(&due.secret, timestamp, &body);
+
+    let request = client
+        .post(url)
+        .header("Webhook-Id", due.event_id.to_string())
+        .header("Webhook-Event", due.event_type.as_str())
+        .header(
+            "Webhook-Signature",
+            format!("t={},v1={}", timestamp, signature),
+        )
+        .body_string(body)
+        .content_type(surf::http::mime::JSON);
+
+    match future::timeout(TIMEOUT, request).await {
+        Err(_) => Err((None, String::from("The receiver did not respond in time"))),
+        Ok(Err(err)) => Err((None, err.to_string())),
+        Ok(Ok(response)) => {
+            let status = u16::from(response.status());
+            if response.status().is_success() {
+                Ok(status)
+            } else if response.status().is_redirection() {
+                Err((
+                    Some(status),
+                    String::from("The receiver redirected, redirects are not followed"),
+                ))
+            } else {
+                Err((Some(status), format!("The receiver responded {}", status)))
+            }
+        }
+    }
+}
+
+/// Records a delivered attempt, `false` when the lease on the delivery ended
+async fn succeeded(
+    tx: &mut Transaction<'_, Postgres>,
+    due: &Due,
+    status: u16,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query(
+        "update webhook_deliveries set
+            status = 'succeeded', attempts = attempts + 1, response_status = $3, error = null,
+            delivered_at = now()
+        where webhook_id = $1 and event_id = $2
+            and status = 'pending' and next_attempt_at = $4",
+    )
+    .bind(due.webhook_id)
+    .bind(due.event_id)
+    .bind(status as i16)
+    .bind(due.leased_until)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query("update webhooks set consecutive_failures = 0 where id = $1")
+        .bind(due.webhook_id)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(true)
+}
+
+/// Records a failed attempt, `false` when the lease on the delivery ended
+async fn failed(
+    tx: &mut Transaction<'_, Postgres>,
+    due: &Due,
+    status: Option<u16>,
+    error: &str,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query(
+        "update webhook_deliveries set
+            status = (case when attempts + 1 >= $5 then 'failed' else 'pending' end)
+                ::webhook_delivery_status,
+            attempts = attempts + 1,
+            next_attempt_at = now() + least(
+                interval '30 seconds' * power(2, attempts),
+                interval '6 hours'
+            ),
+            response_status = $3,
+            error = $4
+        where webhook_id = $1 and event_id = $2
+            and status = 'pending' and next_attempt_at = $6",
+    )
+    .bind(due.webhook_id)
+    .bind(due.event_id)
+    .bind(status.map(|status| status as i16))
+    .bind(error)
+    .bind(MAX_ATTEMPTS)
+    .bind(due.leased_until)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let (disabled,): (bool,) = sqlx::query_as(
+        "update webhooks set
+            consecutive_failures = consecutive_failures + 1,
+            disabled_at = (case when consecutive_failures + 1 >= $2 then now() end)
+        where id = $1
+        returning disabled_at is not null",
+    )
+    .bind(due.webhook_id)
+    .bind(DISABLE_AFTER)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if disabled {
+        log::warn!(
+            "Disabled webhook {} after {} failed deliveries in a row",
+            due.webhook_id,
+            DISABLE_AFTER
+        );
+
+        sqlx::query(
+            "update webhook_deliveries set
+                status = 'failed', error = 'The webhook was disabled after failing repeatedly'
+            where webhook_id = $1 and status = 'pending'",
+        )
+        .bind(due.webhook_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{io::prelude::*, net::TcpListener};
+
+    use super::*;
+
+    const BODY: &str = r#"{"event":"contact.created"}"#;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // printf '1600000000.{"event":"contact.created"}' | openssl dgst -sha256 -hmac whsec_test
+        assert_eq!(
+            sign("whsec_test", 1600000000, BODY),
+            "304725f8ced093dc1cfcdd89b8e2ac362ef804c29f41e4cc2398c9d16efa704c"
+        );
+    }
+
+    #[test]
+    fn signature_depends_on_every_input() {
+        let signature = sign("whsec_test", 1600000000, BODY);
+        assert_ne!(sign("other", 1600000000, BODY), signature);
+        assert_ne!(sign("whsec_test", 1600000001, BODY), signature);
+        assert_ne!(sign("whsec_test", 1600000000, "{}"), signature);
+    }
+
+    #[async_std::test]
+    async fn connects_to_the_checked_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let receiver = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        // The name does not resolve, the client must not try to
+        let url = Url::parse(&format!("http://hooks.example.invalid:{}/hook", port)).unwrap();
+        let client = pinned_client(&url, "127.0.0.1".parse().unwrap()).unwrap();
+        let response = client
+            .post(url)
+            .body_string(String::from("{}"))
+            .await
+            .unwrap();
+
+        assert_eq!(u16::from(response.status()), 204);
+        let request = receiver.await.to_lowercase();
+        assert!(
+            request.contains(&format!("host: hooks.example.invalid:{}", port)),
+            "{}",
+            request
+        );
+    }
+}
@@ -0,0 +1,366 @@
+/*!
+Outbound webhooks for contact events
+
+Owners register URLs to be notified of changes to their contacts, filtered by event type.
+URLs must resolve to public addresses, see [`target`]. Changes are not sent right away: [`enqueue`] writes one row per interested webhook to the
+`webhook_deliveries` outbox in the transaction of the change, so a delivery exists exactly
+when the change was committed. The [`delivery`] workers then send them, retrying failures
+with a backoff, and keep the outcome of each one as the delivery log of the webhook.
+
+Deliveries are sent at least once: a worker that stops between sending a delivery and
+recording it sends it again. Receivers can tell repeated deliveries apart by their
+`Webhook-Id` header, which is the id of the event.
+*/
+
+pub mod delivery;
+pub mod rpc;
+pub mod target;
+
+use chrono::{DateTime, Utc};
+use common::validation::{Validate, ValidationErrors, MAX_TEXT_LENGTH};
+use event_sauce::{prelude::*, Event};
+use event_sauce_storage_sqlx::SqlxPgStoreTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgPool};
+use uuid::Uuid;
+
+/// A contact was created
+pub const CONTACT_CREATED: &str = "contact.created";
+/// A contact, its photo or its tags changed
+pub const CONTACT_UPDATED: &str = "contact.updated";
+/// A contact was deleted
+pub const CONTACT_DELETED: &str = "contact.deleted";
+
+/// Every event type a webhook can subscribe to
+pub const EVENT_TYPES: &[&str] = &[CONTACT_CREATED, CONTACT_UPDATED, CONTACT_DELETED];
+
+/// Fields of a webhook the owner can edit
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct WebhookData {
+    /// http or https URL deliveries are posted to
+    pub url: String,
+    /// Event types the webhook is sent, at least one of [`EVENT_TYPES`]
+    pub events: Vec<String>,
+    pub description: Option<String>,
+}
+
+impl Validate for WebhookData {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/url", &self.url).required().url();
+        errors.field("/events", &self.events).not_empty();
+        errors.items("/events", &self.events, |event| {
+            event.one_of(EVENT_TYPES);
+        });
+        errors
+            .field("/description", &self.description)
+            .max_length(MAX_TEXT_LENGTH);
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, event_sauce_derive::Entity)]
+#[event_sauce(entity_name = "webhooks")]
+pub struct Webhook {
+    #[event_sauce(id)]
+    pub id: Uuid,
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: WebhookData,
+    /// Key of the HMAC-SHA256 signature of deliveries
+    pub secret: String,
+    /// Failed delivery attempts since the last one that succeeded. Kept up to date by the
+    /// delivery workers rather than by webhook events.
+    #[serde(default)]
+    pub consecutive_failures: i32,
+    /// When the webhook was disabled for failing too often, updating it enables it again
+    #[serde(default)]
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or to be retried
+    Pending,
+    Succeeded,
+    /// Failed on its last attempt, or its webhook was disabled
+    Failed,
+}
+
+/// A delivery of the outbox, as shown in the delivery log
+#[derive(Serialize, JsonSchema, Debug, FromRow)]
+pub struct Delivery {
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    /// When a pending delivery is attempted next
+    pub next_attempt_at: DateTime<Utc>,
+    /// HTTP status of the latest response
+    pub response_status: Option<i16>,
+    /// Why the latest attempt failed
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Body of a delivery
+#[derive(Serialize, Debug)]
+struct Payload<'a, T: Serialize> {
+    id: Uuid,
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    created_at: DateTime<Utc>,
+    data: &'a T,
+}
+
+/// Adds a delivery of an event to the outbox for each enabled webhook of `owner` subscribed
+/// to `event_type`
+pub async fn enqueue<T: Serialize + Sync>(
+    tx: &mut SqlxPgStoreTransaction,
+    owner: Uuid,
+    event_type: &str,
+    data: &T,
+) -> Result<(), sqlx::Error> {
+    let payload = Payload {
+        id: Uuid::new_v4(),
+        event_type,
+        created_at: Utc::now(),
+        data,
+    };
+
+    sqlx::query(
+        "insert into webhook_deliveries (webhook_id, event_id, event_type, payload)
+        select id, $2, $3, $4 from webhooks
+        where owner = $1 and disabled_at is null and $3 = any(events)",
+    )
+    .bind(owner)
+    .bind(payload.id)
+    .bind(event_type)
+    .bind(Json(&payload))
+    .execute(tx.get())
+    .await?;
+
+    Ok(())
+}
+
+/// A random signing secret
+pub fn generate_secret() -> String {
+    format!(
+        "whsec_{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+const COLUMNS: &str =
+    "id, owner, url, events, description, secret, consecutive_failures, disabled_at";
+
+impl Webhook {
+    /// Webhooks of `owner`, sorted by URL
+    pub async fn list(pool: &PgPool, owner: Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows: Vec<WebhookRow> = sqlx::query_as(&format!(
+            "select {} from webhooks where owner = $1 order by url",
+            COLUMNS
+        ))
+        .bind(owner)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Webhook::from).collect())
+    }
+
+    pub async fn find(pool: &PgPool, owner: Uuid, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let row: Option<WebhookRow> = sqlx::query_as(&format!(
+            "select {} from webhooks where owner = $1 and id = $2",
+            COLUMNS
+        ))
+        .bind(owner)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(Webhook::from))
+    }
+
+    /// Latest deliveries of the webhook, newest first
+    pub async fn deliveries(
+        &self,
+        pool: &PgPool,
+        status: Option<DeliveryStatus>,
+        limit: i64,
+    ) -> Result<Vec<Delivery>, sqlx::Error> {
+        sqlx::query_as(
+            "select event_id, event_type, status, attempts, next_attempt_at, response_status,
+                error, created_at, delivered_at
+            from webhook_deliveries
+            where webhook_id = $1 and ($2::webhook_delivery_status is null or status = $2)
+            order by created_at desc
+            limit $3",
+        )
+        .bind(self.id)
+        .bind(status)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Queues a delivery of the webhook again, with a fresh set of attempts. `None` when
+    /// there is no such delivery.
+    pub async fn redeliver(
+        &self,
+        pool: &PgPool,
+        event_id: Uuid,
+    ) -> Result<Option<Delivery>, sqlx::Error> {
+        sqlx::query_as(
+            "update webhook_deliveries set
+                status = 'pending', attempts = 0, next_attempt_at = now()
+            where webhook_id = $1 and event_id = $2
+            returning event_id, event_type, status, attempts, next_attempt_at,
+                response_status, error, created_at, delivered_at",
+        )
+        .bind(self.id)
+        .bind(event_id)
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+#[derive(FromRow)]
+struct WebhookRow {
+    id: Uuid,
+    owner: Uuid,
+    url: String,
+    events: Vec<String>,
+    description: Option<String>,
+    secret: String,
+    consecutive_failures: i32,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        Webhook {
+            id: row.id,
+            owner: row.owner,
+            data: WebhookData {
+                url: row.url,
+                events: row.events,
+                description: row.description,
+            },
+            secret: row.secret,
+            consecutive_failures: row.consecutive_failures,
+            disabled_at: row.disabled_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::CreateEventData)]
+#[event_sauce(Webhook)]
+pub struct WebhookCreated {
+    pub owner: Uuid,
+    #[serde(flatten)]
+    pub data: WebhookData,
+    pub secret: String,
+}
+
+/// Changes the URL, events or description of the webhook, and enables it again
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::UpdateEventData)]
+#[event_sauce(Webhook)]
+pub struct WebhookUpdated {
+    #[serde(flatten)]
+    pub data: WebhookData,
+}
+
+#[derive(Serialize, Deserialize, Debug, event_sauce_derive::DeleteEventData)]
+#[event_sauce(Webhook)]
+pub struct WebhookDeleted {}
+
+impl AggregateCreate<WebhookCreated> for Webhook {
+    type Error = &'static str;
+
+    fn try_aggregate_create(event: &Event<WebhookCreated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to create Webhook from WebhookCreated event")?;
+
+        Ok(Webhook {
+            id: event.entity_id,
+            owner: data.owner,
+            data: data.data.clone(),
+            secret: data.secret.clone(),
+            consecutive_failures: 0,
+            disabled_at: None,
+        })
+    }
+}
+
+impl AggregateUpdate<WebhookUpdated> for Webhook {
+    type Error = &'static str;
+    type Output = Self;
+
+    fn try_aggregate_update(self, event: &Event<WebhookUpdated>) -> Result<Self, Self::Error> {
+        let data = event
+            .data
+            .as_ref()
+            .ok_or("Event data must be populated to update Webhook from WebhookUpdated event")?;
+
+        Ok(Webhook {
+            data: data.data.clone(),
+            consecutive_failures: 0,
+            disabled_at: None,
+            ..self
+        })
+    }
+}
+
+impl AggregateDelete<WebhookDeleted> for Webhook {
+    type Error = &'static str;
+
+    fn try_aggregate_delete(self, _event: &Event<WebhookDeleted>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistable<SqlxPgStoreTransaction, Webhook> for Webhook {
+    async fn persist(self, tx: &mut SqlxPgStoreTransaction) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "insert into webhooks
+                (id, owner, url, events, description, secret, consecutive_failures, disabled_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)
+            on conflict (id) do update set
+                url = excluded.url,
+                events = excluded.events,
+                description = excluded.description,
+                consecutive_failures = excluded.consecutive_failures,
+                disabled_at = excluded.disabled_at",
+        )
+        .bind(self.id)
+        .bind(self.owner)
+        .bind(&self.data.url)
+        .bind(&self.data.events)
+        .bind(&self.data.description)
+        .bind(&self.secret)
+        .bind(self.consecutive_failures)
+        .bind(self.disabled_at)
+        .execute(tx.get())
+        .await?;
+
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Deletable<SqlxPgStoreTransaction> for Webhook {
+    async fn delete(self, tx: &mut SqlxPgStoreTransaction) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from webhooks where id = $1")
+            .bind(self.id)
+            .execute(tx.get())
+            .await?;
+
+        Ok(())
+    }
+}
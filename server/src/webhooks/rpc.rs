@@ -0,0 +1,250 @@
+use common::jsonrpc::{ById, NoParams, RpcMethod};
+use common::validation::{Validate, ValidationErrors};
+use event_sauce::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tide::http::Url;
+use uuid::Uuid;
+
+use super::{
+    generate_secret, target, Delivery, DeliveryStatus, Webhook, WebhookCreated, WebhookData,
+    WebhookDeleted, WebhookUpdated,
+};
+use crate::rpc::{self, Context, MethodResult};
+
+/// Most deliveries a single `webhooks.deliveries` call returns
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct UpdateParams {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub webhook: WebhookData,
+}
+
+impl Validate for UpdateParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        self.webhook.rules(errors);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct DeliveriesParams {
+    pub id: Uuid,
+    pub status: Option<DeliveryStatus>,
+    #[serde(default = "DeliveriesParams::default_limit")]
+    pub limit: i64,
+}
+
+impl DeliveriesParams {
+    fn default_limit() -> i64 {
+        50
+    }
+}
+
+impl Validate for DeliveriesParams {
+    fn rules(&self, errors: &mut ValidationErrors) {
+        errors.field("/limit", &self.limit).range(1, MAX_LIMIT);
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct RedeliverParams {
+    pub id: Uuid,
+    pub event_id: Uuid,
+}
+
+impl Validate for RedeliverParams {}
+
+/// Normalizes a webhook that follows its [`Validate`] rules, its URL must only resolve to
+/// public addresses
+async fn validate(input: WebhookData) -> Result<WebhookData, rpc::RpcError> {
+    let data = normalize(input);
+
+    let url = Url::parse(&data.url).map_err(rpc::internal)?;
+    if let Err(err) = target::check(&url).await {
+        let mut errors = ValidationErrors::new();
+        errors.add("/url", err.code(), err.message());
+        return Err(errors.into());
+    }
+
+    Ok(data)
+}
+
+/// Trims a webhook and drops duplicate event types
+fn normalize(input: WebhookData) -> WebhookData {
+    let mut events: Vec<String> = Vec::with_capacity(input.events.len());
+    for event in input.events {
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+
+    WebhookData {
+        url: Url::parse(input.url.trim())
+            .map(|url| url.to_string())
+            .unwrap_or_default(),
+        events,
+        description: input
+            .description
+            .map(|description| description.trim().to_string())
+            .filter(|description| !description.is_empty()),
+    }
+}
+
+async fn find(ctx: &Context<'_>, id: Uuid) -> Result<Webhook, rpc::RpcError> {
+    Webhook::find(&ctx.state.postgres, ctx.owner(), id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)
+}
+
+/// Definition of `webhooks.create`
+pub struct CreateWebhook;
+
+impl RpcMethod for CreateWebhook {
+    const NAME: &'static str = "webhooks.create";
+    type Params = WebhookData;
+    type Result = Webhook;
+}
+
+/// `webhooks.create`
+///
+/// The webhook is returned with the secret its deliveries are signed with
+pub async fn create(ctx: &Context<'_>, input: WebhookData) -> MethodResult<Webhook> {
+    let data = validate(input).await?;
+
+    let webhook = Webhook::try_create(WebhookCreated {
+        owner: ctx.owner(),
+        data,
+        secret: generate_secret(),
+    })
+    .map_err(rpc::internal)?
+    .persist(&ctx.state.store)
+    .await
+    .map_err(rpc::internal)?;
+
+    rpc::created(webhook)
+}
+
+/// Definition of `webhooks.list`
+pub struct ListWebhooks;
+
+impl RpcMethod for ListWebhooks {
+    const NAME: &'static str = "webhooks.list";
+    type Params = NoParams;
+    type Result = Vec<Webhook>;
+}
+
+/// `webhooks.list`
+pub async fn list(ctx: &Context<'_>, _params: NoParams) -> MethodResult<Vec<Webhook>> {
+    let webhooks = Webhook::list(&ctx.state.postgres, ctx.owner())
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(webhooks)
+}
+
+/// Definition of `webhooks.update`
+pub struct UpdateWebhook;
+
+impl RpcMethod for UpdateWebhook {
+    const NAME: &'static str = "webhooks.update";
+    type Params = UpdateParams;
+    type Result = Webhook;
+}
+
+/// `webhooks.update`
+///
+/// Also enables a webhook that was disabled for failing repeatedly
+pub async fn update(
+    ctx: &Context<'_>,
+    UpdateParams { id, webhook: input }: UpdateParams,
+) -> MethodResult<Webhook> {
+    let webhook = find(ctx, id).await?;
+    let data = validate(input).await?;
+
+    let webhook = webhook
+        .try_update(WebhookUpdated { data })
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(webhook)
+}
+
+/// Definition of `webhooks.delete`
+pub struct DeleteWebhook;
+
+impl RpcMethod for DeleteWebhook {
+    const NAME: &'static str = "webhooks.delete";
+    type Params = ById;
+    type Result = Uuid;
+}
+
+/// `webhooks.delete`
+///
+/// Pending deliveries of the webhook are dropped with it
+pub async fn delete(ctx: &Context<'_>, ById { id }: ById) -> MethodResult<Uuid> {
+    find(ctx, id)
+        .await?
+        .try_delete(WebhookDeleted {})
+        .map_err(rpc::internal)?
+        .persist(&ctx.state.store)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(id)
+}
+
+/// Definition of `webhooks.deliveries`
+pub struct ListDeliveries;
+
+impl RpcMethod for ListDeliveries {
+    const NAME: &'static str = "webhooks.deliveries";
+    type Params = DeliveriesParams;
+    type Result = Vec<Delivery>;
+}
+
+/// `webhooks.deliveries`
+///
+/// Delivery log of a webhook, newest first
+pub async fn deliveries(
+    ctx: &Context<'_>,
+    DeliveriesParams { id, status, limit }: DeliveriesParams,
+) -> MethodResult<Vec<Delivery>> {
+    let deliveries = find(ctx, id)
+        .await?
+        .deliveries(&ctx.state.postgres, status, limit)
+        .await
+        .map_err(rpc::internal)?;
+
+    rpc::ok(deliveries)
+}
+
+/// Definition of `webhooks.redeliver`
+pub struct Redeliver;
+
+impl RpcMethod for Redeliver {
+    const NAME: &'static str = "webhooks.redeliver";
+    type Params = RedeliverParams;
+    type Result = Delivery;
+}
+
+/// `webhooks.redeliver`
+///
+/// Sends a delivery again, for example once the receiver of a failed one is fixed
+pub async fn redeliver(
+    ctx: &Context<'_>,
+    RedeliverParams { id, event_id }: RedeliverParams,
+) -> MethodResult<Delivery> {
+    let delivery = find(ctx, id)
+        .await?
+        .redeliver(&ctx.state.postgres, event_id)
+        .await
+        .map_err(rpc::internal)?
+        .ok_or_else(rpc::not_found)?;
+
+    rpc::ok(delivery)
+}
@@ -0,0 +1,215 @@
+/*!
+Keeps webhooks from reaching the network the server runs in
+
+The host of a webhook URL is resolved when the webhook is saved and again before every
+delivery, because what a name resolves to can change in between. URLs are refused unless
+every address their host resolves to is public: loopback, private, link-local (which includes
+the `169.254.169.254` metadata endpoint of cloud providers) and other special purpose ranges
+are rejected. Deliveries connect to the address that was checked rather than resolving the
+host again.
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_std::net::ToSocketAddrs;
+use tide::http::url::{Host, Url};
+
+/// Why a webhook URL cannot be delivered to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TargetError {
+    /// The URL has no host, or its host does not resolve
+    Unresolved,
+    /// The host resolves to an address that is not public
+    NotPublic(IpAddr),
+}
+
+impl TargetError {
+    /// Machine readable reason
+    pub fn code(&self) -> &'static str {
+        match self {
+            TargetError::Unresolved => "unresolved_host",
+            TargetError::NotPublic(_) => "private_address",
+        }
+    }
+
+    /// Human readable reason. The address is left out so the error cannot be used to map
+    /// internal names.
+    pub fn message(&self) -> &'static str {
+        match self {
+            TargetError::Unresolved => "The host of the URL could not be resolved",
+            TargetError::NotPublic(_) => "The URL points to an address that is not public",
+        }
+    }
+}
+
+/// Resolves the host of `url`, failing unless every address it resolves to is public. Returns
+/// the address to connect to.
+pub async fn check(url: &Url) -> Result<IpAddr, TargetError> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        Some(Host::Domain(domain)) => (domain, port)
+            .to_socket_addrs()
+            .await
+            .map_err(|_| TargetError::Unresolved)?
+            .map(|address| address.ip())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    match addresses.iter().find(|ip| !is_public(**ip)) {
+        Some(ip) => Err(TargetError::NotPublic(*ip)),
+        None => addresses.first().copied().ok_or(TargetError::Unresolved),
+    }
+}
+
+/// Whether `ip` is a public unicast address
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, this network
+        || a == 0
+        // 100.64.0.0/10, carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // 192.0.0.0/24, IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15, benchmarking
+        || (a == 198 && (b == 18 || b == 19))
+        // 240.0.0.0/4, reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    // IPv4 addresses mapped into IPv6 (::ffff:0:0/96) or translated by NAT64 (64:ff9b::/96)
+    // reach the IPv4 address they embed
+    let mapped = segments[..5] == [0; 5] && segments[5] == 0xffff;
+    let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    if mapped || nat64 {
+        let [.., a, b, c, d] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7, unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // fe80::/10, link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // 2001:db8::/32, documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_loopback_and_unspecified() {
+        assert!(!public("127.0.0.1"));
+        assert!(!public("127.255.0.1"));
+        assert!(!public("0.0.0.0"));
+        assert!(!public("::1"));
+        assert!(!public("::"));
+    }
+
+    #[test]
+    fn rejects_private_ranges() {
+        assert!(!public("10.0.0.1"));
+        assert!(!public("172.16.0.1"));
+        assert!(!public("172.31.255.255"));
+        assert!(!public("192.168.1.1"));
+        assert!(!public("100.64.0.1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("fc00::1"));
+    }
+
+    #[test]
+    fn rejects_link_local_and_metadata() {
+        assert!(!public("169.254.169.254"));
+        assert!(!public("169.254.0.1"));
+        assert!(!public("fe80::1"));
+        assert!(!public("fd00:ec2::254"));
+    }
+
+    #[test]
+    fn rejects_ipv4_embedded_in_ipv6() {
+        assert!(!public("::ffff:127.0.0.1"));
+        assert!(!public("::ffff:169.254.169.254"));
+        assert!(!public("64:ff9b::10.0.0.1"));
+        assert!(public("::ffff:93.184.216.34"));
+    }
+
+    #[test]
+    fn rejects_special_purpose_ranges() {
+        assert!(!public("224.0.0.1"));
+        assert!(!public("255.255.255.255"));
+        assert!(!public("240.0.0.1"));
+        assert!(!public("198.18.0.1"));
+        assert!(!public("192.0.0.8"));
+        assert!(!public("192.0.2.1"));
+        assert!(!public("2001:db8::1"));
+        assert!(!public("ff02::1"));
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        assert!(public("93.184.216.34"));
+        assert!(public("8.8.8.8"));
+        assert!(public("172.32.0.1"));
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+    }
+
+    async fn check_url(url: &str) -> Result<IpAddr, TargetError> {
+        check(&Url::parse(url).unwrap()).await
+    }
+
+    #[async_std::test]
+    async fn checks_literal_hosts() {
+        assert_eq!(
+            check_url("http://127.0.0.1:8080/hook").await,
+            Err(TargetError::NotPublic("127.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            check_url("http://[::1]/hook").await,
+            Err(TargetError::NotPublic("::1".parse().unwrap()))
+        );
+        assert_eq!(
+            check_url("http://169.254.169.254/latest/meta-data").await,
+            Err(TargetError::NotPublic("169.254.169.254".parse().unwrap()))
+        );
+        assert_eq!(
+            check_url("https://93.184.216.34/hook").await,
+            Ok("93.184.216.34".parse().unwrap())
+        );
+    }
+
+    #[async_std::test]
+    async fn checks_resolved_hosts() {
+        assert!(matches!(
+            check_url("http://localhost/hook").await,
+            Err(TargetError::NotPublic(_)) | Err(TargetError::Unresolved)
+        ));
+    }
+}
@@ -0,0 +1,517 @@
+/*!
+JSON-RPC over WebSocket
+
+`GET /ws` upgrades to a WebSocket that takes the same requests as `POST /`, one per text
+frame, and answers each of them with a frame holding its response. Requests are handled one
+at a time, in the order they arrive.
+
+The first frame must authenticate the session, within [`AUTH_TIMEOUT`]:
+
+```json
+{"id": "…", "method": "auth", "params": {"token": "<JWT>"}}
+```
+
+Sending it again with a refreshed token extends the session, which ends when the latest token
+expires. A token that is not valid is rejected with a 401 response, and the session goes on
+with the previous one. Sessions are closed with these codes:
+
+- `4401` when the first frame is not a valid `auth` request
+- `4403` when a token of another user is sent
+- `4440` when the token expired
+
+`contacts.subscribe` pushes the changes to the contacts committed after it as
+`contacts.changed` notifications, requests without id, until `contacts.unsubscribe` is called
+with the subscription it returned or the session ends:
+
+```json
+{"method": "contacts.changed", "params": {"subscription": "…", "sequence": 42, "event": "contact.updated", "contact": {…}}}
+```
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std::{channel::Receiver, future, prelude::*, task};
+use chrono::Utc;
+use common::errors::AppError;
+use common::jsonrpc::{JSONRPCRequest, NoParams, RpcMethod};
+use common::validation::Validate;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tide::Request;
+use tide_websockets::{
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame},
+    Message, WebSocketConnection,
+};
+use uuid::Uuid;
+
+use crate::{
+    keycloak::{self, KeycloakClaims, JWKS},
+    rpc::{self, Context, MethodResult, RpcError, RpcResult},
+    state::State,
+    streams,
+};
+
+/// Time a new connection has to send its `auth` request
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Subscriptions a single session can hold at once
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// Close code of sessions that did not authenticate
+const UNAUTHORIZED: u16 = 4401;
+/// Close code of sessions that sent a token of another user
+const FORBIDDEN: u16 = 4403;
+/// Close code of sessions whose token expired
+const EXPIRED: u16 = 4440;
+
+#[derive(Deserialize, Debug)]
+struct AuthParams {
+    token: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Authenticated {
+    sub: Uuid,
+    /// Unix time the session ends at, unless authenticated again before
+    expires_at: u32,
+}
+
+/// A subscription to the changes of the contacts
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct Subscription {
+    pub subscription: Uuid,
+}
+
+impl Validate for Subscription {}
+
+/// Definition of `contacts.subscribe`
+pub struct SubscribeContacts;
+
+impl RpcMethod for SubscribeContacts {
+    const NAME: &'static str = "contacts.subscribe";
+    type Params = NoParams;
+    type Result = Subscription;
+}
+
+/// Definition of `contacts.unsubscribe`
+pub struct UnsubscribeContacts;
+
+impl RpcMethod for UnsubscribeContacts {
+    const NAME: &'static str = "contacts.unsubscribe";
+    type Params = Subscription;
+    type Result = Uuid;
+}
+
+#[derive(Serialize, Debug)]
+struct Notification<T: Serialize> {
+    method: &'static str,
+    params: T,
+}
+
+#[derive(Serialize, Debug)]
+struct Changed<'a> {
+    subscription: Uuid,
+    sequence: i64,
+    event: &'a str,
+    contact: &'a Value,
+}
+
+/// Subscriptions of a session, stopped when the session ends
+#[derive(Default)]
+struct Subscriptions(HashMap<Uuid, Arc<AtomicBool>>);
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for stopped in self.0.values() {
+            stopped.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// `GET /ws`
+pub async fn handler(req: Request<State>, mut conn: WebSocketConnection) -> tide::Result<()> {
+    let state = req.state();
+
+    let first = match future::timeout(AUTH_TIMEOUT, conn.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<JSONRPCRequest>(&text).ok(),
+        _ => None,
+    };
+    let mut claims = match first {
+        Some(request) if request.method == "auth" => {
+            match authenticate(&state.auth_keys, request.params) {
+                Ok(claims) => {
+                    respond(&conn, request.id, rpc::into_value(authenticated(&claims))).await?;
+                    claims
+                }
+                Err(error) => {
+                    respond(&conn, request.id, Err(error)).await?;
+                    return close(&conn, UNAUTHORIZED, "Invalid token").await;
+                }
+            }
+        }
+        _ => return close(&conn, UNAUTHORIZED, "Authentication required").await,
+    };
+
+    log::debug!("WebSocket session of {} started", claims.sub);
+
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        let frame = match future::timeout(expires_in(&claims), conn.next()).await {
+            Err(_) => return close(&conn, EXPIRED, "Session expired").await,
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(err))) => {
+                log::debug!("WebSocket session of {} failed: {:?}", claims.sub, err);
+                return Ok(());
+            }
+            Ok(None) => return Ok(()),
+        };
+        // A frame may be ready right as the token expires
+        if expires_in(&claims) == Duration::from_secs(0) {
+            return close(&conn, EXPIRED, "Session expired").await;
+        }
+        let text = match frame {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+
+        let request: JSONRPCRequest = match serde_json::from_str(&text) {
+            Ok(request) => request,
+            Err(err) => {
+                log::debug!("Invalid RPC request: {:?}", err);
+                conn.send_json(&RpcError::from(AppError::InvalidRequest))
+                    .await?;
+                continue;
+            }
+        };
+
+        log::debug!(
+            "Calling {} as {} over WebSocket",
+            request.method,
+            claims.sub
+        );
+
+        let result = match request.method.as_str() {
+            "auth" => match authenticate(&state.auth_keys, request.params) {
+                Ok(refreshed) if refreshed.sub != claims.sub => {
+                    return close(&conn, FORBIDDEN, "Token of another user").await;
+                }
+                Ok(refreshed) => {
+                    claims = refreshed;
+                    rpc::into_value(authenticated(&claims))
+                }
+                Err(error) => Err(error),
+            },
+            method => {
+                let ctx = Context {
+                    state,
+                    actor: &claims,
+                };
+                match rpc::authorize(&ctx, method) {
+                    Err(error) => Err(error),
+                    Ok(()) => match method {
+                        SubscribeContacts::NAME => rpc::into_value(
+                            subscribe(state, &conn, claims.sub, &mut subscriptions).await,
+                        ),
+                        UnsubscribeContacts::NAME => {
+                            rpc::into_value(unsubscribe(&mut subscriptions, request.params))
+                        }
+                        method => rpc::route(&ctx, method, request.params).await,
+                    },
+                }
+            }
+        };
+
+        respond(&conn, request.id, result).await?;
+    }
+}
+
+/// Claims of the token of an `auth` request, verified with `keys`
+fn authenticate(keys: &JWKS, params: Value) -> Result<KeycloakClaims, rpc::RpcError> {
+    let AuthParams { token } = rpc::params(params)?;
+
+    keycloak::decode_token(keys, &token).map_err(|err| {
+        log::debug!("Rejected WebSocket token: {:?}", err);
+        RpcError::from(AppError::Unauthorized)
+    })
+}
+
+fn authenticated(claims: &KeycloakClaims) -> MethodResult<Authenticated> {
+    rpc::ok(Authenticated {
+        sub: claims.sub,
+        expires_at: claims.exp,
+    })
+}
+
+/// Time left until the token expires
+fn expires_in(claims: &KeycloakClaims) -> Duration {
+    let seconds = i64::from(claims.exp) - Utc::now().timestamp();
+    Duration::from_secs(seconds.max(0) as u64)
+}
+
+async fn respond(
+    conn: &WebSocketConnection,
+    id: Option<Uuid>,
+    result: RpcResult,
+) -> tide::Result<()> {
+    match (result, id) {
+        (Ok(success), Some(id)) => conn.send_json(&success.id(id)).await,
+        (Ok(success), None) => conn.send_json(&success).await,
+        (Err(error), Some(id)) => conn.send_json(&error.id(id)).await,
+        (Err(error), None) => conn.send_json(&error).await,
+    }
+}
+
+async fn close(conn: &WebSocketConnection, code: u16, reason: &'static str) -> tide::Result<()> {
+    log::debug!("Closing WebSocket session: {}", reason);
+
+    // The client may be gone already
+    let _ = conn
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        })))
+        .await;
+
+    Ok(())
+}
+
+/// `contacts.subscribe`
+async fn subscribe(
+    state: &State,
+    conn: &WebSocketConnection,
+    owner: Uuid,
+    subscriptions: &mut Subscriptions,
+) -> MethodResult<Subscription> {
+    if subscriptions.0.len() >= MAX_SUBSCRIPTIONS {
+        return Err(RpcError::from(AppError::QuotaExceeded {
+            limit: MAX_SUBSCRIPTIONS as u64,
+        })
+        .message("Too many subscriptions"));
+    }
+
+    // Subscribing first, so no change committed in between is missed
+    let wakes = state.changes.subscribe(owner);
+    let after = streams::latest(&state.postgres, owner)
+        .await
+        .map_err(rpc::internal)?;
+
+    let subscription = Uuid::new_v4();
+    let stopped = Arc::new(AtomicBool::new(false));
+    subscriptions.0.insert(subscription, stopped.clone());
+
+    task::spawn(push(
+        state.clone(),
+        conn.clone(),
+        owner,
+        subscription,
+        after,
+        wakes,
+        stopped,
+    ));
+
+    rpc::ok(Subscription { subscription })
+}
+
+/// `contacts.unsubscribe`
+fn unsubscribe(subscriptions: &mut Subscriptions, params: Value) -> MethodResult<Uuid> {
+    let Subscription { subscription } = rpc::params(params)?;
+
+    let stopped = subscriptions
+        .0
+        .remove(&subscription)
+        .ok_or_else(rpc::not_found)?;
+    stopped.store(true, Ordering::Relaxed);
+
+    rpc::ok(subscription)
+}
+
+/// Sends the changes of `owner` after the `after` sequence number as they are committed,
+/// until the subscription is stopped or the client goes away
+async fn push(
+    state: State,
+    conn: WebSocketConnection,
+    owner: Uuid,
+    subscription: Uuid,
+    mut after: i64,
+    wakes: Receiver<()>,
+    stopped: Arc<AtomicBool>,
+) {
+    while !stopped.load(Ordering::Relaxed) {
+        let changes = match streams::changes(&state.postgres, owner, after).await {
+            Ok(changes) => changes,
+            Err(err) => {
+                log::error!("Error reading changes of {}: {:?}", owner, err);
+                Vec::new()
+            }
+        };
+
+        if changes.is_empty() {
+            // Waking up now and then to notice stopped subscriptions and lost notifications
+            let _ = future::timeout(streams::HEARTBEAT_INTERVAL, wakes.recv()).await;
+            continue;
+        }
+
+        for change in changes {
+            if stopped.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let notification = Notification {
+                method: "contacts.changed",
+                params: Changed {
+                    subscription,
+                    sequence: change.sequence,
+                    event: &change.event_type,
+                    contact: &change.contact.0,
+                },
+            };
+            if conn.send_json(&notification).await.is_err() {
+                return;
+            }
+            after = change.sequence;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycloak::KeycloakRealmAccess;
+    use serde_json::json;
+
+    fn claims(exp: i64) -> KeycloakClaims {
+        KeycloakClaims {
+            acr: String::from("1"),
+            allowed_origins: Vec::new(),
+            aud: String::from("account"),
+            auth_time: 0,
+            azp: String::from("frontend"),
+            email: None,
+            email_verified: false,
+            exp: exp as u32,
+            family_name: None,
+            given_name: None,
+            iat: 0,
+            iss: String::from("http://localhost:8081/auth/realms/demo"),
+            jti: Uuid::nil(),
+            name: None,
+            nonce: Uuid::nil(),
+            preferred_username: String::from("ada"),
+            realm_access: KeycloakRealmAccess { roles: Vec::new() },
+            session_state: Uuid::nil(),
+            sub: Uuid::nil(),
+            typ: String::from("Bearer"),
+        }
+    }
+
+    /// Error of an `auth` request with `params`
+    fn auth_error(params: Value) -> AppError {
+        authenticate(&JWKS::default(), params)
+            .unwrap_err()
+            .into_error()
+            .data
+    }
+
+    /// Paths and codes of the fields that failed
+    fn failed(error: AppError) -> Vec<(String, String)> {
+        match error {
+            AppError::ValidationFailed(errors) => errors
+                .errors
+                .into_iter()
+                .map(|error| (error.path, error.code))
+                .collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn sessions_expire_with_their_token() {
+        let now = Utc::now().timestamp();
+
+        let left = expires_in(&claims(now + 60));
+        assert!(left <= Duration::from_secs(60) && left >= Duration::from_secs(59));
+        assert_eq!(expires_in(&claims(now)), Duration::from_secs(0));
+        assert_eq!(expires_in(&claims(now - 60)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn auth_needs_a_token() {
+        assert_eq!(
+            failed(auth_error(json!({}))),
+            vec![(String::from("/token"), String::from("required"))]
+        );
+        assert_eq!(
+            failed(auth_error(Value::Null)),
+            vec![(String::from("/token"), String::from("required"))]
+        );
+        assert_eq!(
+            failed(auth_error(json!({ "token": 42 }))),
+            vec![(String::from("/token"), String::from("invalid_value"))]
+        );
+        assert!(matches!(
+            auth_error(json!("token")),
+            AppError::InvalidParams { .. }
+        ));
+    }
+
+    #[test]
+    fn auth_rejects_tokens_that_cannot_be_verified() {
+        assert_eq!(
+            auth_error(json!({ "token": "not.a.jwt" })),
+            AppError::Unauthorized
+        );
+    }
+
+    #[test]
+    fn unsubscribing_stops_the_subscription_once() {
+        let mut subscriptions = Subscriptions::default();
+        let subscription = Uuid::new_v4();
+        let stopped = Arc::new(AtomicBool::new(false));
+        subscriptions.0.insert(subscription, stopped.clone());
+
+        let params = json!({ "subscription": subscription });
+        assert!(unsubscribe(&mut subscriptions, params.clone()).is_ok());
+        assert!(stopped.load(Ordering::Relaxed));
+
+        let error = unsubscribe(&mut subscriptions, params)
+            .unwrap_err()
+            .into_error();
+        assert_eq!(error.data, AppError::NotFound);
+        assert_eq!(
+            failed(
+                unsubscribe(&mut subscriptions, json!({}))
+                    .unwrap_err()
+                    .into_error()
+                    .data
+            ),
+            vec![(String::from("/subscription"), String::from("required"))]
+        );
+    }
+
+    #[test]
+    fn ending_the_session_stops_its_subscriptions() {
+        let stopped: Vec<Arc<AtomicBool>> =
+            (0..2).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        let subscriptions = Subscriptions(
+            stopped
+                .iter()
+                .map(|stopped| (Uuid::new_v4(), stopped.clone()))
+                .collect(),
+        );
+
+        drop(subscriptions);
+        assert!(stopped
+            .iter()
+            .all(|stopped| stopped.load(Ordering::Relaxed)));
+    }
+}